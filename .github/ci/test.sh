@@ -13,16 +13,26 @@ export CARGO_TARGET_DIR=/ci/cache/target
 export CARGO_NET_GIT_FETCH_WITH_CLI=true
 
 cargo test --manifest-path ./embassy-executor/Cargo.toml
+cargo test --manifest-path ./embassy-executor/Cargo.toml --features task-priority
+cargo test --manifest-path ./embassy-executor/Cargo.toml --features join-handle
+cargo test --manifest-path ./embassy-executor/Cargo.toml --features arch-sim,executor-thread
+cargo test --manifest-path ./embassy-executor/Cargo.toml --features task-stats
+cargo test --manifest-path ./embassy-executor/Cargo.toml --features task-registry
 cargo test --manifest-path ./embassy-futures/Cargo.toml
+cargo test --manifest-path ./embassy-futures/Cargo.toml --features futures-set
 cargo test --manifest-path ./embassy-sync/Cargo.toml
 cargo test --manifest-path ./embassy-embedded-hal/Cargo.toml
 cargo test --manifest-path ./embassy-hal-internal/Cargo.toml
 cargo test --manifest-path ./embassy-time/Cargo.toml --features mock-driver,embassy-time-queue-utils/generic-queue-8
 cargo test --manifest-path ./embassy-time-driver/Cargo.toml
+cargo test --manifest-path ./embassy-time-queue-utils/Cargo.toml --features timer-wheel
 
 cargo test --manifest-path ./embassy-boot/Cargo.toml
 cargo test --manifest-path ./embassy-boot/Cargo.toml --features ed25519-dalek
 cargo test --manifest-path ./embassy-boot/Cargo.toml --features ed25519-salty
+cargo test --manifest-path ./embassy-boot/Cargo.toml --features image-header
+cargo test --manifest-path ./embassy-boot/Cargo.toml --features image-header,ed25519-dalek
+cargo test --manifest-path ./embassy-boot/Cargo.toml --features std
 
 cargo test --manifest-path ./embassy-nrf/Cargo.toml --no-default-features --features nrf52840,time-driver-rtc1,gpiote
 
@@ -33,4 +43,8 @@ cargo test --manifest-path ./embassy-stm32/Cargo.toml --no-default-features --fe
 cargo test --manifest-path ./embassy-stm32/Cargo.toml --no-default-features --features stm32f732ze,exti,time-driver-any,exti
 cargo test --manifest-path ./embassy-stm32/Cargo.toml --no-default-features --features stm32f769ni,exti,time-driver-any,exti
 
+cargo test --manifest-path ./embassy-net/Cargo.toml --features proto-ipv4,medium-ethernet,sntp
 cargo test --manifest-path ./embassy-net-adin1110/Cargo.toml
+cargo test --manifest-path ./embassy-http-server/Cargo.toml
+cargo test --manifest-path ./embassy-net-loopback/Cargo.toml
+cargo test --manifest-path ./embassy-mqtt/Cargo.toml
@@ -0,0 +1,663 @@
+use core::cell::RefCell;
+
+use embassy_embedded_hal::flash::partition::BlockingPartition;
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embedded_storage::nor_flash::NorFlash;
+
+use crate::boot_loader::{raise_security_counter, read_header, read_security_counter, validate_image};
+use crate::image_header::{ImageError, ImageHeader};
+use crate::{
+    state_data_len, BootError, State, DFU_DETACH_MAGIC, REJECTED_MAGIC, REVERT_MAGIC, STATE_ERASE_VALUE, SWAP_MAGIC,
+};
+
+/// A slot of an [`AbBootLoader`].
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Slot {
+    /// The first slot.
+    A,
+    /// The second slot.
+    B,
+}
+
+const SLOT_A_MAGIC: u8 = 0xA0;
+const SLOT_B_MAGIC: u8 = 0xA1;
+
+impl Slot {
+    /// The other slot.
+    pub const fn other(self) -> Slot {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+
+    /// Value of the state word recording that an update was written to this slot.
+    pub(crate) const fn magic(self) -> u8 {
+        match self {
+            Slot::A => SLOT_A_MAGIC,
+            Slot::B => SLOT_B_MAGIC,
+        }
+    }
+}
+
+/// A/B bootloader flash configuration holding the two slots and the state partition.
+pub struct AbBootLoaderConfig<A, B, STATE> {
+    /// Flash type used for the first slot.
+    pub slot_a: A,
+    /// Flash type used for the second slot.
+    pub slot_b: B,
+    /// Flash type used for the state partition.
+    pub state: STATE,
+}
+
+impl<'a, A: NorFlash, B: NorFlash, STATE: NorFlash>
+    AbBootLoaderConfig<
+        BlockingPartition<'a, NoopRawMutex, A>,
+        BlockingPartition<'a, NoopRawMutex, B>,
+        BlockingPartition<'a, NoopRawMutex, STATE>,
+    >
+{
+    /// Constructs an `AbBootLoaderConfig` instance from flash memory and address symbols defined in the linker file.
+    ///
+    /// The slots are defined by the `__bootloader_slot_a_start`, `__bootloader_slot_a_end`,
+    /// `__bootloader_slot_b_start` and `__bootloader_slot_b_end` symbols, and the state partition
+    /// by the `__bootloader_state_start` and `__bootloader_state_end` symbols.
+    ///
+    /// # Safety
+    /// The method contains `unsafe` blocks for dereferencing raw pointers that represent the start and end addresses
+    /// of the bootloader's partitions in flash memory. It is crucial that these addresses are accurately defined
+    /// in the memory.x file to prevent undefined behavior.
+    pub fn from_linkerfile_blocking(
+        slot_a_flash: &'a Mutex<NoopRawMutex, RefCell<A>>,
+        slot_b_flash: &'a Mutex<NoopRawMutex, RefCell<B>>,
+        state_flash: &'a Mutex<NoopRawMutex, RefCell<STATE>>,
+    ) -> Self {
+        extern "C" {
+            static __bootloader_state_start: u32;
+            static __bootloader_state_end: u32;
+            static __bootloader_slot_a_start: u32;
+            static __bootloader_slot_a_end: u32;
+            static __bootloader_slot_b_start: u32;
+            static __bootloader_slot_b_end: u32;
+        }
+
+        let slot_a = unsafe {
+            let start = &__bootloader_slot_a_start as *const u32 as u32;
+            let end = &__bootloader_slot_a_end as *const u32 as u32;
+            trace!("SLOT A: 0x{:x} - 0x{:x}", start, end);
+
+            BlockingPartition::new(slot_a_flash, start, end - start)
+        };
+        let slot_b = unsafe {
+            let start = &__bootloader_slot_b_start as *const u32 as u32;
+            let end = &__bootloader_slot_b_end as *const u32 as u32;
+            trace!("SLOT B: 0x{:x} - 0x{:x}", start, end);
+
+            BlockingPartition::new(slot_b_flash, start, end - start)
+        };
+        let state = unsafe {
+            let start = &__bootloader_state_start as *const u32 as u32;
+            let end = &__bootloader_state_end as *const u32 as u32;
+            trace!("STATE: 0x{:x} - 0x{:x}", start, end);
+
+            BlockingPartition::new(state_flash, start, end - start)
+        };
+
+        Self { slot_a, slot_b, state }
+    }
+}
+
+/// Bootloader booting images in place from one of two slots, without swapping them.
+///
+/// Images start with an `ImageHeader`, and the bootloader boots the valid image with the newest
+/// version. The application updates the firmware with a [`FirmwareUpdater`](crate::FirmwareUpdater)
+/// whose DFU partition is the slot it isn't running from, and marks it updated with
+/// [`FirmwareState::mark_updated_slot()`](crate::FirmwareState::mark_updated_slot), which records
+/// the slot written to. The bootloader then boots the new image on trial: if the application
+/// doesn't mark it booted before the next boot, the new image is erased, and the bootloader falls
+/// back to the other slot.
+///
+/// The images are run from the slot they're booted from, so they must either be position
+/// independent, built for the slot they're written to, or run from flash remapped by the
+/// hardware.
+pub struct AbBootLoader<A: NorFlash, B: NorFlash, STATE: NorFlash> {
+    slot_a: A,
+    slot_b: B,
+    /// The state partition has the following format:
+    /// All ranges are in multiples of WRITE_SIZE bytes.
+    /// | Range | Description                                                                        |
+    /// | 0..1  | Magic indicating bootloader state. BOOT_MAGIC means boot, SWAP_MAGIC means trial.  |
+    /// | 1..2  | Slot the update was written to, recorded by the updater with SWAP_MAGIC.           |
+    /// | 2..3  | Written when the trial boot of slot A starts.                                      |
+    /// | 3..4  | Written when the trial boot of slot B starts.                                      |
+    ///
+    /// The last erase page of the state partition holds the security counter.
+    state: STATE,
+    /// Public key the images must be signed with.
+    #[cfg(feature = "_verify")]
+    public_key: Option<[u8; 32]>,
+}
+
+impl<A: NorFlash, B: NorFlash, STATE: NorFlash> AbBootLoader<A, B, STATE> {
+    /// Create a new instance of an A/B bootloader with the flash partitions.
+    pub fn new(config: AbBootLoaderConfig<A, B, STATE>) -> Self {
+        Self {
+            slot_a: config.slot_a,
+            slot_b: config.slot_b,
+            state: config.state,
+            #[cfg(feature = "_verify")]
+            public_key: None,
+        }
+    }
+
+    /// Only boot images signed with the private key matching `public_key`.
+    ///
+    /// The signature of an image follows its header, see `ImageHeader`.
+    #[cfg(feature = "_verify")]
+    pub fn with_public_key(mut self, public_key: [u8; 32]) -> Self {
+        self.public_key = Some(public_key);
+        self
+    }
+
+    /// Select the slot to boot, and perform the state transitions of trial boots.
+    ///
+    /// The provided aligned_buf argument must satisfy any alignment requirements
+    /// given by the partition flashes. All flash operations will use this buffer.
+    ///
+    /// An image is valid if its hash (and signature, with a public key) match, and its security
+    /// counter isn't lower than the one stored in the state partition. The valid image with the
+    /// newest version is booted, slot A first if both have the same version. The returned state
+    /// is:
+    ///
+    /// - [`State::Swap`] when the application marked the firmware updated: the image in the slot
+    ///   recorded by the updater is booted on trial. The application must mark it booted,
+    ///   otherwise it's erased on the next boot.
+    /// - [`State::Revert`] when the trial boot failed, and the other slot is booted instead.
+    /// - [`State::Rejected`] when the application marked the firmware updated, but the update is
+    ///   invalid, isn't newer than the image in the other slot, or no slot was recorded for it.
+    ///   The update is erased from its slot, and the newest valid image is booted instead. The update is also rejected, but booted, if the
+    ///   other slot doesn't hold a valid image, as there would be nothing to fall back to.
+    /// - [`State::Boot`] or [`State::DfuDetach`] otherwise. When booting normally, the stored
+    ///   security counter is raised to the one of the booted image.
+    ///
+    /// The firmware starts `header_size` bytes after the start of the returned slot, see
+    /// [`header`](Self::header).
+    pub fn prepare_boot(&mut self, aligned_buf: &mut [u8]) -> Result<(State, Slot), BootError> {
+        assert!(aligned_buf.len() >= STATE::WRITE_SIZE);
+        assert_eq!(0, aligned_buf.len() % A::WRITE_SIZE);
+        assert_eq!(0, aligned_buf.len() % B::WRITE_SIZE);
+        assert_partitions(&self.slot_a, &self.slot_b, &self.state);
+
+        let state = self.read_state(aligned_buf)?;
+        if state != State::Swap {
+            let (slot, header) = self.select_slot(aligned_buf)?;
+            if state == State::Boot {
+                raise_security_counter(&mut self.state, header.security_counter, aligned_buf)?;
+            }
+            return Ok((state, slot));
+        }
+
+        match self.trial_slot(aligned_buf)? {
+            None => {
+                let Some(slot) = self.target_slot(aligned_buf)? else {
+                    warn!("Rejecting update, the slot it was written to isn't recorded");
+                    return self.reject_update(None, aligned_buf);
+                };
+
+                let update = match self.validate_slot(slot, aligned_buf) {
+                    Ok(header) => header,
+                    Err(BootError::Image(e)) => {
+                        warn!("Rejecting update, slot {:?} is invalid: {:?}", slot, e);
+                        return self.reject_update(Some(slot), aligned_buf);
+                    }
+                    Err(e) => return Err(e),
+                };
+                match self.validate_slot(slot.other(), aligned_buf) {
+                    Ok(running) if update.version <= running.version => {
+                        warn!(
+                            "Rejecting update, it isn't newer than the image in slot {:?}",
+                            slot.other()
+                        );
+                        return self.reject_update(Some(slot), aligned_buf);
+                    }
+                    Ok(_) => {}
+                    Err(BootError::Image(e)) => {
+                        warn!("Rejecting update, slot {:?} is invalid: {:?}", slot.other(), e);
+                        self.set_magic(REJECTED_MAGIC, aligned_buf)?;
+                        return Ok((State::Rejected, slot));
+                    }
+                    Err(e) => return Err(e),
+                }
+
+                trace!("Trial boot of slot {:?}", slot);
+                self.start_trial(slot, aligned_buf)?;
+                Ok((State::Swap, slot))
+            }
+            Some(slot) => {
+                if self.validate_slot(slot.other(), aligned_buf).is_err() {
+                    // There is nothing to fall back to, keep on trying.
+                    let (slot, _) = self.select_slot(aligned_buf)?;
+                    return Ok((State::Swap, slot));
+                }
+
+                trace!("Trial boot of slot {:?} failed, reverting", slot);
+                self.erase_header(slot)?;
+                self.set_magic(REVERT_MAGIC, aligned_buf)?;
+                Ok((State::Revert, slot.other()))
+            }
+        }
+    }
+
+    /// Reject the update, erasing it if the slot it was written to is known, and boot the newest
+    /// valid image.
+    fn reject_update(&mut self, slot: Option<Slot>, aligned_buf: &mut [u8]) -> Result<(State, Slot), BootError> {
+        if let Some(slot) = slot {
+            self.erase_header(slot)?;
+        }
+        self.set_magic(REJECTED_MAGIC, aligned_buf)?;
+        let (slot, _) = self.select_slot(aligned_buf)?;
+        Ok((State::Rejected, slot))
+    }
+
+    /// Read the header of the image in `slot`.
+    pub fn header(&mut self, slot: Slot, aligned_buf: &mut [u8]) -> Result<ImageHeader, BootError> {
+        match slot {
+            Slot::A => read_header(&mut self.slot_a, aligned_buf),
+            Slot::B => read_header(&mut self.slot_b, aligned_buf),
+        }
+    }
+
+    /// Read the security counter stored in the state partition.
+    pub fn security_counter(&mut self, aligned_buf: &mut [u8]) -> Result<u32, BootError> {
+        read_security_counter(&mut self.state, aligned_buf)
+    }
+
+    /// Select the valid slot with the newest image.
+    fn select_slot(&mut self, aligned_buf: &mut [u8]) -> Result<(Slot, ImageHeader), BootError> {
+        let first = match (self.header(Slot::A, aligned_buf), self.header(Slot::B, aligned_buf)) {
+            (Ok(a), Ok(b)) if b.version > a.version => Slot::B,
+            (Err(BootError::Image(_)), Ok(_)) => Slot::B,
+            (Err(e @ BootError::Flash(_)), _) | (_, Err(e @ BootError::Flash(_))) => return Err(e),
+            _ => Slot::A,
+        };
+
+        match self.validate_slot(first, aligned_buf) {
+            Ok(header) => Ok((first, header)),
+            Err(BootError::Image(e)) => {
+                warn!("Slot {:?} is invalid: {:?}", first, e);
+                let header = self.validate_slot(first.other(), aligned_buf)?;
+                Ok((first.other(), header))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn validate_slot(&mut self, slot: Slot, aligned_buf: &mut [u8]) -> Result<ImageHeader, BootError> {
+        #[cfg(feature = "_verify")]
+        let public_key = self.public_key.as_ref();
+        #[cfg(not(feature = "_verify"))]
+        let public_key = None;
+
+        let header = match slot {
+            Slot::A => validate_image(&mut self.slot_a, public_key, aligned_buf)?,
+            Slot::B => validate_image(&mut self.slot_b, public_key, aligned_buf)?,
+        };
+
+        let max = (STATE::ERASE_SIZE / STATE::WRITE_SIZE) as u32;
+        let counter = read_security_counter(&mut self.state, aligned_buf)?;
+        if header.security_counter < counter || header.security_counter > max {
+            return Err(BootError::Image(ImageError::SecurityCounter));
+        }
+        Ok(header)
+    }
+
+    /// Erase the first page of `slot`, which holds the header of its image.
+    fn erase_header(&mut self, slot: Slot) -> Result<(), BootError> {
+        match slot {
+            Slot::A => self.slot_a.erase(0, A::ERASE_SIZE as u32)?,
+            Slot::B => self.slot_b.erase(0, B::ERASE_SIZE as u32)?,
+        }
+        Ok(())
+    }
+
+    fn trial_offset(slot: Slot) -> u32 {
+        let index = match slot {
+            Slot::A => 2,
+            Slot::B => 3,
+        };
+        index * STATE::WRITE_SIZE as u32
+    }
+
+    /// Read which slot the updater wrote the update to, if it recorded it.
+    fn target_slot(&mut self, aligned_buf: &mut [u8]) -> Result<Option<Slot>, BootError> {
+        let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
+        self.state.read(STATE::WRITE_SIZE as u32, state_word)?;
+        Ok([Slot::A, Slot::B]
+            .into_iter()
+            .find(|slot| !state_word.iter().any(|&b| b != slot.magic())))
+    }
+
+    /// Read which slot is booted on trial, if any.
+    fn trial_slot(&mut self, aligned_buf: &mut [u8]) -> Result<Option<Slot>, BootError> {
+        let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
+        for slot in [Slot::A, Slot::B] {
+            self.state.read(Self::trial_offset(slot), state_word)?;
+            if state_word.iter().any(|&b| b != STATE_ERASE_VALUE) {
+                return Ok(Some(slot));
+            }
+        }
+        Ok(None)
+    }
+
+    fn start_trial(&mut self, slot: Slot, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
+        state_word.fill(!STATE_ERASE_VALUE);
+        self.state.write(Self::trial_offset(slot), state_word)?;
+        Ok(())
+    }
+
+    fn set_magic(&mut self, magic: u8, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
+
+        // Clear magic, target slot and trial records
+        let len = state_data_len(self.state.capacity(), STATE::ERASE_SIZE);
+        self.state.erase(0, len as u32)?;
+
+        // Set magic
+        state_word.fill(magic);
+        self.state.write(0, state_word)?;
+        Ok(())
+    }
+
+    fn read_state(&mut self, aligned_buf: &mut [u8]) -> Result<State, BootError> {
+        let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
+        self.state.read(0, state_word)?;
+
+        if !state_word.iter().any(|&b| b != SWAP_MAGIC) {
+            Ok(State::Swap)
+        } else if !state_word.iter().any(|&b| b != DFU_DETACH_MAGIC) {
+            Ok(State::DfuDetach)
+        } else if !state_word.iter().any(|&b| b != REVERT_MAGIC) {
+            Ok(State::Revert)
+        } else if !state_word.iter().any(|&b| b != REJECTED_MAGIC) {
+            Ok(State::Rejected)
+        } else {
+            Ok(State::Boot)
+        }
+    }
+}
+
+fn assert_partitions<A: NorFlash, B: NorFlash, STATE: NorFlash>(slot_a: &A, slot_b: &B, state: &STATE) {
+    assert_eq!(slot_a.capacity() % A::ERASE_SIZE, 0);
+    assert_eq!(slot_b.capacity() % B::ERASE_SIZE, 0);
+    assert!(slot_a.capacity() >= A::ERASE_SIZE);
+    assert!(slot_b.capacity() >= B::ERASE_SIZE);
+    // The security counter needs an erase page of its own
+    assert!(state.capacity() >= 2 * STATE::ERASE_SIZE);
+    assert!(4 * STATE::WRITE_SIZE <= state_data_len(state.capacity(), STATE::ERASE_SIZE));
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_storage::nor_flash::ReadNorFlash;
+
+    use super::*;
+    use crate::fault_flash::{Fault, FaultFlash, PowerSupply};
+    use crate::image_header::ImageVersion;
+    use crate::mem_flash::MemFlash;
+    use crate::{BlockingFirmwareState, BlockingFirmwareUpdater, FirmwareUpdaterConfig, FirmwareUpdaterError};
+
+    const STATE_SIZE: u32 = 8192;
+    const SLOT_SIZE: u32 = 16384;
+    const IMAGE_SIZE: usize = 4096 + 256;
+
+    type TestMemFlash = MemFlash<{ (STATE_SIZE + 2 * SLOT_SIZE) as usize }, 4096, 4>;
+    type TestFlash<F = TestMemFlash> = Mutex<NoopRawMutex, RefCell<F>>;
+
+    fn state<F: NorFlash>(flash: &TestFlash<F>) -> BlockingPartition<'_, NoopRawMutex, F> {
+        BlockingPartition::new(flash, 0, STATE_SIZE)
+    }
+
+    fn slot<F: NorFlash>(flash: &TestFlash<F>, slot: Slot) -> BlockingPartition<'_, NoopRawMutex, F> {
+        match slot {
+            Slot::A => BlockingPartition::new(flash, STATE_SIZE, SLOT_SIZE),
+            Slot::B => BlockingPartition::new(flash, STATE_SIZE + SLOT_SIZE, SLOT_SIZE),
+        }
+    }
+
+    fn image(minor: u8, security_counter: u32, fill: u8) -> [u8; IMAGE_SIZE] {
+        let version = ImageVersion {
+            major: 1,
+            minor,
+            patch: 0,
+        };
+        let mut image = [fill; IMAGE_SIZE];
+        let header = ImageHeader::new(256, version, security_counter, &image[256..]);
+        image[..256].fill(0);
+        image[..ImageHeader::SIZE].copy_from_slice(&header.to_bytes());
+        image
+    }
+
+    /// Write `update` to `to` and mark it updated, as the application running from the other slot would.
+    fn try_update<F: NorFlash>(flash: &TestFlash<F>, to: Slot, update: &[u8]) -> Result<(), FirmwareUpdaterError> {
+        let mut aligned = [0; 4];
+        let mut updater = BlockingFirmwareUpdater::new(
+            FirmwareUpdaterConfig {
+                dfu: slot(flash, to),
+                state: state(flash),
+            },
+            &mut aligned,
+        );
+        updater.write_firmware(0, update)?;
+
+        let mut aligned = [0; 4];
+        BlockingFirmwareState::new(state(flash), &mut aligned).mark_updated_slot(to)
+    }
+
+    fn update<F: NorFlash>(flash: &TestFlash<F>, to: Slot, update: &[u8]) {
+        try_update(flash, to, update).unwrap();
+    }
+
+    fn try_mark_booted<F: NorFlash>(flash: &TestFlash<F>) -> Result<(), FirmwareUpdaterError> {
+        let mut aligned = [0; 4];
+        BlockingFirmwareState::new(state(flash), &mut aligned).mark_booted()
+    }
+
+    fn mark_booted<F: NorFlash>(flash: &TestFlash<F>) {
+        try_mark_booted(flash).unwrap();
+    }
+
+    fn boot<F: NorFlash>(flash: &TestFlash<F>) -> Result<(State, Slot), BootError> {
+        let mut aligned_buf = [0; 1024];
+        AbBootLoader::new(AbBootLoaderConfig {
+            slot_a: slot(flash, Slot::A),
+            slot_b: slot(flash, Slot::B),
+            state: state(flash),
+        })
+        .prepare_boot(&mut aligned_buf)
+    }
+
+    fn new_flash(a: &[u8]) -> TestFlash {
+        let flash = Mutex::new(RefCell::new(MemFlash::default()));
+        slot(&flash, Slot::A).write(0, a).unwrap();
+        flash
+    }
+
+    #[test]
+    fn test_ab_trial_boot() {
+        let flash = new_flash(&image(0, 0, 0x55));
+        assert_eq!((State::Boot, Slot::A), boot(&flash).unwrap());
+
+        update(&flash, Slot::B, &image(1, 0, 0xAA));
+        assert_eq!((State::Swap, Slot::B), boot(&flash).unwrap());
+        mark_booted(&flash);
+        assert_eq!((State::Boot, Slot::B), boot(&flash).unwrap());
+        assert_eq!((State::Boot, Slot::B), boot(&flash).unwrap());
+
+        update(&flash, Slot::A, &image(2, 0, 0x11));
+        assert_eq!((State::Swap, Slot::A), boot(&flash).unwrap());
+        mark_booted(&flash);
+        assert_eq!((State::Boot, Slot::A), boot(&flash).unwrap());
+    }
+
+    #[test]
+    fn test_ab_revert() {
+        let original = image(0, 0, 0x55);
+        let flash = new_flash(&original);
+        assert_eq!((State::Boot, Slot::A), boot(&flash).unwrap());
+
+        update(&flash, Slot::B, &image(1, 0, 0xAA));
+        assert_eq!((State::Swap, Slot::B), boot(&flash).unwrap());
+
+        // The update isn't marked booted.
+        assert_eq!((State::Revert, Slot::A), boot(&flash).unwrap());
+        assert_eq!((State::Revert, Slot::A), boot(&flash).unwrap());
+
+        let mut aligned_buf = [0; 4];
+        let header = read_header(&mut slot(&flash, Slot::B), &mut aligned_buf);
+        assert_eq!(Err(BootError::Image(ImageError::BadMagic)), header);
+        let mut read_buf = [0; IMAGE_SIZE];
+        slot(&flash, Slot::A).read(0, &mut read_buf).unwrap();
+        assert_eq!(original, read_buf);
+
+        mark_booted(&flash);
+        assert_eq!((State::Boot, Slot::A), boot(&flash).unwrap());
+    }
+
+    #[test]
+    fn test_ab_rejected() {
+        let flash = new_flash(&image(0, 0, 0x55));
+
+        let mut corrupted = image(1, 0, 0xAA);
+        corrupted[IMAGE_SIZE - 1] = 0;
+        update(&flash, Slot::B, &corrupted);
+        assert_eq!((State::Rejected, Slot::A), boot(&flash).unwrap());
+        assert_eq!((State::Rejected, Slot::A), boot(&flash).unwrap());
+
+        // A new valid update replaces the rejected one.
+        update(&flash, Slot::B, &image(1, 0, 0xAA));
+        assert_eq!((State::Swap, Slot::B), boot(&flash).unwrap());
+    }
+
+    #[test]
+    fn test_ab_security_counter() {
+        let flash = new_flash(&image(0, 0, 0x55));
+        assert_eq!((State::Boot, Slot::A), boot(&flash).unwrap());
+
+        update(&flash, Slot::B, &image(1, 2, 0xAA));
+        assert_eq!((State::Swap, Slot::B), boot(&flash).unwrap());
+        mark_booted(&flash);
+        assert_eq!((State::Boot, Slot::B), boot(&flash).unwrap());
+
+        let mut aligned_buf = [0; 4];
+        assert_eq!(2, read_security_counter(&mut state(&flash), &mut aligned_buf).unwrap());
+
+        // The image in slot A has a lower security counter, and can't be booted anymore.
+        update(&flash, Slot::A, &image(2, 1, 0x11));
+        assert_eq!((State::Rejected, Slot::B), boot(&flash).unwrap());
+    }
+
+    #[test]
+    fn test_ab_older_update() {
+        let flash = new_flash(&image(0, 0, 0x55));
+        update(&flash, Slot::B, &image(2, 0, 0xAA));
+        assert_eq!((State::Swap, Slot::B), boot(&flash).unwrap());
+        mark_booted(&flash);
+
+        // An update older than the running image is rejected, the running image is kept.
+        update(&flash, Slot::A, &image(1, 0, 0x11));
+        assert_eq!((State::Rejected, Slot::B), boot(&flash).unwrap());
+        assert_eq!((State::Rejected, Slot::B), boot(&flash).unwrap());
+        let mut aligned_buf = [0; 4];
+        assert!(read_header(&mut slot(&flash, Slot::B), &mut aligned_buf).is_ok());
+        let header = read_header(&mut slot(&flash, Slot::A), &mut aligned_buf);
+        assert_eq!(Err(BootError::Image(ImageError::BadMagic)), header);
+
+        // So is an update with the same version.
+        update(&flash, Slot::A, &image(2, 0, 0x11));
+        assert_eq!((State::Rejected, Slot::B), boot(&flash).unwrap());
+        mark_booted(&flash);
+        assert_eq!((State::Boot, Slot::B), boot(&flash).unwrap());
+    }
+
+    #[test]
+    fn test_ab_unrecorded_update() {
+        let flash = new_flash(&image(0, 0, 0x55));
+        assert_eq!((State::Boot, Slot::A), boot(&flash).unwrap());
+
+        // Marking the update without the slot it was written to doesn't start a trial boot, the
+        // newest image is booted as usual.
+        slot(&flash, Slot::B).write(0, &image(1, 0, 0xAA)).unwrap();
+        let mut aligned = [0; 4];
+        BlockingFirmwareState::new(state(&flash), &mut aligned)
+            .mark_updated()
+            .unwrap();
+        assert_eq!((State::Rejected, Slot::B), boot(&flash).unwrap());
+        assert_eq!((State::Rejected, Slot::B), boot(&flash).unwrap());
+    }
+
+    /// Cut the power after every number of flash operations during an A/B update, and check that
+    /// a reboot boots the slot holding the original or the updated image.
+    fn power_fail_update(fault: Fault) {
+        let original = image(0, 0, 0x55);
+        let new = image(1, 0, 0xAA);
+        let mut read_buf = [0; IMAGE_SIZE];
+
+        for cut_after in 0.. {
+            let power = PowerSupply::new();
+            let flash = Mutex::new(RefCell::new(FaultFlash::new(TestMemFlash::default(), &power)));
+            slot(&flash, Slot::A).write(0, &original).unwrap();
+            assert_eq!((State::Boot, Slot::A), boot(&flash).unwrap());
+
+            power.cut_after(cut_after, fault);
+            let _ = try_update(&flash, Slot::B, &new)
+                .map_err(|_| ())
+                .and_then(|_| boot(&flash).map_err(|_| ()))
+                .and_then(|_| try_mark_booted(&flash).map_err(|_| ()))
+                .and_then(|_| boot(&flash).map_err(|_| ()));
+            let power_cut = power.is_cut();
+            power.restore();
+
+            if !power_cut {
+                // The whole update ran, after trying every cut.
+                assert_eq!((State::Boot, Slot::B), boot(&flash).unwrap());
+                assert!(cut_after > 5);
+                break;
+            }
+
+            let (_, booted) = boot(&flash).unwrap();
+            slot(&flash, booted).read(0, &mut read_buf).unwrap();
+            if booted == Slot::A {
+                assert!(
+                    read_buf == original,
+                    "Corrupted slot A, power cut after {} operations",
+                    cut_after
+                );
+                // The original firmware can update again.
+                mark_booted(&flash);
+                update(&flash, Slot::B, &new);
+                assert_eq!((State::Swap, Slot::B), boot(&flash).unwrap());
+            } else {
+                assert!(
+                    read_buf == new,
+                    "Corrupted slot B, power cut after {} operations",
+                    cut_after
+                );
+            }
+            mark_booted(&flash);
+            assert_eq!((State::Boot, Slot::B), boot(&flash).unwrap());
+        }
+    }
+
+    #[test]
+    fn test_ab_power_fail_update() {
+        power_fail_update(Fault::Atomic);
+    }
+
+    #[test]
+    fn test_ab_power_fail_torn_erase() {
+        power_fail_update(Fault::TornErase);
+    }
+}
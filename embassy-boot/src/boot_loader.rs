@@ -5,7 +5,9 @@ use embassy_sync::blocking_mutex::raw::NoopRawMutex;
 use embassy_sync::blocking_mutex::Mutex;
 use embedded_storage::nor_flash::{NorFlash, NorFlashError, NorFlashErrorKind};
 
-use crate::{State, DFU_DETACH_MAGIC, REVERT_MAGIC, STATE_ERASE_VALUE, SWAP_MAGIC};
+#[cfg(feature = "image-header")]
+use crate::image_header::{ImageError, ImageHeader};
+use crate::{state_data_len, State, DFU_DETACH_MAGIC, REJECTED_MAGIC, REVERT_MAGIC, STATE_ERASE_VALUE, SWAP_MAGIC};
 
 /// Errors returned by bootloader
 #[derive(PartialEq, Eq, Debug)]
@@ -14,6 +16,9 @@ pub enum BootError {
     Flash(NorFlashErrorKind),
     /// Invalid bootloader magic
     BadMagic,
+    /// Invalid firmware image.
+    #[cfg(feature = "image-header")]
+    Image(ImageError),
 }
 
 #[cfg(feature = "defmt")]
@@ -22,6 +27,8 @@ impl defmt::Format for BootError {
         match self {
             BootError::Flash(_) => defmt::write!(fmt, "BootError::Flash(_)"),
             BootError::BadMagic => defmt::write!(fmt, "BootError::BadMagic"),
+            #[cfg(feature = "image-header")]
+            BootError::Image(e) => defmt::write!(fmt, "BootError::Image({})", e),
         }
     }
 }
@@ -139,7 +146,13 @@ pub struct BootLoader<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> {
     /// | 0..1     | Magic indicating bootloader state. BOOT_MAGIC means boot, SWAP_MAGIC means swap. |
     /// | 1..2     | Progress validity. ERASE_VALUE means valid, !ERASE_VALUE means invalid.          |
     /// | 2..2 + N | Progress index used while swapping or reverting      
+    ///
+    /// With the `image-header` feature, the last erase page of the state partition holds the
+    /// security counter, as the number of words that are not erased at its start. It is never erased.
     state: STATE,
+    /// Public key the images must be signed with, and whether to verify the active image on every boot.
+    #[cfg(all(feature = "image-header", feature = "_verify"))]
+    verify: Option<([u8; 32], bool)>,
 }
 
 impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, STATE> {
@@ -159,9 +172,29 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
             active: config.active,
             dfu: config.dfu,
             state: config.state,
+            #[cfg(all(feature = "image-header", feature = "_verify"))]
+            verify: None,
         }
     }
 
+    /// Verify the signature of the images with `public_key`.
+    ///
+    /// Updates which aren't signed with the matching private key are rejected, even if the
+    /// application marked them as updated. If `verify_active` is set, the active image is also
+    /// verified on every boot, and [`prepare_boot`](Self::prepare_boot) returns an error instead
+    /// of booting it if it isn't signed.
+    ///
+    /// The active image is verified after a swap or a revert in any case, as the application can
+    /// write the state partition to resume a swap that was never validated. An update which fails
+    /// the verification once swapped in is reverted and [`State::Rejected`] is returned.
+    ///
+    /// The signature of an image follows its header, see `ImageHeader`.
+    #[cfg(all(feature = "image-header", feature = "_verify"))]
+    pub fn with_public_key(mut self, public_key: [u8; 32], verify_active: bool) -> Self {
+        self.verify = Some((public_key, verify_active));
+        self
+    }
+
     /// Perform necessary boot preparations like swapping images.
     ///
     /// The DFU partition is assumed to be 1 page bigger than the active partition for the swap
@@ -234,6 +267,19 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
     /// |    Active |            3 |      1 |      2 |      3 |      - |
     /// |       DFU |            3 |      4 |      5 |      6 |      3 |
     ///
+    /// ## IMAGE HEADERS
+    ///
+    /// With the `image-header` feature, the images start with an `ImageHeader`. Before swapping,
+    /// the image in the DFU partition is validated: its hash must match, its version must not be
+    /// older than the active image, and its security counter must not be lower than the one stored
+    /// in the state partition. If it's invalid, the partitions aren't swapped, and
+    /// [`State::Rejected`] is returned.
+    ///
+    /// The stored security counter is raised to the one of the active image when booting it
+    /// normally, that is once the application has marked it as booted.
+    ///
+    /// With a signature feature, the bootloader can also verify the signature of the images, see
+    /// [`with_public_key`](Self::with_public_key).
     pub fn prepare_boot(&mut self, aligned_buf: &mut [u8]) -> Result<State, BootError> {
         const {
             core::assert!(Self::PAGE_SIZE % ACTIVE::WRITE_SIZE as u32 == 0);
@@ -253,34 +299,162 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
 
         // Copy contents from partition N to active
         let state = self.read_state(aligned_buf)?;
+        #[cfg(all(feature = "image-header", feature = "_verify"))]
+        let mut swapped = false;
         if state == State::Swap {
             //
             // Check if we already swapped. If we're in the swap state, this means we should revert
             // since the app has failed to mark boot as successful
             //
             if !self.is_swapped(aligned_buf)? {
+                // Validate the update before starting to swap, as the DFU partition is modified by the swap.
+                #[cfg(feature = "image-header")]
+                if self.current_progress(aligned_buf)? == 0 {
+                    match self.validate_update(aligned_buf) {
+                        Ok(()) => {}
+                        Err(BootError::Image(e)) => {
+                            warn!("Rejecting update: {:?}", e);
+                            self.set_magic(REJECTED_MAGIC, aligned_buf)?;
+                            return Ok(State::Rejected);
+                        }
+                        Err(e) => return Err(e),
+                    }
+                }
+
                 trace!("Swapping");
                 self.swap(aligned_buf)?;
                 trace!("Swapping done");
+                #[cfg(all(feature = "image-header", feature = "_verify"))]
+                {
+                    swapped = true;
+                }
             } else {
                 trace!("Reverting");
                 self.revert(aligned_buf)?;
+                self.set_magic(REVERT_MAGIC, aligned_buf)?;
+            }
+        }
 
-                let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
+        // The application can write the state partition, so it could forge the progress of a swap to
+        // skip the validation of the update. Validate the active image whenever the partitions were
+        // swapped or reverted, and revert an update which doesn't pass.
+        #[cfg(all(feature = "image-header", feature = "_verify"))]
+        if let Some((public_key, verify_active)) = self.verify {
+            if verify_active || state == State::Swap {
+                match self.validate_active(&public_key, aligned_buf) {
+                    Ok(()) => {}
+                    Err(BootError::Image(e)) if swapped => {
+                        warn!("Reverting invalid update: {:?}", e);
+                        self.revert(aligned_buf)?;
+                        self.set_magic(REJECTED_MAGIC, aligned_buf)?;
+                        if let Err(e) = self.validate_active(&public_key, aligned_buf) {
+                            warn!("Refusing to boot the active image: {:?}", e);
+                            return Err(e);
+                        }
+                        return Ok(State::Rejected);
+                    }
+                    Err(e) => {
+                        warn!("Refusing to boot the active image: {:?}", e);
+                        return Err(e);
+                    }
+                }
+            }
+        }
+
+        #[cfg(feature = "image-header")]
+        if state == State::Boot {
+            self.update_security_counter(aligned_buf)?;
+        }
 
-                // Invalidate progress
-                state_word.fill(!STATE_ERASE_VALUE);
-                self.state.write(STATE::WRITE_SIZE as u32, state_word)?;
+        Ok(state)
+    }
 
-                // Clear magic and progress
-                self.state.erase(0, self.state.capacity() as u32)?;
+    fn set_magic(&mut self, magic: u8, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
 
-                // Set magic
-                state_word.fill(REVERT_MAGIC);
-                self.state.write(0, state_word)?;
-            }
+        // Invalidate progress, unless a power loss left it invalidated already
+        self.state.read(STATE::WRITE_SIZE as u32, state_word)?;
+        if state_word.iter().all(|&b| b == STATE_ERASE_VALUE) {
+            state_word.fill(!STATE_ERASE_VALUE);
+            self.state.write(STATE::WRITE_SIZE as u32, state_word)?;
         }
-        Ok(state)
+
+        // Clear magic and progress
+        let len = state_data_len(self.state.capacity(), STATE::ERASE_SIZE);
+        self.state.erase(0, len as u32)?;
+
+        // Set magic
+        state_word.fill(magic);
+        self.state.write(0, state_word)?;
+        Ok(())
+    }
+
+    /// Read the header of the image in the active partition.
+    ///
+    /// The firmware starts `header_size` bytes after the start of the active partition.
+    #[cfg(feature = "image-header")]
+    pub fn active_header(&mut self, aligned_buf: &mut [u8]) -> Result<ImageHeader, BootError> {
+        read_header(&mut self.active, aligned_buf)
+    }
+
+    #[cfg(feature = "image-header")]
+    fn validate_update(&mut self, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        #[cfg(feature = "_verify")]
+        let public_key = self.verify.as_ref().map(|(public_key, _)| public_key);
+        #[cfg(not(feature = "_verify"))]
+        let public_key = None;
+
+        let header = validate_image(&mut self.dfu, public_key, aligned_buf)?;
+        if header.total_len() > self.active.capacity() as u64 {
+            return Err(BootError::Image(ImageError::BadLength));
+        }
+
+        // An active partition without a valid header accepts any version.
+        match read_header(&mut self.active, aligned_buf) {
+            Ok(active) if header.version < active.version => return Err(BootError::Image(ImageError::Downgrade)),
+            Ok(_) | Err(BootError::Image(_)) => {}
+            Err(e) => return Err(e),
+        }
+
+        self.check_security_counter(&header, aligned_buf)
+    }
+
+    /// Validate the image in the active partition, including its security counter.
+    #[cfg(all(feature = "image-header", feature = "_verify"))]
+    fn validate_active(&mut self, public_key: &[u8; 32], aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        let header = validate_image(&mut self.active, Some(public_key), aligned_buf)?;
+        self.check_security_counter(&header, aligned_buf)
+    }
+
+    /// Check that the security counter of `header` isn't lower than the stored one.
+    #[cfg(feature = "image-header")]
+    fn check_security_counter(&mut self, header: &ImageHeader, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        let counter = self.security_counter(aligned_buf)?;
+        if header.security_counter < counter || header.security_counter > Self::MAX_SECURITY_COUNTER {
+            return Err(BootError::Image(ImageError::SecurityCounter));
+        }
+        Ok(())
+    }
+
+    /// Highest security counter that the state partition can store.
+    #[cfg(feature = "image-header")]
+    const MAX_SECURITY_COUNTER: u32 = (STATE::ERASE_SIZE / STATE::WRITE_SIZE) as u32;
+
+    /// Read the security counter stored in the state partition.
+    #[cfg(feature = "image-header")]
+    pub fn security_counter(&mut self, aligned_buf: &mut [u8]) -> Result<u32, BootError> {
+        read_security_counter(&mut self.state, aligned_buf)
+    }
+
+    /// Raise the stored security counter to the one of the active image.
+    #[cfg(feature = "image-header")]
+    fn update_security_counter(&mut self, aligned_buf: &mut [u8]) -> Result<(), BootError> {
+        let header = match read_header(&mut self.active, aligned_buf) {
+            Ok(header) => header,
+            Err(BootError::Image(_)) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        raise_security_counter(&mut self.state, header.security_counter, aligned_buf)
     }
 
     fn is_swapped(&mut self, aligned_buf: &mut [u8]) -> Result<bool, BootError> {
@@ -292,7 +466,8 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
 
     fn current_progress(&mut self, aligned_buf: &mut [u8]) -> Result<usize, BootError> {
         let write_size = STATE::WRITE_SIZE as u32;
-        let max_index = ((self.state.capacity() - STATE::WRITE_SIZE) / STATE::WRITE_SIZE) - 2;
+        let state_len = state_data_len(self.state.capacity(), STATE::ERASE_SIZE);
+        let max_index = ((state_len - STATE::WRITE_SIZE) / STATE::WRITE_SIZE) - 2;
         let state_word = &mut aligned_buf[..write_size as usize];
 
         self.state.read(write_size, state_word)?;
@@ -413,6 +588,8 @@ impl<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash> BootLoader<ACTIVE, DFU, S
             Ok(State::DfuDetach)
         } else if !state_word.iter().any(|&b| b != REVERT_MAGIC) {
             Ok(State::Revert)
+        } else if !state_word.iter().any(|&b| b != REJECTED_MAGIC) {
+            Ok(State::Rejected)
         } else {
             Ok(State::Boot)
         }
@@ -429,7 +606,155 @@ fn assert_partitions<ACTIVE: NorFlash, DFU: NorFlash, STATE: NorFlash>(
     assert_eq!(dfu.capacity() as u32 % page_size, 0);
     // DFU partition has to be bigger than ACTIVE partition to handle swap algorithm
     assert!(dfu.capacity() as u32 - active.capacity() as u32 >= page_size);
-    assert!(2 + 2 * (active.capacity() as u32 / page_size) <= state.capacity() as u32 / STATE::WRITE_SIZE as u32);
+    // The security counter needs an erase page of its own
+    #[cfg(feature = "image-header")]
+    assert!(state.capacity() >= 2 * STATE::ERASE_SIZE);
+    let state_len = state_data_len(state.capacity(), STATE::ERASE_SIZE) as u32;
+    assert!(2 + 2 * (active.capacity() as u32 / page_size) <= state_len / STATE::WRITE_SIZE as u32);
+}
+
+/// Read the security counter stored in the last erase page of the state partition.
+#[cfg(feature = "image-header")]
+pub(crate) fn read_security_counter<STATE: NorFlash>(
+    state: &mut STATE,
+    aligned_buf: &mut [u8],
+) -> Result<u32, BootError> {
+    let max = (STATE::ERASE_SIZE / STATE::WRITE_SIZE) as u32;
+    let base = state_data_len(state.capacity(), STATE::ERASE_SIZE) as u32;
+    let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
+    for index in 0..max {
+        state.read(base + index * STATE::WRITE_SIZE as u32, state_word)?;
+        if !state_word.iter().any(|&b| b != STATE_ERASE_VALUE) {
+            return Ok(index);
+        }
+    }
+    Ok(max)
+}
+
+/// Raise the security counter stored in the state partition to `target`.
+#[cfg(feature = "image-header")]
+pub(crate) fn raise_security_counter<STATE: NorFlash>(
+    state: &mut STATE,
+    target: u32,
+    aligned_buf: &mut [u8],
+) -> Result<(), BootError> {
+    let target = core::cmp::min(target, (STATE::ERASE_SIZE / STATE::WRITE_SIZE) as u32);
+    let counter = read_security_counter(state, aligned_buf)?;
+    if counter < target {
+        trace!("Raising security counter from {} to {}", counter, target);
+    }
+
+    let base = state_data_len(state.capacity(), STATE::ERASE_SIZE) as u32;
+    let state_word = &mut aligned_buf[..STATE::WRITE_SIZE];
+    state_word.fill(!STATE_ERASE_VALUE);
+    for index in counter..target {
+        state.write(base + index * STATE::WRITE_SIZE as u32, state_word)?;
+    }
+    Ok(())
+}
+
+/// Read `bytes.len()` bytes at `offset` of a partition, through `aligned_buf`.
+#[cfg(feature = "image-header")]
+fn read_bytes<F: NorFlash>(
+    flash: &mut F,
+    offset: u32,
+    bytes: &mut [u8],
+    aligned_buf: &mut [u8],
+) -> Result<(), BootError> {
+    let chunk_size = aligned_buf.len();
+    for (index, chunk) in bytes.chunks_mut(chunk_size).enumerate() {
+        let buf = &mut aligned_buf[..chunk.len()];
+        flash.read(offset + (index * chunk_size) as u32, buf)?;
+        chunk.copy_from_slice(buf);
+    }
+    Ok(())
+}
+
+/// Read the header at the start of a partition.
+#[cfg(feature = "image-header")]
+pub(crate) fn read_header<F: NorFlash>(flash: &mut F, aligned_buf: &mut [u8]) -> Result<ImageHeader, BootError> {
+    let mut bytes = [0; ImageHeader::SIZE];
+    read_bytes(flash, 0, &mut bytes, aligned_buf)?;
+    ImageHeader::from_bytes(&bytes).map_err(BootError::Image)
+}
+
+/// Validate the image at the start of a partition: check the hash of the firmware, and its
+/// signature if a public key is given.
+#[cfg(feature = "image-header")]
+pub(crate) fn validate_image<F: NorFlash>(
+    flash: &mut F,
+    public_key: Option<&[u8; 32]>,
+    aligned_buf: &mut [u8],
+) -> Result<ImageHeader, BootError> {
+    use sha2::{Digest, Sha256};
+
+    let header = read_header(flash, aligned_buf)?;
+    if header.total_len() > flash.capacity() as u64 {
+        return Err(BootError::Image(ImageError::BadLength));
+    }
+
+    if let Some(_public_key) = public_key {
+        #[cfg(feature = "_verify")]
+        {
+            if (header.header_size as usize) < ImageHeader::SIZE + ImageHeader::SIGNATURE_SIZE {
+                return Err(BootError::Image(ImageError::BadSignature));
+            }
+            let mut signature = [0; ImageHeader::SIGNATURE_SIZE];
+            read_bytes(flash, ImageHeader::SIZE as u32, &mut signature, aligned_buf)?;
+            if !verify_signature(_public_key, &signature, &header.to_bytes()) {
+                return Err(BootError::Image(ImageError::BadSignature));
+            }
+        }
+    }
+
+    let mut digest = Sha256::new();
+    let end = header.total_len() as u32;
+    for offset in (header.header_size as u32..end).step_by(aligned_buf.len()) {
+        let len = core::cmp::min((end - offset) as usize, aligned_buf.len());
+        // Only read whole chunks while they fit in the partition.
+        if offset as usize + aligned_buf.len() <= flash.capacity() {
+            flash.read(offset, aligned_buf)?;
+        } else {
+            flash.read(offset, &mut aligned_buf[..len])?;
+        }
+        digest.update(&aligned_buf[..len]);
+    }
+    if digest.finalize().as_slice() != header.hash {
+        return Err(BootError::Image(ImageError::BadHash));
+    }
+    Ok(header)
+}
+
+/// Verify the ed25519 signature of the SHA-512 hash of `message`.
+#[cfg(all(feature = "image-header", feature = "_verify"))]
+fn verify_signature(public_key: &[u8; 32], signature: &[u8; 64], message: &[u8]) -> bool {
+    #[cfg(feature = "ed25519-dalek")]
+    {
+        use digest::Digest;
+        use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+        use crate::digest_adapters::ed25519_dalek::Sha512;
+
+        let Ok(public_key) = VerifyingKey::from_bytes(public_key) else {
+            return false;
+        };
+        let signature = Signature::from_bytes(signature);
+        let message = Sha512::digest(message);
+        public_key.verify(&message, &signature).is_ok()
+    }
+    #[cfg(all(feature = "ed25519-salty", not(feature = "ed25519-dalek")))]
+    {
+        use digest::Digest;
+        use salty::{PublicKey, Signature};
+
+        use crate::digest_adapters::salty::Sha512;
+
+        let (Ok(public_key), Ok(signature)) = (PublicKey::try_from(public_key), Signature::try_from(signature)) else {
+            return false;
+        };
+        let message = Sha512::digest(message);
+        public_key.verify(&message, &signature).is_ok()
+    }
 }
 
 #[cfg(test)]
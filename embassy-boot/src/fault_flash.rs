@@ -0,0 +1,194 @@
+#![allow(unused)]
+
+use core::cell::Cell;
+
+use embedded_storage::nor_flash::{ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash};
+use embedded_storage_async::nor_flash::{NorFlash as AsyncNorFlash, ReadNorFlash as AsyncReadNorFlash};
+
+/// How the operation during which the power is cut changes the flash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// The operation doesn't change the flash.
+    Atomic,
+    /// An erase only erases the first half of its range, and leaves the rest unchanged. A write
+    /// doesn't change the flash.
+    TornErase,
+}
+
+/// Power supply of the flashes of a test, which can be cut after a number of operations.
+pub struct PowerSupply {
+    /// Number of writes and erases left before the power is cut.
+    remaining: Cell<Option<usize>>,
+    fault: Cell<Fault>,
+    cut: Cell<bool>,
+}
+
+impl PowerSupply {
+    pub const fn new() -> Self {
+        Self {
+            remaining: Cell::new(None),
+            fault: Cell::new(Fault::Atomic),
+            cut: Cell::new(false),
+        }
+    }
+
+    /// Cut the power after `ops` more writes or erases, which complete, and during the next one.
+    pub fn cut_after(&self, ops: usize, fault: Fault) {
+        self.remaining.set(Some(ops));
+        self.fault.set(fault);
+        self.cut.set(false);
+    }
+
+    /// Restore the power, and stop cutting it.
+    pub fn restore(&self) {
+        self.remaining.set(None);
+        self.cut.set(false);
+    }
+
+    /// Whether the power was cut.
+    pub fn is_cut(&self) -> bool {
+        self.cut.get()
+    }
+
+    fn check(&self) -> Result<(), PowerCut> {
+        if self.cut.get() {
+            Err(PowerCut)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn operate(&self) -> Result<(), PowerCut> {
+        self.check()?;
+        match self.remaining.get() {
+            Some(0) => {
+                self.cut.set(true);
+                Err(PowerCut)
+            }
+            Some(remaining) => {
+                self.remaining.set(Some(remaining - 1));
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PowerCut;
+
+/// Flash failing every operation once its power supply is cut.
+///
+/// The operation during which the power is cut changes the flash according to the [`Fault`] of
+/// the cut.
+pub struct FaultFlash<'a, F> {
+    flash: F,
+    power: &'a PowerSupply,
+}
+
+impl<'a, F> FaultFlash<'a, F> {
+    pub fn new(flash: F, power: &'a PowerSupply) -> Self {
+        Self { flash, power }
+    }
+}
+
+#[derive(Debug)]
+pub enum FaultFlashError<E> {
+    PowerCut,
+    Flash(E),
+}
+
+impl<E: NorFlashError> NorFlashError for FaultFlashError<E> {
+    fn kind(&self) -> NorFlashErrorKind {
+        match self {
+            FaultFlashError::PowerCut => NorFlashErrorKind::Other,
+            FaultFlashError::Flash(e) => e.kind(),
+        }
+    }
+}
+
+impl<E> From<PowerCut> for FaultFlashError<E> {
+    fn from(_: PowerCut) -> Self {
+        FaultFlashError::PowerCut
+    }
+}
+
+impl<F: ErrorType> ErrorType for FaultFlash<'_, F> {
+    type Error = FaultFlashError<F::Error>;
+}
+
+impl<F: ReadNorFlash> ReadNorFlash for FaultFlash<'_, F> {
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.power.check()?;
+        self.flash.read(offset, bytes).map_err(FaultFlashError::Flash)
+    }
+
+    fn capacity(&self) -> usize {
+        self.flash.capacity()
+    }
+}
+
+impl<F: NorFlash> NorFlash for FaultFlash<'_, F> {
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.power.operate()?;
+        self.flash.write(offset, bytes).map_err(FaultFlashError::Flash)
+    }
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.power.check()?;
+        if let Err(cut) = self.power.operate() {
+            if self.power.fault.get() == Fault::TornErase {
+                let mid = from + (to - from) / 2;
+                let mut kept = vec![0; (to - mid) as usize];
+                self.flash.read(mid, &mut kept).map_err(FaultFlashError::Flash)?;
+                self.flash.erase(from, to).map_err(FaultFlashError::Flash)?;
+                self.flash.write(mid, &kept).map_err(FaultFlashError::Flash)?;
+            }
+            return Err(cut.into());
+        }
+        self.flash.erase(from, to).map_err(FaultFlashError::Flash)
+    }
+}
+
+impl<F: AsyncReadNorFlash> AsyncReadNorFlash for FaultFlash<'_, F> {
+    const READ_SIZE: usize = F::READ_SIZE;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.power.check()?;
+        self.flash.read(offset, bytes).await.map_err(FaultFlashError::Flash)
+    }
+
+    fn capacity(&self) -> usize {
+        self.flash.capacity()
+    }
+}
+
+impl<F: AsyncNorFlash> AsyncNorFlash for FaultFlash<'_, F> {
+    const WRITE_SIZE: usize = F::WRITE_SIZE;
+    const ERASE_SIZE: usize = F::ERASE_SIZE;
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.power.operate()?;
+        self.flash.write(offset, bytes).await.map_err(FaultFlashError::Flash)
+    }
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.power.check()?;
+        if let Err(cut) = self.power.operate() {
+            if self.power.fault.get() == Fault::TornErase {
+                let mid = from + (to - from) / 2;
+                let mut kept = vec![0; (to - mid) as usize];
+                self.flash.read(mid, &mut kept).await.map_err(FaultFlashError::Flash)?;
+                self.flash.erase(from, to).await.map_err(FaultFlashError::Flash)?;
+                self.flash.write(mid, &kept).await.map_err(FaultFlashError::Flash)?;
+            }
+            return Err(cut.into());
+        }
+        self.flash.erase(from, to).await.map_err(FaultFlashError::Flash)
+    }
+}
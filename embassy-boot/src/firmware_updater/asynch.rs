@@ -3,10 +3,13 @@ use digest::Digest;
 use embassy_embedded_hal::flash::partition::Partition;
 #[cfg(target_os = "none")]
 use embassy_sync::blocking_mutex::raw::NoopRawMutex;
-use embedded_storage_async::nor_flash::NorFlash;
+use embedded_storage_async::nor_flash::{NorFlash, ReadNorFlash};
 
+use super::patch::{Op, PatchDecoder};
 use super::FirmwareUpdaterConfig;
-use crate::{FirmwareUpdaterError, State, BOOT_MAGIC, DFU_DETACH_MAGIC, STATE_ERASE_VALUE, SWAP_MAGIC};
+#[cfg(feature = "image-header")]
+use crate::Slot;
+use crate::{state_data_len, FirmwareUpdaterError, State, BOOT_MAGIC, DFU_DETACH_MAGIC, STATE_ERASE_VALUE, SWAP_MAGIC};
 
 /// FirmwareUpdater is an application API for interacting with the BootLoader without the ability to
 /// 'mess up' the internal bootloader state
@@ -255,6 +258,188 @@ impl<'d, DFU: NorFlash, STATE: NorFlash> FirmwareUpdater<'d, DFU, STATE> {
 
         Ok(&mut self.dfu)
     }
+
+    /// Write a compressed or delta update to the DFU partition.
+    ///
+    /// The update is a patch, see [`PATCH_MAGIC`](crate::PATCH_MAGIC) for its format, which is
+    /// expanded into the DFU partition from its start as it's written to the returned writer.
+    /// Delta updates are relative to `active`, which isn't read by other patches.
+    ///
+    /// `buf` holds the firmware before it's written to the DFU partition, and the data copied
+    /// within the firmware and from `active`: its length must be a multiple of twice
+    /// `DFU::WRITE_SIZE`. Both flashes must be readable at any offset.
+    pub fn patch_writer<'a, ACTIVE: ReadNorFlash>(
+        &'a mut self,
+        active: ACTIVE,
+        buf: &'a mut [u8],
+    ) -> PatchWriter<'a, 'd, DFU, STATE, ACTIVE> {
+        assert_eq!(DFU::READ_SIZE, 1);
+        assert_eq!(ACTIVE::READ_SIZE, 1);
+        assert!(!buf.is_empty());
+        assert_eq!(buf.len() % (2 * DFU::WRITE_SIZE), 0);
+
+        let (pending, scratch) = buf.split_at_mut(buf.len() / 2);
+        PatchWriter {
+            updater: self,
+            active,
+            decoder: PatchDecoder::new(),
+            pending,
+            scratch,
+            pending_len: 0,
+            flushed: 0,
+        }
+    }
+}
+
+/// Writer expanding a compressed or delta update into the DFU partition.
+///
+/// Created with [`FirmwareUpdater::patch_writer`].
+pub struct PatchWriter<'a, 'd, DFU: NorFlash, STATE: NorFlash, ACTIVE: ReadNorFlash> {
+    updater: &'a mut FirmwareUpdater<'d, DFU, STATE>,
+    active: ACTIVE,
+    decoder: PatchDecoder,
+    /// Firmware not yet written to the DFU partition.
+    pending: &'a mut [u8],
+    scratch: &'a mut [u8],
+    pending_len: usize,
+    /// Length of the firmware written to the DFU partition.
+    flushed: usize,
+}
+
+impl<'a, 'd, DFU: NorFlash, STATE: NorFlash, ACTIVE: ReadNorFlash> PatchWriter<'a, 'd, DFU, STATE, ACTIVE> {
+    /// Write the next bytes of the patch.
+    ///
+    /// The patch can be split in any way.
+    pub async fn write(&mut self, mut data: &[u8]) -> Result<(), FirmwareUpdaterError> {
+        while let Some(op) = self.decoder.decode(&mut data)? {
+            match op {
+                Op::Literal(bytes) => {
+                    for chunk in bytes.chunks(self.scratch.len()) {
+                        self.scratch[..chunk.len()].copy_from_slice(chunk);
+                        self.push(chunk.len()).await?;
+                    }
+                }
+                Op::Fill { byte, len } => {
+                    let mut remaining = len as usize;
+                    while remaining > 0 {
+                        let n = core::cmp::min(remaining, self.scratch.len());
+                        self.scratch[..n].fill(byte);
+                        self.push(n).await?;
+                        remaining -= n;
+                    }
+                }
+                Op::CopyOutput { distance, len } => {
+                    let distance = distance as usize;
+                    if distance == 0 || distance > self.len() {
+                        return Err(FirmwareUpdaterError::BadPatch);
+                    }
+                    let mut remaining = len as usize;
+                    while remaining > 0 {
+                        // Only copy what's already written, when copying overlaps the output.
+                        let n = core::cmp::min(core::cmp::min(remaining, distance), self.scratch.len());
+                        self.read_output(self.len() - distance, n).await?;
+                        self.push(n).await?;
+                        remaining -= n;
+                    }
+                }
+                Op::CopyActive { offset, len } => {
+                    self.check_active(offset, len as usize)?;
+                    let mut offset = offset;
+                    let mut remaining = len as usize;
+                    while remaining > 0 {
+                        let n = core::cmp::min(remaining, self.scratch.len());
+                        self.active.read(offset, &mut self.scratch[..n]).await?;
+                        self.push(n).await?;
+                        offset += n as u32;
+                        remaining -= n;
+                    }
+                }
+                Op::AddActive { offset, diff } => {
+                    self.check_active(offset, diff.len())?;
+                    let mut offset = offset;
+                    for chunk in diff.chunks(self.scratch.len()) {
+                        let n = chunk.len();
+                        self.active.read(offset, &mut self.scratch[..n]).await?;
+                        for (byte, diff) in self.scratch[..n].iter_mut().zip(chunk) {
+                            *byte = byte.wrapping_add(*diff);
+                        }
+                        self.push(n).await?;
+                        offset += n as u32;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the end of the firmware to the DFU partition, and return its length.
+    ///
+    /// Fails if the patch is truncated.
+    pub async fn finish(self) -> Result<usize, FirmwareUpdaterError> {
+        if !self.decoder.is_idle() {
+            return Err(FirmwareUpdaterError::BadPatch);
+        }
+        let len = self.len();
+        if self.pending_len > 0 {
+            // Pad the last write.
+            let padded = self.pending_len.next_multiple_of(DFU::WRITE_SIZE);
+            self.pending[self.pending_len..padded].fill(0xFF);
+            self.updater
+                .write_firmware(self.flushed, &self.pending[..padded])
+                .await?;
+        }
+        Ok(len)
+    }
+
+    /// Length of the firmware expanded so far.
+    fn len(&self) -> usize {
+        self.flushed + self.pending_len
+    }
+
+    fn check_active(&self, offset: u32, len: usize) -> Result<(), FirmwareUpdaterError> {
+        match (offset as usize).checked_add(len) {
+            Some(end) if end <= self.active.capacity() => Ok(()),
+            _ => Err(FirmwareUpdaterError::BadPatch),
+        }
+    }
+
+    /// Read `n` bytes of the firmware expanded so far at `offset` into the scratch buffer.
+    async fn read_output(&mut self, offset: usize, n: usize) -> Result<(), FirmwareUpdaterError> {
+        let from_flash = self.flushed.saturating_sub(offset).min(n);
+        if from_flash > 0 {
+            self.updater
+                .dfu
+                .read(offset as u32, &mut self.scratch[..from_flash])
+                .await?;
+        }
+        if from_flash < n {
+            let start = offset + from_flash - self.flushed;
+            self.scratch[from_flash..n].copy_from_slice(&self.pending[start..start + n - from_flash]);
+        }
+        Ok(())
+    }
+
+    /// Append the first `n` bytes of the scratch buffer to the firmware.
+    async fn push(&mut self, n: usize) -> Result<(), FirmwareUpdaterError> {
+        if self.len() + n > self.updater.dfu.capacity() {
+            return Err(FirmwareUpdaterError::BadPatch);
+        }
+        let mut written = 0;
+        while written < n {
+            let count = core::cmp::min(n - written, self.pending.len() - self.pending_len);
+            self.pending[self.pending_len..self.pending_len + count]
+                .copy_from_slice(&self.scratch[written..written + count]);
+            self.pending_len += count;
+            written += count;
+
+            if self.pending_len == self.pending.len() {
+                self.updater.write_firmware(self.flushed, self.pending).await?;
+                self.flushed += self.pending_len;
+                self.pending_len = 0;
+            }
+        }
+        Ok(())
+    }
 }
 
 /// Manages the state partition of the firmware update.
@@ -290,7 +475,7 @@ impl<'d, STATE: NorFlash> FirmwareState<'d, STATE> {
     // Make sure we are running a booted firmware to avoid reverting to a bad state.
     async fn verify_booted(&mut self) -> Result<(), FirmwareUpdaterError> {
         let state = self.get_state().await?;
-        if state == State::Boot || state == State::DfuDetach || state == State::Revert {
+        if matches!(state, State::Boot | State::DfuDetach | State::Revert | State::Rejected) {
             Ok(())
         } else {
             Err(FirmwareUpdaterError::BadState)
@@ -312,6 +497,36 @@ impl<'d, STATE: NorFlash> FirmwareState<'d, STATE> {
         self.set_magic(SWAP_MAGIC).await
     }
 
+    /// Mark to trigger a trial boot of the update written to `slot` on next boot, with an
+    /// [`AbBootLoader`](crate::AbBootLoader).
+    #[cfg(feature = "image-header")]
+    pub async fn mark_updated_slot(&mut self, slot: Slot) -> Result<(), FirmwareUpdaterError> {
+        if self.get_state().await? == State::Swap {
+            // Read the target slot
+            if STATE::READ_SIZE < 2 * STATE::WRITE_SIZE {
+                self.state.read(STATE::WRITE_SIZE as u32, self.aligned).await?;
+            } else {
+                self.aligned.rotate_left(STATE::WRITE_SIZE);
+            }
+            if !self.aligned[..STATE::WRITE_SIZE].iter().any(|&b| b != slot.magic()) {
+                return Ok(());
+            }
+        }
+
+        // Clear magic and target slot
+        let len = state_data_len(self.state.capacity(), STATE::ERASE_SIZE);
+        self.state.erase(0, len as u32).await?;
+
+        // Record the target slot before setting the magic, so the update is never marked without it
+        self.aligned.fill(slot.magic());
+        self.state
+            .write(STATE::WRITE_SIZE as u32, &self.aligned[..STATE::WRITE_SIZE])
+            .await?;
+        self.aligned.fill(SWAP_MAGIC);
+        self.state.write(0, &self.aligned[..STATE::WRITE_SIZE]).await?;
+        Ok(())
+    }
+
     /// Mark to trigger USB DFU on next boot.
     pub async fn mark_dfu(&mut self) -> Result<(), FirmwareUpdaterError> {
         self.set_magic(DFU_DETACH_MAGIC).await
@@ -347,7 +562,8 @@ impl<'d, STATE: NorFlash> FirmwareState<'d, STATE> {
             }
 
             // Clear magic and progress
-            self.state.erase(0, self.state.capacity() as u32).await?;
+            let len = state_data_len(self.state.capacity(), STATE::ERASE_SIZE);
+            self.state.erase(0, len as u32).await?;
 
             // Set magic
             self.aligned.fill(magic);
@@ -366,6 +582,7 @@ mod tests {
     use sha1::{Digest, Sha1};
 
     use super::*;
+    use crate::firmware_updater::patch::PatchBuilder;
     use crate::mem_flash::MemFlash;
 
     #[test]
@@ -459,4 +676,113 @@ mod tests {
 
         assert_eq!(Sha1::digest(update).as_slice(), hash);
     }
+
+    #[test]
+    fn can_write_compressed_patch() {
+        let flash = Mutex::<NoopRawMutex, _>::new(MemFlash::<131072, 4096, 8>::default());
+        let state = Partition::new(&flash, 0, 4096);
+        let active = Partition::new(&flash, 4096, 61440);
+        let dfu = Partition::new(&flash, 65536, 65536);
+        let mut aligned = [0; 8];
+
+        let patch = PatchBuilder::new()
+            .literal(b"embassy")
+            .fill(0xAB, 5000)
+            .copy_output(5007, 7)
+            .literal(&[1, 2, 3])
+            .copy_output(3, 1000);
+        let mut expected = [0; 6017];
+        expected[..7].copy_from_slice(b"embassy");
+        expected[7..5007].fill(0xAB);
+        expected[5007..5014].copy_from_slice(b"embassy");
+        for (i, byte) in expected[5014..].iter_mut().enumerate() {
+            *byte = [1, 2, 3][i % 3];
+        }
+
+        let mut updater = FirmwareUpdater::new(FirmwareUpdaterConfig { dfu, state }, &mut aligned);
+        let mut buf = [0; 64];
+        let mut writer = updater.patch_writer(active, &mut buf);
+        for chunk in patch.as_bytes().chunks(5) {
+            block_on(writer.write(chunk)).unwrap();
+        }
+        assert_eq!(expected.len(), block_on(writer.finish()).unwrap());
+
+        let mut written = [0; 6017];
+        block_on(updater.dfu.read(0, &mut written)).unwrap();
+        assert_eq!(expected, written);
+    }
+
+    #[test]
+    fn can_write_delta_patch() {
+        let flash = Mutex::<NoopRawMutex, _>::new(MemFlash::<131072, 4096, 8>::default());
+        let state = Partition::new(&flash, 0, 4096);
+        let mut active = Partition::new(&flash, 4096, 61440);
+        let dfu = Partition::new(&flash, 65536, 65536);
+        let mut aligned = [0; 8];
+
+        let mut old = [0; 4096];
+        for (i, byte) in old.iter_mut().enumerate() {
+            *byte = (i * 7) as u8;
+        }
+        block_on(active.write(0, &old)).unwrap();
+
+        // Move code, and change it slightly.
+        let patch = PatchBuilder::new()
+            .copy_active(1000, 3000)
+            .add_active(0, &[1; 1000])
+            .literal(&[9; 8]);
+        let mut expected = [0; 4008];
+        expected[..3000].copy_from_slice(&old[1000..4000]);
+        for (byte, old) in expected[3000..4000].iter_mut().zip(&old[..1000]) {
+            *byte = old.wrapping_add(1);
+        }
+        expected[4000..].fill(9);
+
+        let mut updater = FirmwareUpdater::new(FirmwareUpdaterConfig { dfu, state }, &mut aligned);
+        let mut buf = [0; 32];
+        let mut writer = updater.patch_writer(active, &mut buf);
+        for chunk in patch.as_bytes().chunks(100) {
+            block_on(writer.write(chunk)).unwrap();
+        }
+        assert_eq!(expected.len(), block_on(writer.finish()).unwrap());
+
+        let mut written = [0; 4008];
+        block_on(updater.dfu.read(0, &mut written)).unwrap();
+        assert_eq!(expected, written);
+    }
+
+    #[test]
+    fn rejects_bad_patch() {
+        let flash = Mutex::<NoopRawMutex, _>::new(MemFlash::<131072, 4096, 8>::default());
+        let state = Partition::new(&flash, 0, 4096);
+        let mut active = Partition::new(&flash, 4096, 61440);
+        let dfu = Partition::new(&flash, 65536, 65536);
+        let mut aligned = [0; 8];
+        let mut updater = FirmwareUpdater::new(FirmwareUpdaterConfig { dfu, state }, &mut aligned);
+        let mut buf = [0; 32];
+
+        // Copy from before the start of the firmware.
+        let patch = PatchBuilder::new().literal(&[1]).copy_output(2, 1);
+        let mut writer = updater.patch_writer(&mut active, &mut buf);
+        let result = block_on(writer.write(patch.as_bytes()));
+        assert!(matches!(result, Err(FirmwareUpdaterError::BadPatch)));
+
+        // Copy from past the end of the active partition.
+        let patch = PatchBuilder::new().copy_active(61000, 1000);
+        let mut writer = updater.patch_writer(&mut active, &mut buf);
+        let result = block_on(writer.write(patch.as_bytes()));
+        assert!(matches!(result, Err(FirmwareUpdaterError::BadPatch)));
+
+        // Firmware larger than the DFU partition.
+        let patch = PatchBuilder::new().fill(0, 65537);
+        let mut writer = updater.patch_writer(&mut active, &mut buf);
+        let result = block_on(writer.write(patch.as_bytes()));
+        assert!(matches!(result, Err(FirmwareUpdaterError::BadPatch)));
+
+        // Truncated patch.
+        let patch = PatchBuilder::new().literal(&[1, 2, 3]);
+        let mut writer = updater.patch_writer(&mut active, &mut buf);
+        block_on(writer.write(&patch.as_bytes()[..patch.as_bytes().len() - 1])).unwrap();
+        assert!(matches!(block_on(writer.finish()), Err(FirmwareUpdaterError::BadPatch)));
+    }
 }
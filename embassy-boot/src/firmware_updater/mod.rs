@@ -1,9 +1,13 @@
 mod asynch;
 mod blocking;
+mod patch;
 
-pub use asynch::{FirmwareState, FirmwareUpdater};
-pub use blocking::{BlockingFirmwareState, BlockingFirmwareUpdater};
+pub use asynch::{FirmwareState, FirmwareUpdater, PatchWriter};
+pub use blocking::{BlockingFirmwareState, BlockingFirmwareUpdater, BlockingPatchWriter};
 use embedded_storage::nor_flash::{NorFlashError, NorFlashErrorKind};
+#[cfg(feature = "std")]
+pub use patch::PatchBuilder;
+pub use patch::PATCH_MAGIC;
 
 /// Firmware updater flash configuration holding the two flashes used by the updater
 ///
@@ -26,6 +30,8 @@ pub enum FirmwareUpdaterError {
     Signature(signature::Error),
     /// Bad state.
     BadState,
+    /// Invalid compressed or delta update.
+    BadPatch,
 }
 
 #[cfg(feature = "defmt")]
@@ -35,6 +41,7 @@ impl defmt::Format for FirmwareUpdaterError {
             FirmwareUpdaterError::Flash(_) => defmt::write!(fmt, "FirmwareUpdaterError::Flash(_)"),
             FirmwareUpdaterError::Signature(_) => defmt::write!(fmt, "FirmwareUpdaterError::Signature(_)"),
             FirmwareUpdaterError::BadState => defmt::write!(fmt, "FirmwareUpdaterError::BadState"),
+            FirmwareUpdaterError::BadPatch => defmt::write!(fmt, "FirmwareUpdaterError::BadPatch"),
         }
     }
 }
@@ -0,0 +1,497 @@
+//! Decoder of compressed and delta updates.
+//!
+//! A patch describes the new firmware as a sequence of operations, each producing the next bytes
+//! of the firmware. It starts with [`PATCH_MAGIC`], followed by the operations. An operation is a
+//! tag byte followed by its arguments, encoded as unsigned LEB128 varints of up to 32 bits:
+//!
+//! | Tag | Arguments          | Description                                                                 |
+//! |-----|--------------------|-----------------------------------------------------------------------------|
+//! | 0   | `len`              | Literal: the `len` bytes following the arguments.                           |
+//! | 1   | `len`, `byte`      | Fill: `len` times `byte`.                                                   |
+//! | 2   | `distance`, `len`  | Copy `len` bytes of the firmware, from `distance` bytes before the output.  |
+//! | 3   | `offset`, `len`    | Copy `len` bytes of the active partition, from `offset`.                    |
+//! | 4   | `offset`, `len`    | Add the `len` bytes following the arguments to the active partition bytes from `offset`. |
+//!
+//! Literals, fills and copies of the firmware compress the image, with the firmware already
+//! written to the DFU partition used as the window, so that no RAM is needed for it. Copies of the
+//! active partition and additions to it make a binary delta, in the style of bsdiff: code which
+//! moved is copied, and code which changed slightly, like the addresses in it, is added to.
+//!
+//! The copy of the firmware can overlap the output, to repeat a pattern.
+//!
+//! Patches are encoded on the host with `PatchBuilder`, available with the `std` feature.
+
+use crate::FirmwareUpdaterError;
+
+/// Magic at the start of a patch.
+pub const PATCH_MAGIC: [u8; 4] = *b"EMBP";
+
+const TAG_LITERAL: u8 = 0;
+const TAG_FILL: u8 = 1;
+const TAG_COPY_OUTPUT: u8 = 2;
+const TAG_COPY_ACTIVE: u8 = 3;
+const TAG_ADD_ACTIVE: u8 = 4;
+
+/// Operation decoded from a patch.
+///
+/// Literals and additions are split when their bytes are split between several inputs.
+#[derive(PartialEq, Eq, Debug)]
+pub(crate) enum Op<'i> {
+    Literal(&'i [u8]),
+    Fill { byte: u8, len: u32 },
+    CopyOutput { distance: u32, len: u32 },
+    CopyActive { offset: u32, len: u32 },
+    AddActive { offset: u32, diff: &'i [u8] },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum DecodeState {
+    Magic(u8),
+    Tag,
+    Args { tag: u8, index: u8, shift: u8 },
+    Literal { remaining: u32 },
+    AddActive { offset: u32, remaining: u32 },
+}
+
+/// Streaming decoder of a patch.
+pub(crate) struct PatchDecoder {
+    state: DecodeState,
+    args: [u32; 2],
+}
+
+impl PatchDecoder {
+    pub(crate) const fn new() -> Self {
+        Self {
+            state: DecodeState::Magic(0),
+            args: [0; 2],
+        }
+    }
+
+    /// Whether the decoder is between two operations, so that the patch can end.
+    pub(crate) fn is_idle(&self) -> bool {
+        matches!(
+            self.state,
+            DecodeState::Tag | DecodeState::Literal { remaining: 0 } | DecodeState::AddActive { remaining: 0, .. }
+        )
+    }
+
+    /// Decode the next operation from `input`, consuming the bytes it used.
+    ///
+    /// Returns `None` when `input` is exhausted.
+    pub(crate) fn decode<'i>(&mut self, input: &mut &'i [u8]) -> Result<Option<Op<'i>>, FirmwareUpdaterError> {
+        loop {
+            match self.state {
+                DecodeState::Literal { remaining } if remaining > 0 => {
+                    if input.is_empty() {
+                        return Ok(None);
+                    }
+                    let bytes = take(input, remaining);
+                    self.state = DecodeState::Literal {
+                        remaining: remaining - bytes.len() as u32,
+                    };
+                    return Ok(Some(Op::Literal(bytes)));
+                }
+                DecodeState::AddActive { offset, remaining } if remaining > 0 => {
+                    if input.is_empty() {
+                        return Ok(None);
+                    }
+                    let diff = take(input, remaining);
+                    let next = offset
+                        .checked_add(diff.len() as u32)
+                        .ok_or(FirmwareUpdaterError::BadPatch)?;
+                    self.state = DecodeState::AddActive {
+                        offset: next,
+                        remaining: remaining - diff.len() as u32,
+                    };
+                    return Ok(Some(Op::AddActive { offset, diff }));
+                }
+                DecodeState::Literal { .. } | DecodeState::AddActive { .. } => self.state = DecodeState::Tag,
+                _ => {}
+            }
+
+            let Some((&byte, rest)) = input.split_first() else {
+                return Ok(None);
+            };
+            *input = rest;
+
+            match self.state {
+                DecodeState::Magic(index) => {
+                    if byte != PATCH_MAGIC[index as usize] {
+                        return Err(FirmwareUpdaterError::BadPatch);
+                    }
+                    self.state = if index as usize + 1 == PATCH_MAGIC.len() {
+                        DecodeState::Tag
+                    } else {
+                        DecodeState::Magic(index + 1)
+                    };
+                }
+                DecodeState::Tag => {
+                    if byte > TAG_ADD_ACTIVE {
+                        return Err(FirmwareUpdaterError::BadPatch);
+                    }
+                    self.args = [0; 2];
+                    self.state = DecodeState::Args {
+                        tag: byte,
+                        index: 0,
+                        shift: 0,
+                    };
+                }
+                DecodeState::Args { tag, index, shift } => {
+                    let value = (byte & 0x7F) as u32;
+                    if shift > 28 || (shift == 28 && value > 0x0F) {
+                        return Err(FirmwareUpdaterError::BadPatch);
+                    }
+                    self.args[index as usize] |= value << shift;
+                    if byte & 0x80 != 0 {
+                        self.state = DecodeState::Args {
+                            tag,
+                            index,
+                            shift: shift + 7,
+                        };
+                    } else if index == 0 && tag != TAG_LITERAL {
+                        self.state = DecodeState::Args {
+                            tag,
+                            index: 1,
+                            shift: 0,
+                        };
+                    } else if let Some(op) = self.finish_args(tag)? {
+                        return Ok(Some(op));
+                    }
+                }
+                DecodeState::Literal { .. } | DecodeState::AddActive { .. } => unreachable!(),
+            }
+        }
+    }
+
+    /// Handle an operation whose arguments were all decoded.
+    fn finish_args(&mut self, tag: u8) -> Result<Option<Op<'static>>, FirmwareUpdaterError> {
+        let [first, second] = self.args;
+        self.state = DecodeState::Tag;
+        match tag {
+            TAG_LITERAL => {
+                self.state = DecodeState::Literal { remaining: first };
+                Ok(None)
+            }
+            TAG_FILL => {
+                let byte = u8::try_from(second).map_err(|_| FirmwareUpdaterError::BadPatch)?;
+                Ok(Some(Op::Fill { byte, len: first }))
+            }
+            TAG_COPY_OUTPUT => Ok(Some(Op::CopyOutput {
+                distance: first,
+                len: second,
+            })),
+            TAG_COPY_ACTIVE => Ok(Some(Op::CopyActive {
+                offset: first,
+                len: second,
+            })),
+            _ => {
+                self.state = DecodeState::AddActive {
+                    offset: first,
+                    remaining: second,
+                };
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Split up to `len` bytes off the start of `input`.
+fn take<'i>(input: &mut &'i [u8], len: u32) -> &'i [u8] {
+    let (bytes, rest) = input.split_at(core::cmp::min(len as usize, input.len()));
+    *input = rest;
+    bytes
+}
+
+/// Builder of patches, to encode updates on the host.
+///
+/// Operations can be appended one by one, or [`diff()`](Self::diff) encodes a whole firmware.
+#[cfg(any(test, feature = "std"))]
+#[derive(Clone, Debug)]
+pub struct PatchBuilder {
+    bytes: Vec<u8>,
+}
+
+#[cfg(any(test, feature = "std"))]
+impl Default for PatchBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(any(test, feature = "std"))]
+impl PatchBuilder {
+    /// Shortest copy or fill encoded as such, shorter ones are encoded as literals.
+    const MIN_MATCH: usize = 8;
+    /// Shortest addition to the active partition encoded as such.
+    const MIN_ADD: usize = 16;
+
+    /// Create a patch without operations.
+    pub fn new() -> Self {
+        Self {
+            bytes: PATCH_MAGIC.to_vec(),
+        }
+    }
+
+    /// Encode `firmware` as a patch against `active`, the firmware running on the devices to
+    /// update.
+    ///
+    /// Parts of `firmware` found in `active` are copied from it, and parts which changed slightly
+    /// at the same place relative to the last copy are added to it. The rest is compressed. Pass
+    /// an empty `active` for an update which only compresses the firmware.
+    pub fn diff(active: &[u8], firmware: &[u8]) -> Self {
+        let mut active_index = std::collections::HashMap::new();
+        for (offset, key) in active.windows(Self::MIN_MATCH).enumerate() {
+            active_index.entry(key).or_insert(offset);
+        }
+        let mut output_index = std::collections::HashMap::new();
+
+        let mut builder = Self::new();
+        let mut literal_start = 0;
+        // Offset of the active partition from the firmware, as of the last copy of it.
+        let mut displacement = 0isize;
+        let mut pos = 0;
+        while pos < firmware.len() {
+            let rest = &firmware[pos..];
+
+            let fill = rest.iter().take_while(|&&b| b == rest[0]).count();
+            let mut copy_active = (0, 0);
+            let mut copy_output = (0, 0);
+            if rest.len() >= Self::MIN_MATCH {
+                let key = &rest[..Self::MIN_MATCH];
+                let aligned = pos.checked_add_signed(displacement).filter(|&o| o < active.len());
+                for offset in [active_index.get(key).copied(), aligned].into_iter().flatten() {
+                    let len = common_prefix(&active[offset..], rest);
+                    if len > copy_active.1 {
+                        copy_active = (offset, len);
+                    }
+                }
+                if let Some(&from) = output_index.get(key) {
+                    copy_output = (pos - from, common_prefix(&firmware[from..], rest));
+                }
+            }
+
+            let longest = fill.max(copy_active.1).max(copy_output.1);
+            let add = pos
+                .checked_add_signed(displacement)
+                .filter(|_| longest < Self::MIN_MATCH)
+                .and_then(|offset| Some((offset, added_len(active.get(offset..)?, rest))))
+                .filter(|&(_, len)| len >= Self::MIN_ADD);
+
+            let literal = longest < Self::MIN_MATCH && add.is_none();
+            let len = if literal {
+                1
+            } else {
+                builder = builder.literal(&firmware[literal_start..pos]);
+                if let Some((offset, len)) = add {
+                    let diff: Vec<u8> = (0..len).map(|i| rest[i].wrapping_sub(active[offset + i])).collect();
+                    builder = builder.add_active(offset as u32, &diff);
+                    len
+                } else if longest == copy_active.1 {
+                    displacement = copy_active.0 as isize - pos as isize;
+                    builder = builder.copy_active(copy_active.0 as u32, copy_active.1 as u32);
+                    copy_active.1
+                } else if longest == copy_output.1 {
+                    builder = builder.copy_output(copy_output.0 as u32, copy_output.1 as u32);
+                    copy_output.1
+                } else {
+                    builder = builder.fill(rest[0], fill as u32);
+                    fill
+                }
+            };
+
+            for from in pos..pos + len {
+                if let Some(key) = firmware.get(from..from + Self::MIN_MATCH) {
+                    output_index.insert(key, from);
+                }
+            }
+            pos += len;
+            if !literal {
+                literal_start = pos;
+            }
+        }
+        builder.literal(&firmware[literal_start..])
+    }
+
+    /// Bytes of the patch.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Take the bytes of the patch.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    fn varint(&mut self, mut value: u32) {
+        while value >= 0x80 {
+            self.bytes.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.bytes.push(value as u8);
+    }
+
+    /// Append `bytes` to the firmware. Does nothing if `bytes` is empty.
+    pub fn literal(mut self, bytes: &[u8]) -> Self {
+        if !bytes.is_empty() {
+            self.bytes.push(TAG_LITERAL);
+            self.varint(bytes.len() as u32);
+            self.bytes.extend_from_slice(bytes);
+        }
+        self
+    }
+
+    /// Append `len` times `byte` to the firmware.
+    pub fn fill(mut self, byte: u8, len: u32) -> Self {
+        self.bytes.push(TAG_FILL);
+        self.varint(len);
+        self.varint(byte as u32);
+        self
+    }
+
+    /// Append `len` bytes of the firmware, from `distance` bytes before its end.
+    pub fn copy_output(mut self, distance: u32, len: u32) -> Self {
+        self.bytes.push(TAG_COPY_OUTPUT);
+        self.varint(distance);
+        self.varint(len);
+        self
+    }
+
+    /// Append `len` bytes of the active partition, from `offset`.
+    pub fn copy_active(mut self, offset: u32, len: u32) -> Self {
+        self.bytes.push(TAG_COPY_ACTIVE);
+        self.varint(offset);
+        self.varint(len);
+        self
+    }
+
+    /// Append the bytes of the active partition from `offset`, with `diff` added to them.
+    pub fn add_active(mut self, offset: u32, diff: &[u8]) -> Self {
+        self.bytes.push(TAG_ADD_ACTIVE);
+        self.varint(offset);
+        self.varint(diff.len() as u32);
+        self.bytes.extend_from_slice(diff);
+        self
+    }
+}
+
+/// Length of the common prefix of `a` and `b`.
+#[cfg(any(test, feature = "std"))]
+fn common_prefix(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+/// Length of the start of `new` worth encoding as an addition to `old`: up to the last byte they
+/// have in common before they differ for [`PatchBuilder::MIN_MATCH`] bytes in a row, or before
+/// they're the same for [`PatchBuilder::MIN_ADD`] bytes in a row, which are cheaper to copy. At
+/// least half of the bytes must be the same.
+#[cfg(any(test, feature = "std"))]
+fn added_len(old: &[u8], new: &[u8]) -> usize {
+    let (mut len, mut same, mut run, mut different) = (0, 0, 0, 0);
+    for (i, (a, b)) in old.iter().zip(new).enumerate() {
+        if a == b {
+            same += 1;
+            run += 1;
+            different = 0;
+            if run == PatchBuilder::MIN_ADD {
+                len = i + 1 - run;
+                same -= run;
+                break;
+            }
+            len = i + 1;
+        } else {
+            run = 0;
+            different += 1;
+            if different == PatchBuilder::MIN_MATCH {
+                break;
+            }
+        }
+    }
+    if same * 2 >= len {
+        len
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_split_input() {
+        let patch = PatchBuilder::new()
+            .literal(&[1, 2, 3])
+            .fill(0xFF, 300)
+            .copy_output(4, 2)
+            .copy_active(0x12345, 7)
+            .add_active(16, &[5, 6]);
+
+        // Feed the patch byte by byte.
+        let mut decoder = PatchDecoder::new();
+        let mut literal = [0; 3];
+        let mut literal_len = 0;
+        let mut diff = [0; 2];
+        let mut diff_len = 0;
+        let mut ops = 0;
+        for byte in patch.as_bytes().chunks(1) {
+            let mut input = byte;
+            while let Some(op) = decoder.decode(&mut input).unwrap() {
+                match op {
+                    Op::Literal(bytes) => {
+                        literal[literal_len..literal_len + bytes.len()].copy_from_slice(bytes);
+                        literal_len += bytes.len();
+                    }
+                    Op::AddActive { offset, diff: bytes } => {
+                        assert_eq!(16 + diff_len as u32, offset);
+                        diff[diff_len..diff_len + bytes.len()].copy_from_slice(bytes);
+                        diff_len += bytes.len();
+                    }
+                    Op::Fill { byte, len } => {
+                        assert_eq!((0xFF, 300), (byte, len));
+                        ops += 1;
+                    }
+                    Op::CopyOutput { distance, len } => {
+                        assert_eq!((4, 2), (distance, len));
+                        ops += 1;
+                    }
+                    Op::CopyActive { offset, len } => {
+                        assert_eq!((0x12345, 7), (offset, len));
+                        ops += 1;
+                    }
+                }
+            }
+        }
+        assert_eq!([1, 2, 3], literal);
+        assert_eq!([5, 6], diff);
+        assert_eq!(3, ops);
+        assert!(decoder.is_idle());
+    }
+
+    #[test]
+    fn decode_errors() {
+        let mut input: &[u8] = b"EMBX";
+        assert!(PatchDecoder::new().decode(&mut input).is_err());
+
+        let mut input: &[u8] = b"EMBP\x05";
+        assert!(PatchDecoder::new().decode(&mut input).is_err());
+
+        // Varint overflowing 32 bits.
+        let mut input: &[u8] = b"EMBP\x01\xFF\xFF\xFF\xFF\x7F";
+        assert!(PatchDecoder::new().decode(&mut input).is_err());
+
+        // Fill byte out of range.
+        let mut input: &[u8] = b"EMBP\x01\x01\x80\x02";
+        assert!(PatchDecoder::new().decode(&mut input).is_err());
+
+        // Addition past the end of the address space.
+        let mut input: &[u8] = b"EMBP\x04\xFE\xFF\xFF\xFF\x0F\x03\x01\x02\x03";
+        assert!(PatchDecoder::new().decode(&mut input).is_err());
+
+        // Truncated literal.
+        let mut input: &[u8] = b"EMBP\x00\x03\x01";
+        let mut decoder = PatchDecoder::new();
+        assert_eq!(Some(Op::Literal(&[1])), decoder.decode(&mut input).unwrap());
+        assert_eq!(None, decoder.decode(&mut input).unwrap());
+        assert!(!decoder.is_idle());
+    }
+}
@@ -0,0 +1,139 @@
+use sha2::{Digest, Sha256};
+
+/// Magic identifying an image header.
+pub const IMAGE_MAGIC: [u8; 4] = *b"EMBI";
+
+/// Version of a firmware image.
+///
+/// Versions are ordered by major, minor, then patch number.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ImageVersion {
+    /// Major version.
+    pub major: u8,
+    /// Minor version.
+    pub minor: u8,
+    /// Patch version.
+    pub patch: u16,
+}
+
+/// Errors found when validating a firmware image.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ImageError {
+    /// The image doesn't start with a header.
+    BadMagic,
+    /// The header and image don't fit in the partition.
+    BadLength,
+    /// The image doesn't match the hash of the header.
+    BadHash,
+    /// The header isn't signed with the private key matching the public key of the bootloader.
+    BadSignature,
+    /// The image has an older version than the active image.
+    Downgrade,
+    /// The security counter of the image is lower than the one stored in the STATE partition, or
+    /// higher than the STATE partition can store.
+    SecurityCounter,
+}
+
+/// Header of a firmware image.
+///
+/// The header is placed at the start of the image, and is followed by the firmware at
+/// `header_size`, which is usually chosen to satisfy the alignment of the vector table. The
+/// application must be linked at the start of the ACTIVE partition plus `header_size`, and
+/// booted from there.
+///
+/// The header is encoded on [`ImageHeader::SIZE`] bytes, in little endian:
+///
+/// | Range  | Description                                  |
+/// |--------|----------------------------------------------|
+/// | 0..4   | Magic, [`IMAGE_MAGIC`]                       |
+/// | 4..6   | Header size, offset of the firmware          |
+/// | 6..8   | Reserved, zero                               |
+/// | 8..12  | Length of the firmware                       |
+/// | 12..16 | Version: major (u8), minor (u8), patch (u16) |
+/// | 16..20 | Security counter                             |
+/// | 20..32 | Reserved, zero                               |
+/// | 32..64 | SHA-256 hash of the firmware                 |
+///
+/// When the bootloader verifies signatures, the encoded header is followed by the ed25519
+/// signature of the SHA-512 hash of the encoded header, on [`ImageHeader::SIGNATURE_SIZE`] bytes.
+/// As the header holds the hash of the firmware, this signs the whole image. `header_size` must
+/// then be at least 128.
+#[derive(PartialEq, Eq, Debug, Clone)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ImageHeader {
+    /// Offset of the firmware from the start of the header.
+    pub header_size: u16,
+    /// Length of the firmware, excluding the header.
+    pub image_len: u32,
+    /// Version of the firmware.
+    pub version: ImageVersion,
+    /// Security counter of the firmware.
+    ///
+    /// The bootloader refuses images with a security counter lower than the one of the last
+    /// image marked as booted.
+    pub security_counter: u32,
+    /// SHA-256 hash of the firmware.
+    pub hash: [u8; 32],
+}
+
+impl ImageHeader {
+    /// Size of the encoded header.
+    pub const SIZE: usize = 64;
+    /// Size of the signature following the encoded header.
+    pub const SIGNATURE_SIZE: usize = 64;
+
+    /// Create a header for `firmware`, computing its hash.
+    pub fn new(header_size: u16, version: ImageVersion, security_counter: u32, firmware: &[u8]) -> Self {
+        assert!(header_size as usize >= Self::SIZE);
+        Self {
+            header_size,
+            image_len: firmware.len() as u32,
+            version,
+            security_counter,
+            hash: Sha256::digest(firmware).into(),
+        }
+    }
+
+    /// Decode a header.
+    pub fn from_bytes(bytes: &[u8; Self::SIZE]) -> Result<Self, ImageError> {
+        if bytes[0..4] != IMAGE_MAGIC {
+            return Err(ImageError::BadMagic);
+        }
+        let header_size = u16::from_le_bytes([bytes[4], bytes[5]]);
+        if (header_size as usize) < Self::SIZE {
+            return Err(ImageError::BadLength);
+        }
+        Ok(Self {
+            header_size,
+            image_len: u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+            version: ImageVersion {
+                major: bytes[12],
+                minor: bytes[13],
+                patch: u16::from_le_bytes([bytes[14], bytes[15]]),
+            },
+            security_counter: u32::from_le_bytes([bytes[16], bytes[17], bytes[18], bytes[19]]),
+            hash: bytes[32..64].try_into().unwrap(),
+        })
+    }
+
+    /// Encode the header.
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0..4].copy_from_slice(&IMAGE_MAGIC);
+        bytes[4..6].copy_from_slice(&self.header_size.to_le_bytes());
+        bytes[8..12].copy_from_slice(&self.image_len.to_le_bytes());
+        bytes[12] = self.version.major;
+        bytes[13] = self.version.minor;
+        bytes[14..16].copy_from_slice(&self.version.patch.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.security_counter.to_le_bytes());
+        bytes[32..64].copy_from_slice(&self.hash);
+        bytes
+    }
+
+    /// Length of the header and the firmware.
+    pub fn total_len(&self) -> u64 {
+        self.header_size as u64 + self.image_len as u64
+    }
+}
@@ -1,12 +1,18 @@
-#![no_std]
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
 #![allow(async_fn_in_trait)]
 #![warn(missing_docs)]
 #![doc = include_str!("../README.md")]
 mod fmt;
 
+#[cfg(feature = "image-header")]
+mod ab_boot_loader;
 mod boot_loader;
 mod digest_adapters;
+#[cfg(test)]
+mod fault_flash;
 mod firmware_updater;
+#[cfg(feature = "image-header")]
+mod image_header;
 #[cfg(test)]
 mod mem_flash;
 #[cfg(test)]
@@ -19,16 +25,35 @@ pub(crate) const STATE_ERASE_VALUE: u8 = 0xFF;
 #[cfg(feature = "flash-erase-zero")]
 pub(crate) const STATE_ERASE_VALUE: u8 = 0x00;
 
+#[cfg(feature = "image-header")]
+pub use ab_boot_loader::{AbBootLoader, AbBootLoaderConfig, Slot};
 pub use boot_loader::{BootError, BootLoader, BootLoaderConfig};
+#[cfg(feature = "std")]
+pub use firmware_updater::PatchBuilder;
 pub use firmware_updater::{
-    BlockingFirmwareState, BlockingFirmwareUpdater, FirmwareState, FirmwareUpdater, FirmwareUpdaterConfig,
-    FirmwareUpdaterError,
+    BlockingFirmwareState, BlockingFirmwareUpdater, BlockingPatchWriter, FirmwareState, FirmwareUpdater,
+    FirmwareUpdaterConfig, FirmwareUpdaterError, PatchWriter, PATCH_MAGIC,
 };
+#[cfg(feature = "image-header")]
+pub use image_header::{ImageError, ImageHeader, ImageVersion, IMAGE_MAGIC};
 
 pub(crate) const REVERT_MAGIC: u8 = 0xC0;
 pub(crate) const BOOT_MAGIC: u8 = 0xD0;
 pub(crate) const SWAP_MAGIC: u8 = 0xF0;
 pub(crate) const DFU_DETACH_MAGIC: u8 = 0xE0;
+pub(crate) const REJECTED_MAGIC: u8 = 0xB0;
+
+/// Length of the part of the STATE partition holding the magic and the swap progress.
+///
+/// With the `image-header` feature, the last erase page of the STATE partition is reserved for
+/// the security counter, and is never erased.
+pub(crate) const fn state_data_len(capacity: usize, erase_size: usize) -> usize {
+    if cfg!(feature = "image-header") {
+        capacity - erase_size
+    } else {
+        capacity
+    }
+}
 
 /// The state of the bootloader after running prepare.
 #[derive(PartialEq, Eq, Debug)]
@@ -42,6 +67,9 @@ pub enum State {
     Revert,
     /// Application has received a request to reboot into DFU mode to apply an update.
     DfuDetach,
+    /// Bootloader has refused to swap in the dfu partition, as it doesn't hold a valid image, and will boot the
+    /// active partition.
+    Rejected,
 }
 
 impl<T> From<T> for State
@@ -56,6 +84,8 @@ where
             State::Revert
         } else if !magic.iter().any(|&b| b != DFU_DETACH_MAGIC) {
             State::DfuDetach
+        } else if !magic.iter().any(|&b| b != REJECTED_MAGIC) {
+            State::Rejected
         } else {
             State::Boot
         }
@@ -88,6 +118,7 @@ mod tests {
 
     use super::*;
     use crate::boot_loader::BootLoaderConfig;
+    use crate::fault_flash::{Fault, FaultFlash, PowerSupply};
     use crate::firmware_updater::FirmwareUpdaterConfig;
     use crate::mem_flash::MemFlash;
     use crate::test_flash::{AsyncTestFlash, BlockingTestFlash};
@@ -108,6 +139,7 @@ mod tests {
     */
 
     #[test]
+    #[cfg(not(feature = "image-header"))]
     fn test_boot_state() {
         let flash = BlockingTestFlash::new(BootLoaderConfig {
             active: MemFlash::<57344, 4096, 4>::default(),
@@ -128,7 +160,28 @@ mod tests {
     }
 
     #[test]
-    #[cfg(not(feature = "_verify"))]
+    #[cfg(feature = "image-header")]
+    fn test_boot_state_image_header() {
+        let flash = BlockingTestFlash::new(BootLoaderConfig {
+            active: MemFlash::<57344, 4096, 4>::default(),
+            dfu: MemFlash::<61440, 4096, 4>::default(),
+            state: MemFlash::<8192, 4096, 4>::default(),
+        });
+
+        flash.state().write(0, &[BOOT_MAGIC; 4]).unwrap();
+
+        let mut bootloader = BootLoader::new(BootLoaderConfig {
+            active: flash.active(),
+            dfu: flash.dfu(),
+            state: flash.state(),
+        });
+
+        let mut page = [0; 4096];
+        assert_eq!(State::Boot, bootloader.prepare_boot(&mut page).unwrap());
+    }
+
+    #[test]
+    #[cfg(not(any(feature = "_verify", feature = "image-header")))]
     fn test_swap_state() {
         const FIRMWARE_SIZE: usize = 57344;
         let flash = AsyncTestFlash::new(BootLoaderConfig {
@@ -209,7 +262,7 @@ mod tests {
     }
 
     #[test]
-    #[cfg(not(feature = "_verify"))]
+    #[cfg(not(any(feature = "_verify", feature = "image-header")))]
     fn test_swap_state_active_page_biggest() {
         const FIRMWARE_SIZE: usize = 12288;
         let flash = AsyncTestFlash::new(BootLoaderConfig {
@@ -254,7 +307,7 @@ mod tests {
     }
 
     #[test]
-    #[cfg(not(feature = "_verify"))]
+    #[cfg(not(any(feature = "_verify", feature = "image-header")))]
     fn test_swap_state_dfu_page_biggest() {
         const FIRMWARE_SIZE: usize = 12288;
         let flash = AsyncTestFlash::new(BootLoaderConfig {
@@ -297,6 +350,441 @@ mod tests {
         assert_eq!(ORIGINAL, read_buf);
     }
 
+    const POWER_FAIL_SIZE: usize = 16384;
+
+    /// Original and updated images of the power failure tests.
+    #[cfg(not(feature = "image-header"))]
+    fn power_fail_images() -> [[u8; POWER_FAIL_SIZE]; 2] {
+        [[0x55; POWER_FAIL_SIZE], [0xAA; POWER_FAIL_SIZE]]
+    }
+
+    /// Original and updated images of the power failure tests.
+    #[cfg(feature = "image-header")]
+    fn power_fail_images() -> [[u8; POWER_FAIL_SIZE]; 2] {
+        let v1 = ImageVersion {
+            major: 1,
+            minor: 0,
+            patch: 0,
+        };
+        [image(v1, 1, 0x55), image(ImageVersion { minor: 1, ..v1 }, 2, 0xAA)]
+    }
+
+    type PowerFailFlash<'a, const SIZE: usize> = FaultFlash<'a, &'a mut MemFlash<SIZE, 4096, 4>>;
+
+    /// Flashes of a device whose power can be cut.
+    struct PowerFailDevice {
+        active: MemFlash<POWER_FAIL_SIZE, 4096, 4>,
+        dfu: MemFlash<{ POWER_FAIL_SIZE + 4096 }, 4096, 4>,
+        state: MemFlash<8192, 4096, 4>,
+        power: PowerSupply,
+    }
+
+    impl PowerFailDevice {
+        fn new(active: &[u8; POWER_FAIL_SIZE]) -> Self {
+            let mut device = Self {
+                active: MemFlash::default(),
+                dfu: MemFlash::default(),
+                state: MemFlash::default(),
+                power: PowerSupply::new(),
+            };
+            device.active.program(0, active).unwrap();
+            device
+        }
+
+        fn config(
+            &mut self,
+        ) -> BootLoaderConfig<
+            PowerFailFlash<'_, POWER_FAIL_SIZE>,
+            PowerFailFlash<'_, { POWER_FAIL_SIZE + 4096 }>,
+            PowerFailFlash<'_, 8192>,
+        > {
+            BootLoaderConfig {
+                active: FaultFlash::new(&mut self.active, &self.power),
+                dfu: FaultFlash::new(&mut self.dfu, &self.power),
+                state: FaultFlash::new(&mut self.state, &self.power),
+            }
+        }
+
+        /// Run the bootloader, and return its state and the active image.
+        fn boot(&mut self) -> Result<(State, [u8; POWER_FAIL_SIZE]), BootError> {
+            let flash = BlockingTestFlash::new(self.config());
+            let mut bootloader = BootLoader::new(BootLoaderConfig {
+                active: flash.active(),
+                dfu: flash.dfu(),
+                state: flash.state(),
+            });
+            let mut page = [0; 4096];
+            let state = bootloader.prepare_boot(&mut page)?;
+
+            let mut active = [0; POWER_FAIL_SIZE];
+            flash.active().read(0, &mut active)?;
+            Ok((state, active))
+        }
+
+        /// Write `update` to DFU and mark it updated, with the async or blocking updater.
+        fn update(&mut self, update: &[u8; POWER_FAIL_SIZE], asynch: bool) -> Result<(), FirmwareUpdaterError> {
+            let mut aligned = [0; 4];
+            let mut state_aligned = [0; 4];
+            if asynch {
+                let flash = AsyncTestFlash::new(self.config());
+                let mut updater = FirmwareUpdater::new(
+                    FirmwareUpdaterConfig {
+                        dfu: flash.dfu(),
+                        state: flash.state(),
+                    },
+                    &mut aligned,
+                );
+                block_on(updater.write_firmware(0, update))?;
+                block_on(FirmwareState::new(flash.state(), &mut state_aligned).mark_updated())
+            } else {
+                let flash = BlockingTestFlash::new(self.config());
+                let mut updater = BlockingFirmwareUpdater::new(
+                    FirmwareUpdaterConfig {
+                        dfu: flash.dfu(),
+                        state: flash.state(),
+                    },
+                    &mut aligned,
+                );
+                updater.write_firmware(0, update)?;
+                BlockingFirmwareState::new(flash.state(), &mut state_aligned).mark_updated()
+            }
+        }
+
+        fn mark_booted(&mut self, asynch: bool) -> Result<(), FirmwareUpdaterError> {
+            let mut aligned = [0; 4];
+            if asynch {
+                let flash = AsyncTestFlash::new(self.config());
+                block_on(FirmwareState::new(flash.state(), &mut aligned).mark_booted())
+            } else {
+                let flash = BlockingTestFlash::new(self.config());
+                BlockingFirmwareState::new(flash.state(), &mut aligned).mark_booted()
+            }
+        }
+
+        /// Update the firmware, swap it in and mark it booted, until the power is cut.
+        fn replay_update(&mut self, update: &[u8; POWER_FAIL_SIZE], asynch: bool) {
+            if self.update(update, asynch).is_err() {
+                return;
+            }
+            if self.boot().is_err() {
+                return;
+            }
+            if self.mark_booted(asynch).is_err() {
+                return;
+            }
+            let _ = self.boot();
+        }
+    }
+
+    /// Cut the power after every number of flash operations during an update, and check that
+    /// the active partition holds either the original or the updated image after a reboot.
+    fn power_fail_update(asynch: bool, fault: Fault) {
+        let [original, update] = power_fail_images();
+
+        for cut_after in 0.. {
+            let mut device = PowerFailDevice::new(&original);
+            device.power.cut_after(cut_after, fault);
+            device.replay_update(&update, asynch);
+            let power_cut = device.power.is_cut();
+            device.power.restore();
+
+            if !power_cut {
+                // The whole update ran, after trying every cut.
+                assert_eq!((State::Boot, update), device.boot().unwrap());
+                assert!(cut_after > 20);
+                break;
+            }
+
+            let (_, active) = device.boot().unwrap();
+            if active == original {
+                // The original firmware can update again.
+                device.mark_booted(asynch).unwrap();
+                device.update(&update, asynch).unwrap();
+                assert_eq!((State::Swap, update), device.boot().unwrap());
+            } else {
+                assert!(
+                    active == update,
+                    "Corrupted active image, power cut after {} operations",
+                    cut_after
+                );
+            }
+            device.mark_booted(asynch).unwrap();
+            assert_eq!((State::Boot, update), device.boot().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_power_fail_update_blocking() {
+        power_fail_update(false, Fault::Atomic);
+    }
+
+    #[test]
+    fn test_power_fail_update_async() {
+        power_fail_update(true, Fault::Atomic);
+    }
+
+    #[test]
+    fn test_power_fail_torn_erase_blocking() {
+        power_fail_update(false, Fault::TornErase);
+    }
+
+    #[test]
+    fn test_power_fail_torn_erase_async() {
+        power_fail_update(true, Fault::TornErase);
+    }
+
+    #[cfg(feature = "image-header")]
+    const IMAGE_SIZE: usize = 57344;
+
+    /// Image with a header, and firmware filled with `fill`.
+    #[cfg(feature = "image-header")]
+    fn image<const SIZE: usize>(version: ImageVersion, security_counter: u32, fill: u8) -> [u8; SIZE] {
+        const HEADER_SIZE: usize = 256;
+
+        let mut image = [0xFF; SIZE];
+        image[HEADER_SIZE..HEADER_SIZE + 4096].fill(fill);
+        let header = ImageHeader::new(
+            HEADER_SIZE as u16,
+            version,
+            security_counter,
+            &image[HEADER_SIZE..HEADER_SIZE + 4096],
+        );
+        image[..ImageHeader::SIZE].copy_from_slice(&header.to_bytes());
+        image
+    }
+
+    #[cfg(feature = "image-header")]
+    type HeaderTestFlash =
+        BlockingTestFlash<MemFlash<IMAGE_SIZE, 4096, 4>, MemFlash<61440, 4096, 4>, MemFlash<8192, 4096, 4>>;
+
+    /// Write `update` to DFU and mark it updated, without verifying it.
+    #[cfg(feature = "image-header")]
+    fn write_update(flash: &HeaderTestFlash, update: &[u8; IMAGE_SIZE]) {
+        let mut aligned = [0; 4];
+        let mut updater = BlockingFirmwareUpdater::new(
+            FirmwareUpdaterConfig {
+                dfu: flash.dfu(),
+                state: flash.state(),
+            },
+            &mut aligned,
+        );
+        updater.write_firmware(0, update).unwrap();
+
+        let mut aligned = [0; 4];
+        let mut state = BlockingFirmwareState::new(flash.state(), &mut aligned);
+        state.mark_updated().unwrap();
+    }
+
+    /// Write `update` to DFU, mark it updated and run the bootloader.
+    #[cfg(feature = "image-header")]
+    fn update_with_header(flash: &HeaderTestFlash, update: &[u8; IMAGE_SIZE]) -> State {
+        write_update(flash, update);
+
+        let mut bootloader = BootLoader::new(BootLoaderConfig {
+            active: flash.active(),
+            dfu: flash.dfu(),
+            state: flash.state(),
+        });
+        let mut page = [0; 1024];
+        bootloader.prepare_boot(&mut page).unwrap()
+    }
+
+    #[cfg(feature = "image-header")]
+    fn new_header_flash(active: &[u8; IMAGE_SIZE]) -> HeaderTestFlash {
+        let flash = BlockingTestFlash::new(BootLoaderConfig {
+            active: MemFlash::<IMAGE_SIZE, 4096, 4>::default(),
+            dfu: MemFlash::<61440, 4096, 4>::default(),
+            state: MemFlash::<8192, 4096, 4>::default(),
+        });
+        flash.active().write(0, active).unwrap();
+        flash
+    }
+
+    #[test]
+    #[cfg(feature = "image-header")]
+    fn test_image_header_swap() {
+        let v1 = ImageVersion {
+            major: 1,
+            minor: 0,
+            patch: 0,
+        };
+        let v2 = ImageVersion { minor: 1, ..v1 };
+        let original = image(v1, 1, 0x55);
+        let update = image(v2, 2, 0xAA);
+        let flash = new_header_flash(&original);
+        let mut page = [0; 1024];
+
+        // Booting the original image stores its security counter.
+        let mut bootloader = BootLoader::new(BootLoaderConfig {
+            active: flash.active(),
+            dfu: flash.dfu(),
+            state: flash.state(),
+        });
+        assert_eq!(State::Boot, bootloader.prepare_boot(&mut page).unwrap());
+        assert_eq!(1, bootloader.security_counter(&mut page).unwrap());
+
+        assert_eq!(State::Swap, update_with_header(&flash, &update));
+        let mut read_buf = [0; IMAGE_SIZE];
+        flash.active().read(0, &mut read_buf).unwrap();
+        assert_eq!(update, read_buf);
+
+        let mut bootloader = BootLoader::new(BootLoaderConfig {
+            active: flash.active(),
+            dfu: flash.dfu(),
+            state: flash.state(),
+        });
+        assert_eq!(v2, bootloader.active_header(&mut page).unwrap().version);
+        // The security counter is only raised once the update is marked as booted.
+        assert_eq!(1, bootloader.security_counter(&mut page).unwrap());
+
+        let mut aligned = [0; 4];
+        let mut state = BlockingFirmwareState::new(flash.state(), &mut aligned);
+        state.mark_booted().unwrap();
+
+        assert_eq!(State::Boot, bootloader.prepare_boot(&mut page).unwrap());
+        assert_eq!(2, bootloader.security_counter(&mut page).unwrap());
+    }
+
+    #[test]
+    #[cfg(feature = "image-header")]
+    fn test_image_header_rejected() {
+        let v1 = ImageVersion {
+            major: 1,
+            minor: 2,
+            patch: 3,
+        };
+        let original = image(v1, 3, 0x55);
+        let flash = new_header_flash(&original);
+        let mut page = [0; 1024];
+        let mut read_buf = [0; IMAGE_SIZE];
+
+        let mut bootloader = BootLoader::new(BootLoaderConfig {
+            active: flash.active(),
+            dfu: flash.dfu(),
+            state: flash.state(),
+        });
+        assert_eq!(State::Boot, bootloader.prepare_boot(&mut page).unwrap());
+
+        // No header
+        assert_eq!(State::Rejected, update_with_header(&flash, &[0xAA; IMAGE_SIZE]));
+
+        // Corrupted firmware
+        let mut update = image(ImageVersion { patch: 4, ..v1 }, 3, 0xAA);
+        update[1000] = 0;
+        assert_eq!(State::Rejected, update_with_header(&flash, &update));
+
+        // Firmware too large for the active partition
+        let mut update = image(ImageVersion { patch: 4, ..v1 }, 3, 0xAA);
+        update[8..12].copy_from_slice(&(IMAGE_SIZE as u32).to_le_bytes());
+        assert_eq!(State::Rejected, update_with_header(&flash, &update));
+
+        // Older version
+        let update = image(ImageVersion { patch: 2, ..v1 }, 3, 0xAA);
+        assert_eq!(State::Rejected, update_with_header(&flash, &update));
+
+        // Lower security counter
+        let update = image(ImageVersion { patch: 4, ..v1 }, 2, 0xAA);
+        assert_eq!(State::Rejected, update_with_header(&flash, &update));
+
+        flash.active().read(0, &mut read_buf).unwrap();
+        assert_eq!(original, read_buf);
+
+        // The rejection is reported until the next update.
+        let mut bootloader = BootLoader::new(BootLoaderConfig {
+            active: flash.active(),
+            dfu: flash.dfu(),
+            state: flash.state(),
+        });
+        assert_eq!(State::Rejected, bootloader.prepare_boot(&mut page).unwrap());
+
+        let update = image(ImageVersion { patch: 4, ..v1 }, 3, 0xAA);
+        assert_eq!(State::Swap, update_with_header(&flash, &update));
+        flash.active().read(0, &mut read_buf).unwrap();
+        assert_eq!(update, read_buf);
+    }
+
+    #[test]
+    #[cfg(all(feature = "image-header", feature = "ed25519-dalek"))]
+    fn test_image_header_signature() {
+        use ed25519_dalek::{Digest, Sha512, Signer, SigningKey};
+        use rand::rngs::OsRng;
+
+        let keypair = SigningKey::generate(&mut OsRng {});
+        let other_keypair = SigningKey::generate(&mut OsRng {});
+        let sign = |image: &mut [u8; IMAGE_SIZE], keypair: &SigningKey| {
+            let message = Sha512::digest(&image[..ImageHeader::SIZE]);
+            let signature = keypair.sign(&message).to_bytes();
+            image[ImageHeader::SIZE..ImageHeader::SIZE + ImageHeader::SIGNATURE_SIZE].copy_from_slice(&signature);
+        };
+        let public_key = keypair.verifying_key().to_bytes();
+        let boot = |flash: &HeaderTestFlash, verify_active| {
+            let mut page = [0; 1024];
+            BootLoader::new(BootLoaderConfig {
+                active: flash.active(),
+                dfu: flash.dfu(),
+                state: flash.state(),
+            })
+            .with_public_key(public_key, verify_active)
+            .prepare_boot(&mut page)
+        };
+
+        let v1 = ImageVersion {
+            major: 1,
+            minor: 0,
+            patch: 0,
+        };
+        let mut original = image(v1, 0, 0x55);
+        sign(&mut original, &keypair);
+        let flash = new_header_flash(&original);
+        let mut read_buf = [0; IMAGE_SIZE];
+        assert_eq!(State::Boot, boot(&flash, true).unwrap());
+
+        // Unsigned
+        let mut update = image(ImageVersion { minor: 1, ..v1 }, 0, 0xAA);
+        write_update(&flash, &update);
+        assert_eq!(State::Rejected, boot(&flash, true).unwrap());
+
+        // Signed with another key
+        sign(&mut update, &other_keypair);
+        write_update(&flash, &update);
+        assert_eq!(State::Rejected, boot(&flash, true).unwrap());
+
+        // Signed, but modified after signing
+        sign(&mut update, &keypair);
+        let mut modified = update;
+        modified[12] = 2;
+        write_update(&flash, &modified);
+        assert_eq!(State::Rejected, boot(&flash, true).unwrap());
+
+        flash.active().read(0, &mut read_buf).unwrap();
+        assert_eq!(original, read_buf);
+
+        write_update(&flash, &update);
+        assert_eq!(State::Swap, boot(&flash, true).unwrap());
+        flash.active().read(0, &mut read_buf).unwrap();
+        assert_eq!(update, read_buf);
+
+        let mut aligned = [0; 4];
+        BlockingFirmwareState::new(flash.state(), &mut aligned)
+            .mark_booted()
+            .unwrap();
+        assert_eq!(State::Boot, boot(&flash, false).unwrap());
+
+        // An application forging the progress of a swap doesn't skip the verification.
+        let unsigned = image(ImageVersion { minor: 2, ..v1 }, 0, 0xCC);
+        write_update(&flash, &unsigned);
+        flash.state().write(8, &[0; 4]).unwrap();
+        assert_eq!(State::Rejected, boot(&flash, false).unwrap());
+        flash.active().read(0, &mut read_buf).unwrap();
+        assert_eq!(update, read_buf);
+
+        // An unsigned active image isn't booted, if the active image is verified.
+        let unsigned = image(v1, 0, 0x55);
+        let flash = new_header_flash(&unsigned);
+        assert_eq!(Err(BootError::Image(ImageError::BadSignature)), boot(&flash, true));
+        assert_eq!(State::Boot, boot(&flash, false).unwrap());
+    }
+
     #[test]
     #[cfg(feature = "_verify")]
     fn test_verify() {
@@ -10,12 +10,16 @@ use macros::*;
 /// Declares an async task that can be run by `embassy-executor`. The optional `pool_size` parameter can be used to specify how
 /// many concurrent tasks can be spawned (default is 1) for the function.
 ///
+/// The optional `priority` parameter sets the priority of the task (default is 0). Woken tasks with
+/// a higher priority are polled first. It requires the `task-priority` feature of `embassy-executor`.
+///
 ///
 /// The following restrictions apply:
 ///
 /// * The function must be declared `async`.
 /// * The function must not use generics.
 /// * The optional `pool_size` attribute must be 1 or greater.
+/// * The optional `priority` attribute must be lower than `embassy_executor::raw::TASK_PRIORITY_LEVELS`.
 ///
 ///
 /// ## Examples
@@ -37,6 +41,15 @@ use macros::*;
 ///     // Function body
 /// }
 /// ```
+///
+/// Declaring a task polled before the default priority ones:
+///
+/// ``` rust,ignore
+/// #[embassy_executor::task(priority = 1)]
+/// async fn mytask() {
+///     // Function body
+/// }
+/// ```
 #[proc_macro_attribute]
 pub fn task(args: TokenStream, item: TokenStream) -> TokenStream {
     task::run(args.into(), item.into()).into()
@@ -13,6 +13,9 @@ use crate::util::*;
 struct Args {
     #[darling(default)]
     pool_size: Option<syn::Expr>,
+    /// Priority of the task. Requires the `task-priority` feature of `embassy_executor`.
+    #[darling(default)]
+    priority: Option<syn::Expr>,
     /// Use this to override the `embassy_executor` crate path. Defaults to `::embassy_executor`.
     #[darling(default)]
     embassy_executor: Option<syn::Expr>,
@@ -47,6 +50,8 @@ pub fn run(args: TokenStream, item: TokenStream) -> TokenStream {
         lit: Lit::Int(LitInt::new("1", Span::call_site())),
     }));
 
+    let priority = args.priority;
+
     let embassy_executor = args
         .embassy_executor
         .unwrap_or(Expr::Verbatim(TokenStream::from_str("::embassy_executor").unwrap()));
@@ -66,6 +71,8 @@ pub fn run(args: TokenStream, item: TokenStream) -> TokenStream {
     if !f.sig.variadic.is_none() {
         error(&mut errors, &f.sig, "task functions must not be variadic");
     }
+    // Without `JoinHandle`s, the output of a task can't be observed.
+    #[cfg(not(feature = "join-handle"))]
     match &f.sig.output {
         ReturnType::Default => {}
         ReturnType::Type(_, ty) => match &**ty {
@@ -79,6 +86,20 @@ pub fn run(args: TokenStream, item: TokenStream) -> TokenStream {
         },
     }
 
+    // The output type of the task. `!` can't be named on stable, but
+    // the output of a task that never returns is never observed anyway.
+    let never = matches!(&f.sig.output, ReturnType::Type(_, ty) if matches!(&**ty, Type::Never(_)));
+    let output = match &f.sig.output {
+        ReturnType::Default => quote!(()),
+        ReturnType::Type(..) if never => quote!(impl Sized),
+        ReturnType::Type(_, ty) => quote!(#ty),
+    };
+    // `impl Trait` isn't allowed in the dummy body emitted on errors.
+    let dummy_output = match &f.sig.output {
+        ReturnType::Type(_, ty) if !matches!(&**ty, Type::Never(_) | Type::ImplTrait(_)) => quote!(#ty),
+        _ => quote!(()),
+    };
+
     let mut args = Vec::new();
     let mut fargs = f.sig.inputs.clone();
 
@@ -125,24 +146,62 @@ pub fn run(args: TokenStream, item: TokenStream) -> TokenStream {
         ));
     }
 
+    // Name of the task pool in the task registry.
+    #[cfg(feature = "task-registry")]
+    let pool_name = {
+        let name = task_ident.to_string();
+        quote!(::core::concat!(::core::module_path!(), "::", #name))
+    };
+
+    // List the task pool in the task registry, even if it's never spawned.
+    #[cfg(feature = "task-registry")]
+    let register_pool = |pool: TokenStream| {
+        quote! {
+            #[#embassy_executor::_registry::distributed_slice(#embassy_executor::_registry::EMBASSY_TASK_POOLS)]
+            #[linkme(crate = #embassy_executor::_registry::linkme)]
+            static __REGISTER_POOL: fn() = || #pool._register();
+        }
+    };
+    #[cfg(not(feature = "task-registry"))]
+    let register_pool = |_: TokenStream| TokenStream::new();
+
+    #[cfg(all(feature = "nightly", feature = "task-registry"))]
+    let pool_new = quote!(#embassy_executor::raw::TaskPool::new_named(#pool_name));
+    #[cfg(all(feature = "nightly", not(feature = "task-registry")))]
+    let pool_new = quote!(#embassy_executor::raw::TaskPool::new());
+    #[cfg(feature = "nightly")]
+    let fut_bound = match never {
+        true => quote!(::core::future::Future + 'static),
+        false => quote!(::core::future::Future<Output = #output> + 'static),
+    };
+    #[cfg(feature = "nightly")]
+    let register_pool = register_pool(quote!(POOL));
     #[cfg(feature = "nightly")]
     let mut task_outer_body = quote! {
         trait _EmbassyInternalTaskTrait {
-            type Fut: ::core::future::Future + 'static;
+            type Fut: #fut_bound;
             fn construct(#fargs) -> Self::Fut;
         }
 
         impl _EmbassyInternalTaskTrait for () {
-            type Fut = impl core::future::Future + 'static;
+            type Fut = impl #fut_bound;
             fn construct(#fargs) -> Self::Fut {
                 #task_inner_ident(#(#full_args,)*)
             }
         }
 
         const POOL_SIZE: usize = #pool_size;
-        static POOL: #embassy_executor::raw::TaskPool<<() as _EmbassyInternalTaskTrait>::Fut, POOL_SIZE> = #embassy_executor::raw::TaskPool::new();
+        static POOL: #embassy_executor::raw::TaskPool<<() as _EmbassyInternalTaskTrait>::Fut, POOL_SIZE> = #pool_new;
+        #register_pool
         unsafe { POOL._spawn_async_fn(move || <() as _EmbassyInternalTaskTrait>::construct(#(#full_args,)*)) }
     };
+    #[cfg(all(not(feature = "nightly"), feature = "task-registry"))]
+    let pool_new =
+        quote!(#embassy_executor::_export::task_pool_new_named::<_, _, _, POOL_SIZE>(#task_inner_ident, #pool_name));
+    #[cfg(all(not(feature = "nightly"), not(feature = "task-registry")))]
+    let pool_new = quote!(#embassy_executor::_export::task_pool_new::<_, _, _, POOL_SIZE>(#task_inner_ident));
+    #[cfg(not(feature = "nightly"))]
+    let register_pool = register_pool(quote!(__task_pool_get(#task_inner_ident)));
     #[cfg(not(feature = "nightly"))]
     let mut task_outer_body = quote! {
         const fn __task_pool_get<F, Args, Fut>(_: F) -> &'static #embassy_executor::raw::TaskPool<Fut, POOL_SIZE>
@@ -157,16 +216,24 @@ pub fn run(args: TokenStream, item: TokenStream) -> TokenStream {
         static POOL: #embassy_executor::_export::TaskPoolHolder<
             {#embassy_executor::_export::task_pool_size::<_, _, _, POOL_SIZE>(#task_inner_ident)},
             {#embassy_executor::_export::task_pool_align::<_, _, _, POOL_SIZE>(#task_inner_ident)},
-        > = unsafe { ::core::mem::transmute(#embassy_executor::_export::task_pool_new::<_, _, _, POOL_SIZE>(#task_inner_ident)) };
+        > = unsafe { ::core::mem::transmute(#pool_new) };
+        #register_pool
         unsafe { __task_pool_get(#task_inner_ident)._spawn_async_fn(move || #task_inner_ident(#(#full_args,)*)) }
     };
 
+    if let Some(priority) = priority {
+        task_outer_body = quote! {
+            let token = { #task_outer_body };
+            token.with_priority(#priority)
+        };
+    }
+
     let task_outer_attrs = task_inner.attrs.clone();
 
     if !errors.is_empty() {
         task_outer_body = quote! {
             #![allow(unused_variables, unreachable_code)]
-            let _x: #embassy_executor::SpawnToken<()> = ::core::todo!();
+            let _x: #embassy_executor::SpawnToken<(), #dummy_output> = ::core::todo!();
             _x
         };
     }
@@ -184,7 +251,7 @@ pub fn run(args: TokenStream, item: TokenStream) -> TokenStream {
         #task_inner
 
         #(#task_outer_attrs)*
-        #visibility fn #task_ident #generics (#fargs) -> #embassy_executor::SpawnToken<impl Sized> #where_clause{
+        #visibility fn #task_ident #generics (#fargs) -> #embassy_executor::SpawnToken<impl Sized, #output> #where_clause{
             #task_outer_body
         }
 
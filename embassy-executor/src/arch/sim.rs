@@ -0,0 +1,265 @@
+#[cfg(feature = "executor-interrupt")]
+compile_error!("`executor-interrupt` is not supported with `arch-sim`.");
+
+#[cfg(feature = "executor-thread")]
+pub use thread::*;
+#[cfg(feature = "executor-thread")]
+mod thread {
+    use std::cell::{Cell, RefCell};
+    use std::future::Future;
+    use std::marker::PhantomData;
+    use std::pin::pin;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::task::{Context, Poll, Wake, Waker};
+
+    pub use embassy_executor_macros::main_std as main;
+    use embassy_time_driver::Driver;
+
+    use crate::{raw, Spawner};
+
+    #[export_name = "__pender"]
+    fn __pender(context: *mut ()) {
+        let pended: &'static AtomicBool = unsafe { &*(context as *const AtomicBool) };
+        pended.store(true, Ordering::Relaxed);
+    }
+
+    /// Simulation executor, for testing on the host.
+    ///
+    /// The executor owns a virtual clock, which it provides as the `embassy-time` driver: don't
+    /// enable another time driver, such as the `std` or `mock-driver` features of `embassy-time`.
+    /// Time only passes when all tasks are idle: the clock then jumps to the next timer deadline,
+    /// so tests with long timeouts run instantly and deterministically.
+    ///
+    /// When no task can make progress, because none is runnable and no timer is pending, the
+    /// executor reports a [`Deadlock`].
+    ///
+    /// With [`Executor::with_seed()`], tasks woken at the same time are polled in a pseudo-random
+    /// order derived from the seed, to shake out ordering bugs. A given seed always gives the same
+    /// order, so failures are reproducible.
+    ///
+    /// The clock is per thread, so that tests running in parallel don't interfere with each
+    /// other. Only one executor may be used at a time on a given thread.
+    pub struct Executor {
+        inner: raw::Executor,
+        not_send: PhantomData<*mut ()>,
+        pended: &'static AtomicBool,
+        rng: Option<Cell<u64>>,
+    }
+
+    impl Executor {
+        /// Create a new Executor, polling woken tasks in the same order as the other executors.
+        ///
+        /// This resets the virtual clock of the current thread to 0.
+        pub fn new() -> Self {
+            Self::new_inner(None)
+        }
+
+        /// Create a new Executor, polling woken tasks in a pseudo-random order derived from `seed`.
+        ///
+        /// This resets the virtual clock of the current thread to 0.
+        pub fn with_seed(seed: u64) -> Self {
+            // xorshift must not be seeded with 0.
+            Self::new_inner(Some(Cell::new(seed | 1)))
+        }
+
+        fn new_inner(rng: Option<Cell<u64>>) -> Self {
+            CLOCK.with(|clock| clock.replace(Clock::new()));
+            let pended = Box::leak(Box::new(AtomicBool::new(false)));
+            Self {
+                inner: raw::Executor::new(pended as *mut AtomicBool as *mut ()),
+                not_send: PhantomData,
+                pended,
+                rng,
+            }
+        }
+
+        /// Get a spawner that spawns tasks in this executor.
+        pub fn spawner(&'static self) -> Spawner {
+            self.inner.spawner()
+        }
+
+        /// Run the executor until `future` completes, and return its output.
+        ///
+        /// The tasks spawned in this executor run concurrently with `future`, which is polled
+        /// by this function itself: it isn't an embassy task, so it must use [`spawner()`](Self::spawner)
+        /// instead of [`Spawner::for_current_executor()`].
+        ///
+        /// Returns [`Deadlock`] if `future` can't complete because no task can make progress.
+        pub fn block_on<F: Future>(&'static self, future: F) -> Result<F::Output, Deadlock> {
+            let mut future = pin!(future);
+            let woken = Arc::new(RootWaker(AtomicBool::new(true)));
+            let waker = Waker::from(woken.clone());
+            let mut cx = Context::from_waker(&waker);
+
+            loop {
+                if woken.0.swap(false, Ordering::Relaxed) {
+                    if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                        return Ok(output);
+                    }
+                }
+                self.step(|| woken.0.load(Ordering::Relaxed))?;
+            }
+        }
+
+        /// Run the executor.
+        ///
+        /// The `init` closure is called with a [`Spawner`] that spawns tasks on
+        /// this executor. Use it to spawn the initial task(s). After `init` returns,
+        /// the executor starts running the tasks.
+        ///
+        /// This function requires `&'static mut self`. This means you have to store the
+        /// Executor instance in a place where it'll live forever and grants you mutable
+        /// access. There's a few ways to do this:
+        ///
+        /// - a [StaticCell](https://docs.rs/static_cell/latest/static_cell/) (safe)
+        /// - a `static mut` (unsafe)
+        /// - a local variable in a function you know never returns (like `fn main() -> !`), upgrading its lifetime with `transmute`. (unsafe)
+        ///
+        /// This function never returns.
+        ///
+        /// # Panics
+        ///
+        /// Panics when no task can make progress anymore.
+        pub fn run(&'static mut self, init: impl FnOnce(Spawner)) -> ! {
+            init(self.inner.spawner());
+
+            loop {
+                if let Err(e) = self.step(|| false) {
+                    panic!("{}", e);
+                }
+            }
+        }
+
+        /// Poll the woken tasks, or advance the clock to the next timer deadline if there are none
+        /// and `busy` returns false.
+        fn step(&'static self, busy: impl Fn() -> bool) -> Result<(), Deadlock> {
+            if self.pended.swap(false, Ordering::Relaxed) {
+                match &self.rng {
+                    None => unsafe { self.inner.poll() },
+                    Some(rng) => unsafe { self.inner.inner.poll_shuffled(|batch| shuffle(rng, batch)) },
+                }
+                return Ok(());
+            }
+            if busy() {
+                return Ok(());
+            }
+
+            let wakers = CLOCK.with_borrow_mut(|clock| clock.advance());
+            if wakers.is_empty() {
+                return Err(Deadlock);
+            }
+            for waker in wakers {
+                waker.wake();
+            }
+            Ok(())
+        }
+    }
+
+    /// Fisher-Yates shuffle, with a xorshift64* generator.
+    fn shuffle<T>(rng: &Cell<u64>, items: &mut [T]) {
+        for i in (1..items.len()).rev() {
+            let mut x = rng.get();
+            x ^= x >> 12;
+            x ^= x << 25;
+            x ^= x >> 27;
+            rng.set(x);
+            let j = (x.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 32) as usize % (i + 1);
+            items.swap(i, j);
+        }
+    }
+
+    struct RootWaker(AtomicBool);
+
+    impl Wake for RootWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Error returned by the simulation [`Executor`] when no task can make progress.
+    ///
+    /// No task is runnable, and no timer is pending that could wake one.
+    #[derive(Copy, Clone, PartialEq, Eq)]
+    pub struct Deadlock;
+
+    impl core::fmt::Debug for Deadlock {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            core::fmt::Display::fmt(self, f)
+        }
+    }
+
+    impl core::fmt::Display for Deadlock {
+        fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+            write!(
+                f,
+                "Deadlock - No task can make progress: none is runnable and no timer is pending."
+            )
+        }
+    }
+
+    impl core::error::Error for Deadlock {}
+
+    thread_local! {
+        static CLOCK: RefCell<Clock> = const { RefCell::new(Clock::new()) };
+    }
+
+    /// Virtual clock, with the pending timers.
+    struct Clock {
+        now: u64,
+        timers: Vec<(u64, Waker)>,
+    }
+
+    impl Clock {
+        const fn new() -> Self {
+            Self {
+                now: 0,
+                timers: Vec::new(),
+            }
+        }
+
+        /// Jump to the next timer deadline, returning the wakers of the expired timers.
+        fn advance(&mut self) -> Vec<Waker> {
+            let Some(next) = self.timers.iter().map(|(at, _)| *at).min() else {
+                return Vec::new();
+            };
+            self.now = self.now.max(next);
+
+            let mut expired = Vec::new();
+            self.timers.retain(|(at, waker)| {
+                let keep = *at > self.now;
+                if !keep {
+                    expired.push(waker.clone());
+                }
+                keep
+            });
+            expired
+        }
+    }
+
+    struct SimDriver;
+
+    embassy_time_driver::time_driver_impl!(static DRIVER: SimDriver = SimDriver);
+
+    impl Driver for SimDriver {
+        fn now(&self) -> u64 {
+            CLOCK.with_borrow(|clock| clock.now)
+        }
+
+        fn schedule_wake(&self, at: u64, waker: &Waker) {
+            let expired = CLOCK.with_borrow_mut(|clock| {
+                if at <= clock.now {
+                    return true;
+                }
+                match clock.timers.iter_mut().find(|(_, w)| w.will_wake(waker)) {
+                    Some(timer) => timer.0 = timer.0.min(at),
+                    None => clock.timers.push((at, waker.clone())),
+                }
+                false
+            });
+            if expired {
+                waker.wake_by_ref();
+            }
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use core::future::Future;
+use core::marker::PhantomData;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use super::raw;
+
+/// Handle to a spawned task, obtained with [`Spawner::spawn_with_handle()`](crate::Spawner::spawn_with_handle).
+///
+/// Awaiting the handle returns the output of the task once it ends. The handle can also
+/// [cancel](JoinHandle::cancel) the task.
+///
+/// While the handle exists, the task's storage stays claimed even if the task ended, so that its
+/// output can be taken: the task can't be spawned again until the handle is awaited to completion
+/// or dropped. Dropping the handle detaches the task, it keeps running.
+pub struct JoinHandle<T> {
+    task: Option<raw::TaskRef>,
+    phantom: PhantomData<T>,
+}
+
+impl<T> JoinHandle<T> {
+    /// Claim a task for a new handle.
+    ///
+    /// # Safety
+    ///
+    /// The task must not be spawned yet, and `T` must be its output type.
+    pub(crate) unsafe fn new(task: raw::TaskRef) -> Self {
+        task.header().join.attach();
+        Self {
+            task: Some(task),
+            phantom: PhantomData,
+        }
+    }
+
+    /// Request cancellation of the task.
+    ///
+    /// The task's future is dropped the next time the executor would poll it, instead of being
+    /// polled. It is woken so that this happens promptly, but a task that is currently being polled
+    /// is only cancelled once it yields. Awaiting the handle then returns [`JoinError::Cancelled`].
+    ///
+    /// This has no effect if the task already ended.
+    pub fn cancel(&self) {
+        if let Some(task) = self.task {
+            task.header().join.cancel();
+            raw::wake_task(task);
+        }
+    }
+
+    /// Returns whether the task ended, either returning or being cancelled.
+    pub fn is_finished(&self) -> bool {
+        match self.task {
+            Some(task) => task.header().join.is_finished(),
+            None => true,
+        }
+    }
+}
+
+impl<T> Unpin for JoinHandle<T> {}
+
+// The output of the task is moved to the thread awaiting the handle.
+unsafe impl<T: Send> Send for JoinHandle<T> {}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let task = unwrap!(self.task, "JoinHandle polled after completion");
+        let header = task.header();
+        let outcome = match header.join.poll(cx) {
+            Poll::Ready(outcome) => outcome,
+            Poll::Pending => return Poll::Pending,
+        };
+        self.task = None;
+
+        let res = match outcome {
+            raw::Outcome::Output => {
+                let mut output = None;
+                unsafe { task.take_output(Some(&mut output)) };
+                Ok(unwrap!(output))
+            }
+            raw::Outcome::Cancelled => Err(JoinError::Cancelled),
+        };
+        header.state.despawn();
+        Poll::Ready(res)
+    }
+}
+
+impl<T> Drop for JoinHandle<T> {
+    fn drop(&mut self) {
+        let Some(task) = self.task else {
+            return;
+        };
+        let header = task.header();
+        match header.join.detach() {
+            None => {}
+            Some(outcome) => {
+                if outcome == raw::Outcome::Output {
+                    unsafe { task.take_output::<T>(None) };
+                }
+                header.state.despawn();
+            }
+        }
+    }
+}
+
+/// Error returned when awaiting a [`JoinHandle`].
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub enum JoinError {
+    /// The task was cancelled with [`JoinHandle::cancel()`] before it returned.
+    Cancelled,
+}
+
+impl core::fmt::Debug for JoinError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        core::fmt::Display::fmt(self, f)
+    }
+}
+
+impl core::fmt::Display for JoinError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            JoinError::Cancelled => write!(f, "Cancelled - The task was cancelled before it returned."),
+        }
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for JoinError {
+    fn format(&self, f: defmt::Formatter) {
+        match self {
+            JoinError::Cancelled => defmt::write!(f, "Cancelled - The task was cancelled before it returned."),
+        }
+    }
+}
+
+impl core::error::Error for JoinError {}
@@ -1,4 +1,4 @@
-#![cfg_attr(not(any(feature = "arch-std", feature = "arch-wasm")), no_std)]
+#![cfg_attr(not(any(feature = "arch-std", feature = "arch-wasm", feature = "arch-sim")), no_std)]
 #![allow(clippy::new_without_default)]
 #![doc = include_str!("../README.md")]
 #![warn(missing_docs)]
@@ -30,6 +30,7 @@ check_at_most_one!(
     "arch-std",
     "arch-wasm",
     "arch-spin",
+    "arch-sim",
 );
 
 #[cfg(feature = "_arch")]
@@ -39,6 +40,7 @@ check_at_most_one!(
 #[cfg_attr(feature = "arch-std", path = "arch/std.rs")]
 #[cfg_attr(feature = "arch-wasm", path = "arch/wasm.rs")]
 #[cfg_attr(feature = "arch-spin", path = "arch/spin.rs")]
+#[cfg_attr(feature = "arch-sim", path = "arch/sim.rs")]
 mod arch;
 
 #[cfg(feature = "_arch")]
@@ -52,6 +54,21 @@ pub mod raw;
 mod spawner;
 pub use spawner::*;
 
+#[cfg(feature = "join-handle")]
+mod join_handle;
+#[cfg(feature = "join-handle")]
+pub use join_handle::*;
+
+/// Implementation details for embassy macros.
+/// Do not use. Used for macros only. Not covered by semver guarantees.
+#[doc(hidden)]
+#[cfg(feature = "task-registry")]
+pub mod _registry {
+    pub use linkme::{self, distributed_slice};
+
+    pub use crate::raw::registry::EMBASSY_TASK_POOLS;
+}
+
 /// Implementation details for embassy macros.
 /// Do not use. Used for macros and HALs only. Not covered by semver guarantees.
 #[doc(hidden)]
@@ -144,6 +161,18 @@ pub mod _export {
         TaskPool::new()
     }
 
+    #[cfg(feature = "task-registry")]
+    pub const fn task_pool_new_named<F, Args, Fut, const POOL_SIZE: usize>(
+        _: F,
+        name: &'static str,
+    ) -> TaskPool<Fut, POOL_SIZE>
+    where
+        F: TaskFn<Args, Fut = Fut>,
+        Fut: Future + 'static,
+    {
+        TaskPool::new_named(name)
+    }
+
     #[allow(private_bounds)]
     #[repr(transparent)]
     pub struct Align<const N: usize>([<Self as Alignment>::Archetype; 0])
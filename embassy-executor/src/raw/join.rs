@@ -0,0 +1,114 @@
+use core::cell::Cell;
+#[cfg(not(feature = "arch-avr"))]
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering;
+use core::task::{Context, Poll, Waker};
+
+use critical_section::Mutex;
+#[cfg(feature = "arch-avr")]
+use portable_atomic::AtomicBool;
+
+/// How a task ended.
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Outcome {
+    /// The future returned, its output is stored in the task.
+    Output,
+    /// The future was dropped before returning.
+    Cancelled,
+}
+
+/// State shared between a task and its [`JoinHandle`](crate::JoinHandle).
+///
+/// While a handle exists, the task stays claimed after it ends, so that its output can still be
+/// taken. Whoever comes last, the task or the handle, releases it.
+pub(crate) struct JoinState {
+    /// Cancellation was requested. Checked by the executor before polling the task.
+    cancelled: AtomicBool,
+    /// A handle exists for the task.
+    handle: Mutex<Cell<bool>>,
+    /// How the task ended, if it did.
+    outcome: Mutex<Cell<Option<Outcome>>>,
+    /// Waker of the future awaiting the handle.
+    waker: Mutex<Cell<Option<Waker>>>,
+}
+
+impl JoinState {
+    pub const fn new() -> Self {
+        Self {
+            cancelled: AtomicBool::new(false),
+            handle: Mutex::new(Cell::new(false)),
+            outcome: Mutex::new(Cell::new(None)),
+            waker: Mutex::new(Cell::new(None)),
+        }
+    }
+
+    /// Reset the state of a freshly claimed task.
+    pub fn reset(&self) {
+        self.cancelled.store(false, Ordering::Relaxed);
+        critical_section::with(|cs| {
+            self.handle.borrow(cs).set(false);
+            self.outcome.borrow(cs).set(None);
+        })
+    }
+
+    /// Register a handle for the task. Must be called before the task is spawned.
+    pub fn attach(&self) {
+        critical_section::with(|cs| self.handle.borrow(cs).set(true))
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    #[inline(always)]
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        critical_section::with(|cs| self.outcome.borrow(cs).get().is_some())
+    }
+
+    /// Record that the task ended, and wake the handle.
+    ///
+    /// Returns whether the task must be released now, because it has no handle.
+    pub fn finish(&self, outcome: Outcome) -> bool {
+        let (release, waker) = critical_section::with(|cs| {
+            self.outcome.borrow(cs).set(Some(outcome));
+            (!self.handle.borrow(cs).get(), self.waker.borrow(cs).take())
+        });
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        release
+    }
+
+    /// Poll for the end of the task. Once it returns `Ready`, the handle is gone and the caller
+    /// must release the task.
+    pub fn poll(&self, cx: &mut Context<'_>) -> Poll<Outcome> {
+        critical_section::with(|cs| match self.outcome.borrow(cs).get() {
+            Some(outcome) => {
+                self.handle.borrow(cs).set(false);
+                Poll::Ready(outcome)
+            }
+            None => {
+                let waker = self.waker.borrow(cs);
+                match waker.take() {
+                    Some(w) if w.will_wake(cx.waker()) => waker.set(Some(w)),
+                    _ => waker.set(Some(cx.waker().clone())),
+                }
+                Poll::Pending
+            }
+        })
+    }
+
+    /// Drop the handle. If the task already ended, returns how, and the caller must release the
+    /// task.
+    pub fn detach(&self) -> Option<Outcome> {
+        let (outcome, _waker) = critical_section::with(|cs| {
+            self.handle.borrow(cs).set(false);
+            (self.outcome.borrow(cs).get(), self.waker.borrow(cs).take())
+        });
+        outcome
+    }
+}
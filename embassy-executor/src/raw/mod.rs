@@ -16,8 +16,14 @@ mod run_queue;
 #[cfg_attr(not(target_has_atomic = "8"), path = "state_critical_section.rs")]
 mod state;
 
+#[cfg(feature = "join-handle")]
+mod join;
+#[cfg(feature = "task-registry")]
+pub(crate) mod registry;
+#[cfg(feature = "task-stats")]
+mod stats;
 pub mod timer_queue;
-#[cfg(feature = "trace")]
+#[cfg(feature = "_trace")]
 mod trace;
 pub(crate) mod util;
 #[cfg_attr(feature = "turbowakers", path = "waker_turbo.rs")]
@@ -36,12 +42,28 @@ use core::task::{Context, Poll};
 #[cfg(feature = "arch-avr")]
 use portable_atomic::AtomicPtr;
 
+#[cfg(feature = "join-handle")]
+use self::join::JoinState;
+#[cfg(feature = "join-handle")]
+pub(crate) use self::join::Outcome;
+#[cfg(feature = "task-registry")]
+pub use self::registry::{task_pools, TaskPoolInfo};
 use self::run_queue::{RunQueue, RunQueueItem};
 use self::state::State;
+#[cfg(feature = "task-stats")]
+pub use self::stats::{ExecutorStats, TaskStats};
 use self::util::{SyncUnsafeCell, UninitCell};
 pub use self::waker::task_from_waker;
 use super::SpawnToken;
 
+/// Number of task priority levels.
+///
+/// Tasks have priority 0 by default. Woken tasks with a higher priority are polled first.
+#[cfg(feature = "task-priority")]
+pub const TASK_PRIORITY_LEVELS: usize = 4;
+#[cfg(not(feature = "task-priority"))]
+pub(crate) const TASK_PRIORITY_LEVELS: usize = 1;
+
 /// Raw task header for use in task pointers.
 ///
 /// A task can be in one of the following states:
@@ -78,7 +100,8 @@ use super::SpawnToken;
 /// - 1: Task is spawned - `AvailableTask::claim -> Executor::spawn`
 /// - 2: During poll - `RunQueue::dequeue_all -> State::run_dequeue`
 /// - 3: Task wakes itself, waker wakes task, or task exits - `Waker::wake -> wake_task -> State::run_enqueue`
-/// - 4: A run-queued task exits - `TaskStorage::poll -> Poll::Ready`
+/// - 4: A run-queued task exits - `TaskStorage::poll -> Poll::Ready`, or is cancelled. If the task
+///   has a `JoinHandle`, it stays `SPAWNED` until the handle is awaited or dropped.
 /// - 5: Task is dequeued. The task's future is not polled, because exiting the task replaces its `poll_fn`.
 /// - 6: A task is waken when it is not spawned - `wake_task -> State::run_enqueue`
 pub(crate) struct TaskHeader {
@@ -86,11 +109,41 @@ pub(crate) struct TaskHeader {
     pub(crate) run_queue_item: RunQueueItem,
     pub(crate) executor: AtomicPtr<SyncExecutor>,
     poll_fn: SyncUnsafeCell<Option<unsafe fn(TaskRef)>>,
+    #[cfg(feature = "join-handle")]
+    pub(crate) join: JoinState,
+    #[cfg(feature = "join-handle")]
+    take_output: SyncUnsafeCell<Option<TakeOutputFn>>,
+    #[cfg(feature = "task-priority")]
+    priority: SyncUnsafeCell<u8>,
+    #[cfg(feature = "task-stats")]
+    pub(crate) stats: stats::TaskStatsCell,
 
     /// Integrated timer queue storage. This field should not be accessed outside of the timer queue.
     pub(crate) timer_queue_item: timer_queue::TimerQueueItem,
 }
 
+impl TaskHeader {
+    /// Priority level of the task, used to pick its run queue.
+    #[inline(always)]
+    pub(crate) fn priority(&self) -> usize {
+        // safety: the priority is only written before the task is spawned.
+        #[cfg(feature = "task-priority")]
+        return unsafe { self.priority.get() } as usize;
+        #[cfg(not(feature = "task-priority"))]
+        return 0;
+    }
+
+    /// Set the priority level of the task. Must be called before the task is spawned.
+    #[cfg(feature = "task-priority")]
+    pub(crate) unsafe fn set_priority(&self, priority: u8) {
+        self.priority.set(priority)
+    }
+}
+
+/// Moves the output of the task to the `Option<F::Output>` pointed to, or drops it if null.
+#[cfg(feature = "join-handle")]
+type TakeOutputFn = unsafe fn(TaskRef, *mut ());
+
 /// This is essentially a `&'static TaskStorage<F>` where the type of the future has been erased.
 #[derive(Clone, Copy, PartialEq)]
 pub struct TaskRef {
@@ -144,6 +197,18 @@ impl TaskRef {
         self.ptr.as_ptr()
     }
 
+    /// Move the output of an ended task into `dst`, or drop it if `dst` is `None`.
+    ///
+    /// # Safety
+    ///
+    /// The task must have ended with [`Outcome::Output`], and the output must not have been taken
+    /// already. `T` must be the output type of the task.
+    #[cfg(feature = "join-handle")]
+    pub(crate) unsafe fn take_output<T>(self, dst: Option<&mut Option<T>>) {
+        let dst = dst.map_or(core::ptr::null_mut(), |dst| dst as *mut Option<T> as *mut ());
+        self.header().take_output.get().unwrap_unchecked()(self, dst)
+    }
+
     /// Get the ID for a task
     #[cfg(feature = "trace")]
     pub fn as_id(self) -> u32 {
@@ -169,11 +234,13 @@ impl TaskRef {
 #[repr(C)]
 pub struct TaskStorage<F: Future + 'static> {
     raw: TaskHeader,
-    future: UninitCell<F>, // Valid if STATE_SPAWNED
+    future: UninitCell<F>, // Valid if STATE_SPAWNED and not ended
+    #[cfg(feature = "join-handle")]
+    output: UninitCell<F::Output>, // Valid if ended with `Outcome::Output`, until taken
 }
 
 unsafe fn poll_exited(_p: TaskRef) {
-    // Nothing to do, the task is already !SPAWNED and dequeued.
+    // Nothing to do, the task has already ended and is dequeued.
 }
 
 impl<F: Future + 'static> TaskStorage<F> {
@@ -188,10 +255,20 @@ impl<F: Future + 'static> TaskStorage<F> {
                 executor: AtomicPtr::new(core::ptr::null_mut()),
                 // Note: this is lazily initialized so that a static `TaskStorage` will go in `.bss`
                 poll_fn: SyncUnsafeCell::new(None),
+                #[cfg(feature = "join-handle")]
+                join: JoinState::new(),
+                #[cfg(feature = "join-handle")]
+                take_output: SyncUnsafeCell::new(None),
+                #[cfg(feature = "task-priority")]
+                priority: SyncUnsafeCell::new(0),
+                #[cfg(feature = "task-stats")]
+                stats: stats::TaskStatsCell::new(),
 
                 timer_queue_item: timer_queue::TimerQueueItem::new(),
             },
             future: UninitCell::uninit(),
+            #[cfg(feature = "join-handle")]
+            output: UninitCell::uninit(),
         }
     }
 
@@ -208,7 +285,7 @@ impl<F: Future + 'static> TaskStorage<F> {
     ///
     /// Once the task has finished running, you may spawn it again. It is allowed to spawn it
     /// on a different executor.
-    pub fn spawn(&'static self, future: impl FnOnce() -> F) -> SpawnToken<impl Sized> {
+    pub fn spawn(&'static self, future: impl FnOnce() -> F) -> SpawnToken<impl Sized, F::Output> {
         let task = AvailableTask::claim(self);
         match task {
             Some(task) => task.initialize(future),
@@ -219,35 +296,83 @@ impl<F: Future + 'static> TaskStorage<F> {
     unsafe fn poll(p: TaskRef) {
         let this = &*p.as_ptr().cast::<TaskStorage<F>>();
 
+        #[cfg(feature = "join-handle")]
+        if this.raw.join.is_cancelled() {
+            // The future is dropped without being polled again.
+            this.future.drop_in_place();
+            Self::end(p, None);
+            return;
+        }
+
         let future = Pin::new_unchecked(this.future.as_mut());
         let waker = waker::from_task(p);
         let mut cx = Context::from_waker(&waker);
         match future.poll(&mut cx) {
-            Poll::Ready(_) => {
-                #[cfg(feature = "trace")]
-                let exec_ptr: *const SyncExecutor = this.raw.executor.load(Ordering::Relaxed);
-
+            Poll::Ready(output) => {
                 // As the future has finished and this function will not be called
                 // again, we can safely drop the future here.
                 this.future.drop_in_place();
+                Self::end(p, Some(output));
+            }
+            Poll::Pending => {}
+        }
+
+        // the compiler is emitting a virtual call for waker drop, but we know
+        // it's a noop for our waker.
+        mem::forget(waker);
+    }
+
+    /// Clean up after the future has been dropped, with its output if it returned.
+    unsafe fn end(p: TaskRef, output: Option<F::Output>) {
+        let this = &*p.as_ptr().cast::<TaskStorage<F>>();
 
-                // We replace the poll_fn with a despawn function, so that the task is cleaned up
-                // when the executor polls it next.
-                this.raw.poll_fn.set(Some(poll_exited));
+        // We replace the poll_fn with a despawn function, so that the task is cleaned up
+        // when the executor polls it next.
+        this.raw.poll_fn.set(Some(poll_exited));
+
+        // Trace the end before the task can be despawned, as it can then be spawned again.
+        #[cfg(feature = "_trace")]
+        trace::task_end(this.raw.executor.load(Ordering::Relaxed), &p);
+
+        #[cfg(feature = "join-handle")]
+        {
+            let outcome = match output {
+                Some(output) => {
+                    this.output.write_in_place(|| output);
+                    Outcome::Output
+                }
+                None => Outcome::Cancelled,
+            };
+
+            // If there's a JoinHandle, it takes the output and despawns the task instead.
+            if this.raw.join.finish(outcome) {
+                if outcome == Outcome::Output {
+                    this.output.drop_in_place();
+                }
 
                 // Make sure we despawn last, so that other threads can only spawn the task
                 // after we're done with it.
                 this.raw.state.despawn();
-
-                #[cfg(feature = "trace")]
-                trace::task_end(exec_ptr, &p);
             }
-            Poll::Pending => {}
         }
+        #[cfg(not(feature = "join-handle"))]
+        {
+            drop(output);
 
-        // the compiler is emitting a virtual call for waker drop, but we know
-        // it's a noop for our waker.
-        mem::forget(waker);
+            // Make sure we despawn last, so that other threads can only spawn the task
+            // after we're done with it.
+            this.raw.state.despawn();
+        }
+    }
+
+    #[cfg(feature = "join-handle")]
+    unsafe fn take_output(p: TaskRef, dst: *mut ()) {
+        let this = &*p.as_ptr().cast::<TaskStorage<F>>();
+        let output = this.output.as_mut_ptr().read();
+        match (dst as *mut Option<F::Output>).as_mut() {
+            Some(dst) => *dst = Some(output),
+            None => drop(output),
+        }
     }
 
     #[doc(hidden)]
@@ -269,12 +394,23 @@ impl<F: Future + 'static> AvailableTask<F> {
     ///
     /// This function returns `None` if a task has already been spawned and has not finished running.
     pub fn claim(task: &'static TaskStorage<F>) -> Option<Self> {
-        task.raw.state.spawn().then(|| Self { task })
+        if !task.raw.state.spawn() {
+            return None;
+        }
+        #[cfg(feature = "join-handle")]
+        task.raw.join.reset();
+        #[cfg(feature = "task-priority")]
+        unsafe {
+            task.raw.set_priority(0)
+        };
+        Some(Self { task })
     }
 
-    fn initialize_impl<S>(self, future: impl FnOnce() -> F) -> SpawnToken<S> {
+    fn initialize_impl<S>(self, future: impl FnOnce() -> F) -> SpawnToken<S, F::Output> {
         unsafe {
             self.task.raw.poll_fn.set(Some(TaskStorage::<F>::poll));
+            #[cfg(feature = "join-handle")]
+            self.task.raw.take_output.set(Some(TaskStorage::<F>::take_output));
             self.task.future.write_in_place(future);
 
             let task = TaskRef::new(self.task);
@@ -284,7 +420,7 @@ impl<F: Future + 'static> AvailableTask<F> {
     }
 
     /// Initialize the [`TaskStorage`] to run the given future.
-    pub fn initialize(self, future: impl FnOnce() -> F) -> SpawnToken<F> {
+    pub fn initialize(self, future: impl FnOnce() -> F) -> SpawnToken<F, F::Output> {
         self.initialize_impl::<F>(future)
     }
 
@@ -295,7 +431,7 @@ impl<F: Future + 'static> AvailableTask<F> {
     /// `future` must be a closure of the form `move || my_async_fn(args)`, where `my_async_fn`
     /// is an `async fn`, NOT a hand-written `Future`.
     #[doc(hidden)]
-    pub unsafe fn __initialize_async_fn<FutFn>(self, future: impl FnOnce() -> F) -> SpawnToken<FutFn> {
+    pub unsafe fn __initialize_async_fn<FutFn>(self, future: impl FnOnce() -> F) -> SpawnToken<FutFn, F::Output> {
         // When send-spawning a task, we construct the future in this thread, and effectively
         // "send" it to the executor thread by enqueuing it in its queue. Therefore, in theory,
         // send-spawning should require the future `F` to be `Send`.
@@ -330,6 +466,8 @@ impl<F: Future + 'static> AvailableTask<F> {
 /// This is essentially a `[TaskStorage<F>; N]`.
 pub struct TaskPool<F: Future + 'static, const N: usize> {
     pool: [TaskStorage<F>; N],
+    #[cfg(feature = "task-registry")]
+    registry: registry::PoolNode,
 }
 
 impl<F: Future + 'static, const N: usize> TaskPool<F, N> {
@@ -337,10 +475,35 @@ impl<F: Future + 'static, const N: usize> TaskPool<F, N> {
     pub const fn new() -> Self {
         Self {
             pool: [TaskStorage::NEW; N],
+            #[cfg(feature = "task-registry")]
+            registry: registry::PoolNode::new(None),
+        }
+    }
+
+    /// Create a new TaskPool, with all tasks in non-spawned state, listed as `name` in the
+    /// [task registry](task_pools).
+    #[cfg(feature = "task-registry")]
+    pub const fn new_named(name: &'static str) -> Self {
+        Self {
+            pool: [TaskStorage::NEW; N],
+            registry: registry::PoolNode::new(Some(name)),
         }
     }
 
-    fn spawn_impl<T>(&'static self, future: impl FnOnce() -> F) -> SpawnToken<T> {
+    /// Add the pool to the [task registry](task_pools), if it isn't already.
+    ///
+    /// Not covered by semver guarantees. DO NOT call this directly. Intended to be used
+    /// by the Embassy macros ONLY.
+    #[doc(hidden)]
+    #[cfg(feature = "task-registry")]
+    pub fn _register(&'static self) {
+        self.registry.register(&self.pool);
+    }
+
+    fn spawn_impl<T>(&'static self, future: impl FnOnce() -> F) -> SpawnToken<T, F::Output> {
+        #[cfg(feature = "task-registry")]
+        self._register();
+
         match self.pool.iter().find_map(AvailableTask::claim) {
             Some(task) => task.initialize_impl::<T>(future),
             None => SpawnToken::new_failed(),
@@ -354,7 +517,7 @@ impl<F: Future + 'static, const N: usize> TaskPool<F, N> {
     /// This will loop over the pool and spawn the task in the first storage that
     /// is currently free. If none is free, a "poisoned" SpawnToken is returned,
     /// which will cause [`Spawner::spawn()`](super::Spawner::spawn) to return the error.
-    pub fn spawn(&'static self, future: impl FnOnce() -> F) -> SpawnToken<impl Sized> {
+    pub fn spawn(&'static self, future: impl FnOnce() -> F) -> SpawnToken<impl Sized, F::Output> {
         self.spawn_impl::<F>(future)
     }
 
@@ -367,7 +530,7 @@ impl<F: Future + 'static, const N: usize> TaskPool<F, N> {
     /// SAFETY: `future` must be a closure of the form `move || my_async_fn(args)`, where `my_async_fn`
     /// is an `async fn`, NOT a hand-written `Future`.
     #[doc(hidden)]
-    pub unsafe fn _spawn_async_fn<FutFn>(&'static self, future: FutFn) -> SpawnToken<impl Sized>
+    pub unsafe fn _spawn_async_fn<FutFn>(&'static self, future: FutFn) -> SpawnToken<impl Sized, F::Output>
     where
         FutFn: FnOnce() -> F,
     {
@@ -394,6 +557,8 @@ impl Pender {
 pub(crate) struct SyncExecutor {
     run_queue: RunQueue,
     pender: Pender,
+    #[cfg(feature = "task-stats")]
+    pub(crate) stats: stats::ExecutorStatsCell,
 }
 
 impl SyncExecutor {
@@ -401,6 +566,8 @@ impl SyncExecutor {
         Self {
             run_queue: RunQueue::new(),
             pender,
+            #[cfg(feature = "task-stats")]
+            stats: stats::ExecutorStatsCell::new(),
         }
     }
 
@@ -412,7 +579,7 @@ impl SyncExecutor {
     /// - `task` must NOT be already enqueued (in this executor or another one).
     #[inline(always)]
     unsafe fn enqueue(&self, task: TaskRef, l: state::Token) {
-        #[cfg(feature = "trace")]
+        #[cfg(feature = "_trace")]
         trace::task_ready_begin(self, &task);
 
         if self.run_queue.enqueue(task, l) {
@@ -425,7 +592,7 @@ impl SyncExecutor {
             .executor
             .store((self as *const Self).cast_mut(), Ordering::Relaxed);
 
-        #[cfg(feature = "trace")]
+        #[cfg(feature = "_trace")]
         trace::task_new(self, &task);
 
         state::locked(|l| {
@@ -437,25 +604,51 @@ impl SyncExecutor {
     ///
     /// Same as [`Executor::poll`], plus you must only call this on the thread this executor was created.
     pub(crate) unsafe fn poll(&'static self) {
-        #[cfg(feature = "trace")]
+        #[cfg(feature = "_trace")]
         trace::poll_start(self);
 
-        self.run_queue.dequeue_all(|p| {
-            let task = p.header();
+        self.run_queue.dequeue_all(|p| self.poll_task(p));
 
-            #[cfg(feature = "trace")]
-            trace::task_exec_begin(self, &p);
+        #[cfg(feature = "_trace")]
+        trace::executor_idle(self)
+    }
 
-            // Run the task
-            task.poll_fn.get().unwrap_unchecked()(p);
+    /// Like [`poll`](Self::poll), but polls the queued tasks in the order set by `shuffle`.
+    /// Tasks of a higher priority are still polled first.
+    ///
+    /// # Safety
+    ///
+    /// Same as [`poll`](Self::poll).
+    #[cfg(feature = "arch-sim")]
+    pub(crate) unsafe fn poll_shuffled(&'static self, shuffle: impl FnOnce(&mut [TaskRef])) {
+        #[cfg(feature = "_trace")]
+        trace::poll_start(self);
 
-            #[cfg(feature = "trace")]
-            trace::task_exec_end(self, &p);
-        });
+        let mut batch = Vec::new();
+        self.run_queue.dequeue_all(|p| batch.push(p));
+        shuffle(&mut batch);
+        batch.sort_by_key(|p| core::cmp::Reverse(p.header().priority()));
+        for p in batch {
+            self.poll_task(p);
+        }
 
-        #[cfg(feature = "trace")]
+        #[cfg(feature = "_trace")]
         trace::executor_idle(self)
     }
+
+    #[inline(always)]
+    unsafe fn poll_task(&'static self, p: TaskRef) {
+        let task = p.header();
+
+        #[cfg(feature = "_trace")]
+        trace::task_exec_begin(self, &p);
+
+        // Run the task
+        task.poll_fn.get().unwrap_unchecked()(p);
+
+        #[cfg(feature = "_trace")]
+        trace::task_exec_end(self, &p);
+    }
 }
 
 /// Raw executor.
@@ -0,0 +1,122 @@
+//! # Task registry
+//!
+//! The `task-registry` feature keeps a global list of the [`TaskPool`](super::TaskPool)s, to audit
+//! the RAM used by tasks. The pools of `#[task]` functions are listed in a linker section, so that
+//! they are all in the list, even if no task was ever spawned in them. A pool created manually is
+//! added to the list the first time a task is spawned in it.
+
+use core::cell::Cell;
+use core::mem;
+
+use critical_section::Mutex;
+
+use super::{TaskHeader, TaskStorage};
+
+/// Memory usage of a [`TaskPool`](super::TaskPool).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TaskPoolInfo {
+    /// Name of the task: its path for a `#[task]` function, or the type name of its future.
+    pub name: &'static str,
+    /// Size of the future of the task, in bytes.
+    pub future_size: usize,
+    /// Size of a slot of the pool, in bytes. This includes the future, the task header and, with the
+    /// `join-handle` feature, the output.
+    pub slot_size: usize,
+    /// Number of slots in the pool.
+    pub pool_size: usize,
+    /// Number of slots currently holding a task.
+    pub occupied: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Entry {
+    name: &'static str,
+    future_size: usize,
+    slot_size: usize,
+    pool_size: usize,
+    /// Header of the first slot of the pool.
+    first: &'static TaskHeader,
+    /// Next pool in the registry.
+    next: Option<&'static PoolNode>,
+}
+
+impl Entry {
+    fn info(&self) -> TaskPoolInfo {
+        let first = self.first as *const TaskHeader as *const u8;
+        let occupied = (0..self.pool_size)
+            .filter(|i| {
+                // safety: the slots are `TaskStorage`s, which start with their header, in an array.
+                let header = unsafe { &*(first.add(i * self.slot_size) as *const TaskHeader) };
+                !header.state.is_idle()
+            })
+            .count();
+        TaskPoolInfo {
+            name: self.name,
+            future_size: self.future_size,
+            slot_size: self.slot_size,
+            pool_size: self.pool_size,
+            occupied,
+        }
+    }
+}
+
+/// Registry storage in the task pool.
+pub(crate) struct PoolNode {
+    name: Option<&'static str>,
+    entry: Mutex<Cell<Option<Entry>>>,
+}
+
+static POOLS: Mutex<Cell<Option<&'static PoolNode>>> = Mutex::new(Cell::new(None));
+
+/// Functions registering the pool of each `#[task]` function, collected by the linker.
+#[linkme::distributed_slice]
+pub static EMBASSY_TASK_POOLS: [fn()];
+
+impl PoolNode {
+    pub const fn new(name: Option<&'static str>) -> Self {
+        Self {
+            name,
+            entry: Mutex::new(Cell::new(None)),
+        }
+    }
+
+    /// Add the pool made of `slots` to the registry, if it isn't already.
+    pub fn register<F: core::future::Future + 'static>(&'static self, slots: &'static [TaskStorage<F>]) {
+        critical_section::with(|cs| {
+            let entry = self.entry.borrow(cs);
+            if entry.get().is_some() || slots.is_empty() {
+                return;
+            }
+            let head = POOLS.borrow(cs);
+            entry.set(Some(Entry {
+                name: self.name.unwrap_or(core::any::type_name::<F>()),
+                future_size: mem::size_of::<F>(),
+                slot_size: mem::size_of::<TaskStorage<F>>(),
+                pool_size: slots.len(),
+                first: &slots[0].raw,
+                next: head.get(),
+            }));
+            head.set(Some(self));
+        })
+    }
+}
+
+/// Call `f` with the memory usage of each registered [`TaskPool`](super::TaskPool).
+///
+/// Pools are listed from the most recently registered. The pools of `#[task]` functions are always
+/// listed, while a pool created manually is registered the first time a task is spawned in it.
+pub fn task_pools(mut f: impl FnMut(&TaskPoolInfo)) {
+    for register in EMBASSY_TASK_POOLS {
+        register();
+    }
+
+    let mut next = critical_section::with(|cs| POOLS.borrow(cs).get());
+    while let Some(node) = next {
+        let Some(entry) = critical_section::with(|cs| node.entry.borrow(cs).get()) else {
+            break;
+        };
+        f(&entry.info());
+        next = entry.next;
+    }
+}
@@ -2,7 +2,7 @@ use core::ptr;
 use core::ptr::NonNull;
 use core::sync::atomic::{AtomicPtr, Ordering};
 
-use super::{TaskHeader, TaskRef};
+use super::{TaskHeader, TaskRef, TASK_PRIORITY_LEVELS};
 use crate::raw::util::SyncUnsafeCell;
 
 pub(crate) struct RunQueueItem {
@@ -28,18 +28,22 @@ impl RunQueueItem {
 /// for our purposes: it can't create fairness problems since the next batch won't run until the
 /// current batch is completely processed, so even if a task enqueues itself instantly (for example
 /// by waking its own waker) can't prevent other tasks from running.
+///
+/// There's one such queue per task priority level. Higher priority batches are processed first,
+/// and tasks of a higher priority enqueued while a batch is processed are processed before the rest
+/// of the batch.
 pub(crate) struct RunQueue {
-    head: AtomicPtr<TaskHeader>,
+    heads: [AtomicPtr<TaskHeader>; TASK_PRIORITY_LEVELS],
 }
 
 impl RunQueue {
     pub const fn new() -> Self {
         Self {
-            head: AtomicPtr::new(ptr::null_mut()),
+            heads: [const { AtomicPtr::new(ptr::null_mut()) }; TASK_PRIORITY_LEVELS],
         }
     }
 
-    /// Enqueues an item. Returns true if the queue was empty.
+    /// Enqueues an item. Returns true if the queue of its priority level was empty.
     ///
     /// # Safety
     ///
@@ -48,7 +52,7 @@ impl RunQueue {
     pub(crate) unsafe fn enqueue(&self, task: TaskRef, _: super::state::Token) -> bool {
         let mut was_empty = false;
 
-        self.head
+        self.heads[task.header().priority()]
             .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |prev| {
                 was_empty = prev.is_null();
                 unsafe {
@@ -64,25 +68,40 @@ impl RunQueue {
         was_empty
     }
 
+    /// Atomically empty the queue of a priority level, returning its first task.
+    fn take(&self, level: usize) -> Option<TaskRef> {
+        let head = &self.heads[level];
+        if head.load(Ordering::Relaxed).is_null() {
+            return None;
+        }
+        let ptr = head.swap(ptr::null_mut(), Ordering::AcqRel);
+
+        // safety: the pointer is either null or valid
+        unsafe { NonNull::new(ptr).map(|ptr| TaskRef::from_ptr(ptr.as_ptr())) }
+    }
+
     /// Empty the queue, then call `on_task` for each task that was in the queue.
     /// NOTE: It is OK for `on_task` to enqueue more tasks. In this case they're left in the queue
-    /// and will be processed by the *next* call to `dequeue_all`, *not* the current one.
-    pub(crate) fn dequeue_all(&self, on_task: impl Fn(TaskRef)) {
-        // Atomically empty the queue.
-        let ptr = self.head.swap(ptr::null_mut(), Ordering::AcqRel);
+    /// and will be processed by the *next* call to `dequeue_all`, *not* the current one, unless
+    /// they have a higher priority than the task being processed.
+    pub(crate) fn dequeue_all(&self, mut on_task: impl FnMut(TaskRef)) {
+        let mut batches: [Option<TaskRef>; TASK_PRIORITY_LEVELS] = core::array::from_fn(|level| self.take(level));
 
-        // safety: the pointer is either null or valid
-        let mut next = unsafe { NonNull::new(ptr).map(|ptr| TaskRef::from_ptr(ptr.as_ptr())) };
+        // Iterate the linked lists of tasks that were previously in the queue, highest priority first.
+        while let Some(level) = batches.iter().rposition(Option::is_some) {
+            let task = unsafe { batches[level].unwrap_unchecked() };
 
-        // Iterate the linked list of tasks that were previously in the queue.
-        while let Some(task) = next {
             // If the task re-enqueues itself, the `next` pointer will get overwritten.
             // Therefore, first read the next pointer, and only then process the task.
             // safety: there are no concurrent accesses to `next`
-            next = unsafe { task.header().run_queue_item.next.get() };
+            batches[level] = unsafe { task.header().run_queue_item.next.get() };
 
             task.header().state.run_dequeue();
             on_task(task);
+
+            for (higher, batch) in batches.iter_mut().enumerate().skip(level + 1) {
+                *batch = self.take(higher);
+            }
         }
     }
 }
@@ -2,7 +2,7 @@ use core::cell::Cell;
 
 use critical_section::{CriticalSection, Mutex};
 
-use super::TaskRef;
+use super::{TaskRef, TASK_PRIORITY_LEVELS};
 
 pub(crate) struct RunQueueItem {
     next: Mutex<Cell<Option<TaskRef>>>,
@@ -27,25 +27,29 @@ impl RunQueueItem {
 /// for our purposes: it can't create fairness problems since the next batch won't run until the
 /// current batch is completely processed, so even if a task enqueues itself instantly (for example
 /// by waking its own waker) can't prevent other tasks from running.
+///
+/// There's one such queue per task priority level. Higher priority batches are processed first,
+/// and tasks of a higher priority enqueued while a batch is processed are processed before the rest
+/// of the batch.
 pub(crate) struct RunQueue {
-    head: Mutex<Cell<Option<TaskRef>>>,
+    heads: [Mutex<Cell<Option<TaskRef>>>; TASK_PRIORITY_LEVELS],
 }
 
 impl RunQueue {
     pub const fn new() -> Self {
         Self {
-            head: Mutex::new(Cell::new(None)),
+            heads: [const { Mutex::new(Cell::new(None)) }; TASK_PRIORITY_LEVELS],
         }
     }
 
-    /// Enqueues an item. Returns true if the queue was empty.
+    /// Enqueues an item. Returns true if the queue of its priority level was empty.
     ///
     /// # Safety
     ///
     /// `item` must NOT be already enqueued in any queue.
     #[inline(always)]
     pub(crate) unsafe fn enqueue(&self, task: TaskRef, cs: CriticalSection<'_>) -> bool {
-        let prev = self.head.borrow(cs).replace(Some(task));
+        let prev = self.heads[task.header().priority()].borrow(cs).replace(Some(task));
         task.header().run_queue_item.next.borrow(cs).set(prev);
 
         prev.is_none()
@@ -53,22 +57,34 @@ impl RunQueue {
 
     /// Empty the queue, then call `on_task` for each task that was in the queue.
     /// NOTE: It is OK for `on_task` to enqueue more tasks. In this case they're left in the queue
-    /// and will be processed by the *next* call to `dequeue_all`, *not* the current one.
-    pub(crate) fn dequeue_all(&self, on_task: impl Fn(TaskRef)) {
+    /// and will be processed by the *next* call to `dequeue_all`, *not* the current one, unless
+    /// they have a higher priority than the task being processed.
+    pub(crate) fn dequeue_all(&self, mut on_task: impl FnMut(TaskRef)) {
         // Atomically empty the queue.
-        let mut next = critical_section::with(|cs| self.head.borrow(cs).take());
+        let mut batches: [Option<TaskRef>; TASK_PRIORITY_LEVELS] =
+            critical_section::with(|cs| core::array::from_fn(|level| self.heads[level].borrow(cs).take()));
+
+        // Iterate the linked lists of tasks that were previously in the queue, highest priority first.
+        while let Some(level) = batches.iter().rposition(Option::is_some) {
+            let task = unsafe { batches[level].unwrap_unchecked() };
 
-        // Iterate the linked list of tasks that were previously in the queue.
-        while let Some(task) = next {
             // If the task re-enqueues itself, the `next` pointer will get overwritten.
             // Therefore, first read the next pointer, and only then process the task.
 
             critical_section::with(|cs| {
-                next = task.header().run_queue_item.next.borrow(cs).get();
+                batches[level] = task.header().run_queue_item.next.borrow(cs).get();
                 task.header().state.run_dequeue(cs);
             });
 
             on_task(task);
+
+            if level + 1 < TASK_PRIORITY_LEVELS {
+                critical_section::with(|cs| {
+                    for (head, batch) in self.heads.iter().zip(&mut batches).skip(level + 1) {
+                        *batch = head.borrow(cs).take();
+                    }
+                });
+            }
         }
     }
 }
@@ -34,6 +34,12 @@ impl State {
             .is_ok()
     }
 
+    /// Return whether the task is idle, so that it can be spawned.
+    #[cfg(feature = "task-registry")]
+    pub fn is_idle(&self) -> bool {
+        self.state.load(Ordering::Acquire) == 0
+    }
+
     /// Unmark the task as spawned.
     #[inline(always)]
     pub fn despawn(&self) {
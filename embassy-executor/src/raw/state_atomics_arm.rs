@@ -55,6 +55,12 @@ impl State {
         r
     }
 
+    /// Return whether the task is idle, so that it can be spawned.
+    #[cfg(feature = "task-registry")]
+    pub fn is_idle(&self) -> bool {
+        self.as_u32().load(Ordering::Acquire) == 0
+    }
+
     /// Unmark the task as spawned.
     #[inline(always)]
     pub fn despawn(&self) {
@@ -44,6 +44,12 @@ impl State {
         })
     }
 
+    /// Return whether the task is idle, so that it can be spawned.
+    #[cfg(feature = "task-registry")]
+    pub fn is_idle(&self) -> bool {
+        self.update(|s| *s == 0)
+    }
+
     /// Unmark the task as spawned.
     #[inline(always)]
     pub fn despawn(&self) {
@@ -0,0 +1,227 @@
+//! # Task statistics
+//!
+//! The `task-stats` feature collects runtime statistics for each task and executor, from the
+//! trace hooks. Durations are measured with `embassy-time-driver`, in ticks.
+//!
+//! The tasks of an executor are kept in a list, from when they're spawned to when they end, so
+//! that their statistics can be listed with [`Spawner::task_stats()`](crate::Spawner::task_stats).
+
+use core::cell::Cell;
+
+use critical_section::Mutex;
+
+use super::{SyncExecutor, TaskRef};
+
+/// Runtime statistics of a task, since it was spawned.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TaskStats {
+    /// ID of the task, the same as [`SpawnToken::id()`](crate::SpawnToken::id).
+    pub id: u32,
+    /// Number of times the task was polled.
+    pub polls: u32,
+    /// Number of times the task was woken, including when it was spawned.
+    pub wakes: u32,
+    /// Total time spent polling the task, in ticks.
+    pub poll_ticks: u64,
+    /// Longest time spent in a single poll of the task, in ticks.
+    ///
+    /// A high value means that the task blocks the executor without yielding.
+    pub max_poll_ticks: u64,
+}
+
+/// Runtime statistics of an executor, since it was first polled.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ExecutorStats {
+    /// Time elapsed since the executor was first polled, in ticks.
+    pub total_ticks: u64,
+    /// Time spent polling tasks, in ticks.
+    pub busy_ticks: u64,
+}
+
+impl ExecutorStats {
+    /// Fraction of the time the executor spent idle, between 0 and 1.
+    pub fn idle_ratio(&self) -> f32 {
+        if self.total_ticks == 0 {
+            return 1.0;
+        }
+        1.0 - self.busy_ticks.min(self.total_ticks) as f32 / self.total_ticks as f32
+    }
+}
+
+#[derive(Clone, Copy)]
+struct TaskState {
+    stats: TaskStats,
+    /// When the current poll started.
+    poll_start: u64,
+    /// Next task in the list of the executor.
+    next: Option<TaskRef>,
+}
+
+/// Statistics storage in the task header.
+pub(crate) struct TaskStatsCell {
+    state: Mutex<Cell<TaskState>>,
+}
+
+impl TaskStatsCell {
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(Cell::new(TaskState {
+                stats: TaskStats {
+                    id: 0,
+                    polls: 0,
+                    wakes: 0,
+                    poll_ticks: 0,
+                    max_poll_ticks: 0,
+                },
+                poll_start: 0,
+                next: None,
+            })),
+        }
+    }
+
+    fn update<R>(&self, f: impl FnOnce(&mut TaskState) -> R) -> R {
+        critical_section::with(|cs| {
+            let cell = self.state.borrow(cs);
+            let mut state = cell.get();
+            let r = f(&mut state);
+            cell.set(state);
+            r
+        })
+    }
+}
+
+#[derive(Clone, Copy)]
+struct ExecutorState {
+    /// Head of the list of spawned tasks.
+    tasks: Option<TaskRef>,
+    /// When the executor was first polled.
+    start: Option<u64>,
+    /// When the current call to `poll` started.
+    poll_start: u64,
+    busy_ticks: u64,
+}
+
+/// Statistics storage in the executor.
+pub(crate) struct ExecutorStatsCell {
+    state: Mutex<Cell<ExecutorState>>,
+}
+
+impl ExecutorStatsCell {
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(Cell::new(ExecutorState {
+                tasks: None,
+                start: None,
+                poll_start: 0,
+                busy_ticks: 0,
+            })),
+        }
+    }
+
+    fn update<R>(&self, f: impl FnOnce(&mut ExecutorState) -> R) -> R {
+        critical_section::with(|cs| {
+            let cell = self.state.borrow(cs);
+            let mut state = cell.get();
+            let r = f(&mut state);
+            cell.set(state);
+            r
+        })
+    }
+
+    /// Statistics of the executor.
+    pub fn executor(&self) -> ExecutorStats {
+        let now = embassy_time_driver::now();
+        self.update(|state| ExecutorStats {
+            total_ticks: state.start.map_or(0, |start| now - start),
+            busy_ticks: state.busy_ticks,
+        })
+    }
+
+    /// Call `f` with the statistics of each task currently spawned in the executor.
+    ///
+    /// The list is walked one task at a time, so that `f` isn't called in a critical section.
+    pub fn for_each_task(&self, mut f: impl FnMut(&TaskStats)) {
+        let mut next = self.update(|state| state.tasks);
+        while let Some(task) = next {
+            let state = task.header().stats.update(|state| *state);
+            f(&state.stats);
+            next = state.next;
+        }
+    }
+}
+
+pub(crate) fn poll_start(executor: &SyncExecutor) {
+    let now = embassy_time_driver::now();
+    executor.stats.update(|state| {
+        state.start.get_or_insert(now);
+        state.poll_start = now;
+    })
+}
+
+pub(crate) fn executor_idle(executor: &SyncExecutor) {
+    let now = embassy_time_driver::now();
+    executor
+        .stats
+        .update(|state| state.busy_ticks += now - state.poll_start)
+}
+
+pub(crate) fn task_new(executor: &SyncExecutor, task: &TaskRef) {
+    critical_section::with(|_| {
+        let head = executor.stats.update(|state| state.tasks.replace(*task));
+        task.header().stats.update(|state| {
+            *state = TaskState {
+                stats: TaskStats {
+                    id: task.as_ptr() as u32,
+                    ..TaskStats::default()
+                },
+                poll_start: 0,
+                next: head,
+            }
+        })
+    })
+}
+
+pub(crate) fn task_end(executor: *const SyncExecutor, task: &TaskRef) {
+    let executor = unsafe { &*executor };
+    critical_section::with(|_| {
+        let next = task.header().stats.update(|state| state.next.take());
+        let mut prev = executor.stats.update(|state| state.tasks);
+        if prev == Some(*task) {
+            executor.stats.update(|state| state.tasks = next);
+            return;
+        }
+        while let Some(p) = prev {
+            prev = p.header().stats.update(|state| {
+                if state.next == Some(*task) {
+                    state.next = next;
+                    None
+                } else {
+                    state.next
+                }
+            });
+        }
+    })
+}
+
+pub(crate) fn task_ready_begin(task: &TaskRef) {
+    task.header()
+        .stats
+        .update(|state| state.stats.wakes = state.stats.wakes.wrapping_add(1))
+}
+
+pub(crate) fn task_exec_begin(task: &TaskRef) {
+    let now = embassy_time_driver::now();
+    task.header().stats.update(|state| state.poll_start = now)
+}
+
+pub(crate) fn task_exec_end(task: &TaskRef) {
+    let now = embassy_time_driver::now();
+    task.header().stats.update(|state| {
+        let ticks = now - state.poll_start;
+        state.stats.polls = state.stats.polls.wrapping_add(1);
+        state.stats.poll_ticks += ticks;
+        state.stats.max_poll_ticks = state.stats.max_poll_ticks.max(ticks);
+    })
+}
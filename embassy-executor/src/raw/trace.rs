@@ -22,6 +22,9 @@
 //! Callbacks can be used by enabling the `trace` feature, and providing implementations of the
 //! `extern "Rust"` functions below. All callbacks must be implemented.
 //!
+//! The `task-stats` feature uses the same hooks to collect task statistics, without requiring
+//! the callbacks.
+//!
 //! ## Task Tracing lifecycle
 //!
 //! ```text
@@ -83,7 +86,7 @@
 
 use crate::raw::{SyncExecutor, TaskRef};
 
-#[cfg(not(feature = "rtos-trace"))]
+#[cfg(all(feature = "trace", not(feature = "rtos-trace")))]
 extern "Rust" {
     /// This callback is called when the executor begins polling. This will always
     /// be paired with a later call to `_embassy_trace_executor_idle`.
@@ -145,69 +148,90 @@ extern "Rust" {
 
 #[inline]
 pub(crate) fn poll_start(executor: &SyncExecutor) {
-    #[cfg(not(feature = "rtos-trace"))]
+    #[cfg(all(feature = "trace", not(feature = "rtos-trace")))]
     unsafe {
         _embassy_trace_poll_start(executor as *const _ as u32)
     }
+
+    #[cfg(feature = "task-stats")]
+    super::stats::poll_start(executor);
 }
 
 #[inline]
 pub(crate) fn task_new(executor: &SyncExecutor, task: &TaskRef) {
-    #[cfg(not(feature = "rtos-trace"))]
+    #[cfg(all(feature = "trace", not(feature = "rtos-trace")))]
     unsafe {
         _embassy_trace_task_new(executor as *const _ as u32, task.as_ptr() as u32)
     }
 
     #[cfg(feature = "rtos-trace")]
     rtos_trace::trace::task_new(task.as_ptr() as u32);
+
+    #[cfg(feature = "task-stats")]
+    super::stats::task_new(executor, task);
 }
 
 #[inline]
 pub(crate) fn task_end(executor: *const SyncExecutor, task: &TaskRef) {
-    #[cfg(not(feature = "rtos-trace"))]
+    #[cfg(all(feature = "trace", not(feature = "rtos-trace")))]
     unsafe {
         _embassy_trace_task_end(executor as u32, task.as_ptr() as u32)
     }
+
+    #[cfg(feature = "task-stats")]
+    super::stats::task_end(executor, task);
 }
 
 #[inline]
 pub(crate) fn task_ready_begin(executor: &SyncExecutor, task: &TaskRef) {
-    #[cfg(not(feature = "rtos-trace"))]
+    #[cfg(all(feature = "trace", not(feature = "rtos-trace")))]
     unsafe {
         _embassy_trace_task_ready_begin(executor as *const _ as u32, task.as_ptr() as u32)
     }
     #[cfg(feature = "rtos-trace")]
     rtos_trace::trace::task_ready_begin(task.as_ptr() as u32);
+
+    #[cfg(feature = "task-stats")]
+    super::stats::task_ready_begin(task);
 }
 
 #[inline]
 pub(crate) fn task_exec_begin(executor: &SyncExecutor, task: &TaskRef) {
-    #[cfg(not(feature = "rtos-trace"))]
+    #[cfg(all(feature = "trace", not(feature = "rtos-trace")))]
     unsafe {
         _embassy_trace_task_exec_begin(executor as *const _ as u32, task.as_ptr() as u32)
     }
     #[cfg(feature = "rtos-trace")]
     rtos_trace::trace::task_exec_begin(task.as_ptr() as u32);
+
+    #[cfg(feature = "task-stats")]
+    super::stats::task_exec_begin(task);
 }
 
 #[inline]
 pub(crate) fn task_exec_end(executor: &SyncExecutor, task: &TaskRef) {
-    #[cfg(not(feature = "rtos-trace"))]
+    #[cfg(all(feature = "trace", not(feature = "rtos-trace")))]
     unsafe {
         _embassy_trace_task_exec_end(executor as *const _ as u32, task.as_ptr() as u32)
     }
     #[cfg(feature = "rtos-trace")]
     rtos_trace::trace::task_exec_end();
+
+    #[cfg(feature = "task-stats")]
+    super::stats::task_exec_end(task);
 }
 
 #[inline]
 pub(crate) fn executor_idle(executor: &SyncExecutor) {
-    #[cfg(not(feature = "rtos-trace"))]
+    #[cfg(all(feature = "trace", not(feature = "rtos-trace")))]
     unsafe {
         _embassy_trace_executor_idle(executor as *const _ as u32)
     }
     #[cfg(feature = "rtos-trace")]
     rtos_trace::trace::system_idle();
+
+    #[cfg(feature = "task-stats")]
+    super::stats::executor_idle(executor);
 }
 
 #[cfg(feature = "rtos-trace")]
@@ -5,6 +5,8 @@ use core::sync::atomic::Ordering;
 use core::task::Poll;
 
 use super::raw;
+#[cfg(feature = "join-handle")]
+use super::JoinHandle;
 
 /// Token to spawn a newly-created task in an executor.
 ///
@@ -16,21 +18,26 @@ use super::raw;
 /// in other threads or not. If `S: Send`, it can, which allows spawning it into a [`SendSpawner`].
 /// If not, it can't, so it can only be spawned into the current thread's executor, with [`Spawner`].
 ///
+/// The generic parameter `T` is the output of the task. With the `join-handle` feature, it can be
+/// obtained by spawning the task with `Spawner::spawn_with_handle()`. Otherwise, it is dropped.
+///
 /// # Panics
 ///
 /// Dropping a SpawnToken instance panics. You may not "abort" spawning a task in this way.
 /// Once you've invoked a task function and obtained a SpawnToken, you *must* spawn it.
 #[must_use = "Calling a task function does nothing on its own. You must spawn the returned SpawnToken, typically with Spawner::spawn()"]
-pub struct SpawnToken<S> {
+pub struct SpawnToken<S, T = ()> {
     raw_task: Option<raw::TaskRef>,
     phantom: PhantomData<*mut S>,
+    output: PhantomData<fn() -> T>,
 }
 
-impl<S> SpawnToken<S> {
+impl<S, T> SpawnToken<S, T> {
     pub(crate) unsafe fn new(raw_task: raw::TaskRef) -> Self {
         Self {
             raw_task: Some(raw_task),
             phantom: PhantomData,
+            output: PhantomData,
         }
     }
 
@@ -48,11 +55,41 @@ impl<S> SpawnToken<S> {
         Self {
             raw_task: None,
             phantom: PhantomData,
+            output: PhantomData,
+        }
+    }
+
+    /// Set the priority of the task, from 0 (the default) to [`TASK_PRIORITY_LEVELS`](raw::TASK_PRIORITY_LEVELS)` - 1`.
+    ///
+    /// When several tasks are woken, the ones with a higher priority are polled first. A task
+    /// of a higher priority woken while lower priority tasks are being polled is polled next,
+    /// before the remaining ones. Tasks of a higher priority that are constantly woken can
+    /// therefore starve tasks of a lower priority.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `priority` is not lower than [`TASK_PRIORITY_LEVELS`](raw::TASK_PRIORITY_LEVELS).
+    #[cfg(feature = "task-priority")]
+    pub fn with_priority(self, priority: u8) -> Self {
+        assert!(
+            (priority as usize) < raw::TASK_PRIORITY_LEVELS,
+            "task priority out of range"
+        );
+        if let Some(task) = self.raw_task {
+            // safety: the task is not spawned yet.
+            unsafe { task.header().set_priority(priority) };
         }
+        self
+    }
+
+    /// Claim the task for a [`JoinHandle`] before spawning it.
+    #[cfg(feature = "join-handle")]
+    fn join_handle(&self) -> Option<JoinHandle<T>> {
+        self.raw_task.map(|task| unsafe { JoinHandle::new(task) })
     }
 }
 
-impl<S> Drop for SpawnToken<S> {
+impl<S, T> Drop for SpawnToken<S, T> {
     fn drop(&mut self) {
         // TODO deallocate the task instead.
         panic!("SpawnToken instances may not be dropped. You must pass them to Spawner::spawn()")
@@ -141,7 +178,7 @@ impl Spawner {
     /// Spawn a task into an executor.
     ///
     /// You obtain the `token` by calling a task function (i.e. one marked with `#[embassy_executor::task]`).
-    pub fn spawn<S>(&self, token: SpawnToken<S>) -> Result<(), SpawnError> {
+    pub fn spawn<S, T>(&self, token: SpawnToken<S, T>) -> Result<(), SpawnError> {
         let task = token.raw_task;
         mem::forget(token);
 
@@ -154,6 +191,16 @@ impl Spawner {
         }
     }
 
+    /// Spawn a task into an executor, returning a [`JoinHandle`] to await its output or cancel it.
+    ///
+    /// You obtain the `token` by calling a task function (i.e. one marked with `#[embassy_executor::task]`).
+    #[cfg(feature = "join-handle")]
+    pub fn spawn_with_handle<S, T>(&self, token: SpawnToken<S, T>) -> Result<JoinHandle<T>, SpawnError> {
+        let handle = token.join_handle();
+        self.spawn(token)?;
+        Ok(unwrap!(handle))
+    }
+
     // Used by the `embassy_executor_macros::main!` macro to throw an error when spawn
     // fails. This is here to allow conditional use of `defmt::unwrap!`
     // without introducing a `defmt` feature in the `embassy_executor_macros` package,
@@ -163,7 +210,7 @@ impl Spawner {
     /// # Panics
     ///
     /// Panics if the spawning fails.
-    pub fn must_spawn<S>(&self, token: SpawnToken<S>) {
+    pub fn must_spawn<S, T>(&self, token: SpawnToken<S, T>) {
         unwrap!(self.spawn(token));
     }
 
@@ -178,6 +225,20 @@ impl Spawner {
     pub fn executor_id(&self) -> usize {
         self.executor.id()
     }
+
+    /// Call `f` with the runtime statistics of each task currently spawned in this Spawner's Executor.
+    ///
+    /// Tasks are listed from the most recently spawned. A task is removed from the list when it ends.
+    #[cfg(feature = "task-stats")]
+    pub fn task_stats(&self, f: impl FnMut(&raw::TaskStats)) {
+        self.executor.inner.stats.for_each_task(f)
+    }
+
+    /// Return the runtime statistics of this Spawner's Executor.
+    #[cfg(feature = "task-stats")]
+    pub fn executor_stats(&self) -> raw::ExecutorStats {
+        self.executor.inner.stats.executor()
+    }
 }
 
 /// Handle to spawn tasks into an executor from any thread.
@@ -222,7 +283,7 @@ impl SendSpawner {
     /// Spawn a task into an executor.
     ///
     /// You obtain the `token` by calling a task function (i.e. one marked with `#[embassy_executor::task]`).
-    pub fn spawn<S: Send>(&self, token: SpawnToken<S>) -> Result<(), SpawnError> {
+    pub fn spawn<S: Send, T>(&self, token: SpawnToken<S, T>) -> Result<(), SpawnError> {
         let header = token.raw_task;
         mem::forget(token);
 
@@ -235,12 +296,24 @@ impl SendSpawner {
         }
     }
 
+    /// Spawn a task into an executor, returning a [`JoinHandle`] to await its output or cancel it.
+    ///
+    /// You obtain the `token` by calling a task function (i.e. one marked with `#[embassy_executor::task]`).
+    ///
+    /// The output of the task is sent to the thread awaiting the handle, so it must be `Send`.
+    #[cfg(feature = "join-handle")]
+    pub fn spawn_with_handle<S: Send, T: Send>(&self, token: SpawnToken<S, T>) -> Result<JoinHandle<T>, SpawnError> {
+        let handle = token.join_handle();
+        self.spawn(token)?;
+        Ok(unwrap!(handle))
+    }
+
     /// Spawn a task into an executor, panicking on failure.
     ///
     /// # Panics
     ///
     /// Panics if the spawning fails.
-    pub fn must_spawn<S: Send>(&self, token: SpawnToken<S>) {
+    pub fn must_spawn<S: Send, T>(&self, token: SpawnToken<S, T>) {
         unwrap!(self.spawn(token));
     }
 }
@@ -0,0 +1,107 @@
+#![cfg(all(feature = "arch-sim", feature = "executor-thread"))]
+#![cfg_attr(feature = "nightly", feature(impl_trait_in_assoc_type))]
+
+use std::boxed::Box;
+use std::sync::Mutex;
+use std::vec::Vec;
+
+use embassy_executor::{task, Deadlock, Executor};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
+
+fn executor(executor: Executor) -> &'static Executor {
+    Box::leak(Box::new(executor))
+}
+
+#[test]
+fn sim_time_jumps_to_next_deadline() {
+    static HOUR: Signal<CriticalSectionRawMutex, Instant> = Signal::new();
+    static DAY: Signal<CriticalSectionRawMutex, Instant> = Signal::new();
+
+    #[task(pool_size = 2)]
+    async fn sleeper(secs: u64, woken: &'static Signal<CriticalSectionRawMutex, Instant>) {
+        Timer::after_secs(secs).await;
+        woken.signal(Instant::now());
+    }
+
+    let executor = executor(Executor::new());
+    let spawner = executor.spawner();
+
+    let woken = executor
+        .block_on(async {
+            spawner.spawn(sleeper(3600, &HOUR)).unwrap();
+            spawner.spawn(sleeper(86400, &DAY)).unwrap();
+            (HOUR.wait().await, DAY.wait().await)
+        })
+        .unwrap();
+    assert_eq!(woken.0.as_secs(), 3600);
+    assert_eq!(woken.1.as_secs(), 86400);
+
+    // The clock only moves when everything is idle.
+    let elapsed = executor
+        .block_on(async {
+            let start = Instant::now();
+            Timer::after_millis(5).await;
+            start.elapsed()
+        })
+        .unwrap();
+    assert_eq!(elapsed, Duration::from_millis(5));
+}
+
+#[test]
+fn sim_deadlock() {
+    static SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+    static DONE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+    #[task]
+    async fn waiter() {
+        SIGNAL.wait().await;
+        DONE.signal(());
+    }
+
+    let executor = executor(Executor::new());
+    let spawner = executor.spawner();
+
+    let res = executor.block_on(async {
+        spawner.spawn(waiter()).unwrap();
+        Timer::after_secs(1).await;
+        DONE.wait().await
+    });
+    assert_eq!(res.unwrap_err(), Deadlock);
+    assert_eq!(Instant::now().as_secs(), 1);
+}
+
+#[test]
+fn sim_seeded_poll_order() {
+    #[task(pool_size = 8)]
+    async fn worker(id: u32, order: &'static Mutex<Vec<u32>>) {
+        order.lock().unwrap().push(id);
+    }
+
+    fn run(executor: &'static Executor) -> Vec<u32> {
+        let order: &'static Mutex<Vec<u32>> = Box::leak(Box::new(Mutex::new(Vec::new())));
+        let spawner = executor.spawner();
+        executor
+            .block_on(async {
+                for id in 0..8 {
+                    spawner.spawn(worker(id, order)).unwrap();
+                }
+                // Wait for the workers to end.
+                Timer::after_ticks(1).await;
+            })
+            .unwrap();
+        order.lock().unwrap().clone()
+    }
+
+    let unseeded = run(executor(Executor::new()));
+    assert_eq!(run(executor(Executor::new())), unseeded);
+
+    let seeded = run(executor(Executor::with_seed(0x5eed)));
+    assert_eq!(run(executor(Executor::with_seed(0x5eed))), seeded);
+
+    let mut sorted = seeded.clone();
+    sorted.sort();
+    assert_eq!(sorted, (0..8).collect::<Vec<_>>());
+    assert!((0..8).any(|seed| run(executor(Executor::with_seed(seed))) != unseeded));
+}
@@ -1,4 +1,6 @@
 #![cfg_attr(feature = "nightly", feature(impl_trait_in_assoc_type))]
+// These tests drive the raw executor with their own pender.
+#![cfg(not(feature = "_arch"))]
 
 use std::boxed::Box;
 use std::future::poll_fn;
@@ -283,3 +285,300 @@ fn executor_task_cfg_args() {
         let (_, _, _) = (a, b, c);
     }
 }
+
+#[cfg(feature = "join-handle")]
+mod join_handle {
+    use std::future::Future;
+    use std::pin::pin;
+    use std::task::{Context, Waker};
+
+    use embassy_executor::{JoinError, JoinHandle, SpawnError};
+
+    use super::*;
+
+    fn poll_once<F: Future>(f: F) -> Poll<F::Output> {
+        pin!(f).poll(&mut Context::from_waker(Waker::noop()))
+    }
+
+    #[test]
+    fn join_handle_output() {
+        #[task]
+        async fn task1(trace: Trace) -> u32 {
+            trace.push("poll task1");
+            5
+        }
+
+        #[task]
+        async fn joiner(trace: Trace, handle: JoinHandle<u32>) {
+            trace.push("poll joiner");
+            assert_eq!(handle.await.unwrap(), 5);
+            trace.push("joined");
+        }
+
+        let (executor, trace) = setup();
+        let handle = executor.spawner().spawn_with_handle(task1(trace.clone())).unwrap();
+        assert!(!handle.is_finished());
+        executor.spawner().spawn(joiner(trace.clone(), handle)).unwrap();
+
+        unsafe { executor.poll() };
+        unsafe { executor.poll() };
+
+        assert_eq!(
+            trace.get(),
+            &[
+                "pend",        // spawning a task pends the executor, only once for two tasks
+                "poll joiner", // task1 is still running
+                "poll task1",  //
+                "pend",        // task1 ending wakes the joiner
+                "joined",      //
+            ]
+        );
+    }
+
+    #[test]
+    fn join_handle_keeps_task_claimed() {
+        #[task]
+        async fn task1() -> u32 {
+            5
+        }
+
+        let (executor, _trace) = setup();
+        let spawner = executor.spawner();
+        let handle = spawner.spawn_with_handle(task1()).unwrap();
+        unsafe { executor.poll() };
+        assert!(handle.is_finished());
+
+        // The output hasn't been taken yet.
+        assert!(matches!(spawner.spawn(task1()), Err(SpawnError::Busy)));
+        assert_eq!(poll_once(handle), Poll::Ready(Ok(5)));
+        unsafe { executor.poll() };
+
+        // A detached task releases its storage when it ends.
+        let handle = spawner.spawn_with_handle(task1()).unwrap();
+        drop(handle);
+        unsafe { executor.poll() };
+        spawner.spawn(task1()).unwrap();
+    }
+
+    #[test]
+    fn join_handle_cancel() {
+        struct DropGuard(Trace);
+        impl Drop for DropGuard {
+            fn drop(&mut self) {
+                self.0.push("drop task1")
+            }
+        }
+
+        #[task]
+        async fn task1(trace: Trace) -> u32 {
+            let _guard = DropGuard(trace.clone());
+            poll_fn(|_| {
+                trace.push("poll task1");
+                Poll::<()>::Pending
+            })
+            .await;
+            5
+        }
+
+        let (executor, trace) = setup();
+        let spawner = executor.spawner();
+        let mut handle = spawner.spawn_with_handle(task1(trace.clone())).unwrap();
+        unsafe { executor.poll() };
+        assert_eq!(poll_once(&mut handle), Poll::Pending);
+
+        handle.cancel();
+        assert!(!handle.is_finished());
+        unsafe { executor.poll() };
+        assert!(handle.is_finished());
+        assert_eq!(poll_once(handle), Poll::Ready(Err(JoinError::Cancelled)));
+
+        // The task can be spawned again.
+        spawner.spawn(task1(trace.clone())).unwrap();
+        unsafe { executor.poll() };
+
+        assert_eq!(
+            trace.get(),
+            &[
+                "pend",       // spawning a task pends the executor
+                "poll task1", //
+                "pend",       // cancelling wakes the task
+                "drop task1", // the future is dropped instead of polled
+                "pend",       // respawning a task pends the executor
+                "poll task1", //
+            ]
+        );
+    }
+}
+
+#[cfg(feature = "task-priority")]
+#[test]
+fn executor_task_priority() {
+    use embassy_sync::waitqueue::AtomicWaker;
+
+    #[task(priority = 1)]
+    async fn high(trace: Trace, waker: &'static AtomicWaker) {
+        let mut registered = false;
+        poll_fn(|cx| {
+            trace.push("poll high");
+            if registered {
+                return Poll::Ready(());
+            }
+            waker.register(cx.waker());
+            registered = true;
+            Poll::Pending
+        })
+        .await
+    }
+
+    #[task(pool_size = 2)]
+    async fn low(trace: Trace, name: &'static str, waker: &'static AtomicWaker) {
+        trace.push(name);
+        waker.wake();
+    }
+
+    let waker = Box::leak(Box::new(AtomicWaker::new()));
+
+    let (executor, trace) = setup();
+    let spawner = executor.spawner();
+    spawner.spawn(high(trace.clone(), waker)).unwrap();
+    unsafe { executor.poll() };
+
+    spawner.spawn(low(trace.clone(), "poll low1", waker)).unwrap();
+    spawner.spawn(low(trace.clone(), "poll low2", waker)).unwrap();
+    unsafe { executor.poll() };
+
+    assert_eq!(
+        trace.get(),
+        &[
+            "pend",      // spawning a task pends the executor
+            "poll high", //
+            "pend",      // spawning the low tasks
+            "poll low2", //
+            "pend",      // low2 wakes high
+            "poll high", // high is polled before the rest of the low batch
+            "poll low1", //
+            "pend",      // low1 wakes the exited high task, which is not polled
+        ]
+    )
+}
+
+#[cfg(feature = "task-stats")]
+static NOW: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+
+#[cfg(feature = "task-stats")]
+struct MockDriver;
+
+#[cfg(feature = "task-stats")]
+embassy_time_driver::time_driver_impl!(static DRIVER: MockDriver = MockDriver);
+
+#[cfg(feature = "task-stats")]
+impl embassy_time_driver::Driver for MockDriver {
+    fn now(&self) -> u64 {
+        NOW.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    fn schedule_wake(&self, _at: u64, _waker: &std::task::Waker) {
+        unimplemented!()
+    }
+}
+
+#[cfg(feature = "task-stats")]
+#[test]
+fn executor_task_stats() {
+    use std::sync::atomic::Ordering;
+
+    // Simulates a task that blocks the executor for `ticks` in each poll.
+    #[task]
+    async fn busy(ticks: &'static [u64]) {
+        for t in ticks {
+            NOW.fetch_add(*t, Ordering::Relaxed);
+            let mut yielded = false;
+            poll_fn(|cx| {
+                if yielded {
+                    return Poll::Ready(());
+                }
+                yielded = true;
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            })
+            .await
+        }
+    }
+
+    #[task]
+    async fn idle() {
+        poll_fn(|_| Poll::<()>::Pending).await
+    }
+
+    let (executor, _trace) = setup();
+    let spawner = executor.spawner();
+    let busy_token = busy(&[10, 3]);
+    let busy_id = busy_token.id();
+    spawner.spawn(busy_token).unwrap();
+    let idle_token = idle();
+    let idle_id = idle_token.id();
+    spawner.spawn(idle_token).unwrap();
+
+    let start = NOW.load(Ordering::Relaxed);
+    unsafe { executor.poll() };
+    NOW.fetch_add(30, Ordering::Relaxed);
+
+    let mut stats = Vec::new();
+    spawner.task_stats(|s| stats.push(*s));
+    assert_eq!(stats.len(), 2);
+    assert_eq!(stats[0].id, idle_id);
+    assert_eq!((stats[0].polls, stats[0].wakes, stats[0].max_poll_ticks), (1, 1, 0));
+    assert_eq!(stats[1].id, busy_id);
+    assert_eq!((stats[1].polls, stats[1].wakes), (1, 2));
+    assert_eq!((stats[1].poll_ticks, stats[1].max_poll_ticks), (10, 10));
+
+    let executor_stats = spawner.executor_stats();
+    assert_eq!(executor_stats.total_ticks, NOW.load(Ordering::Relaxed) - start);
+    assert_eq!(executor_stats.busy_ticks, 10);
+    assert_eq!(executor_stats.idle_ratio(), 0.75);
+
+    // The busy task ends, and is removed from the list.
+    unsafe { executor.poll() };
+    unsafe { executor.poll() };
+    let mut ids = Vec::new();
+    spawner.task_stats(|s| ids.push(s.id));
+    assert_eq!(ids, &[idle_id]);
+    assert_eq!(spawner.executor_stats().busy_ticks, 13);
+}
+
+#[cfg(feature = "task-registry")]
+#[test]
+fn task_registry() {
+    use embassy_executor::raw::{task_pools, TaskPoolInfo};
+
+    #[task(pool_size = 3)]
+    async fn registered(buf: [u8; 100]) {
+        poll_fn(|_| Poll::<()>::Pending).await;
+        core::hint::black_box(buf);
+    }
+
+    fn info() -> Option<TaskPoolInfo> {
+        let mut found = None;
+        task_pools(|info| {
+            if info.name.ends_with("::registered") {
+                found = Some(*info);
+            }
+        });
+        found
+    }
+
+    // The pool is listed before any task is spawned in it.
+    assert_eq!(info().map(|info| info.occupied), Some(0));
+
+    let (executor, _trace) = setup();
+    executor.spawner().spawn(registered([0; 100])).unwrap();
+    executor.spawner().spawn(registered([0; 100])).unwrap();
+    unsafe { executor.poll() };
+
+    let info = info().unwrap();
+    assert_eq!(info.name, concat!(module_path!(), "::registered"));
+    assert_eq!(info.pool_size, 3);
+    assert_eq!(info.occupied, 2);
+    assert!(info.future_size >= 100);
+    assert!(info.slot_size > info.future_size);
+}
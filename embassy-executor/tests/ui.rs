@@ -3,6 +3,7 @@
 fn ui() {
     let t = trybuild::TestCases::new();
     t.compile_fail("tests/ui/abi.rs");
+    #[cfg(not(feature = "join-handle"))]
     t.compile_fail("tests/ui/bad_return.rs");
     t.compile_fail("tests/ui/generics.rs");
     t.compile_fail("tests/ui/impl_trait_nested.rs");
@@ -21,4 +22,8 @@ fn ui() {
     t.compile_fail("tests/ui/self.rs");
     t.compile_fail("tests/ui/type_error.rs");
     t.compile_fail("tests/ui/where_clause.rs");
+    #[cfg(feature = "join-handle")]
+    t.pass("tests/ui/return_value.rs");
+    #[cfg(feature = "join-handle")]
+    t.compile_fail("tests/ui/return_not_send.rs");
 }
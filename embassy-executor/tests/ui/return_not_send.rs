@@ -0,0 +1,16 @@
+#![cfg_attr(feature = "nightly", feature(impl_trait_in_assoc_type))]
+
+use std::rc::Rc;
+
+#[embassy_executor::task]
+async fn task() -> Rc<u32> {
+    Rc::new(5)
+}
+
+fn spawn(spawner: embassy_executor::SendSpawner) {
+    let _ = spawner.spawn_with_handle(task());
+}
+
+fn main() {
+    let _ = spawn;
+}
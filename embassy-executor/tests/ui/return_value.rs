@@ -0,0 +1,20 @@
+#![cfg_attr(feature = "nightly", feature(impl_trait_in_assoc_type))]
+
+#[embassy_executor::task]
+async fn task() -> u32 {
+    5
+}
+
+#[embassy_executor::task(pool_size = 2)]
+async fn task_ref(x: &'static u32) -> &'static u32 {
+    x
+}
+
+fn spawn(spawner: embassy_executor::Spawner) {
+    spawner.spawn(task()).unwrap();
+    spawner.spawn(task_ref(&5)).unwrap();
+}
+
+fn main() {
+    let _ = spawn;
+}
@@ -0,0 +1,390 @@
+//! A dynamic set of futures, polled as they're woken.
+
+use core::cell::RefCell;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+use critical_section::Mutex;
+
+/// Wakers of a [`FuturesSet`] of up to `N` futures.
+///
+/// The futures of the set get a waker for their own slot, so that the set only polls the futures
+/// that were woken. Those wakers may outlive the set, so they're stored separately, with a
+/// `'static` lifetime. A set borrows them mutably, so they can't be shared by several sets:
+///
+/// ```
+/// use embassy_futures::futures_set::FuturesSetWakers;
+/// use static_cell::StaticCell;
+///
+/// static WAKERS: StaticCell<FuturesSetWakers<4>> = StaticCell::new();
+/// let wakers: &'static mut FuturesSetWakers<4> = WAKERS.init(FuturesSetWakers::new());
+/// ```
+// `slots` must be the first field, see `slot_wake`.
+#[repr(C)]
+pub struct FuturesSetWakers<const N: usize> {
+    slots: [SlotWaker; N],
+    /// Waker of the task polling the set.
+    waker: Mutex<RefCell<Option<Waker>>>,
+}
+
+struct SlotWaker {
+    index: usize,
+    ready: AtomicBool,
+}
+
+impl<const N: usize> FuturesSetWakers<N> {
+    /// Create new wakers.
+    pub const fn new() -> Self {
+        let mut slots = [const {
+            SlotWaker {
+                index: 0,
+                ready: AtomicBool::new(false),
+            }
+        }; N];
+        let mut i = 0;
+        while i < N {
+            slots[i].index = i;
+            i += 1;
+        }
+        Self {
+            slots,
+            waker: Mutex::new(RefCell::new(None)),
+        }
+    }
+
+    fn slot_waker(&'static self, index: usize) -> Waker {
+        let data = &self.slots[index] as *const SlotWaker as *const ();
+        // safety: the vtable functions get a pointer to a slot of a `'static` `FuturesSetWakers<N>`.
+        unsafe { Waker::from_raw(RawWaker::new(data, &Self::VTABLE)) }
+    }
+
+    const VTABLE: RawWakerVTable = RawWakerVTable::new(
+        |data| RawWaker::new(data, &Self::VTABLE),
+        Self::slot_wake,
+        Self::slot_wake,
+        |_| {},
+    );
+
+    unsafe fn slot_wake(data: *const ()) {
+        let slot = &*(data as *const SlotWaker);
+        slot.ready.store(true, Ordering::Release);
+        // The slots are the first field, so the first slot is at the address of the wakers.
+        let this = &*((data as *const SlotWaker).sub(slot.index) as *const Self);
+        let waker = critical_section::with(|cs| this.waker.borrow_ref_mut(cs).take());
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<const N: usize> Default for FuturesSetWakers<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A fixed-capacity set of futures, which can be added and removed at runtime.
+///
+/// [`next()`](Self::next) waits for the next future of the set to complete, and returns its
+/// output. Each future is woken through its own waker, so only the futures that were woken are
+/// polled, which makes the set a lightweight scheduler inside a task, for example to run a
+/// handler per connection.
+///
+/// The set must be pinned to be used, for example with [`core::pin::pin!`].
+///
+/// ```
+/// use core::pin::pin;
+/// use embassy_futures::block_on;
+/// use embassy_futures::futures_set::{FuturesSet, FuturesSetWakers};
+///
+/// use static_cell::StaticCell;
+///
+/// static WAKERS: StaticCell<FuturesSetWakers<4>> = StaticCell::new();
+///
+/// async fn handle(id: u32) -> u32 {
+///     id * 10
+/// }
+///
+/// block_on(async {
+///     let mut set = pin!(FuturesSet::new(WAKERS.init(FuturesSetWakers::new())));
+///     for id in 1..=3 {
+///         assert!(set.as_mut().insert(handle(id)).is_ok());
+///     }
+///
+///     let mut total = 0;
+///     while !set.is_empty() {
+///         let (output, _slot) = set.as_mut().next().await;
+///         total += output;
+///     }
+///     assert_eq!(total, 60);
+/// });
+/// ```
+pub struct FuturesSet<F, const N: usize> {
+    futures: [Option<F>; N],
+    wakers: &'static FuturesSetWakers<N>,
+    len: usize,
+    /// Slot to poll first, so that a busy future doesn't starve the others.
+    start: usize,
+}
+
+impl<F: Future, const N: usize> FuturesSet<F, N> {
+    /// Create a new empty set, using `wakers` for its futures.
+    pub fn new(wakers: &'static mut FuturesSetWakers<N>) -> Self {
+        Self {
+            futures: core::array::from_fn(|_| None),
+            wakers,
+            len: 0,
+            start: 0,
+        }
+    }
+
+    /// Add a future to the set, and return the slot it was put in.
+    ///
+    /// If the set is full, the future is given back.
+    pub fn insert(self: Pin<&mut Self>, future: F) -> Result<usize, F> {
+        // safety: the futures aren't moved, they're only dropped in place.
+        let this = unsafe { self.get_unchecked_mut() };
+        let Some(index) = this.futures.iter().position(|f| f.is_none()) else {
+            return Err(future);
+        };
+        this.futures[index] = Some(future);
+        this.len += 1;
+        // The future must be polled a first time.
+        this.wakers.slots[index].ready.store(true, Ordering::Release);
+        Ok(index)
+    }
+
+    /// Remove the future in slot `index` from the set, dropping it.
+    ///
+    /// Returns whether there was a future in the slot.
+    pub fn remove(self: Pin<&mut Self>, index: usize) -> bool {
+        // safety: the futures aren't moved, they're only dropped in place.
+        let this = unsafe { self.get_unchecked_mut() };
+        match this.futures.get_mut(index) {
+            Some(slot @ Some(_)) => {
+                *slot = None;
+                this.len -= 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns whether there is a future in slot `index`.
+    pub fn contains(&self, index: usize) -> bool {
+        matches!(self.futures.get(index), Some(Some(_)))
+    }
+
+    /// Returns the number of futures in the set.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the set is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns whether the set is full.
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Returns the maximum number of futures in the set.
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Wait for the next future of the set to complete.
+    ///
+    /// The future is removed from the set, and its output returned along with the slot it was in.
+    ///
+    /// If the set is empty, the returned future will be Pending forever.
+    pub fn next(self: Pin<&mut Self>) -> Next<'_, F, N> {
+        Next { set: self }
+    }
+
+    /// Poll the futures of the set that were woken, until one of them completes.
+    ///
+    /// The future is removed from the set, and its output returned along with the slot it was in.
+    pub fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<(F::Output, usize)> {
+        // safety: the futures aren't moved, they're only dropped in place.
+        let this = unsafe { self.get_unchecked_mut() };
+
+        // Register before checking the slots, so that no wake is missed.
+        critical_section::with(|cs| {
+            let mut waker = this.wakers.waker.borrow_ref_mut(cs);
+            match &*waker {
+                Some(w) if w.will_wake(cx.waker()) => {}
+                _ => *waker = Some(cx.waker().clone()),
+            }
+        });
+
+        for i in (this.start..N).chain(0..this.start) {
+            let slot = &this.wakers.slots[i];
+            let Some(future) = &mut this.futures[i] else {
+                continue;
+            };
+            if !slot.ready.load(Ordering::Acquire) {
+                continue;
+            }
+            slot.ready.store(false, Ordering::Release);
+
+            let waker = this.wakers.slot_waker(i);
+            let mut slot_cx = Context::from_waker(&waker);
+            // safety: the future is pinned, as the set is.
+            if let Poll::Ready(output) = unsafe { Pin::new_unchecked(future) }.poll(&mut slot_cx) {
+                this.futures[i] = None;
+                this.len -= 1;
+                this.start = (i + 1) % N;
+                return Poll::Ready((output, i));
+            }
+        }
+
+        Poll::Pending
+    }
+}
+
+impl<F, const N: usize> Drop for FuturesSet<F, N> {
+    fn drop(&mut self) {
+        critical_section::with(|cs| self.wakers.waker.borrow_ref_mut(cs).take());
+    }
+}
+
+/// Future for the [`FuturesSet::next`] function.
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Next<'a, F, const N: usize> {
+    set: Pin<&'a mut FuturesSet<F, N>>,
+}
+
+impl<F: Future, const N: usize> Future for Next<'_, F, N> {
+    type Output = (F::Output, usize);
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.set.as_mut().poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::cell::Cell;
+    use core::pin::pin;
+    use core::sync::atomic::AtomicUsize;
+    use std::boxed::Box;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::task::Wake;
+
+    use super::*;
+
+    /// A future that records its polls and its waker, and completes once `done` is set.
+    #[derive(Clone, Default)]
+    struct Probe {
+        polls: Rc<Cell<usize>>,
+        waker: Rc<RefCell<Option<Waker>>>,
+        done: Rc<Cell<bool>>,
+    }
+
+    impl Probe {
+        fn wake(&self) {
+            self.waker.borrow().as_ref().unwrap().wake_by_ref();
+        }
+    }
+
+    impl Future for Probe {
+        type Output = usize;
+
+        fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<usize> {
+            self.polls.set(self.polls.get() + 1);
+            *self.waker.borrow_mut() = Some(cx.waker().clone());
+            match self.done.get() {
+                true => Poll::Ready(self.polls.get()),
+                false => Poll::Pending,
+            }
+        }
+    }
+
+    /// Waker of the task polling the set, counting its wakes.
+    #[derive(Default)]
+    struct TaskWaker(AtomicUsize);
+
+    impl Wake for TaskWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn wakers<const N: usize>() -> &'static mut FuturesSetWakers<N> {
+        Box::leak(Box::new(FuturesSetWakers::new()))
+    }
+
+    #[test]
+    fn polls_woken_futures() {
+        let task = Arc::new(TaskWaker::default());
+        let waker = Waker::from(task.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut set = pin!(FuturesSet::new(wakers::<4>()));
+        let probes: [Probe; 3] = Default::default();
+        for (i, probe) in probes.iter().enumerate() {
+            assert_eq!(set.as_mut().insert(probe.clone()).ok(), Some(i));
+        }
+
+        // New futures are polled once, and then only when woken.
+        assert_eq!(set.as_mut().poll_next(&mut cx), Poll::Pending);
+        assert!(probes.iter().all(|p| p.polls.get() == 1));
+        assert_eq!(set.as_mut().poll_next(&mut cx), Poll::Pending);
+        assert!(probes.iter().all(|p| p.polls.get() == 1));
+
+        probes[1].wake();
+        assert_eq!(task.0.load(Ordering::Relaxed), 1);
+        assert_eq!(set.as_mut().poll_next(&mut cx), Poll::Pending);
+        assert_eq!(probes.each_ref().map(|p| p.polls.get()), [1, 2, 1]);
+
+        // Waking twice polls once.
+        probes[2].done.set(true);
+        probes[2].wake();
+        probes[2].wake();
+        assert_eq!(set.as_mut().poll_next(&mut cx), Poll::Ready((2, 2)));
+        assert_eq!(probes.each_ref().map(|p| p.polls.get()), [1, 2, 2]);
+        assert_eq!(set.len(), 2);
+        assert!(!set.contains(2));
+    }
+
+    #[test]
+    fn remove_and_reuse_slot() {
+        let task = Arc::new(TaskWaker::default());
+        let waker = Waker::from(task.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut set = pin!(FuturesSet::new(wakers::<2>()));
+        let old = Probe::default();
+        let other = Probe::default();
+        assert_eq!(set.as_mut().insert(old.clone()).ok(), Some(0));
+        assert_eq!(set.as_mut().insert(other.clone()).ok(), Some(1));
+        assert!(set.is_full());
+        assert!(set.as_mut().insert(Probe::default()).is_err());
+        assert_eq!(set.as_mut().poll_next(&mut cx), Poll::Pending);
+
+        assert!(set.as_mut().remove(0));
+        assert!(!set.as_mut().remove(0));
+        assert!(!set.contains(0));
+        assert_eq!(set.len(), 1);
+        // The dropped future is never polled again.
+        old.wake();
+        assert_eq!(set.as_mut().poll_next(&mut cx), Poll::Pending);
+        assert_eq!(old.polls.get(), 1);
+
+        let new = Probe::default();
+        new.done.set(true);
+        assert_eq!(set.as_mut().insert(new.clone()).ok(), Some(0));
+        assert_eq!(set.as_mut().poll_next(&mut cx), Poll::Ready((1, 0)));
+        assert_eq!(other.polls.get(), 1);
+        assert_eq!(set.len(), 1);
+        assert!(set.contains(1));
+    }
+}
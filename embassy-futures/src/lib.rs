@@ -8,6 +8,8 @@ pub(crate) mod fmt;
 mod block_on;
 mod yield_now;
 
+#[cfg(feature = "futures-set")]
+pub mod futures_set;
 pub mod join;
 pub mod select;
 
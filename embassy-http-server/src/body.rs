@@ -0,0 +1,403 @@
+use core::ops::Range;
+
+use embassy_time::{with_timeout, Duration};
+use embedded_io_async::{Read, Write};
+
+use crate::request::{RequestHead, Version};
+use crate::{io_error, Error};
+
+/// How the end of the request body is determined.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Framing {
+    /// Fixed length body, with this many bytes left to read.
+    Length(usize),
+    /// Chunked transfer coding.
+    Chunked(ChunkedDecoder),
+}
+
+/// Request body reader.
+///
+/// Bytes received along with the request head are served from the server's buffer first,
+/// the rest is read from the connection.
+pub struct Body<'b, C> {
+    conn: &'b mut C,
+    buf: &'b mut [u8],
+    /// Range of `buf` holding received bytes that haven't been consumed yet.
+    start: usize,
+    end: usize,
+    framing: Framing,
+    /// Maximum time to wait for more body data from the connection.
+    timeout: Option<Duration>,
+    /// Whether we still have to send `100 Continue` before reading from the connection.
+    expect_continue: bool,
+    /// Whether the response was started while the client was still waiting for `100 Continue`.
+    ///
+    /// The client may or may not send the body in that case, so the connection can't be reused.
+    continue_skipped: bool,
+}
+
+impl<'b, C> Body<'b, C> {
+    /// Create a body reader for the request described by `head`.
+    ///
+    /// `buf[..len]` holds the bytes received after the request head. Reads from the connection fail
+    /// with [`Error::TimedOut`] if no data arrives within `timeout`.
+    pub(crate) fn new(
+        conn: &'b mut C,
+        buf: &'b mut [u8],
+        len: usize,
+        head: &RequestHead<'_>,
+        timeout: Option<Duration>,
+    ) -> Result<Self, Error> {
+        let framing = match (head.header("Transfer-Encoding"), head.header("Content-Length")) {
+            // Both present is a request smuggling vector, reject it (RFC 9112 section 6.3).
+            (Some(_), Some(_)) => return Err(Error::BadRequest),
+            (Some(te), None) => {
+                if head.version == Version::Http10 || !te.eq_ignore_ascii_case("chunked") {
+                    return Err(Error::BadRequest);
+                }
+                Framing::Chunked(ChunkedDecoder::new())
+            }
+            (None, Some(cl)) => {
+                if cl.is_empty() || !cl.bytes().all(|b| b.is_ascii_digit()) {
+                    return Err(Error::BadRequest);
+                }
+                Framing::Length(cl.parse().map_err(|_| Error::BadRequest)?)
+            }
+            (None, None) => Framing::Length(0),
+        };
+
+        let expect_continue = head.version == Version::Http11
+            && head
+                .header("Expect")
+                .is_some_and(|e| e.eq_ignore_ascii_case("100-continue"));
+
+        Ok(Self {
+            conn,
+            buf,
+            start: 0,
+            end: len,
+            framing,
+            timeout,
+            expect_continue,
+            continue_skipped: false,
+        })
+    }
+
+    pub(crate) fn conn(&mut self) -> &mut C {
+        // Once the response is started, we must not send `100 Continue` anymore.
+        if core::mem::take(&mut self.expect_continue) && !self.is_finished() {
+            self.continue_skipped = true;
+        }
+        self.conn
+    }
+
+    /// Whether the rest of the body can be read to reuse the connection.
+    pub(crate) fn can_discard(&self) -> bool {
+        !self.continue_skipped
+    }
+
+    /// Range of `buf` holding received bytes past the end of the body.
+    pub(crate) fn remaining(&self) -> Range<usize> {
+        self.start..self.end
+    }
+
+    /// Whether the whole body has been read.
+    pub fn is_finished(&self) -> bool {
+        match self.framing {
+            Framing::Length(n) => n == 0,
+            Framing::Chunked(d) => d.is_finished(),
+        }
+    }
+
+    /// Get the body length, if known in advance.
+    ///
+    /// Returns the number of bytes left to read for `Content-Length` bodies, and `None` for
+    /// chunked bodies.
+    pub fn content_length(&self) -> Option<usize> {
+        match self.framing {
+            Framing::Length(n) => Some(n),
+            Framing::Chunked(_) => None,
+        }
+    }
+}
+
+impl<C: Read + Write> Body<'_, C> {
+    /// Read body data.
+    ///
+    /// Returns how many bytes were read. Returns `Ok(0)` once the whole body has been read, or if
+    /// `buf` is empty.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        match &mut self.framing {
+            Framing::Length(0) => Ok(0),
+            Framing::Length(remaining) => {
+                let max = buf.len().min(*remaining);
+                let n = if self.start < self.end {
+                    let n = max.min(self.end - self.start);
+                    buf[..n].copy_from_slice(&self.buf[self.start..self.start + n]);
+                    self.start += n;
+                    n
+                } else {
+                    // Read directly into the caller's buffer. This can't read past the end of the
+                    // body since we know its length.
+                    Self::send_continue(self.conn, &mut self.expect_continue).await?;
+                    match Self::read_conn(self.conn, &mut buf[..max], self.timeout).await? {
+                        0 => return Err(Error::ConnectionClosed),
+                        n => n,
+                    }
+                };
+                *remaining -= n;
+                Ok(n)
+            }
+            Framing::Chunked(decoder) => loop {
+                if decoder.is_finished() {
+                    return Ok(0);
+                }
+                if self.start == self.end {
+                    if self.buf.is_empty() {
+                        // The request head filled the whole buffer, no room to decode chunks in.
+                        return Err(Error::RequestTooLarge);
+                    }
+                    Self::send_continue(self.conn, &mut self.expect_continue).await?;
+                    self.start = 0;
+                    self.end = Self::read_conn(self.conn, self.buf, self.timeout).await?;
+                    if self.end == 0 {
+                        return Err(Error::ConnectionClosed);
+                    }
+                }
+                let (consumed, n) = decoder.decode(&self.buf[self.start..self.end], buf)?;
+                self.start += consumed;
+                if n > 0 {
+                    return Ok(n);
+                }
+            },
+        }
+    }
+
+    /// Read the whole body into `buf`.
+    ///
+    /// Returns the part of `buf` holding the body, or [`Error::RequestTooLarge`] if it does not fit.
+    pub async fn read_to_end<'a>(&mut self, buf: &'a mut [u8]) -> Result<&'a mut [u8], Error> {
+        let mut len = 0;
+        loop {
+            if len == buf.len() {
+                if self.is_finished() {
+                    return Ok(buf);
+                }
+                // Check whether the body ends exactly here.
+                let mut probe = [0; 1];
+                return match self.read(&mut probe).await? {
+                    0 => Ok(buf),
+                    _ => Err(Error::RequestTooLarge),
+                };
+            }
+            match self.read(&mut buf[len..]).await? {
+                0 => return Ok(&mut buf[..len]),
+                n => len += n,
+            }
+        }
+    }
+
+    /// Read and drop the rest of the body.
+    pub(crate) async fn discard(&mut self) -> Result<(), Error> {
+        let mut scratch = [0; 64];
+        while self.read(&mut scratch).await? != 0 {}
+        Ok(())
+    }
+
+    async fn read_conn(conn: &mut C, buf: &mut [u8], timeout: Option<Duration>) -> Result<usize, Error> {
+        match timeout {
+            Some(timeout) => with_timeout(timeout, conn.read(buf))
+                .await
+                .map_err(|_| Error::TimedOut)?
+                .map_err(io_error),
+            None => conn.read(buf).await.map_err(io_error),
+        }
+    }
+
+    async fn send_continue(conn: &mut C, expect_continue: &mut bool) -> Result<(), Error> {
+        if core::mem::take(expect_continue) {
+            conn.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")
+                .await
+                .map_err(io_error)?;
+        }
+        Ok(())
+    }
+}
+
+impl<C> embedded_io_async::ErrorType for Body<'_, C> {
+    type Error = Error;
+}
+
+impl<C: Read + Write> embedded_io_async::Read for Body<'_, C> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        Body::read(self, buf).await
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ChunkedState {
+    /// Reading the hex chunk size.
+    Size,
+    /// Skipping chunk extensions, until the end of the line.
+    Extension,
+    /// Got CR after the chunk size line, expecting LF.
+    SizeLf,
+    /// Reading chunk data.
+    Data,
+    /// Expecting the CRLF after chunk data.
+    DataCr,
+    DataLf,
+    /// At the start of a trailer line, after the last chunk.
+    TrailerStart,
+    /// Skipping a trailer field line.
+    Trailer,
+    /// Got CR at the start of a trailer line, expecting the final LF.
+    TrailerLf,
+    Finished,
+}
+
+/// Incremental decoder for the chunked transfer coding (RFC 9112 section 7.1).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ChunkedDecoder {
+    state: ChunkedState,
+    /// Chunk size while in `Size`, remaining chunk bytes while in `Data`.
+    size: usize,
+    /// Whether at least one size digit was seen.
+    has_digits: bool,
+}
+
+impl ChunkedDecoder {
+    pub const fn new() -> Self {
+        Self {
+            state: ChunkedState::Size,
+            size: 0,
+            has_digits: false,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state == ChunkedState::Finished
+    }
+
+    /// Decode bytes from `input` into `output`.
+    ///
+    /// Returns how many bytes were consumed from `input` and how many were written to `output`.
+    /// Input bytes past the end of the body are not consumed.
+    pub fn decode(&mut self, input: &[u8], output: &mut [u8]) -> Result<(usize, usize), Error> {
+        let mut i = 0;
+        let mut o = 0;
+        while i < input.len() && self.state != ChunkedState::Finished {
+            if self.state == ChunkedState::Data {
+                if o == output.len() {
+                    break;
+                }
+                let n = self.size.min(input.len() - i).min(output.len() - o);
+                output[o..o + n].copy_from_slice(&input[i..i + n]);
+                i += n;
+                o += n;
+                self.size -= n;
+                if self.size == 0 {
+                    self.state = ChunkedState::DataCr;
+                }
+                continue;
+            }
+
+            let b = input[i];
+            i += 1;
+            self.state = match (self.state, b) {
+                (ChunkedState::Size, b) if b.is_ascii_hexdigit() => {
+                    let digit = (b as char).to_digit(16).unwrap() as usize;
+                    self.size = self
+                        .size
+                        .checked_mul(16)
+                        .and_then(|s| s.checked_add(digit))
+                        .ok_or(Error::BadRequest)?;
+                    self.has_digits = true;
+                    ChunkedState::Size
+                }
+                (ChunkedState::Size, b';' | b' ' | b'\t') if self.has_digits => ChunkedState::Extension,
+                (ChunkedState::Size, b'\r') if self.has_digits => ChunkedState::SizeLf,
+                (ChunkedState::Size | ChunkedState::Extension | ChunkedState::SizeLf, b'\n') if self.has_digits => {
+                    self.has_digits = false;
+                    if self.size == 0 {
+                        ChunkedState::TrailerStart
+                    } else {
+                        ChunkedState::Data
+                    }
+                }
+                (ChunkedState::Extension, _) => ChunkedState::Extension,
+                (ChunkedState::DataCr, b'\r') => ChunkedState::DataLf,
+                (ChunkedState::DataCr | ChunkedState::DataLf, b'\n') => ChunkedState::Size,
+                (ChunkedState::TrailerStart, b'\r') => ChunkedState::TrailerLf,
+                (ChunkedState::TrailerStart | ChunkedState::TrailerLf, b'\n') => ChunkedState::Finished,
+                (ChunkedState::Trailer, b'\n') => ChunkedState::TrailerStart,
+                (ChunkedState::TrailerStart | ChunkedState::Trailer, _) => ChunkedState::Trailer,
+                _ => return Err(Error::BadRequest),
+            };
+        }
+        Ok((i, o))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all(input: &[u8], step: usize) -> Result<([u8; 64], usize, usize), Error> {
+        let mut decoder = ChunkedDecoder::new();
+        let mut out = [0; 64];
+        let mut i = 0;
+        let mut o = 0;
+        while !decoder.is_finished() && i < input.len() {
+            let end = (i + step).min(input.len());
+            let (consumed, written) = decoder.decode(&input[i..end], &mut out[o..])?;
+            i += consumed;
+            o += written;
+        }
+        assert!(decoder.is_finished());
+        Ok((out, o, i))
+    }
+
+    #[test]
+    fn chunked() {
+        let input = b"5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\n\r\nGET";
+        for step in 1..input.len() {
+            let (out, len, consumed) = decode_all(input, step).unwrap();
+            assert_eq!(&out[..len], b"hello, world");
+            assert_eq!(&input[consumed..], b"GET");
+        }
+    }
+
+    #[test]
+    fn chunked_trailers() {
+        let input = b"A\r\n0123456789\r\n0\r\nX-Checksum: 1234\r\n\r\n";
+        let (out, len, consumed) = decode_all(input, 3).unwrap();
+        assert_eq!(&out[..len], b"0123456789");
+        assert_eq!(consumed, input.len());
+    }
+
+    #[test]
+    fn chunked_small_output() {
+        let mut decoder = ChunkedDecoder::new();
+        let mut out = [0; 2];
+        let input = b"5\r\nhello\r\n0\r\n\r\n";
+        assert_eq!(decoder.decode(input, &mut out), Ok((5, 2)));
+        assert_eq!(&out, b"he");
+        assert_eq!(decoder.decode(&input[5..], &mut out), Ok((2, 2)));
+        assert_eq!(&out, b"ll");
+    }
+
+    #[test]
+    fn chunked_errors() {
+        let mut out = [0; 64];
+        assert!(ChunkedDecoder::new().decode(b"\r\n", &mut out).is_err());
+        assert!(ChunkedDecoder::new().decode(b"x\r\n", &mut out).is_err());
+        assert!(ChunkedDecoder::new().decode(b"2\r\nabc\r\n", &mut out).is_err());
+        assert!(ChunkedDecoder::new()
+            .decode(b"fffffffffffffffffffff\r\n", &mut out)
+            .is_err());
+    }
+}
@@ -0,0 +1,63 @@
+#![no_std]
+#![allow(async_fn_in_trait)]
+#![warn(missing_docs)]
+#![doc = include_str!("../README.md")]
+
+//! ## Feature flags
+#![doc = document_features::document_features!(feature_label = r#"<span class="stab portability"><code>{feature}</code></span>"#)]
+
+// This mod MUST go first, so that the others see its macros.
+pub(crate) mod fmt;
+
+mod body;
+mod request;
+mod response;
+mod router;
+mod server;
+
+pub use body::Body;
+pub use request::{Header, Method, Request, Version};
+pub use response::{ChunkedWriter, Completed, Response, StatusCode};
+pub use router::{Handler, Route, Router, Routes};
+pub use server::{Config, Server};
+
+/// Maximum number of headers in a request.
+///
+/// Requests with more headers are rejected with `431 Request Header Fields Too Large`.
+pub const MAX_HEADERS: usize = 24;
+
+/// HTTP server error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The underlying connection returned an error.
+    Io(embedded_io_async::ErrorKind),
+    /// The connection was closed by the remote host in the middle of a request.
+    ConnectionClosed,
+    /// The request is malformed.
+    BadRequest,
+    /// The request head does not fit in the buffer, or has more than [`MAX_HEADERS`] headers.
+    RequestTooLarge,
+    /// The request uses an HTTP version other than 1.0 or 1.1.
+    UnsupportedVersion,
+    /// The request head or body was not received within [`Config::request_timeout`] or
+    /// [`Config::body_timeout`].
+    TimedOut,
+}
+
+impl embedded_io_async::Error for Error {
+    fn kind(&self) -> embedded_io_async::ErrorKind {
+        match self {
+            Error::Io(kind) => *kind,
+            Error::ConnectionClosed => embedded_io_async::ErrorKind::ConnectionReset,
+            Error::BadRequest | Error::RequestTooLarge | Error::UnsupportedVersion => {
+                embedded_io_async::ErrorKind::InvalidData
+            }
+            Error::TimedOut => embedded_io_async::ErrorKind::TimedOut,
+        }
+    }
+}
+
+pub(crate) fn io_error<E: embedded_io_async::Error>(e: E) -> Error {
+    Error::Io(e.kind())
+}
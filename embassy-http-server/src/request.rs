@@ -0,0 +1,362 @@
+use heapless::Vec;
+
+use crate::body::Body;
+use crate::response::{Response, ResponseState};
+use crate::{Error, MAX_HEADERS};
+
+/// HTTP request method.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Method {
+    /// `GET`
+    Get,
+    /// `HEAD`
+    Head,
+    /// `POST`
+    Post,
+    /// `PUT`
+    Put,
+    /// `DELETE`
+    Delete,
+    /// `PATCH`
+    Patch,
+    /// `OPTIONS`
+    Options,
+    /// `CONNECT`
+    Connect,
+    /// `TRACE`
+    Trace,
+}
+
+impl Method {
+    fn parse(s: &str) -> Option<Self> {
+        Some(match s {
+            "GET" => Self::Get,
+            "HEAD" => Self::Head,
+            "POST" => Self::Post,
+            "PUT" => Self::Put,
+            "DELETE" => Self::Delete,
+            "PATCH" => Self::Patch,
+            "OPTIONS" => Self::Options,
+            "CONNECT" => Self::Connect,
+            "TRACE" => Self::Trace,
+            _ => return None,
+        })
+    }
+
+    /// Get the method name, as it appears in the request line.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Get => "GET",
+            Self::Head => "HEAD",
+            Self::Post => "POST",
+            Self::Put => "PUT",
+            Self::Delete => "DELETE",
+            Self::Patch => "PATCH",
+            Self::Options => "OPTIONS",
+            Self::Connect => "CONNECT",
+            Self::Trace => "TRACE",
+        }
+    }
+}
+
+/// HTTP protocol version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Version {
+    /// HTTP/1.0
+    Http10,
+    /// HTTP/1.1
+    Http11,
+}
+
+/// HTTP header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Header<'b> {
+    /// Header name, as sent by the client.
+    pub name: &'b str,
+    /// Header value, without leading or trailing whitespace.
+    pub value: &'b str,
+}
+
+/// Parsed request line and headers.
+#[derive(Debug)]
+pub(crate) struct RequestHead<'b> {
+    pub method: Method,
+    pub path: &'b str,
+    pub query: Option<&'b str>,
+    pub version: Version,
+    pub headers: Vec<Header<'b>, MAX_HEADERS>,
+}
+
+/// Find the end of the request head in `buf`.
+///
+/// Returns the length of the head, including the empty line terminating it.
+pub(crate) fn find_head_end(buf: &[u8]) -> Option<usize> {
+    // Accept bare LF line endings too, as recommended by RFC 9112 section 2.2.
+    let mut i = 0;
+    while let Some(pos) = buf[i..].iter().position(|&b| b == b'\n') {
+        let lf = i + pos;
+        match &buf[lf + 1..] {
+            [b'\n', ..] => return Some(lf + 2),
+            [b'\r', b'\n', ..] => return Some(lf + 3),
+            _ => i = lf + 1,
+        }
+    }
+    None
+}
+
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes().all(|b| {
+            b.is_ascii_alphanumeric()
+                || matches!(
+                    b,
+                    b'!' | b'#'
+                        | b'$'
+                        | b'%'
+                        | b'&'
+                        | b'\''
+                        | b'*'
+                        | b'+'
+                        | b'-'
+                        | b'.'
+                        | b'^'
+                        | b'_'
+                        | b'`'
+                        | b'|'
+                        | b'~'
+                )
+        })
+}
+
+impl<'b> RequestHead<'b> {
+    /// Parse a request head, as delimited by [`find_head_end`].
+    pub fn parse(buf: &'b [u8]) -> Result<Self, Error> {
+        let head = core::str::from_utf8(buf).map_err(|_| Error::BadRequest)?;
+        let mut lines = head.split('\n').map(|l| l.strip_suffix('\r').unwrap_or(l));
+
+        // Request line: method SP request-target SP HTTP-version
+        let line = lines.next().ok_or(Error::BadRequest)?;
+        let mut parts = line.split(' ');
+        let (Some(method), Some(target), Some(version), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(Error::BadRequest);
+        };
+
+        let method = Method::parse(method).ok_or(Error::BadRequest)?;
+        let version = match version {
+            "HTTP/1.1" => Version::Http11,
+            "HTTP/1.0" => Version::Http10,
+            v if v.starts_with("HTTP/") => return Err(Error::UnsupportedVersion),
+            _ => return Err(Error::BadRequest),
+        };
+        if target.is_empty() {
+            return Err(Error::BadRequest);
+        }
+        let (path, query) = match target.split_once('?') {
+            Some((path, query)) => (path, Some(query)),
+            None => (target, None),
+        };
+
+        let mut headers = Vec::new();
+        for line in lines {
+            if line.is_empty() {
+                break;
+            }
+            // Obsolete line folding and whitespace before the colon are rejected (RFC 9112 section 5).
+            let (name, value) = line.split_once(':').ok_or(Error::BadRequest)?;
+            if !is_token(name) {
+                return Err(Error::BadRequest);
+            }
+            let value = value.trim_matches(|c| c == ' ' || c == '\t');
+            headers
+                .push(Header { name, value })
+                .map_err(|_| Error::RequestTooLarge)?;
+        }
+
+        Ok(Self {
+            method,
+            path,
+            query,
+            version,
+            headers,
+        })
+    }
+
+    /// Get the value of the first header named `name`, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&'b str> {
+        self.headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .map(|h| h.value)
+    }
+
+    /// Whether the client asked for the connection to be kept open after this request.
+    pub fn keep_alive(&self) -> bool {
+        let connection = self.header("Connection").unwrap_or("");
+        let has = |option: &str| connection.split(',').any(|o| o.trim().eq_ignore_ascii_case(option));
+        match self.version {
+            Version::Http10 => has("keep-alive"),
+            Version::Http11 => !has("close"),
+        }
+    }
+}
+
+/// An HTTP request being handled.
+///
+/// The request line and headers are borrowed from the server's buffer. The body can be read with
+/// [`body()`](Self::body), and the response sent with [`response()`](Self::response).
+pub struct Request<'b, C> {
+    head: RequestHead<'b>,
+    body: Body<'b, C>,
+    state: ResponseState,
+}
+
+impl<'b, C> Request<'b, C> {
+    pub(crate) fn new(head: RequestHead<'b>, body: Body<'b, C>, keep_alive: bool) -> Self {
+        let state = ResponseState {
+            started: false,
+            keep_alive: keep_alive && head.keep_alive(),
+            version: head.version,
+            head_only: head.method == Method::Head,
+        };
+        Self { head, body, state }
+    }
+
+    /// Get the request method.
+    pub fn method(&self) -> Method {
+        self.head.method
+    }
+
+    /// Get the request path, without the query string.
+    pub fn path(&self) -> &'b str {
+        self.head.path
+    }
+
+    /// Get the query string, without the leading `?`.
+    pub fn query(&self) -> Option<&'b str> {
+        self.head.query
+    }
+
+    /// Get the HTTP version of the request.
+    pub fn version(&self) -> Version {
+        self.head.version
+    }
+
+    /// Get the value of the first header named `name`, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&'b str> {
+        self.head.header(name)
+    }
+
+    /// Get all request headers, in the order they were received.
+    pub fn headers(&self) -> &[Header<'b>] {
+        &self.head.headers
+    }
+
+    /// Get the request body reader.
+    pub fn body(&mut self) -> &mut Body<'b, C> {
+        &mut self.body
+    }
+
+    /// Whether the response has been started.
+    pub fn is_response_started(&self) -> bool {
+        self.state.started
+    }
+
+    /// Start the response to this request.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the response has already been started.
+    pub fn response(&mut self) -> Response<'_, C> {
+        assert!(!self.state.started, "response already started");
+        Response::new(self.body.conn(), &mut self.state)
+    }
+
+    /// Consume the rest of the request body, so the connection can be reused.
+    ///
+    /// Returns whether the connection can be kept open, and the range of bytes past the end of the
+    /// request in the body buffer.
+    pub(crate) async fn finish(&mut self) -> Result<(bool, core::ops::Range<usize>), Error>
+    where
+        C: embedded_io_async::Read + embedded_io_async::Write,
+    {
+        if !self.body.can_discard() {
+            self.state.keep_alive = false;
+        }
+        if self.state.keep_alive {
+            self.body.discard().await?;
+        }
+        Ok((self.state.keep_alive, self.body.remaining()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn head_end() {
+        assert_eq!(find_head_end(b"GET / HTTP/1.1\r\n\r\n"), Some(18));
+        assert_eq!(find_head_end(b"GET / HTTP/1.1\r\nHost: a\r\n\r\nbody"), Some(27));
+        assert_eq!(find_head_end(b"GET / HTTP/1.1\n\nbody"), Some(16));
+        assert_eq!(find_head_end(b"GET / HTTP/1.1\r\nHost: a\r\n"), None);
+        assert_eq!(find_head_end(b""), None);
+    }
+
+    #[test]
+    fn parse_request_line() {
+        let head = RequestHead::parse(b"POST /api/led?on=1 HTTP/1.1\r\n\r\n").unwrap();
+        assert_eq!(head.method, Method::Post);
+        assert_eq!(head.path, "/api/led");
+        assert_eq!(head.query, Some("on=1"));
+        assert_eq!(head.version, Version::Http11);
+        assert!(head.headers.is_empty());
+    }
+
+    #[test]
+    fn parse_headers() {
+        let head =
+            RequestHead::parse(b"GET / HTTP/1.0\r\nHost: example.com\r\ncontent-length:  12 \r\nX-Empty:\r\n\r\n")
+                .unwrap();
+        assert_eq!(head.version, Version::Http10);
+        assert_eq!(head.headers.len(), 3);
+        assert_eq!(head.header("host"), Some("example.com"));
+        assert_eq!(head.header("Content-Length"), Some("12"));
+        assert_eq!(head.header("x-empty"), Some(""));
+        assert_eq!(head.header("Missing"), None);
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(RequestHead::parse(b"GET /\r\n\r\n").unwrap_err(), Error::BadRequest);
+        assert_eq!(
+            RequestHead::parse(b"FOO / HTTP/1.1\r\n\r\n").unwrap_err(),
+            Error::BadRequest
+        );
+        assert_eq!(
+            RequestHead::parse(b"GET / HTTP/2.0\r\n\r\n").unwrap_err(),
+            Error::UnsupportedVersion
+        );
+        assert_eq!(
+            RequestHead::parse(b"GET / HTTP/1.1\r\nHost : a\r\n\r\n").unwrap_err(),
+            Error::BadRequest
+        );
+        assert_eq!(
+            RequestHead::parse(b"GET / HTTP/1.1\r\nHost: a\r\n  folded\r\n\r\n").unwrap_err(),
+            Error::BadRequest
+        );
+    }
+
+    #[test]
+    fn keep_alive() {
+        let parse = |s: &'static [u8]| RequestHead::parse(s).unwrap().keep_alive();
+        assert!(parse(b"GET / HTTP/1.1\r\n\r\n"));
+        assert!(!parse(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n"));
+        assert!(!parse(b"GET / HTTP/1.0\r\n\r\n"));
+        assert!(parse(b"GET / HTTP/1.0\r\nConnection: Keep-Alive\r\n\r\n"));
+    }
+}
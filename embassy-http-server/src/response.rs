@@ -0,0 +1,323 @@
+use embedded_io_async::Write;
+
+use crate::request::Version;
+use crate::{io_error, Error};
+
+/// HTTP response status code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StatusCode(pub u16);
+
+impl StatusCode {
+    /// `200 OK`
+    pub const OK: Self = Self(200);
+    /// `201 Created`
+    pub const CREATED: Self = Self(201);
+    /// `204 No Content`
+    pub const NO_CONTENT: Self = Self(204);
+    /// `301 Moved Permanently`
+    pub const MOVED_PERMANENTLY: Self = Self(301);
+    /// `302 Found`
+    pub const FOUND: Self = Self(302);
+    /// `304 Not Modified`
+    pub const NOT_MODIFIED: Self = Self(304);
+    /// `400 Bad Request`
+    pub const BAD_REQUEST: Self = Self(400);
+    /// `401 Unauthorized`
+    pub const UNAUTHORIZED: Self = Self(401);
+    /// `403 Forbidden`
+    pub const FORBIDDEN: Self = Self(403);
+    /// `404 Not Found`
+    pub const NOT_FOUND: Self = Self(404);
+    /// `405 Method Not Allowed`
+    pub const METHOD_NOT_ALLOWED: Self = Self(405);
+    /// `408 Request Timeout`
+    pub const REQUEST_TIMEOUT: Self = Self(408);
+    /// `413 Content Too Large`
+    pub const CONTENT_TOO_LARGE: Self = Self(413);
+    /// `415 Unsupported Media Type`
+    pub const UNSUPPORTED_MEDIA_TYPE: Self = Self(415);
+    /// `431 Request Header Fields Too Large`
+    pub const REQUEST_HEADER_FIELDS_TOO_LARGE: Self = Self(431);
+    /// `500 Internal Server Error`
+    pub const INTERNAL_SERVER_ERROR: Self = Self(500);
+    /// `501 Not Implemented`
+    pub const NOT_IMPLEMENTED: Self = Self(501);
+    /// `503 Service Unavailable`
+    pub const SERVICE_UNAVAILABLE: Self = Self(503);
+    /// `505 HTTP Version Not Supported`
+    pub const HTTP_VERSION_NOT_SUPPORTED: Self = Self(505);
+
+    /// Get the standard reason phrase for this status code.
+    ///
+    /// Returns an empty string for unknown status codes.
+    pub fn reason(&self) -> &'static str {
+        match self.0 {
+            100 => "Continue",
+            200 => "OK",
+            201 => "Created",
+            202 => "Accepted",
+            204 => "No Content",
+            301 => "Moved Permanently",
+            302 => "Found",
+            303 => "See Other",
+            304 => "Not Modified",
+            307 => "Temporary Redirect",
+            308 => "Permanent Redirect",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            409 => "Conflict",
+            413 => "Content Too Large",
+            415 => "Unsupported Media Type",
+            431 => "Request Header Fields Too Large",
+            500 => "Internal Server Error",
+            501 => "Not Implemented",
+            503 => "Service Unavailable",
+            505 => "HTTP Version Not Supported",
+            _ => "",
+        }
+    }
+
+    /// Whether responses with this status code never have a body (RFC 9110 section 6.4.1).
+    fn is_bodyless(&self) -> bool {
+        (100..200).contains(&self.0) || self.0 == 204 || self.0 == 304
+    }
+}
+
+/// Proof that a response was sent completely.
+///
+/// Returned by [`Response::send`] and [`ChunkedWriter::finish`], and required as the result of
+/// [`Handler::handle`](crate::Handler::handle), so a handler can't forget to respond.
+#[derive(Debug)]
+#[must_use]
+pub struct Completed {
+    _private: (),
+}
+
+pub(crate) struct ResponseState {
+    pub started: bool,
+    pub keep_alive: bool,
+    pub version: Version,
+    pub head_only: bool,
+}
+
+/// How the end of the response body is signaled.
+enum Framing {
+    Length(usize),
+    Chunked,
+    /// Delimited by closing the connection, for HTTP/1.0 clients.
+    Close,
+}
+
+/// Response to an HTTP request.
+///
+/// Obtained from [`Request::response`](crate::Request::response).
+pub struct Response<'a, C> {
+    conn: &'a mut C,
+    state: &'a mut ResponseState,
+}
+
+impl<'a, C> Response<'a, C> {
+    pub(crate) fn new(conn: &'a mut C, state: &'a mut ResponseState) -> Self {
+        Self { conn, state }
+    }
+}
+
+impl<'a, C: Write> Response<'a, C> {
+    /// Send a complete response with the given status, headers and body.
+    ///
+    /// The `Content-Length` header is added automatically, and must not be present in `headers`.
+    pub async fn send(self, status: StatusCode, headers: &[(&str, &str)], body: &[u8]) -> Result<Completed, Error> {
+        let head_only = self.state.head_only || status.is_bodyless();
+        write_head(self.conn, self.state, status, headers, Framing::Length(body.len())).await?;
+        if !head_only {
+            self.conn.write_all(body).await.map_err(io_error)?;
+        }
+        Ok(Completed { _private: () })
+    }
+
+    /// Start a response whose body is written incrementally with a [`ChunkedWriter`].
+    ///
+    /// The body is sent with chunked transfer coding. For HTTP/1.0 clients, which don't support
+    /// it, the body is sent as-is and the connection is closed afterwards.
+    pub async fn send_chunked(
+        self,
+        status: StatusCode,
+        headers: &[(&str, &str)],
+    ) -> Result<ChunkedWriter<'a, C>, Error> {
+        let head_only = self.state.head_only || status.is_bodyless();
+        let chunked = self.state.version == Version::Http11;
+        if !chunked {
+            self.state.keep_alive = false;
+        }
+        let framing = if chunked { Framing::Chunked } else { Framing::Close };
+        write_head(self.conn, self.state, status, headers, framing).await?;
+        Ok(ChunkedWriter {
+            conn: self.conn,
+            chunked,
+            head_only,
+        })
+    }
+}
+
+/// Writer for a response body of unknown length.
+///
+/// Obtained from [`Response::send_chunked`].
+pub struct ChunkedWriter<'a, C> {
+    conn: &'a mut C,
+    chunked: bool,
+    head_only: bool,
+}
+
+impl<C: Write> ChunkedWriter<'_, C> {
+    /// Write a chunk of the body.
+    pub async fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        // An empty chunk would terminate the body.
+        if self.head_only || data.is_empty() {
+            return Ok(());
+        }
+        if self.chunked {
+            let mut size = [0; 2 * core::mem::size_of::<usize>()];
+            self.conn
+                .write_all(fmt_hex(data.len(), &mut size))
+                .await
+                .map_err(io_error)?;
+            self.conn.write_all(b"\r\n").await.map_err(io_error)?;
+            self.conn.write_all(data).await.map_err(io_error)?;
+            self.conn.write_all(b"\r\n").await.map_err(io_error)?;
+        } else {
+            self.conn.write_all(data).await.map_err(io_error)?;
+        }
+        Ok(())
+    }
+
+    /// Finish the body.
+    pub async fn finish(self) -> Result<Completed, Error> {
+        if self.chunked && !self.head_only {
+            self.conn.write_all(b"0\r\n\r\n").await.map_err(io_error)?;
+        }
+        Ok(Completed { _private: () })
+    }
+}
+
+impl<C> embedded_io_async::ErrorType for ChunkedWriter<'_, C> {
+    type Error = Error;
+}
+
+impl<C: Write> embedded_io_async::Write for ChunkedWriter<'_, C> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        ChunkedWriter::write(self, buf).await?;
+        Ok(buf.len())
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.conn.flush().await.map_err(io_error)
+    }
+}
+
+async fn write_head<C: Write>(
+    conn: &mut C,
+    state: &mut ResponseState,
+    status: StatusCode,
+    headers: &[(&str, &str)],
+    framing: Framing,
+) -> Result<(), Error> {
+    state.started = true;
+
+    let mut code = [0; 20];
+    conn.write_all(b"HTTP/1.1 ").await.map_err(io_error)?;
+    conn.write_all(fmt_dec(status.0 as usize, &mut code))
+        .await
+        .map_err(io_error)?;
+    conn.write_all(b" ").await.map_err(io_error)?;
+    conn.write_all(status.reason().as_bytes()).await.map_err(io_error)?;
+    conn.write_all(b"\r\n").await.map_err(io_error)?;
+
+    for (name, value) in headers {
+        conn.write_all(name.as_bytes()).await.map_err(io_error)?;
+        conn.write_all(b": ").await.map_err(io_error)?;
+        conn.write_all(value.as_bytes()).await.map_err(io_error)?;
+        conn.write_all(b"\r\n").await.map_err(io_error)?;
+    }
+
+    match framing {
+        _ if status.is_bodyless() => {}
+        Framing::Length(len) => {
+            let mut buf = [0; 20];
+            conn.write_all(b"Content-Length: ").await.map_err(io_error)?;
+            conn.write_all(fmt_dec(len, &mut buf)).await.map_err(io_error)?;
+            conn.write_all(b"\r\n").await.map_err(io_error)?;
+        }
+        Framing::Chunked => conn
+            .write_all(b"Transfer-Encoding: chunked\r\n")
+            .await
+            .map_err(io_error)?,
+        Framing::Close => {}
+    }
+
+    if !state.keep_alive {
+        conn.write_all(b"Connection: close\r\n").await.map_err(io_error)?;
+    }
+    conn.write_all(b"\r\n").await.map_err(io_error)
+}
+
+/// Send a minimal response for a request that couldn't be handled, and close the connection.
+pub(crate) async fn write_error<C: Write>(conn: &mut C, status: StatusCode) -> Result<(), Error> {
+    let mut state = ResponseState {
+        started: false,
+        keep_alive: false,
+        version: Version::Http11,
+        head_only: false,
+    };
+    write_head(conn, &mut state, status, &[], Framing::Length(0)).await
+}
+
+fn fmt_dec(mut n: usize, buf: &mut [u8; 20]) -> &[u8] {
+    let mut i = buf.len();
+    loop {
+        i -= 1;
+        buf[i] = b'0' + (n % 10) as u8;
+        n /= 10;
+        if n == 0 {
+            return &buf[i..];
+        }
+    }
+}
+
+fn fmt_hex(mut n: usize, buf: &mut [u8; 2 * core::mem::size_of::<usize>()]) -> &[u8] {
+    let mut i = buf.len();
+    loop {
+        i -= 1;
+        buf[i] = b"0123456789abcdef"[n % 16];
+        n /= 16;
+        if n == 0 {
+            return &buf[i..];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::string::ToString;
+
+    use super::*;
+
+    #[test]
+    fn format_numbers() {
+        let mut buf = [0; 20];
+        assert_eq!(fmt_dec(0, &mut buf), b"0");
+        assert_eq!(fmt_dec(404, &mut buf), b"404");
+        assert_eq!(fmt_dec(usize::MAX, &mut buf), usize::MAX.to_string().as_bytes());
+
+        let mut buf = [0; 2 * core::mem::size_of::<usize>()];
+        assert_eq!(fmt_hex(0, &mut buf), b"0");
+        assert_eq!(fmt_hex(0x1f, &mut buf), b"1f");
+        assert_eq!(fmt_hex(usize::MAX, &mut buf).len(), buf.len());
+    }
+}
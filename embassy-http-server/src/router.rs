@@ -0,0 +1,161 @@
+use embedded_io_async::{Read, Write};
+
+use crate::request::{Method, Request};
+use crate::response::{Completed, StatusCode};
+use crate::Error;
+
+/// Request handler.
+pub trait Handler {
+    /// Handle a request.
+    ///
+    /// The handler must send a response with [`Request::response`], proven by the returned
+    /// [`Completed`]. It doesn't need to read the whole request body, the server discards the rest.
+    async fn handle<C: Read + Write>(&self, request: &mut Request<'_, C>) -> Result<Completed, Error>;
+}
+
+impl<H: Handler> Handler for &H {
+    async fn handle<C: Read + Write>(&self, request: &mut Request<'_, C>) -> Result<Completed, Error> {
+        H::handle(self, request).await
+    }
+}
+
+/// A list of routes, built with [`Router::route`].
+pub trait Routes {
+    /// Whether any route matches `path`, regardless of the method.
+    fn matches_path(&self, path: &str) -> bool;
+
+    /// Dispatch the request to the first matching route.
+    ///
+    /// Returns `None` if no route matches.
+    async fn dispatch<C: Read + Write>(&self, request: &mut Request<'_, C>) -> Option<Result<Completed, Error>>;
+}
+
+impl Routes for () {
+    fn matches_path(&self, _path: &str) -> bool {
+        false
+    }
+
+    async fn dispatch<C: Read + Write>(&self, _request: &mut Request<'_, C>) -> Option<Result<Completed, Error>> {
+        None
+    }
+}
+
+/// A route in a [`Router`], followed by the previously added routes.
+pub struct Route<H, R> {
+    method: Method,
+    path: &'static str,
+    handler: H,
+    prev: R,
+}
+
+impl<H: Handler, R: Routes> Routes for Route<H, R> {
+    fn matches_path(&self, path: &str) -> bool {
+        self.prev.matches_path(path) || path_matches(self.path, path)
+    }
+
+    async fn dispatch<C: Read + Write>(&self, request: &mut Request<'_, C>) -> Option<Result<Completed, Error>> {
+        // Routes added first take precedence.
+        if let Some(res) = self.prev.dispatch(request).await {
+            return Some(res);
+        }
+        let method_matches = match (self.method, request.method()) {
+            // HEAD requests are served by GET handlers, the server omits the body.
+            (Method::Get, Method::Head) => true,
+            (a, b) => a == b,
+        };
+        if method_matches && path_matches(self.path, request.path()) {
+            Some(self.handler.handle(request).await)
+        } else {
+            None
+        }
+    }
+}
+
+/// Check whether `path` matches the route pattern `pattern`.
+///
+/// Patterns match exactly, except a pattern ending in `/*` matches any path under it.
+fn path_matches(pattern: &str, path: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) if prefix.ends_with('/') => path.starts_with(prefix) || path == &prefix[..prefix.len() - 1],
+        _ => pattern == path,
+    }
+}
+
+/// Request router dispatching by method and path.
+///
+/// Routes are checked in the order they were added. Requests that don't match any route get a
+/// `404 Not Found` response, or `405 Method Not Allowed` if a route matches the path but not the
+/// method.
+///
+/// ```rust,ignore
+/// let router = Router::new()
+///     .route(Method::Get, "/", Index)
+///     .route(Method::Get, "/static/*", StaticFiles)
+///     .route(Method::Post, "/api/led", SetLed);
+/// ```
+pub struct Router<R> {
+    routes: R,
+}
+
+impl Router<()> {
+    /// Create a router without any routes.
+    pub const fn new() -> Self {
+        Self { routes: () }
+    }
+}
+
+impl Default for Router<()> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<R: Routes> Router<R> {
+    /// Add a route.
+    ///
+    /// `path` must match the request path exactly, or end in `/*` to match all paths under it.
+    /// `GET` routes also serve `HEAD` requests.
+    pub fn route<H: Handler>(self, method: Method, path: &'static str, handler: H) -> Router<Route<H, R>> {
+        Router {
+            routes: Route {
+                method,
+                path,
+                handler,
+                prev: self.routes,
+            },
+        }
+    }
+}
+
+impl<R: Routes> Handler for Router<R> {
+    async fn handle<C: Read + Write>(&self, request: &mut Request<'_, C>) -> Result<Completed, Error> {
+        if let Some(res) = self.routes.dispatch(request).await {
+            return res;
+        }
+        let status = if self.routes.matches_path(request.path()) {
+            StatusCode::METHOD_NOT_ALLOWED
+        } else {
+            StatusCode::NOT_FOUND
+        };
+        request.response().send(status, &[], status.reason().as_bytes()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn patterns() {
+        assert!(path_matches("/", "/"));
+        assert!(path_matches("/api/led", "/api/led"));
+        assert!(!path_matches("/api/led", "/api/led/"));
+        assert!(!path_matches("/api/led", "/api"));
+
+        assert!(path_matches("/static/*", "/static/"));
+        assert!(path_matches("/static/*", "/static"));
+        assert!(path_matches("/static/*", "/static/css/main.css"));
+        assert!(!path_matches("/static/*", "/staticfoo"));
+        assert!(path_matches("/*", "/anything"));
+    }
+}
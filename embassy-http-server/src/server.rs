@@ -0,0 +1,398 @@
+use embassy_net::tcp::TcpSocket;
+use embassy_net::Stack;
+use embassy_time::{with_timeout, Duration};
+use embedded_io_async::{Read, Write};
+
+use crate::body::Body;
+use crate::request::{find_head_end, Request, RequestHead};
+use crate::response::{write_error, StatusCode};
+use crate::router::Handler;
+use crate::{io_error, Error};
+
+/// Server configuration.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Config {
+    /// Maximum time to wait for a complete request head.
+    ///
+    /// For persistent connections this includes the idle time between requests. If `None`,
+    /// wait forever.
+    pub request_timeout: Option<Duration>,
+    /// Maximum time to wait for more data while reading a request body.
+    ///
+    /// This is an idle timeout: it restarts whenever data arrives, so large bodies can take
+    /// longer. If `None`, wait forever.
+    pub body_timeout: Option<Duration>,
+    /// Keep connections open after a response, if the client allows it.
+    pub keep_alive: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            request_timeout: Some(Duration::from_secs(10)),
+            body_timeout: Some(Duration::from_secs(10)),
+            keep_alive: true,
+        }
+    }
+}
+
+/// HTTP/1.1 server.
+pub struct Server<H> {
+    handler: H,
+    config: Config,
+}
+
+impl<H: Handler> Server<H> {
+    /// Create a new server dispatching requests to `handler`.
+    pub fn new(handler: H, config: Config) -> Self {
+        Self { handler, config }
+    }
+
+    /// Accept and serve connections on `port` forever, one at a time.
+    ///
+    /// To serve several connections concurrently, call this from several tasks, each with its own
+    /// buffers. `buf` holds the request head and must be large enough for the largest request line
+    /// and headers expected.
+    pub async fn run(
+        &self,
+        stack: Stack<'_>,
+        port: u16,
+        rx_buffer: &mut [u8],
+        tx_buffer: &mut [u8],
+        buf: &mut [u8],
+    ) -> ! {
+        loop {
+            let mut socket = TcpSocket::new(stack, rx_buffer, tx_buffer);
+            if let Err(e) = socket.accept(port).await {
+                warn!("accept error: {:?}", e);
+                continue;
+            }
+            debug!("accepted connection from {:?}", socket.remote_endpoint());
+
+            if let Err(e) = self.serve(&mut socket, buf).await {
+                debug!("connection error: {:?}", e);
+                socket.abort();
+            } else {
+                socket.close();
+            }
+            let _ = socket.flush().await;
+        }
+    }
+
+    /// Serve requests on a connection until it is closed.
+    ///
+    /// Returns `Ok(())` when the connection was closed cleanly, either by the client between two
+    /// requests or by the server after a response. The caller is responsible for closing `conn`.
+    ///
+    /// `buf` holds the request head and must be large enough for the largest request line and
+    /// headers expected.
+    pub async fn serve<C: Read + Write>(&self, conn: &mut C, buf: &mut [u8]) -> Result<(), Error> {
+        // Number of bytes at the start of `buf` that have been received but not yet processed.
+        let mut len = 0;
+        loop {
+            let head_len = match self.read_head(conn, buf, &mut len).await {
+                Ok(Some(n)) => n,
+                Ok(None) => return Ok(()),
+                Err(e) => return Err(self.reject(conn, e).await),
+            };
+
+            let (head_buf, body_buf) = buf.split_at_mut(head_len);
+            let head = match RequestHead::parse(head_buf) {
+                Ok(head) => head,
+                Err(e) => return Err(self.reject(conn, e).await),
+            };
+            trace!("{} {}", head.method.as_str(), head.path);
+
+            let body = match Body::new(conn, body_buf, len - head_len, &head, self.config.body_timeout) {
+                Ok(body) => body,
+                Err(e) => return Err(self.reject(conn, e).await),
+            };
+            let mut request = Request::new(head, body, self.config.keep_alive);
+
+            if let Err(e) = self.handler.handle(&mut request).await {
+                if !request.is_response_started() {
+                    let status = match e {
+                        Error::TimedOut => StatusCode::REQUEST_TIMEOUT,
+                        _ => StatusCode::INTERNAL_SERVER_ERROR,
+                    };
+                    let _ = request.response().send(status, &[], status.reason().as_bytes()).await;
+                }
+                return Err(e);
+            }
+
+            let (keep_alive, remaining) = request.finish().await?;
+            drop(request);
+            if !keep_alive {
+                return Ok(());
+            }
+
+            // Move bytes of the next (pipelined) request to the start of the buffer.
+            let start = head_len + remaining.start;
+            let end = head_len + remaining.end;
+            buf.copy_within(start..end, 0);
+            len = end - start;
+        }
+    }
+
+    /// Read until `buf` holds a complete request head, and return its length.
+    ///
+    /// Returns `None` if the connection was closed before receiving any byte of the request.
+    async fn read_head<C: Read>(&self, conn: &mut C, buf: &mut [u8], len: &mut usize) -> Result<Option<usize>, Error> {
+        let fut = async {
+            loop {
+                if let Some(n) = find_head_end(&buf[..*len]) {
+                    return Ok(Some(n));
+                }
+                if *len == buf.len() {
+                    return Err(Error::RequestTooLarge);
+                }
+                match conn.read(&mut buf[*len..]).await.map_err(io_error)? {
+                    0 if *len == 0 => return Ok(None),
+                    0 => return Err(Error::ConnectionClosed),
+                    n => *len += n,
+                }
+            }
+        };
+        match self.config.request_timeout {
+            Some(timeout) => with_timeout(timeout, fut).await.map_err(|_| Error::TimedOut)?,
+            None => fut.await,
+        }
+    }
+
+    /// Send an error response for a request that can't be handled, and return the error.
+    async fn reject<C: Write>(&self, conn: &mut C, e: Error) -> Error {
+        let status = match e {
+            Error::BadRequest => StatusCode::BAD_REQUEST,
+            Error::RequestTooLarge => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            Error::UnsupportedVersion => StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+            Error::TimedOut => StatusCode::REQUEST_TIMEOUT,
+            Error::Io(_) | Error::ConnectionClosed => return e,
+        };
+        debug!("rejecting request: {:?}", e);
+        let _ = write_error(conn, status).await;
+        e
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::convert::Infallible;
+    use std::vec::Vec;
+
+    use futures_executor::block_on;
+
+    use super::*;
+    use crate::{ChunkedWriter, Completed, Method, Router};
+
+    /// In-memory connection, returning `input` in pieces of at most `step` bytes.
+    struct Conn<'a> {
+        input: &'a [u8],
+        step: usize,
+        output: Vec<u8>,
+        /// Whether reads block forever once `input` is exhausted, instead of returning EOF.
+        stall: bool,
+    }
+
+    impl<'a> Conn<'a> {
+        fn new(input: &'a [u8], step: usize) -> Self {
+            Self {
+                input,
+                step,
+                output: Vec::new(),
+                stall: false,
+            }
+        }
+
+        fn output(&self) -> &str {
+            core::str::from_utf8(&self.output).unwrap()
+        }
+    }
+
+    impl embedded_io_async::ErrorType for Conn<'_> {
+        type Error = Infallible;
+    }
+
+    impl Read for Conn<'_> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Infallible> {
+            if self.stall && self.input.is_empty() {
+                core::future::pending::<()>().await;
+            }
+            let n = buf.len().min(self.input.len()).min(self.step);
+            buf[..n].copy_from_slice(&self.input[..n]);
+            self.input = &self.input[n..];
+            Ok(n)
+        }
+    }
+
+    impl Write for Conn<'_> {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Infallible> {
+            self.output.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    struct Hello;
+
+    impl Handler for Hello {
+        async fn handle<C: Read + Write>(&self, request: &mut Request<'_, C>) -> Result<Completed, Error> {
+            request
+                .response()
+                .send(StatusCode::OK, &[("Content-Type", "text/plain")], b"Hello!")
+                .await
+        }
+    }
+
+    /// Echoes the request body back, chunked.
+    struct Echo;
+
+    impl Handler for Echo {
+        async fn handle<C: Read + Write>(&self, request: &mut Request<'_, C>) -> Result<Completed, Error> {
+            let mut body = [0; 64];
+            let body = request.body().read_to_end(&mut body).await?;
+            let mut w: ChunkedWriter<'_, C> = request.response().send_chunked(StatusCode::OK, &[]).await?;
+            w.write(body).await?;
+            w.finish().await
+        }
+    }
+
+    fn serve(input: &[u8], step: usize) -> (Result<(), Error>, std::string::String) {
+        let router = Router::new()
+            .route(Method::Get, "/", Hello)
+            .route(Method::Post, "/echo", Echo);
+        let server = Server::new(
+            router,
+            Config {
+                request_timeout: None,
+                ..Default::default()
+            },
+        );
+        let mut conn = Conn::new(input, step);
+        let mut buf = [0; 256];
+        let res = block_on(server.serve(&mut conn, &mut buf));
+        (res, conn.output().into())
+    }
+
+    #[test]
+    fn simple_get() {
+        for step in [1, 7, 1024] {
+            let (res, out) = serve(b"GET / HTTP/1.1\r\nHost: test\r\n\r\n", step);
+            assert_eq!(res, Ok(()));
+            assert_eq!(
+                out,
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 6\r\n\r\nHello!"
+            );
+        }
+    }
+
+    #[test]
+    fn head() {
+        let (res, out) = serve(b"HEAD / HTTP/1.1\r\n\r\n", 1024);
+        assert_eq!(res, Ok(()));
+        assert_eq!(
+            out,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 6\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn http10_closes() {
+        let (res, out) = serve(b"GET / HTTP/1.0\r\n\r\nGET / HTTP/1.0\r\n\r\n", 1024);
+        assert_eq!(res, Ok(()));
+        assert_eq!(
+            out,
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 6\r\nConnection: close\r\n\r\nHello!"
+        );
+    }
+
+    #[test]
+    fn keep_alive_pipelined() {
+        let input = b"POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloPOST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\nGET / HTTP/1.1\r\nConnection: close\r\n\r\n";
+        for step in [1, 5, 1024] {
+            let (res, out) = serve(input, step);
+            assert_eq!(res, Ok(()));
+            assert_eq!(
+                out,
+                "HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n\
+                 HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\n\
+                 HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 6\r\nConnection: close\r\n\r\nHello!"
+            );
+        }
+    }
+
+    #[test]
+    fn unread_body_is_discarded() {
+        let input = b"GET / HTTP/1.1\r\nContent-Length: 3\r\n\r\nxyzGET / HTTP/1.1\r\nConnection: close\r\n\r\n";
+        let (res, out) = serve(input, 1024);
+        assert_eq!(res, Ok(()));
+        assert_eq!(out.matches("200 OK").count(), 2);
+    }
+
+    #[test]
+    fn expect_continue() {
+        let input = b"POST /echo HTTP/1.1\r\nContent-Length: 2\r\nExpect: 100-continue\r\nConnection: close\r\n\r\nhi";
+        // Read byte by byte, so the body isn't received along with the head.
+        let (res, out) = serve(input, 1);
+        assert_eq!(res, Ok(()));
+        assert!(out.starts_with("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\n"));
+    }
+
+    #[test]
+    fn not_found() {
+        let (res, out) = serve(b"GET /nope HTTP/1.1\r\nConnection: close\r\n\r\n", 1024);
+        assert_eq!(res, Ok(()));
+        assert!(out.starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+        let (res, out) = serve(b"DELETE / HTTP/1.1\r\nConnection: close\r\n\r\n", 1024);
+        assert_eq!(res, Ok(()));
+        assert!(out.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+
+    #[test]
+    fn bad_requests() {
+        let (res, out) = serve(b"GET / HTTP/1.1\r\nBad Header\r\n\r\n", 1024);
+        assert_eq!(res, Err(Error::BadRequest));
+        assert!(out.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        let (res, out) = serve(
+            b"POST /echo HTTP/1.1\r\nContent-Length: 1\r\nTransfer-Encoding: chunked\r\n\r\n",
+            1024,
+        );
+        assert_eq!(res, Err(Error::BadRequest));
+        assert!(out.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        let mut long = Vec::new();
+        long.extend_from_slice(b"GET / HTTP/1.1\r\nCookie: ");
+        long.resize(400, b'a');
+        let (res, out) = serve(&long, 1024);
+        assert_eq!(res, Err(Error::RequestTooLarge));
+        assert!(out.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+    }
+
+    #[test]
+    fn body_timeout() {
+        let router = Router::new().route(Method::Post, "/echo", Echo);
+        let server = Server::new(
+            router,
+            Config {
+                body_timeout: Some(Duration::from_millis(50)),
+                ..Default::default()
+            },
+        );
+        let mut conn = Conn::new(b"POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nhe", 1024);
+        conn.stall = true;
+        let mut buf = [0; 256];
+        let res = block_on(server.serve(&mut conn, &mut buf));
+        assert_eq!(res, Err(Error::TimedOut));
+        assert!(conn.output().starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    }
+
+    #[test]
+    fn closed_mid_request() {
+        let (res, out) = serve(b"GET / HTTP/1.1\r\n", 1024);
+        assert_eq!(res, Err(Error::ConnectionClosed));
+        assert_eq!(out, "");
+    }
+}
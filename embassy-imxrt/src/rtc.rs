@@ -1,4 +1,4 @@
-//! RTC Time Driver.
+//! RTC Time Driver, and calendar time.
 use core::cell::{Cell, RefCell};
 use core::sync::atomic::{compiler_fence, AtomicU32, Ordering};
 
@@ -252,3 +252,53 @@ fn RTC() {
 pub(crate) fn init(irq_prio: crate::interrupt::Priority) {
     DRIVER.init(irq_prio)
 }
+
+/// Calendar time of the RTC.
+///
+/// The calendar time is the 1 Hz counter of the RTC plus an offset kept in GPREG3, in seconds since
+/// the Unix epoch. Both are kept by the RTC across resets. The counter can only be written while the
+/// RTC is disabled, which would also stop the 1 kHz wake timer the time driver runs on, so setting
+/// the time only changes the offset.
+#[cfg(feature = "time")]
+pub struct RtcCalendar<'d> {
+    _p: crate::Peri<'d, crate::peripherals::RTC>,
+}
+
+#[cfg(feature = "time")]
+impl<'d> RtcCalendar<'d> {
+    /// Create the calendar of the RTC.
+    pub fn new(p: crate::Peri<'d, crate::peripherals::RTC>) -> Self {
+        Self { _p: p }
+    }
+
+    /// Access the GPREG3 register, holding the offset of the calendar time from the 1 Hz counter.
+    #[inline]
+    fn offset_reg(&self) -> &pac::rtc::Gpreg {
+        rtc().gpreg(3)
+    }
+}
+
+#[cfg(feature = "time")]
+impl embassy_time::rtc::Rtc for RtcCalendar<'_> {
+    type Error = embassy_time::rtc::DateTimeError;
+
+    fn now(&mut self) -> Result<embassy_time::rtc::DateTime, Self::Error> {
+        let secs = critical_section::with(|_| {
+            let count = rtc().count().read().bits();
+            count.wrapping_add(self.offset_reg().read().bits())
+        });
+        embassy_time::rtc::DateTime::from_unix_timestamp(secs as u64)
+    }
+
+    fn set_datetime(&mut self, datetime: embassy_time::rtc::DateTime) -> Result<(), Self::Error> {
+        let secs =
+            u32::try_from(datetime.unix_timestamp()).map_err(|_| embassy_time::rtc::DateTimeError::InvalidYear)?;
+        critical_section::with(|_| {
+            let count = rtc().count().read().bits();
+            // safety: writing to the gpregs is always considered unsafe. GPREG3 only holds the
+            // calendar offset, the time driver uses GPREG0-2.
+            self.offset_reg().write(|w| unsafe { w.bits(secs.wrapping_sub(count)) });
+        });
+        Ok(())
+    }
+}
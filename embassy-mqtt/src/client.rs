@@ -0,0 +1,578 @@
+use core::cell::RefCell;
+use core::convert::Infallible;
+
+use embassy_futures::select::{select3, Either3};
+use embassy_net::tcp::TcpSocket;
+use embassy_net::{IpEndpoint, Stack};
+use embassy_sync::blocking_mutex::raw::RawMutex;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::pubsub::DynPublisher;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration, Ticker, Timer};
+use embedded_io_async::Write;
+use heapless::{String, Vec};
+
+use crate::packet::{self, Packet};
+use crate::{Broker, Config, Error, Message, ProtocolVersion, QoS, MAX_SUBSCRIPTIONS, MAX_TOPIC_LEN};
+
+/// Buffer for the SUBSCRIBE packets sent when resubscribing.
+const SUBSCRIBE_BUF_LEN: usize = MAX_TOPIC_LEN + 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum RequestKind {
+    Publish(QoS),
+    Subscribe,
+    Unsubscribe,
+}
+
+struct Subscription {
+    filter: String<MAX_TOPIC_LEN>,
+    qos: QoS,
+}
+
+/// The request being sent by a [`Client`], encoded.
+///
+/// The packet is kept here until it's acknowledged, so it can be retransmitted after reconnecting.
+struct Outgoing<const TX: usize> {
+    buf: [u8; TX],
+    len: usize,
+    kind: RequestKind,
+    packet_id: u16,
+}
+
+struct Inner {
+    connected: bool,
+    next_packet_id: u16,
+    subscriptions: Vec<Subscription, MAX_SUBSCRIPTIONS>,
+}
+
+impl Inner {
+    fn next_packet_id(&mut self) -> u16 {
+        // Packet identifiers are non-zero.
+        self.next_packet_id = self.next_packet_id.checked_add(1).unwrap_or(1);
+        self.next_packet_id
+    }
+}
+
+struct Shared<M: RawMutex, const TX: usize> {
+    /// Held by a client for the whole duration of a request.
+    request: Mutex<M, ()>,
+    outgoing: Mutex<M, Outgoing<TX>>,
+    /// Signaled by a client when a new request is encoded in `outgoing`.
+    pending: Signal<M, ()>,
+    /// Signaled by the runner when the request is completed.
+    done: Signal<M, Result<QoS, Error>>,
+    inner: BlockingMutex<M, RefCell<Inner>>,
+}
+
+impl<M: RawMutex, const TX: usize> Shared<M, TX> {
+    fn next_packet_id(&self) -> u16 {
+        self.inner.lock(|i| i.borrow_mut().next_packet_id())
+    }
+}
+
+/// Static state for an MQTT client.
+///
+/// `RX` is the size of the buffer for incoming packets, which limits the size of received
+/// messages: larger messages are dropped. `TX` is the size of the buffer for outgoing packets,
+/// which limits the size of published messages. Both are also used as TCP socket buffer sizes.
+pub struct State<M: RawMutex, const RX: usize, const TX: usize> {
+    socket_rx: [u8; RX],
+    socket_tx: [u8; TX],
+    rx: [u8; RX],
+    shared: Shared<M, TX>,
+}
+
+impl<M: RawMutex, const RX: usize, const TX: usize> State<M, RX, TX> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            socket_rx: [0; RX],
+            socket_tx: [0; TX],
+            rx: [0; RX],
+            shared: Shared {
+                request: Mutex::new(()),
+                outgoing: Mutex::new(Outgoing {
+                    buf: [0; TX],
+                    len: 0,
+                    kind: RequestKind::Publish(QoS::AtMostOnce),
+                    packet_id: 0,
+                }),
+                pending: Signal::new(),
+                done: Signal::new(),
+                inner: BlockingMutex::new(RefCell::new(Inner {
+                    connected: false,
+                    next_packet_id: 0,
+                    subscriptions: Vec::new(),
+                })),
+            },
+        }
+    }
+}
+
+/// Create a new MQTT client.
+///
+/// Messages received from the broker are published to `publisher`, so they can be received by any
+/// number of tasks subscribed to its `PubSubChannel`. Delivery waits for space in the channel, so
+/// slow subscribers delay the acknowledgement of QoS 1 messages and the processing of all
+/// incoming packets.
+///
+/// The returned [`Runner`] must be run in a background task for the client to work.
+pub fn new<'d, M: RawMutex, const RX: usize, const TX: usize, const N: usize>(
+    stack: Stack<'d>,
+    state: &'d mut State<M, RX, TX>,
+    config: Config<'d>,
+    publisher: DynPublisher<'d, Message<N>>,
+) -> (Runner<'d, M, TX, N>, Client<'d, M, TX>) {
+    let State {
+        socket_rx,
+        socket_tx,
+        rx,
+        shared,
+    } = state;
+    let shared = &*shared;
+    (
+        Runner {
+            socket_rx,
+            socket_tx,
+            rx,
+            session: Session {
+                stack,
+                config,
+                shared,
+                publisher,
+                in_flight: None,
+            },
+        },
+        Client {
+            shared,
+            version: config.version,
+        },
+    )
+}
+
+/// Handle for publishing messages and managing subscriptions.
+///
+/// Requests from several tasks are sent one at a time. They wait while the client is disconnected,
+/// and are retransmitted after reconnecting until the broker acknowledges them. Cancelling a
+/// request, for example with `with_timeout`, abandons it.
+pub struct Client<'d, M: RawMutex, const TX: usize> {
+    shared: &'d Shared<M, TX>,
+    version: ProtocolVersion,
+}
+
+impl<M: RawMutex, const TX: usize> Clone for Client<'_, M, TX> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<M: RawMutex, const TX: usize> Copy for Client<'_, M, TX> {}
+
+impl<M: RawMutex, const TX: usize> Client<'_, M, TX> {
+    /// Get whether the client is connected to the broker.
+    pub fn is_connected(&self) -> bool {
+        self.shared.inner.lock(|i| i.borrow().connected)
+    }
+
+    /// Publish a message.
+    ///
+    /// With [`QoS::AtMostOnce`], this returns once the message is sent. With
+    /// [`QoS::AtLeastOnce`], this returns once the broker acknowledges it.
+    pub async fn publish(&self, topic: &str, payload: &[u8], qos: QoS, retain: bool) -> Result<(), Error> {
+        let _request = self.shared.request.lock().await;
+        let version = self.version;
+        self.request(RequestKind::Publish(qos), |buf, packet_id| {
+            packet::encode_publish(buf, version, topic, payload, qos, retain, packet_id)
+        })
+        .await
+        .map(drop)
+    }
+
+    /// Subscribe to a topic filter, and return the QoS granted by the broker.
+    ///
+    /// The subscription is renewed whenever the client reconnects without resuming its session.
+    pub async fn subscribe(&self, filter: &str, qos: QoS) -> Result<QoS, Error> {
+        let subscription = Subscription {
+            filter: String::try_from(filter).map_err(|_| Error::TopicTooLong)?,
+            qos,
+        };
+        let _request = self.shared.request.lock().await;
+        let full = self.shared.inner.lock(|i| {
+            let subscriptions = &i.borrow().subscriptions;
+            subscriptions.is_full() && !subscriptions.iter().any(|s| s.filter == filter)
+        });
+        if full {
+            return Err(Error::TooManySubscriptions);
+        }
+
+        let version = self.version;
+        let granted = self
+            .request(RequestKind::Subscribe, |buf, packet_id| {
+                packet::encode_subscribe(buf, version, packet_id, filter, qos)
+            })
+            .await?;
+        self.shared.inner.lock(|i| {
+            let subscriptions = &mut i.borrow_mut().subscriptions;
+            match subscriptions.iter_mut().find(|s| s.filter == filter) {
+                Some(s) => s.qos = qos,
+                // Can't fail, there's room for the subscription.
+                None => unwrap!(subscriptions.push(subscription).ok()),
+            }
+        });
+        Ok(granted)
+    }
+
+    /// Unsubscribe from a topic filter.
+    pub async fn unsubscribe(&self, filter: &str) -> Result<(), Error> {
+        let _request = self.shared.request.lock().await;
+        let version = self.version;
+        self.request(RequestKind::Unsubscribe, |buf, packet_id| {
+            packet::encode_unsubscribe(buf, version, packet_id, filter)
+        })
+        .await?;
+        self.shared
+            .inner
+            .lock(|i| i.borrow_mut().subscriptions.retain(|s| s.filter != filter));
+        Ok(())
+    }
+
+    /// Send a request, and wait for it to complete. The `request` mutex must be held.
+    async fn request(
+        &self,
+        kind: RequestKind,
+        encode: impl FnOnce(&mut [u8], u16) -> Result<usize, Error>,
+    ) -> Result<QoS, Error> {
+        {
+            let mut out = self.shared.outgoing.lock().await;
+            // Invalidate the previous request first, so it's never retransmitted from a partially
+            // overwritten buffer.
+            out.len = 0;
+            let packet_id = match kind {
+                RequestKind::Publish(QoS::AtMostOnce) => 0,
+                _ => self.shared.next_packet_id(),
+            };
+            out.len = encode(&mut out.buf, packet_id)?;
+            out.kind = kind;
+            out.packet_id = packet_id;
+        }
+        self.shared.done.reset();
+        self.shared.pending.signal(());
+        self.shared.done.wait().await
+    }
+}
+
+#[derive(Clone, Copy)]
+struct InFlight {
+    kind: RequestKind,
+    packet_id: u16,
+    sent: bool,
+}
+
+/// Background runner for the MQTT client.
+///
+/// You must call `.run()` in a background task for the client to operate.
+pub struct Runner<'d, M: RawMutex, const TX: usize, const N: usize> {
+    socket_rx: &'d mut [u8],
+    socket_tx: &'d mut [u8],
+    rx: &'d mut [u8],
+    session: Session<'d, M, TX, N>,
+}
+
+impl<M: RawMutex, const TX: usize, const N: usize> Runner<'_, M, TX, N> {
+    /// Run the client, connecting to the broker and reconnecting whenever the connection is lost.
+    pub async fn run(&mut self) -> ! {
+        let mut delay = self.session.config.reconnect_delay_min;
+        loop {
+            let mut socket = TcpSocket::new(self.session.stack, self.socket_rx, self.socket_tx);
+            let Err(e) = self.session.run(&mut socket, self.rx, &mut delay).await;
+            warn!("mqtt: connection lost: {:?}", e);
+            self.session.set_connected(false);
+            socket.abort();
+            let _ = socket.flush().await;
+            drop(socket);
+
+            Timer::after(delay).await;
+            delay = (delay * 2).min(self.session.config.reconnect_delay_max);
+        }
+    }
+}
+
+/// The part of the runner that persists across connections.
+struct Session<'d, M: RawMutex, const TX: usize, const N: usize> {
+    stack: Stack<'d>,
+    config: Config<'d>,
+    shared: &'d Shared<M, TX>,
+    publisher: DynPublisher<'d, Message<N>>,
+    /// The request of a client being processed.
+    in_flight: Option<InFlight>,
+}
+
+impl<M: RawMutex, const TX: usize, const N: usize> Session<'_, M, TX, N> {
+    async fn run(
+        &mut self,
+        socket: &mut TcpSocket<'_>,
+        rx: &mut [u8],
+        delay: &mut Duration,
+    ) -> Result<Infallible, Error> {
+        let version = self.config.version;
+        let keep_alive = Duration::from_secs(self.config.keep_alive.as_secs());
+
+        self.stack.wait_config_up().await;
+        let endpoint = self.resolve().await?;
+        debug!("mqtt: connecting to {:?}", endpoint);
+        if keep_alive.as_ticks() != 0 {
+            socket.set_timeout(Some(keep_alive * 2));
+        }
+        with_timeout(self.config.connect_timeout, socket.connect(endpoint))
+            .await
+            .map_err(|_| Error::TimedOut)?
+            .map_err(Error::Connect)?;
+
+        let len = packet::encode_connect(rx, &self.config, rx.len() as u32)?;
+        write(socket, &rx[..len]).await?;
+
+        let mut rx_len = 0;
+        let session_present = with_timeout(self.config.connect_timeout, async {
+            loop {
+                rx_len += read(socket, &mut rx[rx_len..]).await?;
+                if let Some((packet, len)) = packet::decode(&rx[..rx_len], rx.len(), version)? {
+                    let Packet::ConnAck { session_present, code } = packet else {
+                        return Err(Error::Protocol);
+                    };
+                    if code != 0 {
+                        return Err(Error::ConnectionRefused(code));
+                    }
+                    rx.copy_within(len..rx_len, 0);
+                    rx_len -= len;
+                    return Ok(session_present);
+                }
+            }
+        })
+        .await
+        .map_err(|_| Error::TimedOut)??;
+        info!("mqtt: connected, session present: {}", session_present);
+        *delay = self.config.reconnect_delay_min;
+
+        if !session_present {
+            self.resubscribe(socket).await?;
+        }
+        self.retransmit(socket).await?;
+        self.set_connected(true);
+
+        let mut ticker = (keep_alive.as_ticks() != 0).then(|| Ticker::every(keep_alive / 2));
+        let mut ping_outstanding = false;
+        // Bytes left to receive of a dropped packet, which are discarded.
+        let mut skip = 0;
+        loop {
+            while let Some((packet, len)) = packet::decode(&rx[..rx_len], rx.len(), version)? {
+                self.handle(socket, packet, &mut ping_outstanding).await?;
+                let consumed = len.min(rx_len);
+                rx.copy_within(consumed..rx_len, 0);
+                rx_len -= consumed;
+                skip = len - consumed;
+            }
+
+            let tick = async {
+                match &mut ticker {
+                    Some(ticker) => ticker.next().await,
+                    None => core::future::pending().await,
+                }
+            };
+            match select3(read(socket, &mut rx[rx_len..]), tick, self.shared.pending.wait()).await {
+                Either3::First(n) => {
+                    rx_len += n?;
+                    let skipped = skip.min(rx_len);
+                    rx.copy_within(skipped..rx_len, 0);
+                    rx_len -= skipped;
+                    skip -= skipped;
+                }
+                Either3::Second(()) => {
+                    if ping_outstanding {
+                        return Err(Error::TimedOut);
+                    }
+                    write(socket, &packet::PINGREQ_PACKET).await?;
+                    ping_outstanding = true;
+                }
+                Either3::Third(()) => {
+                    let out = self.shared.outgoing.lock().await;
+                    self.in_flight = Some(InFlight {
+                        kind: out.kind,
+                        packet_id: out.packet_id,
+                        sent: false,
+                    });
+                    self.send(socket, &out).await?;
+                }
+            }
+        }
+    }
+
+    async fn resolve(&self) -> Result<IpEndpoint, Error> {
+        match self.config.broker {
+            Broker::Endpoint(endpoint) => Ok(endpoint),
+            #[cfg(feature = "dns")]
+            Broker::Host(host, port) => {
+                let addrs = self
+                    .stack
+                    .dns_query(host, embassy_net::dns::DnsQueryType::A)
+                    .await
+                    .map_err(|_| Error::Dns)?;
+                let addr = addrs.first().copied().ok_or(Error::Dns)?;
+                Ok(IpEndpoint::new(addr, port))
+            }
+        }
+    }
+
+    /// Renew all subscriptions, after the broker lost the session.
+    ///
+    /// The acknowledgements are processed along with other incoming packets.
+    async fn resubscribe(&mut self, socket: &mut TcpSocket<'_>) -> Result<(), Error> {
+        let mut buf = [0; SUBSCRIBE_BUF_LEN];
+        for n in 0..MAX_SUBSCRIPTIONS {
+            let len = self.shared.inner.lock(|i| {
+                let mut i = i.borrow_mut();
+                if n >= i.subscriptions.len() {
+                    return Ok(None);
+                }
+                let packet_id = i.next_packet_id();
+                let s = &i.subscriptions[n];
+                packet::encode_subscribe(&mut buf, self.config.version, packet_id, &s.filter, s.qos).map(Some)
+            })?;
+            let Some(len) = len else {
+                break;
+            };
+            write(socket, &buf[..len]).await?;
+        }
+        Ok(())
+    }
+
+    /// Retransmit the unacknowledged request of a client, if any.
+    async fn retransmit(&mut self, socket: &mut TcpSocket<'_>) -> Result<(), Error> {
+        let Some(in_flight) = self.in_flight.take() else {
+            return Ok(());
+        };
+        // A newer request replaces this one.
+        if self.shared.pending.signaled() {
+            return Ok(());
+        }
+        let mut out = self.shared.outgoing.lock().await;
+        if out.len == 0 || out.kind != in_flight.kind || out.packet_id != in_flight.packet_id {
+            return Ok(());
+        }
+        if in_flight.sent && matches!(in_flight.kind, RequestKind::Publish(_)) {
+            out.buf[0] |= packet::DUP;
+        }
+        self.in_flight = Some(in_flight);
+        self.send(socket, &out).await
+    }
+
+    async fn send(&mut self, socket: &mut TcpSocket<'_>, out: &Outgoing<TX>) -> Result<(), Error> {
+        write(socket, &out.buf[..out.len]).await?;
+        if out.kind == RequestKind::Publish(QoS::AtMostOnce) {
+            self.in_flight = None;
+            self.complete(Ok(QoS::AtMostOnce));
+        } else if let Some(in_flight) = &mut self.in_flight {
+            in_flight.sent = true;
+        }
+        Ok(())
+    }
+
+    /// Complete the request in flight, if it matches an acknowledgement.
+    fn acknowledge(&mut self, kind: RequestKind, packet_id: u16, result: Result<QoS, Error>) -> bool {
+        match self.in_flight {
+            Some(f) if f.sent && f.kind == kind && f.packet_id == packet_id => {
+                self.in_flight = None;
+                self.complete(result);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn complete(&self, result: Result<QoS, Error>) {
+        // If a newer request is pending, the client abandoned this one.
+        if !self.shared.pending.signaled() {
+            self.shared.done.signal(result);
+        }
+    }
+
+    async fn handle(
+        &mut self,
+        socket: &mut TcpSocket<'_>,
+        packet: Packet<'_>,
+        ping_outstanding: &mut bool,
+    ) -> Result<(), Error> {
+        match packet {
+            Packet::Publish(p) => {
+                match (String::try_from(p.topic), Vec::from_slice(p.payload)) {
+                    (Ok(topic), Ok(payload)) => {
+                        let message = Message {
+                            topic,
+                            payload,
+                            qos: p.qos,
+                            retain: p.retain,
+                        };
+                        self.publisher.publish(message).await;
+                    }
+                    _ => warn!("mqtt: dropping message too large for the buffers"),
+                }
+                if let Some(packet_id) = p.packet_id {
+                    write(socket, &packet::encode_puback(packet_id)).await?;
+                }
+            }
+            Packet::PubAck { packet_id, code } => {
+                let result = match code {
+                    0x00..=0x7f => Ok(QoS::AtLeastOnce),
+                    _ => Err(Error::Rejected(code)),
+                };
+                self.acknowledge(RequestKind::Publish(QoS::AtLeastOnce), packet_id, result);
+            }
+            Packet::SubAck { packet_id, code } => {
+                let result = match code {
+                    0 => Ok(QoS::AtMostOnce),
+                    1 => Ok(QoS::AtLeastOnce),
+                    0x80.. => Err(Error::Rejected(code)),
+                    _ => Err(Error::Protocol),
+                };
+                if !self.acknowledge(RequestKind::Subscribe, packet_id, result) && result.is_err() {
+                    warn!("mqtt: resubscription rejected: {:?}", result);
+                }
+            }
+            Packet::UnsubAck { packet_id, code } => {
+                let result = match code {
+                    0x00..=0x7f => Ok(QoS::AtMostOnce),
+                    _ => Err(Error::Rejected(code)),
+                };
+                self.acknowledge(RequestKind::Unsubscribe, packet_id, result);
+            }
+            Packet::Dropped { packet_id } => {
+                warn!("mqtt: dropping message too large for the buffers");
+                if let Some(packet_id) = packet_id {
+                    write(socket, &packet::encode_puback(packet_id)).await?;
+                }
+            }
+            Packet::PingResp => *ping_outstanding = false,
+            Packet::Disconnect { code } => return Err(Error::Disconnected(code)),
+            Packet::ConnAck { .. } => return Err(Error::Protocol),
+        }
+        Ok(())
+    }
+
+    fn set_connected(&self, connected: bool) {
+        self.shared.inner.lock(|i| i.borrow_mut().connected = connected);
+    }
+}
+
+async fn read(socket: &mut TcpSocket<'_>, buf: &mut [u8]) -> Result<usize, Error> {
+    match socket.read(buf).await {
+        Ok(0) => Err(Error::ConnectionClosed),
+        Ok(n) => Ok(n),
+        Err(e) => Err(Error::Tcp(e)),
+    }
+}
+
+async fn write(socket: &mut TcpSocket<'_>, data: &[u8]) -> Result<(), Error> {
+    socket.write_all(data).await.map_err(Error::Tcp)?;
+    socket.flush().await.map_err(Error::Tcp)
+}
@@ -0,0 +1,227 @@
+#![no_std]
+#![allow(async_fn_in_trait)]
+#![warn(missing_docs)]
+#![doc = include_str!("../README.md")]
+
+//! ## Feature flags
+#![doc = document_features::document_features!(feature_label = r#"<span class="stab portability"><code>{feature}</code></span>"#)]
+
+// This mod MUST go first, so that the others see its macros.
+pub(crate) mod fmt;
+
+mod client;
+mod packet;
+
+pub use client::{new, Client, Runner, State};
+use embassy_net::IpEndpoint;
+use embassy_time::Duration;
+use heapless::{String, Vec};
+
+/// Maximum length of a topic name or filter.
+///
+/// Messages received on longer topics are dropped.
+pub const MAX_TOPIC_LEN: usize = 128;
+
+/// Maximum number of topic filters the client can be subscribed to.
+pub const MAX_SUBSCRIPTIONS: usize = 8;
+
+/// MQTT client error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The broker host name could not be resolved.
+    Dns,
+    /// The TCP connection to the broker could not be established.
+    Connect(embassy_net::tcp::ConnectError),
+    /// The TCP connection returned an error.
+    Tcp(embassy_net::tcp::Error),
+    /// The connection was closed by the broker.
+    ConnectionClosed,
+    /// The broker did not respond in time.
+    TimedOut,
+    /// The broker refused the connection, with the given return code (MQTT 3.1.1) or reason code
+    /// (MQTT 5).
+    ConnectionRefused(u8),
+    /// The broker closed the session with a DISCONNECT packet, with the given reason code.
+    Disconnected(u8),
+    /// The broker rejected a publish, subscribe or unsubscribe request, with the given return or
+    /// reason code.
+    Rejected(u8),
+    /// The broker sent a malformed or unexpected packet.
+    Protocol,
+    /// A packet does not fit in the buffers.
+    PacketTooLarge,
+    /// A topic filter is longer than [`MAX_TOPIC_LEN`].
+    TopicTooLong,
+    /// The client is already subscribed to [`MAX_SUBSCRIPTIONS`] topic filters.
+    TooManySubscriptions,
+}
+
+/// Quality of service of a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[repr(u8)]
+pub enum QoS {
+    /// QoS 0: the message is delivered at most once, without acknowledgement.
+    AtMostOnce = 0,
+    /// QoS 1: the message is delivered at least once, and retransmitted until acknowledged.
+    AtLeastOnce = 1,
+}
+
+/// MQTT protocol version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ProtocolVersion {
+    /// MQTT 3.1.1.
+    V311,
+    /// MQTT 5.
+    V5,
+}
+
+/// Address of the broker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Broker {
+    /// Connect to an IP address and port.
+    Endpoint(IpEndpoint),
+    /// Connect to a host name and port, resolved to an IPv4 address on every connection attempt.
+    #[cfg(feature = "dns")]
+    Host(&'static str, u16),
+}
+
+/// Message published by the broker on behalf of the client when it disconnects ungracefully.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Will<'a> {
+    /// Topic of the message.
+    pub topic: &'a str,
+    /// Payload of the message.
+    pub payload: &'a [u8],
+    /// Quality of service of the message.
+    pub qos: QoS,
+    /// Whether the broker retains the message.
+    pub retain: bool,
+}
+
+/// MQTT client configuration.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct Config<'a> {
+    /// Address of the broker.
+    pub broker: Broker,
+    /// Client identifier, which identifies the session on the broker.
+    pub client_id: &'a str,
+    /// Protocol version.
+    pub version: ProtocolVersion,
+    /// User name to authenticate with.
+    pub username: Option<&'a str>,
+    /// Password to authenticate with.
+    pub password: Option<&'a [u8]>,
+    /// Will message.
+    pub will: Option<Will<'a>>,
+    /// Keep-alive interval, in whole seconds. The client pings the broker twice per interval.
+    ///
+    /// Zero disables keep-alive.
+    pub keep_alive: Duration,
+    /// Discard the session on the broker when connecting.
+    ///
+    /// When `false`, subscriptions and unacknowledged QoS 1 messages are resumed across
+    /// reconnections, if the broker still holds the session.
+    pub clean_session: bool,
+    /// Number of seconds the broker holds the session after the connection closes (MQTT 5 only).
+    ///
+    /// MQTT 3.1.1 brokers hold sessions without `clean_session` indefinitely.
+    pub session_expiry_interval: u32,
+    /// Timeout for establishing the connection, and for the broker to acknowledge it.
+    pub connect_timeout: Duration,
+    /// Delay before the first reconnection attempt. It doubles after each failed attempt.
+    pub reconnect_delay_min: Duration,
+    /// Maximum delay between reconnection attempts.
+    pub reconnect_delay_max: Duration,
+}
+
+impl<'a> Config<'a> {
+    /// Create a new configuration for connecting to `broker` with `client_id`, using MQTT 3.1.1.
+    pub const fn new(broker: Broker, client_id: &'a str) -> Self {
+        Self {
+            broker,
+            client_id,
+            version: ProtocolVersion::V311,
+            username: None,
+            password: None,
+            will: None,
+            keep_alive: Duration::from_secs(60),
+            clean_session: false,
+            session_expiry_interval: 0,
+            connect_timeout: Duration::from_secs(10),
+            reconnect_delay_min: Duration::from_secs(1),
+            reconnect_delay_max: Duration::from_secs(60),
+        }
+    }
+}
+
+/// A message received from the broker.
+///
+/// Payloads longer than `N` bytes are dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Message<const N: usize> {
+    /// Topic the message was published on.
+    pub topic: String<MAX_TOPIC_LEN>,
+    /// Payload of the message.
+    pub payload: Vec<u8, N>,
+    /// Quality of service the message was delivered with.
+    pub qos: QoS,
+    /// Whether the message was retained by the broker, and sent because of a new subscription.
+    pub retain: bool,
+}
+
+impl<const N: usize> Message<N> {
+    /// Whether the topic of the message matches the topic filter `filter`.
+    pub fn matches(&self, filter: &str) -> bool {
+        topic_matches(filter, &self.topic)
+    }
+}
+
+/// Whether `topic` matches the topic filter `filter`, which may contain `+` and `#` wildcards.
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    // Wildcards at the first level don't match topics starting with `$`.
+    if topic.starts_with('$') && (filter.starts_with('+') || filter.starts_with('#')) {
+        return false;
+    }
+
+    let mut filter = filter.split('/');
+    let mut topic = topic.split('/');
+    loop {
+        match (filter.next(), topic.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(_)) => {}
+            (Some(f), Some(t)) if f == t => {}
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wildcards() {
+        assert!(topic_matches("a/b", "a/b"));
+        assert!(!topic_matches("a/b", "a/c"));
+        assert!(!topic_matches("a/b", "a/b/c"));
+        assert!(topic_matches("a/+", "a/b"));
+        assert!(topic_matches("a/+", "a/"));
+        assert!(!topic_matches("a/+", "a/b/c"));
+        assert!(topic_matches("a/+/c", "a/b/c"));
+        assert!(topic_matches("a/#", "a"));
+        assert!(topic_matches("a/#", "a/b/c"));
+        assert!(topic_matches("#", "a/b"));
+        assert!(!topic_matches("#", "$SYS/uptime"));
+        assert!(!topic_matches("+/uptime", "$SYS/uptime"));
+        assert!(topic_matches("$SYS/#", "$SYS/uptime"));
+    }
+}
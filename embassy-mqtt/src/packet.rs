@@ -0,0 +1,603 @@
+//! MQTT packet encoding and decoding.
+//!
+//! Only the packets and properties used by the client are supported. MQTT 5 properties sent by
+//! the broker are skipped.
+
+use crate::{Config, Error, ProtocolVersion, QoS};
+
+const CONNECT: u8 = 1;
+const CONNACK: u8 = 2;
+const PUBLISH: u8 = 3;
+const PUBACK: u8 = 4;
+const SUBSCRIBE: u8 = 8;
+const SUBACK: u8 = 9;
+const UNSUBSCRIBE: u8 = 10;
+const UNSUBACK: u8 = 11;
+const PINGREQ: u8 = 12;
+const PINGRESP: u8 = 13;
+const DISCONNECT: u8 = 14;
+
+/// MQTT 5 property identifiers.
+const PROP_SESSION_EXPIRY_INTERVAL: u8 = 0x11;
+const PROP_MAXIMUM_PACKET_SIZE: u8 = 0x27;
+
+/// Maximum length of a fixed header: one byte of type and flags, and up to four bytes of
+/// remaining length.
+const MAX_HEADER_LEN: usize = 5;
+
+/// DUP flag of a PUBLISH packet, in the first byte.
+pub(crate) const DUP: u8 = 0x08;
+
+/// A decoded packet sent by the broker.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Packet<'a> {
+    ConnAck { session_present: bool, code: u8 },
+    Publish(Publish<'a>),
+    PubAck { packet_id: u16, code: u8 },
+    SubAck { packet_id: u16, code: u8 },
+    UnsubAck { packet_id: u16, code: u8 },
+    PingResp,
+    Disconnect { code: u8 },
+    // A PUBLISH too large for the buffer, with the packet identifier to acknowledge it with.
+    Dropped { packet_id: Option<u16> },
+}
+
+/// A decoded PUBLISH packet.
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) struct Publish<'a> {
+    pub topic: &'a str,
+    pub packet_id: Option<u16>,
+    pub payload: &'a [u8],
+    pub qos: QoS,
+    pub retain: bool,
+}
+
+/// Decode the packet at the start of `buf`.
+///
+/// Returns the packet and its length, or `None` if `buf` doesn't hold a whole packet yet.
+///
+/// A PUBLISH packet longer than `capacity` can never be received whole, so it's returned as
+/// [`Packet::Dropped`] as soon as its header is in `buf`, and the caller must skip the rest of it.
+/// Any other packet longer than `capacity` is an error.
+pub(crate) fn decode(
+    buf: &[u8],
+    capacity: usize,
+    version: ProtocolVersion,
+) -> Result<Option<(Packet<'_>, usize)>, Error> {
+    let Some(&first) = buf.first() else {
+        return Ok(None);
+    };
+    let mut r = Reader { buf, pos: 1 };
+    let remaining = match r.varint() {
+        Ok(n) => n as usize,
+        Err(_) if buf.len() < MAX_HEADER_LEN => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let len = r.pos + remaining;
+    if len > capacity {
+        if first >> 4 != PUBLISH {
+            return Err(Error::PacketTooLarge);
+        }
+        let packet_id = match (first >> 1) & 0x03 {
+            0 => None,
+            1 => match r.str().and_then(|_| r.u16()) {
+                Ok(packet_id) => Some(packet_id),
+                Err(_) if buf.len() < capacity => return Ok(None),
+                // The topic doesn't fit in the buffer, the message can't be acknowledged.
+                Err(_) => None,
+            },
+            _ => return Err(Error::Protocol),
+        };
+        return Ok(Some((Packet::Dropped { packet_id }, len)));
+    }
+    if len > buf.len() {
+        return Ok(None);
+    }
+
+    let mut r = Reader {
+        buf: &buf[..len],
+        pos: r.pos,
+    };
+    let flags = first & 0x0f;
+    let packet = match first >> 4 {
+        CONNACK => {
+            let session_present = r.u8()? & 0x01 != 0;
+            let code = r.u8()?;
+            Packet::ConnAck { session_present, code }
+        }
+        PUBLISH => {
+            let qos = match (flags >> 1) & 0x03 {
+                0 => QoS::AtMostOnce,
+                1 => QoS::AtLeastOnce,
+                _ => return Err(Error::Protocol),
+            };
+            let topic = r.str()?;
+            let packet_id = match qos {
+                QoS::AtMostOnce => None,
+                QoS::AtLeastOnce => Some(r.u16()?),
+            };
+            r.skip_properties(version)?;
+            Packet::Publish(Publish {
+                topic,
+                packet_id,
+                payload: r.rest(),
+                qos,
+                retain: flags & 0x01 != 0,
+            })
+        }
+        PUBACK => {
+            let packet_id = r.u16()?;
+            let code = r.rest().first().copied().unwrap_or(0);
+            Packet::PubAck { packet_id, code }
+        }
+        SUBACK => {
+            let packet_id = r.u16()?;
+            r.skip_properties(version)?;
+            let code = r.u8()?;
+            Packet::SubAck { packet_id, code }
+        }
+        UNSUBACK => {
+            let packet_id = r.u16()?;
+            r.skip_properties(version)?;
+            let code = r.rest().first().copied().unwrap_or(0);
+            Packet::UnsubAck { packet_id, code }
+        }
+        PINGRESP => Packet::PingResp,
+        DISCONNECT => Packet::Disconnect {
+            code: r.rest().first().copied().unwrap_or(0),
+        },
+        _ => return Err(Error::Protocol),
+    };
+    Ok(Some((packet, len)))
+}
+
+/// Encode a CONNECT packet.
+///
+/// `max_packet_size` is sent to MQTT 5 brokers, so that they don't send larger packets.
+pub(crate) fn encode_connect(buf: &mut [u8], config: &Config<'_>, max_packet_size: u32) -> Result<usize, Error> {
+    let v5 = config.version == ProtocolVersion::V5;
+    encode(buf, CONNECT << 4, |w| {
+        w.str("MQTT")?;
+        w.u8(match config.version {
+            ProtocolVersion::V311 => 4,
+            ProtocolVersion::V5 => 5,
+        })?;
+
+        let mut flags = 0;
+        if config.clean_session {
+            flags |= 0x02;
+        }
+        if let Some(will) = &config.will {
+            flags |= 0x04 | ((will.qos as u8) << 3);
+            if will.retain {
+                flags |= 0x20;
+            }
+        }
+        if config.password.is_some() {
+            flags |= 0x40;
+        }
+        if config.username.is_some() {
+            flags |= 0x80;
+        }
+        w.u8(flags)?;
+        w.u16(config.keep_alive.as_secs().min(u16::MAX as u64) as u16)?;
+
+        if v5 {
+            let mut props = 5;
+            if config.session_expiry_interval != 0 {
+                props += 5;
+            }
+            w.varint(props)?;
+            if config.session_expiry_interval != 0 {
+                w.u8(PROP_SESSION_EXPIRY_INTERVAL)?;
+                w.u32(config.session_expiry_interval)?;
+            }
+            w.u8(PROP_MAXIMUM_PACKET_SIZE)?;
+            w.u32(max_packet_size)?;
+        }
+
+        w.str(config.client_id)?;
+        if let Some(will) = &config.will {
+            if v5 {
+                w.varint(0)?;
+            }
+            w.str(will.topic)?;
+            w.u16(will.payload.len().try_into().map_err(|_| Error::PacketTooLarge)?)?;
+            w.bytes(will.payload)?;
+        }
+        if let Some(username) = config.username {
+            w.str(username)?;
+        }
+        if let Some(password) = config.password {
+            w.u16(password.len().try_into().map_err(|_| Error::PacketTooLarge)?)?;
+            w.bytes(password)?;
+        }
+        Ok(())
+    })
+}
+
+/// Encode a PUBLISH packet. `packet_id` is ignored for QoS 0.
+pub(crate) fn encode_publish(
+    buf: &mut [u8],
+    version: ProtocolVersion,
+    topic: &str,
+    payload: &[u8],
+    qos: QoS,
+    retain: bool,
+    packet_id: u16,
+) -> Result<usize, Error> {
+    let mut first = (PUBLISH << 4) | ((qos as u8) << 1);
+    if retain {
+        first |= 0x01;
+    }
+    encode(buf, first, |w| {
+        w.str(topic)?;
+        if qos != QoS::AtMostOnce {
+            w.u16(packet_id)?;
+        }
+        if version == ProtocolVersion::V5 {
+            w.varint(0)?;
+        }
+        w.bytes(payload)
+    })
+}
+
+/// Encode a SUBSCRIBE packet for a single topic filter.
+pub(crate) fn encode_subscribe(
+    buf: &mut [u8],
+    version: ProtocolVersion,
+    packet_id: u16,
+    filter: &str,
+    qos: QoS,
+) -> Result<usize, Error> {
+    encode(buf, (SUBSCRIBE << 4) | 0x02, |w| {
+        w.u16(packet_id)?;
+        if version == ProtocolVersion::V5 {
+            w.varint(0)?;
+        }
+        w.str(filter)?;
+        w.u8(qos as u8)
+    })
+}
+
+/// Encode an UNSUBSCRIBE packet for a single topic filter.
+pub(crate) fn encode_unsubscribe(
+    buf: &mut [u8],
+    version: ProtocolVersion,
+    packet_id: u16,
+    filter: &str,
+) -> Result<usize, Error> {
+    encode(buf, (UNSUBSCRIBE << 4) | 0x02, |w| {
+        w.u16(packet_id)?;
+        if version == ProtocolVersion::V5 {
+            w.varint(0)?;
+        }
+        w.str(filter)
+    })
+}
+
+/// Encode a PUBACK packet, acknowledging success.
+pub(crate) fn encode_puback(packet_id: u16) -> [u8; 4] {
+    let [hi, lo] = packet_id.to_be_bytes();
+    [PUBACK << 4, 2, hi, lo]
+}
+
+/// A PINGREQ packet.
+pub(crate) const PINGREQ_PACKET: [u8; 2] = [PINGREQ << 4, 0];
+
+/// Encode a packet with the given first byte, and the variable header and payload written by `f`.
+fn encode(buf: &mut [u8], first: u8, f: impl FnOnce(&mut Writer<'_>) -> Result<(), Error>) -> Result<usize, Error> {
+    // Write the body after room for the largest fixed header, then move it in place.
+    let mut w = Writer {
+        buf,
+        pos: MAX_HEADER_LEN,
+    };
+    f(&mut w)?;
+    let end = w.pos;
+    let remaining = end - MAX_HEADER_LEN;
+    if remaining > 0x0fff_ffff {
+        return Err(Error::PacketTooLarge);
+    }
+
+    let mut header = Writer {
+        buf: &mut [0; MAX_HEADER_LEN],
+        pos: 0,
+    };
+    header.u8(first)?;
+    header.varint(remaining as u32)?;
+    let header_len = header.pos;
+    let start = MAX_HEADER_LEN - header_len;
+    buf[start..MAX_HEADER_LEN].copy_from_slice(&header.buf[..header_len]);
+    buf.copy_within(start..end, 0);
+    Ok(end - start)
+}
+
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, data: &[u8]) -> Result<(), Error> {
+        let end = self.pos + data.len();
+        if end > self.buf.len() {
+            return Err(Error::PacketTooLarge);
+        }
+        self.buf[self.pos..end].copy_from_slice(data);
+        self.pos = end;
+        Ok(())
+    }
+
+    fn u8(&mut self, val: u8) -> Result<(), Error> {
+        self.bytes(&[val])
+    }
+
+    fn u16(&mut self, val: u16) -> Result<(), Error> {
+        self.bytes(&val.to_be_bytes())
+    }
+
+    fn u32(&mut self, val: u32) -> Result<(), Error> {
+        self.bytes(&val.to_be_bytes())
+    }
+
+    fn str(&mut self, s: &str) -> Result<(), Error> {
+        self.u16(s.len().try_into().map_err(|_| Error::PacketTooLarge)?)?;
+        self.bytes(s.as_bytes())
+    }
+
+    fn varint(&mut self, mut val: u32) -> Result<(), Error> {
+        loop {
+            let byte = (val & 0x7f) as u8;
+            val >>= 7;
+            if val == 0 {
+                return self.u8(byte);
+            }
+            self.u8(byte | 0x80)?;
+        }
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        let data = self.buf.get(self.pos..self.pos + len).ok_or(Error::Protocol)?;
+        self.pos += len;
+        Ok(data)
+    }
+
+    fn rest(&mut self) -> &'a [u8] {
+        let data = &self.buf[self.pos..];
+        self.pos = self.buf.len();
+        data
+    }
+
+    fn u8(&mut self) -> Result<u8, Error> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, Error> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn str(&mut self) -> Result<&'a str, Error> {
+        let len = self.u16()? as usize;
+        core::str::from_utf8(self.bytes(len)?).map_err(|_| Error::Protocol)
+    }
+
+    fn varint(&mut self) -> Result<u32, Error> {
+        let mut val = 0;
+        for i in 0..4 {
+            let byte = self.u8()?;
+            val |= ((byte & 0x7f) as u32) << (7 * i);
+            if byte & 0x80 == 0 {
+                return Ok(val);
+            }
+        }
+        Err(Error::Protocol)
+    }
+
+    fn skip_properties(&mut self, version: ProtocolVersion) -> Result<(), Error> {
+        if version == ProtocolVersion::V5 {
+            let len = self.varint()? as usize;
+            self.bytes(len)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embassy_net::{IpEndpoint, Ipv4Address};
+
+    use super::*;
+    use crate::{Broker, Will};
+
+    fn config() -> Config<'static> {
+        let mut config = Config::new(
+            Broker::Endpoint(IpEndpoint::new(Ipv4Address::LOCALHOST.into(), 1883)),
+            "dev",
+        );
+        config.keep_alive = embassy_time::Duration::from_secs(30);
+        config
+    }
+
+    #[test]
+    fn connect_v311() {
+        let mut config = config();
+        config.clean_session = true;
+        config.username = Some("u");
+        config.password = Some(b"pw");
+        config.will = Some(Will {
+            topic: "t",
+            payload: b"bye",
+            qos: QoS::AtLeastOnce,
+            retain: true,
+        });
+
+        let mut buf = [0; 64];
+        let len = encode_connect(&mut buf, &config, 1024).unwrap();
+        assert_eq!(
+            &buf[..len],
+            &[
+                0x10, 30, 0, 4, b'M', b'Q', b'T', b'T', 4, 0xee, 0, 30, 0, 3, b'd', b'e', b'v', 0, 1, b't', 0, 3, b'b',
+                b'y', b'e', 0, 1, b'u', 0, 2, b'p', b'w'
+            ]
+        );
+    }
+
+    #[test]
+    fn connect_v5() {
+        let mut config = config();
+        config.version = ProtocolVersion::V5;
+        config.session_expiry_interval = 300;
+
+        let mut buf = [0; 64];
+        let len = encode_connect(&mut buf, &config, 1024).unwrap();
+        assert_eq!(
+            &buf[..len],
+            &[
+                0x10, 26, 0, 4, b'M', b'Q', b'T', b'T', 5, 0x00, 0, 30, 10, 0x11, 0, 0, 1, 44, 0x27, 0, 0, 4, 0, 0, 3,
+                b'd', b'e', b'v'
+            ]
+        );
+    }
+
+    #[test]
+    fn publish_roundtrip() {
+        for version in [ProtocolVersion::V311, ProtocolVersion::V5] {
+            let mut buf = [0; 300];
+            let payload = [0x55; 200];
+            let len = encode_publish(&mut buf, version, "a/b", &payload, QoS::AtLeastOnce, true, 7).unwrap();
+            // 200 bytes of payload need a two byte remaining length.
+            assert_eq!(buf[0], 0x33);
+            assert_eq!(buf[2], 0x01);
+
+            let (packet, used) = decode(&buf[..len], len, version).unwrap().unwrap();
+            assert_eq!(used, len);
+            assert_eq!(
+                packet,
+                Packet::Publish(Publish {
+                    topic: "a/b",
+                    packet_id: Some(7),
+                    payload: &payload,
+                    qos: QoS::AtLeastOnce,
+                    retain: true,
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn decode_incomplete() {
+        let mut buf = [0; 32];
+        let len = encode_publish(
+            &mut buf,
+            ProtocolVersion::V311,
+            "t",
+            b"hello",
+            QoS::AtMostOnce,
+            false,
+            0,
+        )
+        .unwrap();
+        for n in 0..len {
+            assert_eq!(decode(&buf[..n], buf.len(), ProtocolVersion::V311), Ok(None));
+        }
+        assert!(decode(&buf[..len], buf.len(), ProtocolVersion::V311).unwrap().is_some());
+    }
+
+    #[test]
+    fn decode_acks() {
+        let v5 = ProtocolVersion::V5;
+        let v311 = ProtocolVersion::V311;
+        assert_eq!(
+            decode(&[0x20, 2, 1, 0], 16, v311),
+            Ok(Some((
+                Packet::ConnAck {
+                    session_present: true,
+                    code: 0
+                },
+                4
+            )))
+        );
+        assert_eq!(
+            decode(&[0x40, 2, 0, 9], 16, v5),
+            Ok(Some((Packet::PubAck { packet_id: 9, code: 0 }, 4)))
+        );
+        assert_eq!(
+            decode(&[0x40, 4, 0, 9, 0x87, 0], 16, v5),
+            Ok(Some((
+                Packet::PubAck {
+                    packet_id: 9,
+                    code: 0x87
+                },
+                6
+            )))
+        );
+        assert_eq!(
+            decode(&[0x90, 3, 0, 1, 0x80], 16, v311),
+            Ok(Some((
+                Packet::SubAck {
+                    packet_id: 1,
+                    code: 0x80
+                },
+                5
+            )))
+        );
+        assert_eq!(
+            decode(&[0x90, 6, 0, 1, 2, 0x1f, 0, 1], 16, v5),
+            Ok(Some((Packet::SubAck { packet_id: 1, code: 1 }, 8)))
+        );
+        assert_eq!(decode(&[0xd0, 0], 16, v5), Ok(Some((Packet::PingResp, 2))));
+        assert_eq!(decode(&[0x50, 2, 0, 1], 16, v5), Err(Error::Protocol));
+    }
+
+    #[test]
+    fn decode_too_large() {
+        let v311 = ProtocolVersion::V311;
+        // Remaining length of 16384, with only a few bytes of buffer.
+        assert_eq!(
+            decode(&[0x30, 0x80, 0x80, 0x01, 0, 0], 1024, v311),
+            Ok(Some((Packet::Dropped { packet_id: None }, 16388)))
+        );
+        assert_eq!(
+            decode(&[0x90, 0x80, 0x80, 0x01, 0, 0], 1024, v311),
+            Err(Error::PacketTooLarge)
+        );
+
+        // QoS 1 messages are dropped once their packet identifier is received.
+        let publish = [0x32, 0x80, 0x01, 0, 1, b't', 0, 9, 0];
+        assert_eq!(decode(&publish[..6], 64, v311), Ok(None));
+        assert_eq!(
+            decode(&publish, 64, v311),
+            Ok(Some((Packet::Dropped { packet_id: Some(9) }, 131)))
+        );
+        // Or as soon as the buffer is full, if the topic doesn't fit.
+        assert_eq!(
+            decode(&publish[..6], 6, v311),
+            Ok(Some((Packet::Dropped { packet_id: None }, 131)))
+        );
+    }
+
+    #[test]
+    fn encode_too_large() {
+        let mut buf = [0; 16];
+        assert_eq!(
+            encode_publish(
+                &mut buf,
+                ProtocolVersion::V311,
+                "topic",
+                &[0; 16],
+                QoS::AtMostOnce,
+                false,
+                0
+            ),
+            Err(Error::PacketTooLarge)
+        );
+    }
+}
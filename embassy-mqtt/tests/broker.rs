@@ -0,0 +1,223 @@
+use core::future::Future;
+
+use embassy_futures::join::{join, join3};
+use embassy_futures::select::{select, Either};
+use embassy_mqtt::{Broker, Config, Message, QoS, State};
+use embassy_net::tcp::TcpSocket;
+use embassy_net::{IpEndpoint, Ipv4Address, Ipv4Cidr, Stack, StackResources, StaticConfigV4};
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::pubsub::PubSubChannel;
+use embassy_time::{with_timeout, Duration};
+use embedded_io_async::{Read, Write};
+use futures_executor::block_on;
+
+const MTU: usize = 1514;
+
+fn leak<T>(x: T) -> &'static mut T {
+    Box::leak(Box::new(x))
+}
+
+fn ip(n: u8) -> Ipv4Address {
+    Ipv4Address::new(10, 0, 0, n)
+}
+
+fn net_config(n: u8) -> embassy_net::Config {
+    embassy_net::Config::ipv4_static(StaticConfigV4 {
+        address: Ipv4Cidr::new(ip(n), 24),
+        gateway: None,
+        dns_servers: Default::default(),
+    })
+}
+
+/// Run `f` with a client stack and a broker stack, connected by a loopback link.
+fn run<F, Fut>(f: F) -> Fut::Output
+where
+    F: FnOnce(Stack<'static>, Stack<'static>) -> Fut,
+    Fut: Future,
+{
+    let (device_a, device_b, mut link, _control) =
+        embassy_net_loopback::new(leak(embassy_net_loopback::State::<MTU, 8>::new()), Default::default());
+    let (stack_a, mut runner_a) = embassy_net::new(device_a, net_config(1), leak(StackResources::<4>::new()), 1);
+    let (stack_b, mut runner_b) = embassy_net::new(device_b, net_config(2), leak(StackResources::<4>::new()), 2);
+
+    block_on(async {
+        let test = with_timeout(Duration::from_secs(30), f(stack_a, stack_b));
+        match select(join3(runner_a.run(), runner_b.run(), link.run()), test).await {
+            Either::First(_) => unreachable!(),
+            Either::Second(res) => res.expect("test timed out"),
+        }
+    })
+}
+
+fn config() -> Config<'static> {
+    let mut config = Config::new(Broker::Endpoint(IpEndpoint::new(ip(2).into(), 1883)), "dev");
+    config.reconnect_delay_min = Duration::from_millis(100);
+    config
+}
+
+/// Read a whole MQTT packet.
+async fn read_packet(socket: &mut TcpSocket<'_>) -> Vec<u8> {
+    let mut packet = vec![0];
+    socket.read_exact(&mut packet).await.unwrap();
+    let mut len = 0;
+    for shift in (0..28).step_by(7) {
+        let mut byte = [0];
+        socket.read_exact(&mut byte).await.unwrap();
+        packet.push(byte[0]);
+        len |= ((byte[0] & 0x7f) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+    let start = packet.len();
+    packet.resize(start + len, 0);
+    socket.read_exact(&mut packet[start..]).await.unwrap();
+    packet
+}
+
+/// Accept a connection, and acknowledge the CONNECT packet.
+async fn accept(socket: &mut TcpSocket<'_>, session_present: bool) {
+    socket.accept(1883).await.unwrap();
+    let connect = read_packet(socket).await;
+    assert_eq!(connect[0], 0x10);
+    socket.write_all(&[0x20, 2, session_present as u8, 0]).await.unwrap();
+}
+
+#[test]
+fn publish_subscribe() {
+    run(|a, b| async move {
+        let channel = PubSubChannel::<NoopRawMutex, Message<64>, 4, 1, 1>::new();
+        let mut subscriber = channel.subscriber().unwrap();
+        let state = leak(State::<NoopRawMutex, 256, 256>::new());
+        let (mut runner, client) = embassy_mqtt::new(a, state, config(), channel.dyn_publisher().unwrap());
+
+        let broker = async {
+            let (mut rx, mut tx) = ([0; 1024], [0; 1024]);
+            let mut socket = TcpSocket::new(b, &mut rx, &mut tx);
+            accept(&mut socket, false).await;
+
+            let subscribe = read_packet(&mut socket).await;
+            assert_eq!(subscribe, [0x82, 8, 0, 1, 0, 3, b'a', b'/', b'+', 1]);
+            socket.write_all(&[0x90, 3, 0, 1, 1]).await.unwrap();
+
+            let publish = read_packet(&mut socket).await;
+            assert_eq!(
+                publish,
+                [0x32, 12, 0, 3, b'a', b'/', b'b', 0, 2, b'h', b'e', b'l', b'l', b'o']
+            );
+            socket.write_all(&[0x40, 2, 0, 2]).await.unwrap();
+
+            // A retained QoS 1 message.
+            socket
+                .write_all(&[0x33, 9, 0, 3, b'a', b'/', b'c', 0, 7, b'h', b'i'])
+                .await
+                .unwrap();
+            assert_eq!(read_packet(&mut socket).await, [0x40, 2, 0, 7]);
+        };
+
+        let app = async {
+            assert_eq!(client.subscribe("a/+", QoS::AtLeastOnce).await, Ok(QoS::AtLeastOnce));
+            assert!(client.is_connected());
+            client.publish("a/b", b"hello", QoS::AtLeastOnce, false).await.unwrap();
+
+            let message = subscriber.next_message_pure().await;
+            assert!(message.matches("a/+"));
+            assert_eq!(message.topic, "a/c");
+            assert_eq!(message.payload, b"hi");
+            assert_eq!(message.qos, QoS::AtLeastOnce);
+            assert!(message.retain);
+        };
+
+        match select(runner.run(), join(broker, app)).await {
+            Either::First(_) => unreachable!(),
+            Either::Second(_) => {}
+        }
+    })
+}
+
+#[test]
+fn oversized_message_dropped() {
+    run(|a, b| async move {
+        let channel = PubSubChannel::<NoopRawMutex, Message<64>, 4, 1, 1>::new();
+        let mut subscriber = channel.subscriber().unwrap();
+        let state = leak(State::<NoopRawMutex, 256, 256>::new());
+        let (mut runner, client) = embassy_mqtt::new(a, state, config(), channel.dyn_publisher().unwrap());
+
+        let broker = async {
+            let (mut rx, mut tx) = ([0; 1024], [0; 1024]);
+            let mut socket = TcpSocket::new(b, &mut rx, &mut tx);
+            accept(&mut socket, false).await;
+            assert_eq!(read_packet(&mut socket).await[..4], [0x82, 6, 0, 1]);
+            socket.write_all(&[0x90, 3, 0, 1, 1]).await.unwrap();
+
+            // A QoS 1 message larger than the receive buffer, followed by a small one.
+            let mut big = vec![0x32, 0xed, 0x07, 0, 1, b't', 0, 5];
+            big.resize(big.len() + 1000, 0xaa);
+            socket.write_all(&big).await.unwrap();
+            socket.write_all(&[0x30, 5, 0, 1, b't', b'h', b'i']).await.unwrap();
+            socket.flush().await.unwrap();
+
+            // The big message is acknowledged, on the same connection.
+            assert_eq!(read_packet(&mut socket).await, [0x40, 2, 0, 5]);
+        };
+
+        let app = async {
+            assert_eq!(client.subscribe("t", QoS::AtLeastOnce).await, Ok(QoS::AtLeastOnce));
+            let message = subscriber.next_message_pure().await;
+            assert_eq!(message.topic, "t");
+            assert_eq!(message.payload, b"hi");
+        };
+
+        match select(runner.run(), join(broker, app)).await {
+            Either::First(_) => unreachable!(),
+            Either::Second(_) => {}
+        }
+    })
+}
+
+#[test]
+fn reconnect_resubscribes_and_retransmits() {
+    run(|a, b| async move {
+        let channel = PubSubChannel::<NoopRawMutex, Message<64>, 4, 1, 1>::new();
+        let state = leak(State::<NoopRawMutex, 256, 256>::new());
+        let (mut runner, client) = embassy_mqtt::new(a, state, config(), channel.dyn_publisher().unwrap());
+
+        let broker = async {
+            let (mut rx, mut tx) = ([0; 1024], [0; 1024]);
+            {
+                let mut socket = TcpSocket::new(b, &mut rx, &mut tx);
+                accept(&mut socket, false).await;
+                assert_eq!(read_packet(&mut socket).await[..4], [0x82, 6, 0, 1]);
+                socket.write_all(&[0x90, 3, 0, 1, 0]).await.unwrap();
+
+                // Close the connection before acknowledging the publish.
+                let publish = read_packet(&mut socket).await;
+                assert_eq!(publish[0], 0x32);
+                socket.close();
+                socket.flush().await.unwrap();
+            }
+
+            // The broker lost the session: the client subscribes again, and retransmits the
+            // publish with the DUP flag.
+            let mut socket = TcpSocket::new(b, &mut rx, &mut tx);
+            accept(&mut socket, false).await;
+            assert_eq!(read_packet(&mut socket).await, [0x82, 6, 0, 3, 0, 1, b't', 0]);
+            socket.write_all(&[0x90, 3, 0, 3, 0]).await.unwrap();
+
+            let publish = read_packet(&mut socket).await;
+            assert_eq!(publish, [0x3a, 7, 0, 1, b'x', 0, 2, b'h', b'i']);
+            socket.write_all(&[0x40, 2, 0, 2]).await.unwrap();
+            socket.flush().await.unwrap();
+        };
+
+        let app = async {
+            assert_eq!(client.subscribe("t", QoS::AtMostOnce).await, Ok(QoS::AtMostOnce));
+            client.publish("x", b"hi", QoS::AtLeastOnce, false).await.unwrap();
+        };
+
+        match select(runner.run(), join(broker, app)).await {
+            Either::First(_) => unreachable!(),
+            Either::Second(_) => {}
+        }
+    })
+}
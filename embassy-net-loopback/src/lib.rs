@@ -0,0 +1,300 @@
+#![no_std]
+#![warn(missing_docs)]
+#![doc = include_str!("../README.md")]
+
+// must be first
+mod fmt;
+
+use core::future::{poll_fn, Future};
+use core::pin::Pin;
+use core::task::{Context, Poll};
+
+use embassy_net_driver_channel as ch;
+use embassy_net_driver_channel::driver::{HardwareAddress, LinkState};
+use embassy_time::{Duration, Instant, Timer};
+
+/// Type alias for the embassy-net driver.
+pub type Device<'d, const MTU: usize> = embassy_net_driver_channel::Device<'d, MTU>;
+
+/// Link configuration.
+///
+/// Impairments apply to each direction independently.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Config {
+    /// Hardware address of the first device.
+    pub hardware_address_a: HardwareAddress,
+    /// Hardware address of the second device.
+    pub hardware_address_b: HardwareAddress,
+    /// Delay between a packet being sent and it being received.
+    pub latency: Duration,
+    /// Probability for a packet to be dropped, between 0.0 and 1.0.
+    pub loss: f32,
+    /// Probability for a packet to be delayed by an additional [`reorder_delay`](Self::reorder_delay),
+    /// between 0.0 and 1.0.
+    ///
+    /// Packets sent after a delayed packet can overtake it.
+    pub reorder: f32,
+    /// Additional delay for reordered packets.
+    pub reorder_delay: Duration,
+    /// Seed for the pseudo-random generator deciding which packets are dropped or reordered.
+    ///
+    /// The same seed and the same sequence of packets gives the same impairments.
+    pub seed: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            hardware_address_a: HardwareAddress::Ethernet([0x02, 0, 0, 0, 0, 0x0a]),
+            hardware_address_b: HardwareAddress::Ethernet([0x02, 0, 0, 0, 0, 0x0b]),
+            latency: Duration::from_ticks(0),
+            loss: 0.0,
+            reorder: 0.0,
+            reorder_delay: Duration::from_millis(10),
+            seed: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+}
+
+/// A packet travelling on the link.
+struct InFlight<const MTU: usize> {
+    /// When to deliver the packet, `None` if the slot is free.
+    deliver_at: Option<Instant>,
+    /// Order in which the packet entered the link, to deliver packets due at the same time in order.
+    seq: u32,
+    len: usize,
+    buf: [u8; MTU],
+}
+
+impl<const MTU: usize> InFlight<MTU> {
+    const fn new() -> Self {
+        Self {
+            deliver_at: None,
+            seq: 0,
+            len: 0,
+            buf: [0; MTU],
+        }
+    }
+}
+
+/// Internal state for the loopback link.
+///
+/// Each device can queue `N` packets for transmission and reception, and each direction of the
+/// link can hold `N` packets in flight.
+pub struct State<const MTU: usize, const N: usize> {
+    ch_a: ch::State<MTU, N, N>,
+    ch_b: ch::State<MTU, N, N>,
+    a_to_b: [InFlight<MTU>; N],
+    b_to_a: [InFlight<MTU>; N],
+}
+
+impl<const MTU: usize, const N: usize> State<MTU, N> {
+    /// Create a new `State`.
+    pub const fn new() -> Self {
+        Self {
+            ch_a: ch::State::new(),
+            ch_b: ch::State::new(),
+            a_to_b: [const { InFlight::new() }; N],
+            b_to_a: [const { InFlight::new() }; N],
+        }
+    }
+}
+
+/// Handle to control the link state of both devices.
+#[derive(Clone, Copy)]
+pub struct Control<'d> {
+    a: ch::StateRunner<'d>,
+    b: ch::StateRunner<'d>,
+}
+
+impl Control<'_> {
+    /// Set the link state of both devices, like plugging or unplugging a cable.
+    pub fn set_link_state(&self, state: LinkState) {
+        self.a.set_link_state(state);
+        self.b.set_link_state(state);
+    }
+}
+
+/// One direction of the link.
+struct Direction<'d, const MTU: usize> {
+    tx: ch::TxRunner<'d, MTU>,
+    rx: ch::RxRunner<'d, MTU>,
+    in_flight: &'d mut [InFlight<MTU>],
+    /// Sequence number of the next packet entering the link.
+    next_seq: u32,
+}
+
+impl<const MTU: usize> Direction<'_, MTU> {
+    /// Move packets from the sender into the link, and from the link to the receiver.
+    fn pump(&mut self, config: &Config, rng: &mut Rng, now: Instant) {
+        // Take packets from the sender as long as there is room on the link.
+        while let Some(slot) = self.in_flight.iter_mut().find(|p| p.deliver_at.is_none()) {
+            let Some(pkt) = self.tx.try_tx_buf() else { break };
+
+            if rng.chance(config.loss) {
+                trace!("dropping packet, len {}", pkt.len());
+            } else {
+                let mut delay = config.latency;
+                if rng.chance(config.reorder) {
+                    trace!("delaying packet, len {}", pkt.len());
+                    delay += config.reorder_delay;
+                }
+                slot.len = pkt.len();
+                slot.buf[..pkt.len()].copy_from_slice(pkt);
+                slot.deliver_at = Some(now + delay);
+                slot.seq = self.next_seq;
+                self.next_seq = self.next_seq.wrapping_add(1);
+            }
+            self.tx.tx_done();
+        }
+
+        // Deliver due packets, in order of delivery time.
+        while let Some(slot) = self.next_due(now) {
+            let Some(buf) = self.rx.try_rx_buf() else { break };
+            let slot = &mut self.in_flight[slot];
+            buf[..slot.len].copy_from_slice(&slot.buf[..slot.len]);
+            self.rx.rx_done(slot.len);
+            slot.deliver_at = None;
+        }
+    }
+
+    /// Index of the packet to deliver next, if it's due at `now`.
+    fn next_due(&self, now: Instant) -> Option<usize> {
+        let (i, at) = self.next_delivery()?;
+        (at <= now).then_some(i)
+    }
+
+    fn next_delivery(&self) -> Option<(usize, Instant)> {
+        // The packets in flight are the last `N` to enter the link, so their age is their sequence
+        // number relative to the next one, even when it wraps around.
+        self.in_flight
+            .iter()
+            .enumerate()
+            .filter_map(|(i, p)| Some((i, p.deliver_at?, self.next_seq.wrapping_sub(p.seq))))
+            .min_by_key(|(_, at, age)| (*at, core::cmp::Reverse(*age)))
+            .map(|(i, at, _)| (i, at))
+    }
+
+    /// Register wakers for the events that let [`pump`](Self::pump) make progress.
+    fn poll_ready(&mut self, cx: &mut Context<'_>, now: Instant) -> Poll<()> {
+        if self.in_flight.iter().any(|p| p.deliver_at.is_none()) && self.tx.poll_tx_buf(cx).is_ready() {
+            return Poll::Ready(());
+        }
+        if self.next_due(now).is_some() && self.rx.poll_rx_buf(cx).is_ready() {
+            return Poll::Ready(());
+        }
+        Poll::Pending
+    }
+}
+
+/// Loopback link runner.
+///
+/// You must call [`Runner::run()`] in a background task for packets to be exchanged.
+pub struct Runner<'d, const MTU: usize> {
+    a_to_b: Direction<'d, MTU>,
+    b_to_a: Direction<'d, MTU>,
+    config: Config,
+    rng: Rng,
+}
+
+impl<const MTU: usize> Runner<'_, MTU> {
+    /// Run the link.
+    pub async fn run(&mut self) -> ! {
+        loop {
+            let now = Instant::now();
+            self.a_to_b.pump(&self.config, &mut self.rng, now);
+            self.b_to_a.pump(&self.config, &mut self.rng, now);
+
+            // Packets already due are waiting for room at the receiver, which `poll_ready` waits for.
+            let next = [self.a_to_b.next_delivery(), self.b_to_a.next_delivery()]
+                .into_iter()
+                .flatten()
+                .map(|(_, at)| at)
+                .filter(|at| *at > now)
+                .min();
+            let mut timer = next.map(Timer::at);
+
+            poll_fn(|cx| {
+                if let Some(timer) = &mut timer {
+                    if Pin::new(timer).poll(cx).is_ready() {
+                        return Poll::Ready(());
+                    }
+                }
+                let now = Instant::now();
+                if self.a_to_b.poll_ready(cx, now).is_ready() || self.b_to_a.poll_ready(cx, now).is_ready() {
+                    return Poll::Ready(());
+                }
+                Poll::Pending
+            })
+            .await;
+        }
+    }
+}
+
+/// Create a loopback link between two devices.
+///
+/// Returns the two devices, to be passed to [`embassy_net::new`](https://docs.embassy.dev/embassy-net),
+/// a [`Runner`] that must be run for packets to be exchanged, and a [`Control`] handle.
+///
+/// Both links start in the up state.
+pub fn new<'d, const MTU: usize, const N: usize>(
+    state: &'d mut State<MTU, N>,
+    config: Config,
+) -> (Device<'d, MTU>, Device<'d, MTU>, Runner<'d, MTU>, Control<'d>) {
+    let (runner_a, device_a) = ch::new(&mut state.ch_a, config.hardware_address_a);
+    let (runner_b, device_b) = ch::new(&mut state.ch_b, config.hardware_address_b);
+
+    let (state_a, rx_a, tx_a) = runner_a.split();
+    let (state_b, rx_b, tx_b) = runner_b.split();
+    state_a.set_link_state(LinkState::Up);
+    state_b.set_link_state(LinkState::Up);
+
+    let rng = Rng::new(config.seed);
+    let runner = Runner {
+        a_to_b: Direction {
+            tx: tx_a,
+            rx: rx_b,
+            in_flight: &mut state.a_to_b,
+            next_seq: 0,
+        },
+        b_to_a: Direction {
+            tx: tx_b,
+            rx: rx_a,
+            in_flight: &mut state.b_to_a,
+            next_seq: 0,
+        },
+        config,
+        rng,
+    };
+    let control = Control { a: state_a, b: state_b };
+
+    (device_a, device_b, runner, control)
+}
+
+/// xorshift64* pseudo-random number generator.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        // The state must not be zero.
+        Self(seed | 1)
+    }
+
+    fn next_u32(&mut self) -> u32 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        (self.0.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 32) as u32
+    }
+
+    /// Return true with probability `p`.
+    fn chance(&mut self, p: f32) -> bool {
+        if p <= 0.0 {
+            return false;
+        }
+        // 24 bits of randomness are exactly representable in an f32.
+        let x = (self.next_u32() >> 8) as f32 / (1u32 << 24) as f32;
+        x < p
+    }
+}
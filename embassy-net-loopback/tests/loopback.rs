@@ -0,0 +1,1088 @@
+use core::future::Future;
+
+use embassy_futures::join::{join, join3, join5};
+use embassy_futures::select::{select, Either};
+use embassy_net::dhcp_server::{DhcpServer, DhcpServerConfig, DhcpServerState};
+use embassy_net::driver::LinkState;
+use embassy_net::sntp::{Clock, Sntp, SntpConfig, SntpState};
+use embassy_net::tcp::client::{TcpClient, TcpClientState};
+use embassy_net::tcp::listener::{TcpListener, TcpListenerState};
+use embassy_net::tcp::{AcceptError, TcpSocket};
+use embassy_net::tls::{NoVerify, TlsConfig, TlsError, TlsSocket};
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::{
+    ConfigV4, EthernetAddress, HardwareAddress, InterfaceFull, InterfaceId, InterfaceResources, Ipv4Address, Ipv4Cidr,
+    NeighborConflictKind, Route, Stack, StackResources, StaticConfigV4,
+};
+use embassy_net_loopback::{Config, State};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use embedded_io_async::{Read, Write};
+use embedded_nal_async::TcpConnect;
+use futures_executor::block_on;
+use rand_core::{CryptoRng, RngCore};
+
+const MTU: usize = 1514;
+
+fn leak<T>(x: T) -> &'static mut T {
+    Box::leak(Box::new(x))
+}
+
+fn ip(n: u8) -> Ipv4Address {
+    Ipv4Address::new(10, 0, 0, n)
+}
+
+fn net_config(n: u8) -> embassy_net::Config {
+    embassy_net::Config::ipv4_static(StaticConfigV4 {
+        address: Ipv4Cidr::new(ip(n), 24),
+        gateway: None,
+        dns_servers: Default::default(),
+    })
+}
+
+/// Run `f` with two stacks connected by a loopback link configured with `config`.
+fn run<F, Fut>(config: Config, f: F) -> Fut::Output
+where
+    F: FnOnce(Stack<'static>, Stack<'static>) -> Fut,
+    Fut: Future,
+{
+    run_with(config, net_config(1), net_config(2), f)
+}
+
+/// Like [`run`], with the given network configuration for each stack.
+fn run_with<F, Fut>(config: Config, net_a: embassy_net::Config, net_b: embassy_net::Config, f: F) -> Fut::Output
+where
+    F: FnOnce(Stack<'static>, Stack<'static>) -> Fut,
+    Fut: Future,
+{
+    let (device_a, device_b, mut link, _control) = embassy_net_loopback::new(leak(State::<MTU, 8>::new()), config);
+    let (stack_a, mut runner_a) = embassy_net::new(device_a, net_a, leak(StackResources::<4>::new()), 1);
+    let (stack_b, mut runner_b) = embassy_net::new(device_b, net_b, leak(StackResources::<4>::new()), 2);
+
+    block_on(async {
+        let test = with_timeout(Duration::from_secs(60), f(stack_a, stack_b));
+        match select(join3(runner_a.run(), runner_b.run(), link.run()), test).await {
+            Either::First(_) => unreachable!(),
+            Either::Second(res) => res.expect("test timed out"),
+        }
+    })
+}
+
+#[test]
+fn tcp_transfer_with_loss_and_reordering() {
+    const LEN: usize = 32 * 1024;
+    let data: Vec<u8> = (0..LEN).map(|i| (i % 251) as u8).collect();
+
+    let mut config = Config::default();
+    config.latency = Duration::from_millis(1);
+    config.loss = 0.05;
+    config.reorder = 0.05;
+
+    run(config, |a, b| async move {
+        let server = async {
+            let (mut rx, mut tx) = ([0; 4096], [0; 4096]);
+            let mut socket = TcpSocket::new(b, &mut rx, &mut tx);
+            socket.accept(1234).await.unwrap();
+            let mut received = Vec::new();
+            let mut buf = [0; 1024];
+            loop {
+                match socket.read(&mut buf).await.unwrap() {
+                    0 => break,
+                    n => received.extend_from_slice(&buf[..n]),
+                }
+            }
+            received
+        };
+        let client = async {
+            let (mut rx, mut tx) = ([0; 4096], [0; 4096]);
+            let mut socket = TcpSocket::new(a, &mut rx, &mut tx);
+            socket.connect((ip(2), 1234)).await.unwrap();
+            socket.write_all(&data).await.unwrap();
+            socket.close();
+            socket.flush().await.unwrap();
+        };
+        let (received, ()) = embassy_futures::join::join(server, client).await;
+        assert_eq!(received, data);
+    });
+}
+
+#[test]
+fn tcp_listener_accept() {
+    run(Config::default(), |a, b| async move {
+        let state = TcpListenerState::<1, 256, 256>::new();
+        let mut listener = TcpListener::new(b, &state, 1234);
+        let client_state = TcpClientState::<1, 256, 256>::new();
+        let client = TcpClient::new(a, &client_state);
+        let addr = core::net::SocketAddr::new(core::net::Ipv4Addr::new(10, 0, 0, 2).into(), 1234);
+
+        for i in 0..3u8 {
+            let (conn, client_conn) = join(listener.accept(), client.connect(addr)).await;
+            let (mut conn, mut client_conn) = (conn.unwrap(), client_conn.unwrap());
+            assert_eq!(conn.remote_endpoint().unwrap().addr, ip(1).into());
+
+            client_conn.write_all(&[i]).await.unwrap();
+            client_conn.flush().await.unwrap();
+            let mut buf = [0];
+            conn.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [i]);
+        }
+
+        let mut listener = TcpListener::new(b, &state, 0);
+        assert!(matches!(listener.accept().await, Err(AcceptError::InvalidPort)));
+    });
+}
+
+#[test]
+fn tcp_listener_backlog() {
+    run(Config::default(), |a, b| async move {
+        let state = TcpListenerState::<2, 256, 256>::new();
+        let mut listener = TcpListener::new(b, &state, 1234);
+        let client_state = TcpClientState::<3, 256, 256>::new();
+        let client = TcpClient::new(a, &client_state);
+        let addr = core::net::SocketAddr::new(core::net::Ipv4Addr::new(10, 0, 0, 2).into(), 1234);
+
+        // Sockets listen from the first accept.
+        assert!(client.connect(addr).await.is_err());
+        let (conn_1, client_1) = join(listener.accept(), client.connect(addr)).await;
+        let (conn_1, client_1) = (conn_1.unwrap(), client_1.unwrap());
+
+        // The other socket keeps listening, so a connection is established before it's accepted.
+        let mut client_2 = client.connect(addr).await.unwrap();
+        client_2.write_all(b"2").await.unwrap();
+        client_2.flush().await.unwrap();
+
+        // Once all sockets are handed out, connections are refused.
+        assert!(client.connect(addr).await.is_err());
+
+        let mut conn_2 = listener.accept().await.unwrap();
+        let mut buf = [0];
+        conn_2.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, *b"2");
+
+        // A dropped connection's socket listens again on the next accept.
+        drop(conn_1);
+        drop(client_1);
+        assert!(client.connect(addr).await.is_err());
+        let (conn_3, client_3) = join(listener.accept(), client.connect(addr)).await;
+        let (mut conn_3, mut client_3) = (conn_3.unwrap(), client_3.unwrap());
+        client_3.write_all(b"3").await.unwrap();
+        client_3.flush().await.unwrap();
+        conn_3.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, *b"3");
+
+        // The other connection is still open.
+        conn_2.write_all(b"4").await.unwrap();
+        conn_2.flush().await.unwrap();
+        client_2.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, *b"4");
+
+        // While waiting in accept, a dropped connection's socket listens again right away.
+        let (conn_4, client_4) = join(listener.accept(), async {
+            drop(conn_3);
+            drop(client_3);
+            client.connect(addr).await
+        })
+        .await;
+        conn_4.unwrap();
+        client_4.unwrap();
+    });
+}
+
+#[test]
+fn udp_loss() {
+    const COUNT: usize = 200;
+
+    let mut config = Config::default();
+    config.loss = 0.5;
+
+    let received = run(config, |a, b| async move {
+        let (mut rx_meta, mut rx, mut tx_meta, mut tx) = (
+            [PacketMetadata::EMPTY; 4],
+            [0; 256],
+            [PacketMetadata::EMPTY; 4],
+            [0; 256],
+        );
+        let mut receiver = UdpSocket::new(b, &mut rx_meta, &mut rx, &mut tx_meta, &mut tx);
+        receiver.bind(1234).unwrap();
+
+        let (mut rx_meta, mut rx, mut tx_meta, mut tx) = (
+            [PacketMetadata::EMPTY; 4],
+            [0; 256],
+            [PacketMetadata::EMPTY; 4],
+            [0; 256],
+        );
+        let mut sender = UdpSocket::new(a, &mut rx_meta, &mut rx, &mut tx_meta, &mut tx);
+        sender.bind(1234).unwrap();
+
+        let send = async {
+            for i in 0..COUNT as u32 {
+                sender.send_to(&i.to_le_bytes(), (ip(2), 1234)).await.unwrap();
+                Timer::after_millis(1).await;
+            }
+        };
+        let receive = async {
+            let mut received = Vec::new();
+            let mut buf = [0; 16];
+            while let Ok(Ok((n, _))) = with_timeout(Duration::from_millis(500), receiver.recv_from(&mut buf)).await {
+                assert_eq!(n, 4);
+                received.push(u32::from_le_bytes(buf[..4].try_into().unwrap()));
+            }
+            received
+        };
+        embassy_futures::join::join(send, receive).await.1
+    });
+
+    // Datagrams are never duplicated, and not reordered without `reorder`.
+    assert!(received.windows(2).all(|w| w[0] < w[1]));
+    assert!(
+        (COUNT / 4..COUNT * 3 / 4).contains(&received.len()),
+        "received {} datagrams",
+        received.len()
+    );
+}
+
+#[test]
+fn latency() {
+    let mut config = Config::default();
+    config.latency = Duration::from_millis(50);
+
+    let rtt = run(config, |a, b| async move {
+        let (mut rx_meta, mut rx, mut tx_meta, mut tx) = (
+            [PacketMetadata::EMPTY; 4],
+            [0; 256],
+            [PacketMetadata::EMPTY; 4],
+            [0; 256],
+        );
+        let mut echo = UdpSocket::new(b, &mut rx_meta, &mut rx, &mut tx_meta, &mut tx);
+        echo.bind(7).unwrap();
+
+        let (mut rx_meta, mut rx, mut tx_meta, mut tx) = (
+            [PacketMetadata::EMPTY; 4],
+            [0; 256],
+            [PacketMetadata::EMPTY; 4],
+            [0; 256],
+        );
+        let mut client = UdpSocket::new(a, &mut rx_meta, &mut rx, &mut tx_meta, &mut tx);
+        client.bind(1234).unwrap();
+
+        let server = async {
+            let mut buf = [0; 16];
+            loop {
+                let (n, meta) = echo.recv_from(&mut buf).await.unwrap();
+                echo.send_to(&buf[..n], meta).await.unwrap();
+            }
+        };
+        let ping = async {
+            let mut buf = [0; 16];
+            // The first exchange resolves neighbors, measure the second one.
+            client.send_to(b"warmup", (ip(2), 7)).await.unwrap();
+            client.recv_from(&mut buf).await.unwrap();
+
+            let start = Instant::now();
+            client.send_to(b"ping", (ip(2), 7)).await.unwrap();
+            let (n, _) = client.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..n], b"ping");
+            start.elapsed()
+        };
+        match select(server, ping).await {
+            Either::First(_) => unreachable!(),
+            Either::Second(rtt) => rtt,
+        }
+    });
+
+    assert!(rtt >= Duration::from_millis(100), "rtt {:?}", rtt);
+    assert!(rtt < Duration::from_millis(500), "rtt {:?}", rtt);
+}
+
+#[test]
+fn dhcp_server() {
+    let mut server_config = DhcpServerConfig::new(ip(100));
+    server_config.router = Some(ip(1));
+    server_config.dns_servers.push(ip(53)).unwrap();
+
+    let client_config = embassy_net::Config::dhcpv4(Default::default());
+    run_with(Config::default(), net_config(1), client_config, |a, b| async move {
+        let mut server = DhcpServer::new(a, leak(DhcpServerState::<4>::new()), server_config);
+
+        let client = async {
+            b.wait_config_up().await;
+            let config = b.config_v4().unwrap();
+            assert_eq!(config.address, Ipv4Cidr::new(ip(100), 24));
+            assert_eq!(config.gateway, Some(ip(1)));
+            assert_eq!(config.dns_servers.as_slice(), &[ip(53)]);
+
+            let leases = a.dhcp_server_leases::<4>();
+            assert_eq!(leases.len(), 1);
+            assert_eq!(
+                HardwareAddress::Ethernet(leases[0].hardware_address),
+                b.hardware_address()
+            );
+            assert_eq!(leases[0].address, ip(100));
+            assert!(leases[0].expires_at > Instant::now() + Duration::from_secs(3500));
+        };
+        match select(server.run(), client).await {
+            Either::First(_) => unreachable!(),
+            Either::Second(()) => {}
+        }
+        drop(server);
+        assert!(a.dhcp_server_leases::<4>().is_empty());
+    });
+}
+
+#[test]
+fn dhcp_server_skips_own_address() {
+    // The pool starts at the server's own address, and leases never expire.
+    let mut server_config = DhcpServerConfig::new(ip(1));
+    server_config.lease_duration = Duration::MAX;
+
+    let client_config = embassy_net::Config::dhcpv4(Default::default());
+    run_with(Config::default(), net_config(1), client_config, |a, b| async move {
+        let mut server = DhcpServer::new(a, leak(DhcpServerState::<4>::new()), server_config);
+
+        let client = async {
+            b.wait_config_up().await;
+            assert_eq!(b.config_v4().unwrap().address, Ipv4Cidr::new(ip(2), 24));
+
+            let leases = a.dhcp_server_leases::<4>();
+            assert_eq!(leases.len(), 1);
+            assert_eq!(leases[0].address, ip(2));
+            assert_eq!(leases[0].expires_at, Instant::MAX);
+        };
+        match select(server.run(), client).await {
+            Either::First(_) => unreachable!(),
+            Either::Second(()) => {}
+        }
+    });
+}
+
+/// Build a DHCPDECLINE of `address` from the client `mac`, to the server `server`.
+fn dhcp_decline(mac: [u8; 6], address: Ipv4Address, server: Ipv4Address) -> Vec<u8> {
+    let mut packet = vec![0; 240];
+    // BOOTREQUEST, Ethernet, hardware address length, hops.
+    packet[..4].copy_from_slice(&[1, 1, 6, 0]);
+    packet[4..8].copy_from_slice(&0x1234_5678u32.to_be_bytes());
+    packet[28..34].copy_from_slice(&mac);
+    packet[236..240].copy_from_slice(&[99, 130, 83, 99]);
+    packet.extend_from_slice(&[53, 1, 4]);
+    packet.extend_from_slice(&[50, 4]);
+    packet.extend_from_slice(&address.octets());
+    packet.extend_from_slice(&[54, 4]);
+    packet.extend_from_slice(&server.octets());
+    packet.push(255);
+    packet.resize(300, 0);
+    packet
+}
+
+#[test]
+fn dhcp_server_decline() {
+    let client_config = embassy_net::Config::dhcpv4(Default::default());
+    run_with(Config::default(), net_config(1), client_config, |a, b| async move {
+        let mut server = DhcpServer::new(a, leak(DhcpServerState::<4>::new()), DhcpServerConfig::new(ip(100)));
+
+        let client = async {
+            b.wait_config_up().await;
+            let HardwareAddress::Ethernet(mac) = b.hardware_address();
+
+            let (mut rx_meta, mut rx, mut tx_meta, mut tx) = (
+                [PacketMetadata::EMPTY; 4],
+                [0; 512],
+                [PacketMetadata::EMPTY; 4],
+                [0; 512],
+            );
+            let mut socket = UdpSocket::new(b, &mut rx_meta, &mut rx, &mut tx_meta, &mut tx);
+            socket.bind(6800).unwrap();
+
+            // Another host can't decline the lease of the client.
+            let decline = dhcp_decline([2, 0, 0, 0, 0, 0x99], ip(100), ip(1));
+            socket.send_to(&decline, (ip(1), 67)).await.unwrap();
+            Timer::after_millis(100).await;
+            assert_eq!(a.dhcp_server_leases::<4>().len(), 1);
+
+            // The client can.
+            let decline = dhcp_decline(mac.0, ip(100), ip(1));
+            socket.send_to(&decline, (ip(1), 67)).await.unwrap();
+            Timer::after_millis(100).await;
+            assert!(a.dhcp_server_leases::<4>().is_empty());
+        };
+        match select(server.run(), client).await {
+            Either::First(_) => unreachable!(),
+            Either::Second(()) => {}
+        }
+    });
+}
+
+#[test]
+#[should_panic(expected = "DHCP server pool overflows the address space")]
+fn dhcp_server_pool_overflow() {
+    run(Config::default(), |a, _b| async move {
+        DhcpServer::new(
+            a,
+            leak(DhcpServerState::<4>::new()),
+            DhcpServerConfig::new(Ipv4Address::new(255, 255, 255, 254)),
+        );
+    });
+}
+
+#[test]
+fn sntp() {
+    // 2025-01-01T00:00:00Z
+    const UNIX_SECS: u64 = 1_735_689_600;
+    const NTP_SECS: u64 = UNIX_SECS + 2_208_988_800;
+
+    run(Config::default(), |a, b| async move {
+        let (mut rx_meta, mut rx, mut tx_meta, mut tx) = (
+            [PacketMetadata::EMPTY; 4],
+            [0; 256],
+            [PacketMetadata::EMPTY; 4],
+            [0; 256],
+        );
+        let mut server = UdpSocket::new(a, &mut rx_meta, &mut rx, &mut tx_meta, &mut tx);
+        server.bind(123).unwrap();
+        // The server's clock runs `skew_ms` ahead of its start time, adjusted between queries.
+        let skew_ms = core::cell::Cell::new(0u64);
+        // The server waits `reply_delay_ms` before replying, which adds half of it to the offset.
+        let reply_delay_ms = core::cell::Cell::new(0u64);
+        let start = Instant::now();
+
+        let serve = async {
+            let mut buf = [0; 48];
+            loop {
+                let (n, meta) = server.recv_from(&mut buf).await.unwrap();
+                assert_eq!(n, 48);
+                assert_eq!(buf[0], 0x23);
+                Timer::after_millis(reply_delay_ms.get()).await;
+                let micros = start.elapsed().as_micros() + skew_ms.get() * 1000;
+                let timestamp = ((NTP_SECS + micros / 1_000_000) << 32) | (((micros % 1_000_000) << 32) / 1_000_000);
+                let mut reply = [0; 48];
+                reply[0] = 0x24; // LI = 0, VN = 4, Mode = 4 (server)
+                reply[1] = 1; // stratum
+                reply[24..32].copy_from_slice(&buf[40..48]);
+                reply[32..40].copy_from_slice(&timestamp.to_be_bytes());
+                reply[40..48].copy_from_slice(&timestamp.to_be_bytes());
+                server.send_to(&reply, meta).await.unwrap();
+            }
+        };
+
+        let client = async {
+            let mut config = SntpConfig::new("10.0.0.1");
+            config.slew_rate_ppm = Some(100_000);
+            config.max_sample_age = Duration::from_secs(1);
+            let mut sntp = Sntp::new(b, leak(SntpState::new()), config);
+            let clock = sntp.clock();
+            assert_eq!(clock.now_utc(), None);
+
+            let utc_secs = |clock: Clock| clock.now_utc().unwrap().as_micros() as f64 / 1e6 - UNIX_SECS as f64;
+            let elapsed = || start.elapsed().as_micros() as f64 / 1e6;
+
+            // The first query steps the clock.
+            sntp.sync().await.unwrap();
+            assert!((utc_secs(clock) - elapsed()).abs() < 0.01);
+
+            // Small corrections are slewed, at 10% here.
+            skew_ms.set(50);
+            sntp.sync().await.unwrap();
+            assert!((utc_secs(clock) - elapsed()).abs() < 0.01);
+            Timer::after_millis(600).await;
+            assert!((utc_secs(clock) - elapsed() - 0.05).abs() < 0.01);
+
+            // Large corrections are stepped.
+            skew_ms.set(10_000);
+            sntp.sync().await.unwrap();
+            assert!((utc_secs(clock) - elapsed() - 10.0).abs() < 0.01);
+
+            // The sample with the shortest delay is used...
+            sntp.sync().await.unwrap();
+            reply_delay_ms.set(100);
+            sntp.sync().await.unwrap();
+            Timer::after_millis(600).await;
+            assert!((utc_secs(clock) - elapsed() - 10.0).abs() < 0.01);
+
+            // ...until it's too old.
+            Timer::after_millis(500).await;
+            sntp.sync().await.unwrap();
+            Timer::after_millis(600).await;
+            assert!((utc_secs(clock) - elapsed() - 10.05).abs() < 0.01);
+        };
+
+        match select(serve, client).await {
+            Either::First(_) => unreachable!(),
+            Either::Second(()) => {}
+        }
+    });
+}
+
+/// Deterministic random number generator for the TLS client. Not secure, for tests only.
+struct TestRng(u64);
+
+impl RngCore for TestRng {
+    fn next_u32(&mut self) -> u32 {
+        self.next_u64() as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        // xorshift64
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        rand_core::impls::fill_bytes_via_next(self, dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+impl CryptoRng for TestRng {}
+
+/// Accept a TCP connection on port 4433 and serve TLS 1.3 with rustls, echoing the data it
+/// receives until the client closes the session.
+async fn tls_echo_server(stack: Stack<'static>) {
+    use std::io::{Read as _, Write as _};
+    use std::sync::Arc;
+
+    use rustls::pki_types::pem::PemObject;
+    use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+
+    let cert = CertificateDer::from_pem_slice(include_bytes!("tls/cert.pem")).unwrap();
+    let key = PrivateKeyDer::from_pem_slice(include_bytes!("tls/key.pem")).unwrap();
+    let config = rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+        .with_protocol_versions(&[&rustls::version::TLS13])
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert], key)
+        .unwrap();
+    let mut conn = rustls::ServerConnection::new(Arc::new(config)).unwrap();
+
+    let (mut rx, mut tx) = ([0; 4096], [0; 4096]);
+    let mut socket = TcpSocket::new(stack, &mut rx, &mut tx);
+    socket.accept(4433).await.unwrap();
+
+    let mut buf = [0; 4096];
+    loop {
+        while conn.wants_write() {
+            let mut records = Vec::new();
+            conn.write_tls(&mut records).unwrap();
+            socket.write_all(&records).await.unwrap();
+        }
+        socket.flush().await.unwrap();
+
+        let n = socket.read(&mut buf).await.unwrap();
+        if n == 0 {
+            return;
+        }
+        let mut records = &buf[..n];
+        while !records.is_empty() {
+            conn.read_tls(&mut records).unwrap();
+            let state = conn.process_new_packets().unwrap();
+            let mut data = vec![0; state.plaintext_bytes_to_read()];
+            conn.reader().read_exact(&mut data).unwrap();
+            conn.writer().write_all(&data).unwrap();
+            if state.peer_has_closed() {
+                conn.send_close_notify();
+            }
+        }
+    }
+}
+
+#[test]
+fn tls_handshake() {
+    run(Config::default(), |a, b| async move {
+        let client = async {
+            let (mut rx, mut tx) = ([0; 4096], [0; 4096]);
+            let mut socket = TcpSocket::new(b, &mut rx, &mut tx);
+            socket.connect((ip(1), 4433)).await.unwrap();
+
+            let (mut read_buf, mut write_buf) = ([0; 16640], [0; 4096]);
+            let mut tls: TlsSocket = TlsSocket::new(&mut socket, &mut read_buf, &mut write_buf);
+            let config = TlsConfig::new().with_server_name("embassy.test");
+            tls.open::<NoVerify, _>(&config, &mut TestRng(1)).await.unwrap();
+
+            tls.close().await.unwrap();
+            assert!(socket.may_send());
+        };
+
+        match select(tls_echo_server(a), client).await {
+            Either::First(()) => unreachable!(),
+            Either::Second(()) => {}
+        }
+    });
+}
+
+#[test]
+fn tls_handshake_timeout() {
+    run(Config::default(), |a, b| async move {
+        // The server accepts the connection, but never answers the handshake.
+        let server = async {
+            let (mut rx, mut tx) = ([0; 4096], [0; 4096]);
+            let mut socket = TcpSocket::new(a, &mut rx, &mut tx);
+            socket.accept(4433).await.unwrap();
+            core::future::pending::<()>().await
+        };
+        let client = async {
+            let (mut rx, mut tx) = ([0; 4096], [0; 4096]);
+            let mut socket = TcpSocket::new(b, &mut rx, &mut tx);
+            socket.connect((ip(1), 4433)).await.unwrap();
+
+            let (mut read_buf, mut write_buf) = ([0; 16640], [0; 4096]);
+            let mut tls: TlsSocket = TlsSocket::new(&mut socket, &mut read_buf, &mut write_buf);
+            tls.set_handshake_timeout(Some(Duration::from_millis(100)));
+            let config = TlsConfig::new();
+            let res = tls.open::<NoVerify, _>(&config, &mut TestRng(1)).await;
+            assert!(matches!(res, Err(TlsError::Io(embedded_io_async::ErrorKind::TimedOut))));
+        };
+
+        match select(server, client).await {
+            Either::First(_) => unreachable!(),
+            Either::Second(()) => {}
+        }
+    });
+}
+
+#[test]
+fn tls_read_write() {
+    const LEN: usize = 10 * 1024;
+    let data: Vec<u8> = (0..LEN).map(|i| (i % 251) as u8).collect();
+
+    let mut config = Config::default();
+    config.latency = Duration::from_millis(1);
+    config.loss = 0.05;
+
+    run(config, |a, b| async move {
+        let client = async {
+            let (mut rx, mut tx) = ([0; 4096], [0; 4096]);
+            let mut socket = TcpSocket::new(b, &mut rx, &mut tx);
+            socket.connect((ip(1), 4433)).await.unwrap();
+
+            let (mut read_buf, mut write_buf) = ([0; 16640], [0; 4096]);
+            let mut tls: TlsSocket = TlsSocket::new(&mut socket, &mut read_buf, &mut write_buf);
+            let config = TlsConfig::new().with_server_name("embassy.test");
+            tls.open::<NoVerify, _>(&config, &mut TestRng(2)).await.unwrap();
+
+            // A short exchange on the whole session.
+            tls.write_all(b"hello").await.unwrap();
+            tls.flush().await.unwrap();
+            let mut buf = [0; 5];
+            tls.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+
+            // Data larger than the write buffer, echoed while it's written by the other half.
+            let mut state = embassy_net::tls::SplitConnectionState::default();
+            let (mut reader, mut writer) = tls.split(&mut state);
+            let write = async {
+                for chunk in data.chunks(1000) {
+                    writer.write_all(chunk).await.unwrap();
+                    writer.flush().await.unwrap();
+                }
+            };
+            let read = async {
+                let mut received = vec![0; LEN];
+                reader.read_exact(&mut received).await.unwrap();
+                received
+            };
+            let ((), received) = join(write, read).await;
+            assert!(received == data);
+
+            let tls = TlsSocket::unsplit(reader, writer);
+            tls.close().await.unwrap();
+        };
+
+        match select(tls_echo_server(a), client).await {
+            Either::First(()) => unreachable!(),
+            Either::Second(()) => {}
+        }
+    });
+}
+
+#[test]
+fn tcp_stats() {
+    const LEN: usize = 16 * 1024;
+    let data: Vec<u8> = (0..LEN).map(|i| (i % 251) as u8).collect();
+
+    let mut config = Config::default();
+    config.latency = Duration::from_millis(1);
+    config.loss = 0.1;
+
+    run(config, |a, b| async move {
+        let server = async {
+            let (mut rx, mut tx) = ([0; 4096], [0; 4096]);
+            let mut socket = TcpSocket::new(b, &mut rx, &mut tx);
+            socket.accept(1234).await.unwrap();
+            let mut buf = [0; 1024];
+            while socket.read(&mut buf).await.unwrap() != 0 {}
+            assert_eq!(socket.stats().rx_bytes, LEN as u64);
+            assert_eq!(socket.stats().tx_bytes, 0);
+        };
+        let client = async {
+            let (mut rx, mut tx) = ([0; 4096], [0; 4096]);
+            let mut socket = TcpSocket::new(a, &mut rx, &mut tx);
+            socket.connect((ip(2), 1234)).await.unwrap();
+            socket.write_all(&data).await.unwrap();
+            socket.close();
+            socket.flush().await.unwrap();
+            assert_eq!(socket.stats().tx_bytes, LEN as u64);
+        };
+        embassy_futures::join::join(server, client).await;
+
+        let stats = a.stats();
+        assert!(stats.tx_bytes > LEN as u64);
+        assert!(stats.tcp_retransmits > 0);
+        assert!(stats.rx_packets > 0);
+        assert_eq!(stats.rx_checksum_errors, 0);
+    });
+}
+
+#[test]
+fn udp_stats() {
+    run(Config::default(), |a, b| async move {
+        // Datagrams sent to a socket that doesn't read them are dropped once its buffer is full.
+        let (mut rx_meta, mut rx, mut tx_meta, mut tx) = (
+            [PacketMetadata::EMPTY; 2],
+            [0; 256],
+            [PacketMetadata::EMPTY; 4],
+            [0; 256],
+        );
+        let mut receiver = UdpSocket::new(b, &mut rx_meta, &mut rx, &mut tx_meta, &mut tx);
+        receiver.bind(1234).unwrap();
+
+        let (mut rx_meta, mut rx, mut tx_meta, mut tx) = (
+            [PacketMetadata::EMPTY; 4],
+            [0; 256],
+            [PacketMetadata::EMPTY; 4],
+            [0; 256],
+        );
+        let mut sender = UdpSocket::new(a, &mut rx_meta, &mut rx, &mut tx_meta, &mut tx);
+        sender.bind(1234).unwrap();
+
+        let (rx_packets, no_buffer) = (b.stats().rx_packets, b.stats().rx_no_buffer);
+        for _ in 0..5 {
+            sender.send_to(b"hello", (ip(2), 1234)).await.unwrap();
+            Timer::after_millis(5).await;
+        }
+        assert_eq!(sender.stats().tx_bytes, 25);
+        assert!(b.stats().rx_packets - rx_packets >= 5);
+        assert_eq!(b.stats().rx_no_buffer - no_buffer, 3);
+        assert_eq!(b.stats().rx_checksum_errors, 0);
+
+        let mut buf = [0; 16];
+        receiver.recv_from(&mut buf).await.unwrap();
+        assert_eq!(receiver.stats().rx_bytes, 5);
+    });
+}
+
+#[test]
+fn tcp_client_failover() {
+    // Stack `a` reaches 10.0.0.2 through two links, served by stacks `b` and `c`.
+    let (device_a1, device_b, mut link_1, control_1) =
+        embassy_net_loopback::new(leak(State::<MTU, 8>::new()), Config::default());
+    let (device_a2, device_c, mut link_2, _control_2) =
+        embassy_net_loopback::new(leak(State::<MTU, 8>::new()), Config::default());
+    let (a, mut runner_a1) = embassy_net::new(device_a1, net_config(1), leak(StackResources::<4>::new()), 1);
+    let (a2, mut runner_a2) = a.add_interface(device_a2, net_config(3), leak(InterfaceResources::<4>::new()), 10);
+    let (b, mut runner_b) = embassy_net::new(device_b, net_config(2), leak(StackResources::<4>::new()), 2);
+    let (c, mut runner_c) = embassy_net::new(device_c, net_config(2), leak(StackResources::<4>::new()), 3);
+
+    async fn serve(stack: Stack<'_>, name: u8) {
+        let (mut rx, mut tx) = ([0; 256], [0; 256]);
+        let mut socket = TcpSocket::new(stack, &mut rx, &mut tx);
+        socket.accept(1234).await.unwrap();
+        socket.write_all(&[name]).await.unwrap();
+        socket.close();
+        socket.flush().await.unwrap();
+    }
+
+    let test = async {
+        // Both interfaces are on the network of 10.0.0.2, the one with the lowest metric wins.
+        assert_eq!(a.route(ip(2).into()), Some(InterfaceId::PRIMARY));
+        let route = Route {
+            cidr: Ipv4Cidr::new(Ipv4Address::new(10, 9, 0, 0), 16).into(),
+            via_router: ip(2).into(),
+            interface: a2.id(),
+            metric: 0,
+        };
+        a.add_route(route).unwrap();
+        assert_eq!(a.route(Ipv4Address::new(10, 9, 1, 1).into()), Some(a2.id()));
+        a.remove_route(route);
+        assert_eq!(a.route(Ipv4Address::new(10, 9, 1, 1).into()), None);
+
+        let state = TcpClientState::<1, 256, 256>::new();
+        let client = TcpClient::new(a, &state);
+        let addr = core::net::SocketAddr::new(core::net::Ipv4Addr::new(10, 0, 0, 2).into(), 1234);
+        let mut buf = [0];
+
+        let mut conn = client.connect(addr).await.unwrap();
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, *b"b");
+        drop(conn);
+
+        // Unplug the first link: connections go through the second one.
+        control_1.set_link_state(LinkState::Down);
+        a.wait_link_down().await;
+        assert_eq!(a.route(ip(2).into()), Some(a2.id()));
+
+        let mut conn = client.connect(addr).await.unwrap();
+        conn.read_exact(&mut buf).await.unwrap();
+        assert_eq!(buf, *b"c");
+    };
+
+    block_on(async {
+        let test = with_timeout(Duration::from_secs(60), join3(serve(b, b'b'), serve(c, b'c'), test));
+        let runners = join5(
+            runner_a1.run(),
+            runner_a2.run(),
+            runner_b.run(),
+            runner_c.run(),
+            join(link_1.run(), link_2.run()),
+        );
+        match select(runners, test).await {
+            Either::First(_) => unreachable!(),
+            Either::Second(res) => {
+                res.expect("test timed out");
+            }
+        }
+    })
+}
+
+#[test]
+fn udp_and_listener_failover() {
+    // Stack `a` is on 10.0.0.0/24 through two links, to stacks `b` and `c`.
+    let (device_a1, device_b, mut link_1, control_1) =
+        embassy_net_loopback::new(leak(State::<MTU, 8>::new()), Config::default());
+    let (device_a2, device_c, mut link_2, _control_2) =
+        embassy_net_loopback::new(leak(State::<MTU, 8>::new()), Config::default());
+    let (a, mut runner_a1) = embassy_net::new(device_a1, net_config(1), leak(StackResources::<4>::new()), 1);
+    let (a2, mut runner_a2) = a.add_interface(device_a2, net_config(3), leak(InterfaceResources::<4>::new()), 10);
+    let (_b, mut runner_b) = embassy_net::new(device_b, net_config(2), leak(StackResources::<4>::new()), 2);
+    let (c, mut runner_c) = embassy_net::new(device_c, net_config(2), leak(StackResources::<4>::new()), 3);
+
+    async fn echo(stack: Stack<'_>, name: u8) {
+        let (mut rx_meta, mut rx, mut tx_meta, mut tx) = (
+            [PacketMetadata::EMPTY; 4],
+            [0; 256],
+            [PacketMetadata::EMPTY; 4],
+            [0; 256],
+        );
+        let mut socket = UdpSocket::new(stack, &mut rx_meta, &mut rx, &mut tx_meta, &mut tx);
+        socket.bind(7).unwrap();
+        let mut buf = [0; 16];
+        let (_, meta) = socket.recv_from(&mut buf).await.unwrap();
+        socket.send_to(&[name], meta.endpoint).await.unwrap();
+        socket.flush().await;
+    }
+
+    async fn connect(stack: Stack<'_>) {
+        let (mut rx, mut tx) = ([0; 256], [0; 256]);
+        let mut socket = TcpSocket::new(stack, &mut rx, &mut tx);
+        // Retry until stack `a` listens.
+        while socket.connect((ip(3), 1234)).await.is_err() {
+            Timer::after_millis(100).await;
+        }
+        let mut buf = [0];
+        socket.read_exact(&mut buf).await.unwrap();
+    }
+
+    let test = async {
+        // Unplug the first link: sockets which aren't bound to an interface or an address follow
+        // the failover to the second one.
+        control_1.set_link_state(LinkState::Down);
+        a.wait_link_down().await;
+
+        let (mut rx_meta, mut rx, mut tx_meta, mut tx) = (
+            [PacketMetadata::EMPTY; 4],
+            [0; 256],
+            [PacketMetadata::EMPTY; 4],
+            [0; 256],
+        );
+        let mut socket = UdpSocket::new(a, &mut rx_meta, &mut rx, &mut tx_meta, &mut tx);
+        socket.bind(5000).unwrap();
+        socket.send_to(b"ping", (ip(2), 7)).await.unwrap();
+        assert_eq!(socket.interface(), a2.id());
+        let mut buf = [0; 16];
+        let (n, _) = socket.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"c");
+
+        let (mut rx, mut tx) = ([0; 256], [0; 256]);
+        let mut socket = TcpSocket::new(a, &mut rx, &mut tx);
+        socket.accept(1234).await.unwrap();
+        assert_eq!(socket.interface(), a2.id());
+        socket.write_all(b"a").await.unwrap();
+        socket.flush().await.unwrap();
+    };
+
+    block_on(async {
+        let test = with_timeout(Duration::from_secs(60), join3(echo(c, b'c'), connect(c), test));
+        let runners = join5(
+            runner_a1.run(),
+            runner_a2.run(),
+            runner_b.run(),
+            runner_c.run(),
+            join(link_1.run(), link_2.run()),
+        );
+        match select(runners, test).await {
+            Either::First(_) => unreachable!(),
+            Either::Second(res) => {
+                let ((), (), ()) = res.expect("test timed out");
+            }
+        }
+    })
+}
+
+#[test]
+fn bind_interface_full() {
+    let (device_a1, _device_b, _link, _control) =
+        embassy_net_loopback::new(leak(State::<MTU, 8>::new()), Config::default());
+    let (device_a2, _device_c, _link_2, _control_2) =
+        embassy_net_loopback::new(leak(State::<MTU, 8>::new()), Config::default());
+    let (a, _runner_a1) = embassy_net::new(device_a1, net_config(1), leak(StackResources::<4>::new()), 1);
+    let (a2, _runner_a2) = a.add_interface(device_a2, net_config(3), leak(InterfaceResources::<1>::new()), 10);
+
+    let new_socket = || {
+        UdpSocket::new(
+            a,
+            leak([PacketMetadata::EMPTY; 1]),
+            leak([0; 16]),
+            leak([PacketMetadata::EMPTY; 1]),
+            leak([0; 16]),
+        )
+    };
+    let mut first = new_socket();
+    let mut second = new_socket();
+    first.bind_interface(a2.id()).unwrap();
+    assert_eq!(second.bind_interface(a2.id()), Err(InterfaceFull));
+    assert_eq!(second.interface(), InterfaceId::PRIMARY);
+    second.bind(5000).unwrap();
+}
+
+#[test]
+#[should_panic(expected = "too many sockets")]
+fn stack_full() {
+    let (device_a, _device_b, _link, _control) =
+        embassy_net_loopback::new(leak(State::<MTU, 8>::new()), Config::default());
+    let (a, _runner_a) = embassy_net::new(device_a, net_config(1), leak(StackResources::<1>::new()), 1);
+
+    let new_socket = || {
+        UdpSocket::new(
+            a,
+            leak([PacketMetadata::EMPTY; 1]),
+            leak([0; 16]),
+            leak([PacketMetadata::EMPTY; 1]),
+            leak([0; 16]),
+        )
+    };
+    let _first = new_socket();
+    let _second = new_socket();
+}
+
+#[test]
+#[should_panic(expected = "no such interface")]
+fn add_route_unknown_interface() {
+    // An interface of stack `b`, which stack `a` doesn't have.
+    let (device_a, device_b, _link, _control) =
+        embassy_net_loopback::new(leak(State::<MTU, 8>::new()), Config::default());
+    let (device_b2, _device_c, _link_2, _control_2) =
+        embassy_net_loopback::new(leak(State::<MTU, 8>::new()), Config::default());
+    let (a, _runner_a) = embassy_net::new(device_a, net_config(1), leak(StackResources::<4>::new()), 1);
+    let (b, _runner_b) = embassy_net::new(device_b, net_config(2), leak(StackResources::<4>::new()), 2);
+    let (b2, _runner_b2) = b.add_interface(device_b2, net_config(3), leak(InterfaceResources::<4>::new()), 10);
+    let _ = a.add_route(Route {
+        cidr: Ipv4Cidr::new(Ipv4Address::new(10, 9, 0, 0), 16).into(),
+        via_router: ip(2).into(),
+        interface: b2.id(),
+        metric: 0,
+    });
+}
+
+#[test]
+fn neighbor_table() {
+    run(Config::default(), |a, b| async move {
+        let mac_b = EthernetAddress([0x02, 0, 0, 0, 0, 0x0b]);
+        let (mut rx_meta, mut rx, mut tx_meta, mut tx) = (
+            [PacketMetadata::EMPTY; 4],
+            [0; 256],
+            [PacketMetadata::EMPTY; 4],
+            [0; 256],
+        );
+        let mut receiver = UdpSocket::new(b, &mut rx_meta, &mut rx, &mut tx_meta, &mut tx);
+        receiver.bind(1234).unwrap();
+        let (mut rx_meta, mut rx, mut tx_meta, mut tx) = (
+            [PacketMetadata::EMPTY; 4],
+            [0; 256],
+            [PacketMetadata::EMPTY; 4],
+            [0; 256],
+        );
+        let mut sender = UdpSocket::new(a, &mut rx_meta, &mut rx, &mut tx_meta, &mut tx);
+        sender.bind(1234).unwrap();
+        let mut buf = [0; 16];
+
+        // The address of `b` is resolved when sending to it.
+        sender.send_to(b"hello", (ip(2), 1234)).await.unwrap();
+        receiver.recv_from(&mut buf).await.unwrap();
+        assert_eq!(a.neighbor(ip(2)), Some(mac_b));
+        let neighbors = a.neighbors::<4>();
+        assert_eq!(neighbors.len(), 1);
+        assert!(neighbors[0].expires_at.is_some());
+
+        a.flush_neighbors();
+        assert_eq!(a.neighbor(ip(2)), None);
+
+        // A static entry is used instead of resolving the address.
+        let bogus = EthernetAddress([0x02, 0, 0, 0, 0, 0xff]);
+        a.add_static_neighbor(ip(2), bogus).unwrap();
+        let neighbors = a.neighbors::<4>();
+        assert_eq!(neighbors.len(), 1);
+        assert_eq!(neighbors[0].hardware_address, bogus);
+        assert_eq!(neighbors[0].expires_at, None);
+        sender.send_to(b"hello", (ip(2), 1234)).await.unwrap();
+        assert!(with_timeout(Duration::from_millis(100), receiver.recv_from(&mut buf))
+            .await
+            .is_err());
+
+        a.remove_static_neighbor(ip(2));
+        a.flush_neighbors();
+        sender.send_to(b"hello", (ip(2), 1234)).await.unwrap();
+        receiver.recv_from(&mut buf).await.unwrap();
+        assert_eq!(a.neighbor(ip(2)), Some(mac_b));
+    });
+}
+
+#[test]
+fn neighbor_conflict() {
+    run(Config::default(), |a, b| async move {
+        let mac_a = EthernetAddress([0x02, 0, 0, 0, 0, 0x0a]);
+        let mac_b = EthernetAddress([0x02, 0, 0, 0, 0, 0x0b]);
+        let config = |n| {
+            ConfigV4::Static(StaticConfigV4 {
+                address: Ipv4Cidr::new(ip(n), 24),
+                gateway: None,
+                dns_servers: Default::default(),
+            })
+        };
+
+        // Let the initial announcements go by.
+        Timer::after_millis(100).await;
+
+        // `b` takes the address of `a`: both detect it.
+        let (conflict_a, conflict_b, _) = join3(a.wait_neighbor_conflict(), b.wait_neighbor_conflict(), async {
+            b.set_config_v4(config(1))
+        })
+        .await;
+        assert_eq!(conflict_a.address, ip(1));
+        assert_eq!(conflict_a.hardware_address, mac_b);
+        assert_eq!(conflict_a.kind, NeighborConflictKind::DuplicateAddress);
+        assert_eq!(conflict_b.hardware_address, mac_a);
+        assert_eq!(conflict_b.kind, NeighborConflictKind::DuplicateAddress);
+
+        // `b` takes the address of a static neighbor of `a`.
+        Timer::after_millis(100).await;
+        a.add_static_neighbor(ip(3), EthernetAddress([0x02, 0, 0, 0, 0, 0x03]))
+            .unwrap();
+        let (conflict, _) =
+            embassy_futures::join::join(a.wait_neighbor_conflict(), async { b.set_config_v4(config(3)) }).await;
+        assert_eq!(conflict.address, ip(3));
+        assert_eq!(conflict.hardware_address, mac_b);
+        assert_eq!(conflict.kind, NeighborConflictKind::StaticMismatch);
+    });
+}
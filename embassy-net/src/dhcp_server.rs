@@ -0,0 +1,460 @@
+//! DHCPv4 server.
+//!
+//! Hands out addresses from a fixed pool to clients on the link, for example when the device is a
+//! Wi-Fi access point or a USB network gadget. The stack must have a static IPv4 configuration,
+//! whose address is used as the server identifier and whose prefix is sent as the subnet mask.
+
+use core::cell::Cell;
+
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+use smoltcp::wire::{DhcpMessageType, DhcpPacket, DhcpRepr, EthernetAddress, Ipv4Address, Ipv4Cidr};
+
+use crate::udp::{PacketMetadata, UdpSocket};
+use crate::Stack;
+
+/// How long an offered address is reserved for a client that doesn't request it.
+const OFFER_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Size of the socket buffers, enough for a couple of maximum-size packets.
+const BUFFER_SIZE: usize = 1536;
+
+/// Minimum size of a BOOTP message, some clients drop shorter replies.
+const MIN_PACKET_SIZE: usize = 300;
+
+/// DHCP server configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DhcpServerConfig {
+    /// First address of the pool.
+    ///
+    /// The pool contains the `N` consecutive addresses starting at this one, where `N` is the size
+    /// of the [`DhcpServerState`]. They must be in the stack's subnet. The stack's own address is
+    /// never handed out, even if it's in the pool.
+    pub pool_start: Ipv4Address,
+    /// Lease duration given to clients.
+    pub lease_duration: Duration,
+    /// Default gateway sent to clients.
+    pub router: Option<Ipv4Address>,
+    /// DNS servers sent to clients.
+    pub dns_servers: Vec<Ipv4Address, 3>,
+    /// Server port. This is almost always 67. Do not change unless you know what you're doing.
+    pub server_port: u16,
+    /// Client port. This is almost always 68. Do not change unless you know what you're doing.
+    pub client_port: u16,
+}
+
+impl DhcpServerConfig {
+    /// Create a configuration handing out addresses starting at `pool_start`, for one hour.
+    pub fn new(pool_start: Ipv4Address) -> Self {
+        Self {
+            pool_start,
+            lease_duration: Duration::from_secs(3600),
+            router: None,
+            dns_servers: Vec::new(),
+            server_port: smoltcp::wire::DHCP_SERVER_PORT,
+            client_port: smoltcp::wire::DHCP_CLIENT_PORT,
+        }
+    }
+}
+
+/// An address leased to a client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DhcpLease {
+    /// Hardware address of the client.
+    pub hardware_address: EthernetAddress,
+    /// Address leased to the client.
+    pub address: Ipv4Address,
+    /// When the lease expires, unless the client renews it.
+    pub expires_at: Instant,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SlotState {
+    /// Offered to the client, waiting for a request.
+    Offered,
+    /// Leased to the client.
+    Bound,
+    /// Declined by the client because another host uses the address.
+    Declined,
+}
+
+/// A pool address, and the client it was last given to.
+///
+/// Slots are kept after they expire, so a returning client gets the same address back if possible.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Slot {
+    lease: DhcpLease,
+    state: SlotState,
+}
+
+impl Slot {
+    fn is_free(&self, now: Instant) -> bool {
+        self.lease.expires_at <= now
+    }
+}
+
+/// Collect the active leases in `slots`.
+pub(crate) fn active_leases<const N: usize>(slots: &[Cell<Option<Slot>>]) -> Vec<DhcpLease, N> {
+    let now = Instant::now();
+    slots
+        .iter()
+        .filter_map(|s| s.get())
+        .filter(|s| s.state == SlotState::Bound && !s.is_free(now))
+        .map(|s| s.lease)
+        .take(N)
+        .collect()
+}
+
+/// State for a [`DhcpServer`], with a pool of `N` addresses.
+pub struct DhcpServerState<const N: usize> {
+    slots: [Cell<Option<Slot>>; N],
+    rx_meta: [PacketMetadata; 4],
+    rx_buffer: [u8; BUFFER_SIZE],
+    tx_meta: [PacketMetadata; 4],
+    tx_buffer: [u8; BUFFER_SIZE],
+}
+
+impl<const N: usize> DhcpServerState<N> {
+    /// Create a new `DhcpServerState`.
+    pub const fn new() -> Self {
+        Self {
+            slots: [const { Cell::new(None) }; N],
+            rx_meta: [PacketMetadata::EMPTY; 4],
+            rx_buffer: [0; BUFFER_SIZE],
+            tx_meta: [PacketMetadata::EMPTY; 4],
+            tx_buffer: [0; BUFFER_SIZE],
+        }
+    }
+}
+
+/// A DHCPv4 server.
+///
+/// You must call [`DhcpServer::run()`] in a background task for clients to get addresses. The
+/// current leases can be queried with [`Stack::dhcp_server_leases`].
+///
+/// Only one server can be running on a stack at a time. Relay agents are not supported.
+pub struct DhcpServer<'d> {
+    socket: UdpSocket<'d>,
+    pool: Pool<'d>,
+}
+
+/// Address pool and protocol logic, separate from the socket so it can be used while receiving.
+struct Pool<'d> {
+    stack: Stack<'d>,
+    config: DhcpServerConfig,
+    slots: &'static [Cell<Option<Slot>>],
+}
+
+/// A reply to send to a client.
+struct Reply {
+    repr: DhcpRepr<'static>,
+    dest: Ipv4Address,
+}
+
+impl<'d> DhcpServer<'d> {
+    /// Create a new DHCP server, serving clients on the primary interface of `stack`.
+    ///
+    /// The state is `'static` because the stack keeps a reference to the leases, to return them
+    /// from [`Stack::dhcp_server_leases`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if a DHCP server is already running on `stack`, or if the pool extends past
+    /// `255.255.255.255`.
+    pub fn new<const N: usize>(
+        stack: Stack<'d>,
+        state: &'static mut DhcpServerState<N>,
+        config: DhcpServerConfig,
+    ) -> Self {
+        assert!(
+            config.pool_start.to_bits() as u64 + N as u64 <= 1 << 32,
+            "DHCP server pool overflows the address space"
+        );
+
+        let DhcpServerState {
+            slots,
+            rx_meta,
+            rx_buffer,
+            tx_meta,
+            tx_buffer,
+        } = state;
+        let slots: &'static [Cell<Option<Slot>>] = slots;
+        stack.with_mut(|i| {
+            assert!(i.dhcp_server_slots.is_none(), "a DHCP server is already running");
+            i.dhcp_server_slots = Some(slots);
+        });
+
+        let mut socket = UdpSocket::new(stack, rx_meta, rx_buffer, tx_meta, tx_buffer);
+        unwrap!(socket.bind_interface(crate::InterfaceId::PRIMARY));
+        unwrap!(socket.bind(config.server_port));
+
+        Self {
+            socket,
+            pool: Pool { stack, config, slots },
+        }
+    }
+
+    /// Get the active leases, up to `M` of them.
+    pub fn leases<const M: usize>(&self) -> Vec<DhcpLease, M> {
+        active_leases(self.pool.slots)
+    }
+
+    /// Run the server.
+    pub async fn run(&mut self) -> ! {
+        loop {
+            // The stack can't be accessed while receiving, so get its configuration first.
+            self.socket.wait_recv_ready().await;
+            let cidr = self.pool.stack.config_v4().map(|c| c.address);
+            let reply = self
+                .socket
+                .recv_from_with(|buf, _meta| {
+                    let packet = DhcpPacket::new_checked(buf).ok()?;
+                    let request = DhcpRepr::parse(&packet).ok()?;
+                    self.pool.handle(&request, cidr?)
+                })
+                .await;
+
+            let Some(reply) = reply else { continue };
+            let len = reply.repr.buffer_len().max(MIN_PACKET_SIZE);
+            let res = self
+                .socket
+                .send_to_with(len, (reply.dest, self.pool.config.client_port), |buf| {
+                    buf.fill(0);
+                    reply.repr.emit(&mut DhcpPacket::new_unchecked(buf))
+                })
+                .await;
+            match res {
+                Ok(Ok(())) => {}
+                Ok(Err(_)) => warn!("DHCP server: failed to emit reply"),
+                Err(e) => warn!("DHCP server: failed to send reply: {:?}", e),
+            }
+        }
+    }
+}
+
+impl Pool<'_> {
+    fn handle(&self, request: &DhcpRepr<'_>, cidr: Ipv4Cidr) -> Option<Reply> {
+        // Replies through relay agents are not supported.
+        if !request.relay_agent_ip.is_unspecified() {
+            return None;
+        }
+        let mac = request.client_hardware_address;
+        let now = Instant::now();
+
+        match request.message_type {
+            DhcpMessageType::Discover => {
+                let index = self.allocate(mac, request.requested_ip, cidr.address(), now)?;
+                let mut slot = self.slot(index, mac);
+                if slot.state != SlotState::Bound || slot.is_free(now) {
+                    slot.state = SlotState::Offered;
+                    slot.lease.expires_at = now + OFFER_TIMEOUT;
+                }
+                self.slots[index].set(Some(slot));
+                debug!("DHCP server: offering {} to {}", slot.lease.address, mac);
+                Some(self.reply(request, cidr, DhcpMessageType::Offer, slot.lease.address))
+            }
+            DhcpMessageType::Request => {
+                if let Some(server) = request.server_identifier {
+                    if server != cidr.address() {
+                        // The client accepted another server's offer.
+                        if let Some(index) = self.find(mac) {
+                            if let Some(slot) = self.slots[index].get().filter(|s| s.state == SlotState::Offered) {
+                                self.slots[index].set(Some(Slot {
+                                    lease: DhcpLease {
+                                        expires_at: now,
+                                        ..slot.lease
+                                    },
+                                    ..slot
+                                }));
+                            }
+                        }
+                        return None;
+                    }
+                }
+
+                let address = match request.requested_ip {
+                    Some(address) => address,
+                    // Renewing or rebinding clients only fill in `ciaddr`.
+                    None if !request.client_ip.is_unspecified() => request.client_ip,
+                    None => return None,
+                };
+                let index = self
+                    .allocate(mac, Some(address), cidr.address(), now)
+                    .filter(|&i| self.address(i) == address);
+                let Some(index) = index else {
+                    debug!("DHCP server: refusing {} to {}", address, mac);
+                    return Some(self.reply(request, cidr, DhcpMessageType::Nak, Ipv4Address::UNSPECIFIED));
+                };
+
+                let mut slot = self.slot(index, mac);
+                slot.state = SlotState::Bound;
+                slot.lease.expires_at = now.checked_add(self.config.lease_duration).unwrap_or(Instant::MAX);
+                self.slots[index].set(Some(slot));
+                debug!("DHCP server: leasing {} to {}", address, mac);
+                Some(self.reply(request, cidr, DhcpMessageType::Ack, address))
+            }
+            DhcpMessageType::Decline => {
+                // Only the client the address was given to can decline it, so that other hosts
+                // can't take leases away.
+                let address = request.requested_ip?;
+                let index = self.index(address)?;
+                let slot = self.slots[index]
+                    .get()
+                    .filter(|s| s.lease.hardware_address == mac && s.lease.address == address)?;
+                warn!("DHCP server: {} declined {}, address is in use", mac, address);
+                self.slots[index].set(Some(Slot {
+                    lease: DhcpLease {
+                        expires_at: now.checked_add(self.config.lease_duration).unwrap_or(Instant::MAX),
+                        ..slot.lease
+                    },
+                    state: SlotState::Declined,
+                }));
+                None
+            }
+            DhcpMessageType::Release => {
+                let index = self.index(request.client_ip)?;
+                let slot = self.slots[index].get()?;
+                if slot.lease.hardware_address == mac {
+                    debug!("DHCP server: {} released {}", mac, request.client_ip);
+                    self.slots[index].set(Some(Slot {
+                        lease: DhcpLease {
+                            expires_at: now,
+                            ..slot.lease
+                        },
+                        ..slot
+                    }));
+                }
+                None
+            }
+            DhcpMessageType::Inform => Some(self.reply(request, cidr, DhcpMessageType::Ack, Ipv4Address::UNSPECIFIED)),
+            _ => None,
+        }
+    }
+
+    fn reply(
+        &self,
+        request: &DhcpRepr<'_>,
+        cidr: Ipv4Cidr,
+        message_type: DhcpMessageType,
+        your_ip: Ipv4Address,
+    ) -> Reply {
+        let nak = message_type == DhcpMessageType::Nak;
+        let inform = request.message_type == DhcpMessageType::Inform;
+        let options = !nak;
+        let lease = !nak && !inform;
+
+        let repr = DhcpRepr {
+            message_type,
+            transaction_id: request.transaction_id,
+            secs: 0,
+            client_hardware_address: request.client_hardware_address,
+            client_ip: if inform {
+                request.client_ip
+            } else {
+                Ipv4Address::UNSPECIFIED
+            },
+            your_ip,
+            server_ip: Ipv4Address::UNSPECIFIED,
+            router: self.config.router.filter(|_| options),
+            subnet_mask: options.then(|| cidr.netmask()),
+            relay_agent_ip: Ipv4Address::UNSPECIFIED,
+            broadcast: request.broadcast,
+            requested_ip: None,
+            client_identifier: None,
+            server_identifier: Some(cidr.address()),
+            parameter_request_list: None,
+            dns_servers: (options && !self.config.dns_servers.is_empty()).then(|| self.config.dns_servers.clone()),
+            max_size: None,
+            lease_duration: lease.then(|| u32::try_from(self.config.lease_duration.as_secs()).unwrap_or(u32::MAX)),
+            renew_duration: None,
+            rebind_duration: None,
+            additional_options: &[],
+        };
+
+        // Clients without an address can't answer ARP requests, so replies to them are broadcast
+        // (RFC 2131 section 4.1).
+        let dest = if !nak && !request.client_ip.is_unspecified() {
+            request.client_ip
+        } else {
+            Ipv4Address::BROADCAST
+        };
+        Reply { repr, dest }
+    }
+
+    /// Find a slot to give to `mac`, preferring `requested` if it's in the pool, and skipping the
+    /// server's own address.
+    fn allocate(
+        &self,
+        mac: EthernetAddress,
+        requested: Option<Ipv4Address>,
+        own: Ipv4Address,
+        now: Instant,
+    ) -> Option<usize> {
+        let available = |i: usize| {
+            self.address(i) != own
+                && match self.slots[i].get() {
+                    None => true,
+                    Some(s) => s.lease.hardware_address == mac && s.state != SlotState::Declined || s.is_free(now),
+                }
+        };
+
+        if let Some(i) = requested.and_then(|a| self.index(a)).filter(|&i| available(i)) {
+            return Some(i);
+        }
+        if let Some(i) = self.find(mac).filter(|&i| available(i)) {
+            return Some(i);
+        }
+        // Prefer never used slots, then the one that expired first, to give previous clients a
+        // chance to get their address back.
+        if let Some(i) = (0..self.slots.len()).find(|&i| self.slots[i].get().is_none() && available(i)) {
+            return Some(i);
+        }
+        let i = (0..self.slots.len())
+            .filter(|&i| available(i))
+            .min_by_key(|&i| self.slots[i].get().map(|s| s.lease.expires_at));
+        if i.is_none() {
+            warn!("DHCP server: address pool exhausted");
+        }
+        i
+    }
+
+    /// Find the slot last given to `mac`.
+    fn find(&self, mac: EthernetAddress) -> Option<usize> {
+        self.slots.iter().position(|s| {
+            s.get()
+                .is_some_and(|s| s.lease.hardware_address == mac && s.state != SlotState::Declined)
+        })
+    }
+
+    /// Get the slot at `index`, given to `mac`.
+    fn slot(&self, index: usize, mac: EthernetAddress) -> Slot {
+        match self.slots[index].get() {
+            Some(slot) if slot.lease.hardware_address == mac => slot,
+            _ => Slot {
+                lease: DhcpLease {
+                    hardware_address: mac,
+                    address: self.address(index),
+                    expires_at: Instant::MIN,
+                },
+                state: SlotState::Offered,
+            },
+        }
+    }
+
+    fn address(&self, index: usize) -> Ipv4Address {
+        Ipv4Address::from_bits(self.config.pool_start.to_bits() + index as u32)
+    }
+
+    fn index(&self, address: Ipv4Address) -> Option<usize> {
+        let index = address.to_bits().wrapping_sub(self.config.pool_start.to_bits()) as usize;
+        (index < self.slots.len()).then_some(index)
+    }
+}
+
+impl Drop for DhcpServer<'_> {
+    fn drop(&mut self) {
+        self.pool.stack.with_mut(|i| i.dhcp_server_slots = None);
+    }
+}
@@ -1,9 +1,30 @@
+#[cfg(any(feature = "stats", all(feature = "proto-ipv4", feature = "medium-ethernet")))]
+use core::cell::RefCell;
+#[cfg(not(all(feature = "stats", feature = "proto-ipv4", feature = "medium-ethernet")))]
+use core::marker::PhantomData;
 use core::task::Context;
 
 use embassy_net_driver::{Capabilities, Checksum, Driver, RxToken, TxToken};
 use smoltcp::phy::{self, Medium};
 use smoltcp::time::Instant;
 
+#[cfg(all(feature = "proto-ipv4", feature = "medium-ethernet"))]
+use crate::neighbor::Neighbors;
+#[cfg(feature = "stats")]
+use crate::stats::Stats;
+
+/// Statistics updated by the adapter, if any.
+#[cfg(feature = "stats")]
+pub(crate) type StatsRef<'a> = Option<&'a RefCell<Stats>>;
+#[cfg(not(feature = "stats"))]
+pub(crate) type StatsRef<'a> = PhantomData<&'a ()>;
+
+/// Neighbor table updated from received frames, if any.
+#[cfg(all(feature = "proto-ipv4", feature = "medium-ethernet"))]
+pub(crate) type NeighborsRef<'a> = Option<&'a RefCell<Neighbors>>;
+#[cfg(not(all(feature = "proto-ipv4", feature = "medium-ethernet")))]
+pub(crate) type NeighborsRef<'a> = PhantomData<&'a ()>;
+
 pub(crate) struct DriverAdapter<'d, 'c, T>
 where
     T: Driver,
@@ -12,6 +33,8 @@ where
     pub cx: Option<&'d mut Context<'c>>,
     pub inner: &'d mut T,
     pub medium: Medium,
+    pub stats: StatsRef<'d>,
+    pub neighbors: NeighborsRef<'d>,
 }
 
 impl<'d, 'c, T> phy::Device for DriverAdapter<'d, 'c, T>
@@ -19,23 +42,30 @@ where
     T: Driver,
 {
     type RxToken<'a>
-        = RxTokenAdapter<T::RxToken<'a>>
+        = RxTokenAdapter<'a, T::RxToken<'a>>
     where
         Self: 'a;
     type TxToken<'a>
-        = TxTokenAdapter<T::TxToken<'a>>
+        = TxTokenAdapter<'a, T::TxToken<'a>>
     where
         Self: 'a;
 
     fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        let (stats, neighbors) = (self.stats, self.neighbors);
         self.inner
             .receive(unwrap!(self.cx.as_deref_mut()))
-            .map(|(rx, tx)| (RxTokenAdapter(rx), TxTokenAdapter(tx)))
+            .map(|(rx, tx)| (RxTokenAdapter(rx, stats, neighbors), TxTokenAdapter(tx, stats)))
     }
 
     /// Construct a transmit token.
     fn transmit(&mut self, _timestamp: Instant) -> Option<Self::TxToken<'_>> {
-        self.inner.transmit(unwrap!(self.cx.as_deref_mut())).map(TxTokenAdapter)
+        let stats = self.stats;
+        let tx = self.inner.transmit(unwrap!(self.cx.as_deref_mut()));
+        #[cfg(feature = "stats")]
+        if let (None, Some(stats)) = (&tx, stats) {
+            stats.borrow_mut().tx_no_buffer();
+        }
+        tx.map(|tx| TxTokenAdapter(tx, stats))
     }
 
     /// Get a description of device capabilities.
@@ -70,11 +100,11 @@ where
     }
 }
 
-pub(crate) struct RxTokenAdapter<T>(T)
+pub(crate) struct RxTokenAdapter<'a, T>(T, StatsRef<'a>, NeighborsRef<'a>)
 where
     T: RxToken;
 
-impl<T> phy::RxToken for RxTokenAdapter<T>
+impl<T> phy::RxToken for RxTokenAdapter<'_, T>
 where
     T: RxToken,
 {
@@ -85,16 +115,24 @@ where
         self.0.consume(|buf| {
             #[cfg(feature = "packet-trace")]
             trace!("embassy device rx: {:02x}", buf);
+            #[cfg(feature = "stats")]
+            if let Some(stats) = self.1 {
+                stats.borrow_mut().rx_packet(buf);
+            }
+            #[cfg(all(feature = "proto-ipv4", feature = "medium-ethernet"))]
+            if let Some(neighbors) = self.2 {
+                neighbors.borrow_mut().rx_frame(buf);
+            }
             f(buf)
         })
     }
 }
 
-pub(crate) struct TxTokenAdapter<T>(T)
+pub(crate) struct TxTokenAdapter<'a, T>(T, StatsRef<'a>)
 where
     T: TxToken;
 
-impl<T> phy::TxToken for TxTokenAdapter<T>
+impl<T> phy::TxToken for TxTokenAdapter<'_, T>
 where
     T: TxToken,
 {
@@ -106,6 +144,10 @@ where
             let r = f(buf);
             #[cfg(feature = "packet-trace")]
             trace!("embassy device tx: {:02x}", buf);
+            #[cfg(feature = "stats")]
+            if let Some(stats) = self.1 {
+                stats.borrow_mut().tx_packet(buf);
+            }
             r
         })
     }
@@ -4,7 +4,7 @@ use core::future::{poll_fn, Future};
 use core::mem;
 use core::task::{Context, Poll};
 
-use smoltcp::iface::{Interface, SocketHandle};
+use smoltcp::iface::Interface;
 pub use smoltcp::phy::ChecksumCapabilities;
 use smoltcp::socket::icmp;
 pub use smoltcp::socket::icmp::{Endpoint as IcmpEndpoint, PacketMetadata};
@@ -14,7 +14,7 @@ pub use smoltcp::wire::{Icmpv4Message, Icmpv4Packet, Icmpv4Repr};
 #[cfg(feature = "proto-ipv6")]
 pub use smoltcp::wire::{Icmpv6Message, Icmpv6Packet, Icmpv6Repr};
 
-use crate::Stack;
+use crate::{SocketHandle, Stack};
 
 /// Error returned by [`IcmpSocket::bind`].
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
@@ -56,6 +56,8 @@ pub struct IcmpSocket<'a> {
 
 impl<'a> IcmpSocket<'a> {
     /// Create a new ICMP socket using the provided stack and buffers.
+    ///
+    /// Panics if the stack has no room for another socket, see [`StackResources`](crate::StackResources).
     pub fn new(
         stack: Stack<'a>,
         rx_meta: &'a mut [PacketMetadata],
@@ -68,7 +70,7 @@ impl<'a> IcmpSocket<'a> {
             let rx_buffer: &'static mut [u8] = unsafe { mem::transmute(rx_buffer) };
             let tx_meta: &'static mut [PacketMetadata] = unsafe { mem::transmute(tx_meta) };
             let tx_buffer: &'static mut [u8] = unsafe { mem::transmute(tx_buffer) };
-            i.sockets.add(icmp::Socket::new(
+            i.add_socket(icmp::Socket::new(
                 icmp::PacketBuffer::new(rx_meta, rx_buffer),
                 icmp::PacketBuffer::new(tx_meta, tx_buffer),
             ))
@@ -97,16 +99,16 @@ impl<'a> IcmpSocket<'a> {
 
     fn with<R>(&self, f: impl FnOnce(&icmp::Socket, &Interface) -> R) -> R {
         self.stack.with(|i| {
-            let socket = i.sockets.get::<icmp::Socket>(self.handle);
-            f(socket, &i.iface)
+            let (socket, iface) = i.socket::<icmp::Socket>(self.handle);
+            f(socket, iface)
         })
     }
 
     fn with_mut<R>(&self, f: impl FnOnce(&mut icmp::Socket, &mut Interface) -> R) -> R {
         self.stack.with_mut(|i| {
-            let socket = i.sockets.get_mut::<icmp::Socket>(self.handle);
-            let res = f(socket, &mut i.iface);
-            i.waker.wake();
+            let (socket, iface) = i.socket_mut::<icmp::Socket>(self.handle);
+            let res = f(socket, iface);
+            i.wake(self.handle);
             res
         })
     }
@@ -366,7 +368,7 @@ impl<'a> IcmpSocket<'a> {
 
 impl Drop for IcmpSocket<'_> {
     fn drop(&mut self) {
-        self.stack.with_mut(|i| i.sockets.remove(self.handle));
+        self.stack.with_mut(|i| i.remove_socket(self.handle));
     }
 }
 
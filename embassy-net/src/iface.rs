@@ -0,0 +1,961 @@
+//! Network interfaces and routing.
+
+#[cfg(any(feature = "stats", all(feature = "proto-ipv4", feature = "medium-ethernet")))]
+use core::cell::RefCell;
+use core::future::Future;
+use core::mem::MaybeUninit;
+use core::pin::pin;
+use core::task::Context;
+
+use embassy_net_driver::{Driver, LinkState};
+use embassy_sync::waitqueue::WakerRegistration;
+use embassy_time::{Instant, Timer};
+use heapless::Vec;
+pub use smoltcp::iface::RouteTableFull;
+use smoltcp::iface::{SocketSet, SocketStorage};
+#[cfg(feature = "dhcpv4")]
+use smoltcp::socket::dhcpv4;
+use smoltcp::socket::{AnySocket, Socket};
+
+use crate::driver_util::DriverAdapter;
+#[cfg(all(feature = "proto-ipv4", feature = "medium-ethernet"))]
+use crate::neighbor::{Neighbor, NeighborConflict, StaticNeighborsFull};
+use crate::time::{instant_from_smoltcp, instant_to_smoltcp};
+#[cfg(feature = "dhcpv4-hostname")]
+use crate::MAX_HOSTNAME_LEN;
+use crate::{
+    to_smoltcp_hardware_address, Config, HardwareAddress, Inner, IpAddress, IpCidr, SocketHandle, SocketSlot, Stack,
+};
+#[cfg(feature = "proto-ipv4")]
+use crate::{ConfigV4, StaticConfigV4};
+#[cfg(feature = "proto-ipv6")]
+use crate::{ConfigV6, StaticConfigV6};
+#[cfg(all(feature = "proto-ipv4", feature = "medium-ethernet"))]
+use crate::{EthernetAddress, Ipv4Address};
+
+/// Maximum number of interfaces of a [`Stack`].
+pub const MAX_INTERFACES: usize = 4;
+
+/// Maximum number of static routes of a [`Stack`], added with [`Stack::add_route()`].
+pub const MAX_ROUTES: usize = 4;
+
+/// Identifier of a network interface of a [`Stack`].
+///
+/// The interface of the driver passed to [`new()`](crate::new) is [`InterfaceId::PRIMARY`], others are
+/// added with [`Stack::add_interface()`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct InterfaceId(pub(crate) u8);
+
+impl InterfaceId {
+    /// The primary interface of the stack.
+    pub const PRIMARY: Self = Self(0);
+
+    fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+/// A route to a network through a router reachable on an interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Route {
+    /// Destination network.
+    pub cidr: IpCidr,
+    /// Router the packets are sent to.
+    pub via_router: IpAddress,
+    /// Interface the router is reachable on.
+    pub interface: InterfaceId,
+    /// Metric of the route. Among routes with the same prefix length, the lowest metric wins.
+    pub metric: u32,
+}
+
+/// Error returned when an interface has no room for another socket, see [`InterfaceResources`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct InterfaceFull;
+
+/// Memory resources needed for an interface of a network stack.
+///
+/// `SOCK` is the number of sockets the interface can hold. Sockets are created on the preferred
+/// interface, or on another interface if it's full, and move to the interface they connect
+/// through, so it's the number of sockets which can use the interface at the same time. DHCP
+/// needs an extra socket on each interface using it.
+///
+/// Creating a socket panics if no interface has room for it.
+pub struct InterfaceResources<const SOCK: usize> {
+    sockets: MaybeUninit<[SocketStorage<'static>; SOCK]>,
+    iface: MaybeUninit<Iface>,
+    #[cfg(feature = "dhcpv4-hostname")]
+    hostname: HostnameResources,
+    #[cfg(feature = "stats")]
+    stats: crate::stats::StatsResources<SOCK>,
+}
+
+#[cfg(feature = "dhcpv4-hostname")]
+pub(crate) struct HostnameResources {
+    option: MaybeUninit<smoltcp::wire::DhcpOption<'static>>,
+    data: MaybeUninit<[u8; MAX_HOSTNAME_LEN]>,
+}
+
+impl<const SOCK: usize> InterfaceResources<SOCK> {
+    /// Create a new set of interface resources.
+    pub const fn new() -> Self {
+        Self {
+            sockets: MaybeUninit::uninit(),
+            iface: MaybeUninit::uninit(),
+            #[cfg(feature = "dhcpv4-hostname")]
+            hostname: HostnameResources {
+                option: MaybeUninit::uninit(),
+                data: MaybeUninit::uninit(),
+            },
+            #[cfg(feature = "stats")]
+            stats: crate::stats::StatsResources::new(),
+        }
+    }
+}
+
+/// State of an interface.
+pub(crate) struct Iface {
+    pub(crate) sockets: SocketSet<'static>, // Lifetime type-erased.
+    /// Number of sockets `sockets` can hold.
+    socket_capacity: usize,
+    pub(crate) iface: smoltcp::iface::Interface,
+    /// Waker used for triggering polls.
+    pub(crate) waker: WakerRegistration,
+    hardware_address: HardwareAddress,
+    link_up: bool,
+    metric: u32,
+    #[cfg(feature = "proto-ipv4")]
+    static_v4: Option<StaticConfigV4>,
+    #[cfg(feature = "proto-ipv6")]
+    static_v6: Option<StaticConfigV6>,
+    #[cfg(feature = "dhcpv4")]
+    dhcp_socket: Option<smoltcp::iface::SocketHandle>,
+    #[cfg(feature = "dhcpv4-hostname")]
+    hostname: *mut HostnameResources,
+    #[cfg(feature = "stats")]
+    pub(crate) stats: RefCell<crate::stats::Stats>,
+    #[cfg(all(feature = "proto-ipv4", feature = "medium-ethernet"))]
+    pub(crate) neighbors: RefCell<crate::neighbor::Neighbors>,
+}
+
+impl Iface {
+    /// Initialize the interface state in `resources`.
+    pub(crate) fn init<D: Driver, const SOCK: usize>(
+        driver: &mut D,
+        resources: &mut InterfaceResources<SOCK>,
+        random_seed: u64,
+        metric: u32,
+    ) -> &'static mut Iface {
+        let (hardware_address, medium) = to_smoltcp_hardware_address(driver.hardware_address());
+        let mut iface_cfg = smoltcp::iface::Config::new(hardware_address);
+        iface_cfg.random_seed = random_seed;
+
+        let iface = smoltcp::iface::Interface::new(
+            iface_cfg,
+            &mut DriverAdapter {
+                inner: driver,
+                cx: None,
+                medium,
+                stats: Default::default(),
+                neighbors: Default::default(),
+            },
+            instant_to_smoltcp(Instant::now()),
+        );
+
+        unsafe fn transmute_slice<T>(x: &mut [T]) -> &'static mut [T] {
+            core::mem::transmute(x)
+        }
+
+        let sockets = resources.sockets.write([SocketStorage::EMPTY; SOCK]);
+        let sockets = SocketSet::new(unsafe { transmute_slice(sockets) });
+
+        let iface = resources.iface.write(Iface {
+            sockets,
+            socket_capacity: SOCK,
+            iface,
+            waker: WakerRegistration::new(),
+            hardware_address,
+            link_up: false,
+            metric,
+            #[cfg(feature = "proto-ipv4")]
+            static_v4: None,
+            #[cfg(feature = "proto-ipv6")]
+            static_v6: None,
+            #[cfg(feature = "dhcpv4")]
+            dhcp_socket: None,
+            #[cfg(feature = "dhcpv4-hostname")]
+            hostname: &mut resources.hostname,
+            #[cfg(feature = "stats")]
+            stats: RefCell::new(crate::stats::Stats::new(&mut resources.stats, medium)),
+            #[cfg(all(feature = "proto-ipv4", feature = "medium-ethernet"))]
+            neighbors: RefCell::new(crate::neighbor::Neighbors::new(medium)),
+        });
+        // safety: the stack borrows the resources for as long as it exists.
+        unsafe { &mut *(iface as *mut Iface) }
+    }
+
+    /// Whether the socket set has room for another socket.
+    fn has_room(&self) -> bool {
+        self.sockets.iter().count() < self.socket_capacity
+    }
+
+    fn is_config_up(&self) -> bool {
+        #[cfg(feature = "proto-ipv4")]
+        if self.static_v4.is_some() {
+            return true;
+        }
+        #[cfg(feature = "proto-ipv6")]
+        if self.static_v6.is_some() {
+            return true;
+        }
+        false
+    }
+
+    /// Whether packets can currently be sent through this interface.
+    fn is_usable(&self) -> bool {
+        self.link_up && self.is_config_up()
+    }
+
+    /// Whether the interface has a default gateway for `addr`.
+    fn has_default_route(&self, addr: &IpAddress) -> bool {
+        match addr {
+            #[cfg(feature = "proto-ipv4")]
+            IpAddress::Ipv4(_) => self.static_v4.as_ref().is_some_and(|c| c.gateway.is_some()),
+            #[cfg(feature = "proto-ipv6")]
+            IpAddress::Ipv6(_) => self.static_v6.as_ref().is_some_and(|c| c.gateway.is_some()),
+            #[allow(unreachable_patterns)]
+            _ => false,
+        }
+    }
+
+    #[cfg(feature = "proto-ipv4")]
+    pub(crate) fn set_config_v4(&mut self, config: ConfigV4) {
+        // Handle static config.
+        self.static_v4 = match config.clone() {
+            ConfigV4::None => None,
+            #[cfg(feature = "dhcpv4")]
+            ConfigV4::Dhcp(_) => None,
+            ConfigV4::Static(c) => Some(c),
+        };
+
+        // Handle DHCP config.
+        #[cfg(feature = "dhcpv4")]
+        match config {
+            ConfigV4::Dhcp(c) => {
+                // Create the socket if it doesn't exist.
+                if self.dhcp_socket.is_none() {
+                    assert!(self.has_room(), "no room for the DHCP socket, see InterfaceResources");
+                    let socket = smoltcp::socket::dhcpv4::Socket::new();
+                    let handle = self.sockets.add(socket);
+                    self.dhcp_socket = Some(handle);
+                }
+
+                // Configure it
+                let socket = self.sockets.get_mut::<dhcpv4::Socket>(unwrap!(self.dhcp_socket));
+                socket.set_ignore_naks(c.ignore_naks);
+                socket.set_max_lease_duration(c.max_lease_duration.map(crate::time::duration_to_smoltcp));
+                socket.set_ports(c.server_port, c.client_port);
+                socket.set_retry_config(c.retry_config);
+
+                socket.set_outgoing_options(&[]);
+                #[cfg(feature = "dhcpv4-hostname")]
+                if let Some(h) = c.hostname {
+                    // safety:
+                    // - we just did set_outgoing_options([]) so we know the socket is no longer holding a reference.
+                    // - we know this pointer lives for as long as the stack exists, because the stack borrows
+                    //   the resources for `'d`. Therefore it's OK to pass a reference to this to smoltcp.
+                    let hostname = unsafe { &mut *self.hostname };
+
+                    // create data
+                    let data = hostname.data.write([0; MAX_HOSTNAME_LEN]);
+                    data[..h.len()].copy_from_slice(h.as_bytes());
+                    let data: &[u8] = &data[..h.len()];
+
+                    // set the option.
+                    let option = hostname.option.write(smoltcp::wire::DhcpOption { data, kind: 12 });
+                    socket.set_outgoing_options(core::slice::from_ref(option));
+                }
+
+                socket.reset();
+            }
+            _ => {
+                // Remove DHCP socket if any.
+                if let Some(socket) = self.dhcp_socket {
+                    self.sockets.remove(socket);
+                    self.dhcp_socket = None;
+                }
+            }
+        }
+    }
+
+    #[cfg(feature = "proto-ipv6")]
+    pub(crate) fn set_config_v6(&mut self, config: ConfigV6) {
+        self.static_v6 = match config {
+            ConfigV6::None => None,
+            ConfigV6::Static(c) => Some(c),
+        };
+    }
+
+    /// Apply the addresses and default gateways of the configuration to the smoltcp interface.
+    fn apply_static_config(&mut self) {
+        let mut addrs = Vec::new();
+        #[cfg(feature = "proto-ipv4")]
+        let mut gateway_v4 = None;
+        #[cfg(feature = "proto-ipv6")]
+        let mut gateway_v6 = None;
+
+        #[cfg(feature = "proto-ipv4")]
+        if let Some(config) = &self.static_v4 {
+            debug!("IPv4: UP");
+            debug!("   IP address:      {:?}", config.address);
+            debug!("   Default gateway: {:?}", config.gateway);
+
+            unwrap!(addrs.push(IpCidr::Ipv4(config.address)).ok());
+            gateway_v4 = config.gateway;
+            #[cfg(feature = "dns")]
+            for s in &config.dns_servers {
+                debug!("   DNS server:      {:?}", s);
+            }
+        } else {
+            info!("IPv4: DOWN");
+        }
+
+        #[cfg(feature = "proto-ipv6")]
+        if let Some(config) = &self.static_v6 {
+            debug!("IPv6: UP");
+            debug!("   IP address:      {:?}", config.address);
+            debug!("   Default gateway: {:?}", config.gateway);
+
+            unwrap!(addrs.push(IpCidr::Ipv6(config.address)).ok());
+            gateway_v6 = config.gateway;
+            #[cfg(feature = "dns")]
+            for s in &config.dns_servers {
+                debug!("   DNS server:      {:?}", s);
+            }
+        } else {
+            info!("IPv6: DOWN");
+        }
+
+        // Apply addresses
+        self.iface.update_ip_addrs(|a| *a = addrs);
+        #[cfg(all(feature = "proto-ipv4", feature = "medium-ethernet"))]
+        self.neighbors
+            .get_mut()
+            .set_address(self.static_v4.as_ref().map(|c| c.address));
+
+        // Apply gateways
+        #[cfg(feature = "proto-ipv4")]
+        if let Some(gateway) = gateway_v4 {
+            unwrap!(self.iface.routes_mut().add_default_ipv4_route(gateway));
+        } else {
+            self.iface.routes_mut().remove_default_ipv4_route();
+        }
+        #[cfg(feature = "proto-ipv6")]
+        if let Some(gateway) = gateway_v6 {
+            unwrap!(self.iface.routes_mut().add_default_ipv6_route(gateway));
+        } else {
+            self.iface.routes_mut().remove_default_ipv6_route();
+        }
+    }
+
+    /// Poll the interface. Returns whether the link state changed or a neighbor conflict was
+    /// detected, and whether the configuration must be applied again.
+    fn poll<D: Driver>(&mut self, cx: &mut Context<'_>, driver: &mut D) -> (bool, bool) {
+        self.waker.register(cx.waker());
+
+        let (_hardware_addr, medium) = to_smoltcp_hardware_address(driver.hardware_address());
+
+        #[cfg(any(feature = "medium-ethernet", feature = "medium-ieee802154"))]
+        {
+            let do_set = match medium {
+                #[cfg(feature = "medium-ethernet")]
+                smoltcp::phy::Medium::Ethernet => true,
+                #[cfg(feature = "medium-ieee802154")]
+                smoltcp::phy::Medium::Ieee802154 => true,
+                #[allow(unreachable_patterns)]
+                _ => false,
+            };
+            if do_set {
+                self.iface.set_hardware_addr(_hardware_addr);
+            }
+            #[cfg(all(feature = "proto-ipv4", feature = "medium-ethernet"))]
+            #[allow(irrefutable_let_patterns)]
+            if let HardwareAddress::Ethernet(addr) = _hardware_addr {
+                self.neighbors.get_mut().set_hardware_address(addr);
+            }
+        }
+
+        let timestamp = instant_to_smoltcp(Instant::now());
+        let mut smoldev = DriverAdapter {
+            cx: Some(cx),
+            inner: driver,
+            medium,
+            stats: Default::default(),
+            neighbors: Default::default(),
+        };
+        #[cfg(all(feature = "proto-ipv4", feature = "medium-ethernet"))]
+        let conflicts = self.neighbors.get_mut().conflicts;
+        #[cfg(all(feature = "proto-ipv4", feature = "medium-ethernet"))]
+        {
+            self.neighbors.get_mut().set_now(instant_from_smoltcp(timestamp));
+            smoldev.neighbors = Some(&self.neighbors);
+        }
+        #[cfg(feature = "stats")]
+        {
+            use smoltcp::phy::Device;
+            let checksum = smoldev.capabilities().checksum;
+            self.stats.get_mut().start_poll(&self.iface, &self.sockets, checksum);
+            smoldev.stats = Some(&self.stats);
+        }
+        self.iface.poll(timestamp, &mut smoldev, &mut self.sockets);
+        #[cfg(feature = "stats")]
+        self.stats.borrow_mut().end_poll(&self.sockets);
+        #[cfg(all(feature = "proto-ipv4", feature = "medium-ethernet"))]
+        self.neighbors
+            .borrow_mut()
+            .poll(&mut self.iface, &mut self.sockets, &mut smoldev, self.link_up);
+
+        // Update link up
+        let old_link_up = self.link_up;
+        self.link_up = driver.link_state(cx) == LinkState::Up;
+
+        // Print when changed
+        #[allow(unused_mut)]
+        let mut state_changed = old_link_up != self.link_up;
+        if state_changed {
+            info!("link_up = {:?}", self.link_up);
+        }
+
+        #[cfg(all(feature = "proto-ipv4", feature = "medium-ethernet"))]
+        {
+            let neighbors = self.neighbors.get_mut();
+            if self.link_up && !old_link_up {
+                // Announce the address again, in case another host took it.
+                neighbors.reannounce();
+                cx.waker().wake_by_ref();
+            }
+            state_changed |= neighbors.conflicts != conflicts;
+        }
+
+        #[allow(unused_mut)]
+        let mut configure = false;
+        #[cfg(feature = "dhcpv4")]
+        if let Some(dhcp_handle) = self.dhcp_socket {
+            let socket = self.sockets.get_mut::<dhcpv4::Socket>(dhcp_handle);
+
+            configure = if self.link_up {
+                if old_link_up != self.link_up {
+                    socket.reset();
+                }
+                match socket.poll() {
+                    None => false,
+                    Some(dhcpv4::Event::Deconfigured) => {
+                        self.static_v4 = None;
+                        true
+                    }
+                    Some(dhcpv4::Event::Configured(config)) => {
+                        self.static_v4 = Some(StaticConfigV4 {
+                            address: config.address,
+                            gateway: config.router,
+                            dns_servers: config.dns_servers,
+                        });
+                        true
+                    }
+                }
+            } else if old_link_up {
+                socket.reset();
+                self.static_v4 = None;
+                true
+            } else {
+                false
+            };
+        }
+
+        #[allow(unused_mut)]
+        let mut poll_at = self.iface.poll_at(timestamp, &self.sockets).map(instant_from_smoltcp);
+        #[cfg(all(feature = "proto-ipv4", feature = "medium-ethernet"))]
+        if let Some(neighbors_poll_at) = self.neighbors.get_mut().poll_at() {
+            poll_at = Some(poll_at.map_or(neighbors_poll_at, |t| t.min(neighbors_poll_at)));
+        }
+        if let Some(poll_at) = poll_at {
+            let t = pin!(Timer::at(poll_at));
+            if t.poll(cx).is_ready() {
+                cx.waker().wake_by_ref();
+            }
+        }
+
+        (state_changed, configure)
+    }
+}
+
+impl Inner {
+    pub(crate) fn iface(&self, id: InterfaceId) -> &Iface {
+        self.ifaces[id.index()]
+    }
+
+    pub(crate) fn iface_mut(&mut self, id: InterfaceId) -> &mut Iface {
+        self.ifaces[id.index()]
+    }
+
+    fn interface_ids(&self) -> impl Iterator<Item = InterfaceId> {
+        (0..self.ifaces.len() as u8).map(InterfaceId)
+    }
+
+    fn slot(&self, handle: SocketHandle) -> SocketSlot {
+        unwrap!(self.sockets[handle.0])
+    }
+
+    /// Add a socket on the preferred interface, or else on the first interface with room for it.
+    ///
+    /// Panics if the stack or all its interfaces are full.
+    pub(crate) fn add_socket<T: AnySocket<'static>>(&mut self, socket: T) -> SocketHandle {
+        let Some(index) = self.sockets.iter().position(|s| s.is_none()) else {
+            panic!("too many sockets, the maximum is the SOCK of StackResources");
+        };
+        let Some(iface) = core::iter::once(self.preferred_interface())
+            .chain(self.interface_ids())
+            .find(|id| self.iface(*id).has_room())
+        else {
+            panic!("no interface has room for another socket, see InterfaceResources");
+        };
+        let handle = self.iface_mut(iface).sockets.add(socket);
+        #[cfg(feature = "stats")]
+        self.iface_mut(iface)
+            .stats
+            .get_mut()
+            .add_socket(handle, crate::SocketStats::default());
+        self.sockets[index] = Some(SocketSlot { iface, handle });
+        SocketHandle(index)
+    }
+
+    pub(crate) fn remove_socket(&mut self, handle: SocketHandle) {
+        let slot = unwrap!(self.sockets[handle.0].take());
+        let iface = self.iface_mut(slot.iface);
+        iface.sockets.remove(slot.handle);
+        #[cfg(feature = "stats")]
+        iface.stats.get_mut().remove_socket(slot.handle);
+    }
+
+    pub(crate) fn socket<T: AnySocket<'static>>(&self, handle: SocketHandle) -> (&T, &smoltcp::iface::Interface) {
+        let slot = self.slot(handle);
+        let iface = self.iface(slot.iface);
+        (iface.sockets.get(slot.handle), &iface.iface)
+    }
+
+    pub(crate) fn socket_mut<T: AnySocket<'static>>(
+        &mut self,
+        handle: SocketHandle,
+    ) -> (&mut T, &mut smoltcp::iface::Interface) {
+        let slot = self.slot(handle);
+        let iface = self.iface_mut(slot.iface);
+        (iface.sockets.get_mut(slot.handle), &mut iface.iface)
+    }
+
+    /// Interface a socket is on.
+    pub(crate) fn socket_interface(&self, handle: SocketHandle) -> InterfaceId {
+        self.slot(handle).iface
+    }
+
+    #[cfg(feature = "stats")]
+    pub(crate) fn socket_stats(&self, handle: SocketHandle) -> crate::SocketStats {
+        let slot = self.slot(handle);
+        self.iface(slot.iface).stats.borrow().socket(slot.handle)
+    }
+
+    #[cfg(feature = "stats")]
+    pub(crate) fn count_socket(&mut self, handle: SocketHandle, rx_bytes: usize, tx_bytes: usize) {
+        let slot = self.slot(handle);
+        let stats = self.iface_mut(slot.iface).stats.get_mut();
+        stats.count_socket(slot.handle, rx_bytes, tx_bytes);
+    }
+
+    /// Wake the runner of the interface of a socket.
+    pub(crate) fn wake(&mut self, handle: SocketHandle) {
+        let slot = self.slot(handle);
+        self.iface_mut(slot.iface).waker.wake();
+    }
+
+    /// Move a socket to the socket set of another interface. Its [`SocketHandle`] stays the same.
+    ///
+    /// Fails if the other interface has no room for the socket, which then stays where it is.
+    pub(crate) fn move_socket(&mut self, handle: SocketHandle, to: InterfaceId) -> Result<(), InterfaceFull> {
+        let slot = self.slot(handle);
+        if slot.iface == to {
+            return Ok(());
+        }
+        if !self.iface(to).has_room() {
+            return Err(InterfaceFull);
+        }
+
+        let from = self.iface_mut(slot.iface);
+        let socket = from.sockets.remove(slot.handle);
+        #[cfg(feature = "stats")]
+        let stats = from.stats.get_mut().socket(slot.handle);
+        #[cfg(feature = "stats")]
+        from.stats.get_mut().remove_socket(slot.handle);
+        from.waker.wake();
+
+        let iface = self.iface_mut(to);
+        let moved = match socket {
+            #[cfg(feature = "raw")]
+            Socket::Raw(s) => iface.sockets.add(s),
+            #[cfg(feature = "icmp")]
+            Socket::Icmp(s) => iface.sockets.add(s),
+            #[cfg(feature = "udp")]
+            Socket::Udp(s) => iface.sockets.add(s),
+            #[cfg(feature = "tcp")]
+            Socket::Tcp(s) => iface.sockets.add(s),
+            #[cfg(feature = "dns")]
+            Socket::Dns(s) => iface.sockets.add(s),
+            // DHCP client sockets stay on their interface.
+            #[allow(unreachable_patterns)]
+            _ => unreachable!(),
+        };
+        #[cfg(feature = "stats")]
+        iface.stats.get_mut().add_socket(moved, stats);
+        iface.waker.wake();
+        self.sockets[handle.0] = Some(SocketSlot {
+            iface: to,
+            handle: moved,
+        });
+        Ok(())
+    }
+
+    /// Move a socket to another interface if it has room for it, or else leave it where it is.
+    pub(crate) fn try_move_socket(&mut self, handle: SocketHandle, to: InterfaceId) {
+        if self.move_socket(handle, to).is_err() {
+            warn!(
+                "no room for the socket on interface {:?}, it stays on {:?}",
+                to,
+                self.socket_interface(handle)
+            );
+        }
+    }
+
+    /// Interface that has `addr` as one of its addresses.
+    pub(crate) fn interface_with_addr(&self, addr: IpAddress) -> Option<InterfaceId> {
+        self.interface_ids().find(|id| self.iface(*id).iface.has_ip_addr(addr))
+    }
+
+    /// Interface to send packets to `addr` through, if there's a route to it.
+    ///
+    /// Directly connected networks are preferred, then the route with the longest prefix, with
+    /// default gateways as routes with a zero-length prefix. Ties are broken by metric. Only
+    /// interfaces with link and an IP configuration are considered.
+    pub(crate) fn route(&self, addr: IpAddress) -> Option<InterfaceId> {
+        let usable = |id: InterfaceId| self.iface(id).is_usable();
+
+        let on_link = self
+            .interface_ids()
+            .filter(|id| usable(*id))
+            .filter(|id| self.iface(*id).iface.ip_addrs().iter().any(|c| c.contains_addr(&addr)))
+            .min_by_key(|id| self.iface(*id).metric);
+        if on_link.is_some() {
+            return on_link;
+        }
+
+        let routes = self
+            .routes
+            .iter()
+            .filter(|r| r.cidr.contains_addr(&addr) && usable(r.interface))
+            .map(|r| (r.cidr.prefix_len(), r.metric, r.interface));
+        let defaults = self
+            .interface_ids()
+            .filter(|id| usable(*id) && self.iface(*id).has_default_route(&addr))
+            .map(|id| (0, self.iface(id).metric, id));
+        routes
+            .chain(defaults)
+            .min_by_key(|(prefix_len, metric, _)| (u8::MAX - prefix_len, *metric))
+            .map(|(_, _, id)| id)
+    }
+
+    /// Interface new sockets are created on: the usable interface with a default gateway and the
+    /// lowest metric, or else the usable interface with the lowest metric, or else the primary
+    /// interface.
+    pub(crate) fn preferred_interface(&self) -> InterfaceId {
+        let usable = || self.interface_ids().filter(|id| self.iface(*id).is_usable());
+        let gateway = |id: &InterfaceId| {
+            let iface = self.iface(*id);
+            #[cfg(feature = "proto-ipv4")]
+            if iface.static_v4.as_ref().is_some_and(|c| c.gateway.is_some()) {
+                return true;
+            }
+            #[cfg(feature = "proto-ipv6")]
+            if iface.static_v6.as_ref().is_some_and(|c| c.gateway.is_some()) {
+                return true;
+            }
+            let _ = iface;
+            false
+        };
+        usable()
+            .filter(gateway)
+            .min_by_key(|id| self.iface(*id).metric)
+            .or_else(|| usable().min_by_key(|id| self.iface(*id).metric))
+            .unwrap_or(InterfaceId::PRIMARY)
+    }
+
+    /// Add an interface, and apply its configuration.
+    pub(crate) fn add_interface(&mut self, iface: &'static mut Iface, config: Config) -> InterfaceId {
+        let id = InterfaceId(self.ifaces.len() as u8);
+        if self.ifaces.push(iface).is_err() {
+            panic!("too many interfaces, the maximum is MAX_INTERFACES");
+        }
+
+        #[cfg(feature = "proto-ipv4")]
+        self.iface_mut(id).set_config_v4(config.ipv4);
+        #[cfg(feature = "proto-ipv6")]
+        self.iface_mut(id).set_config_v6(config.ipv6);
+        #[cfg(not(any(feature = "proto-ipv4", feature = "proto-ipv6")))]
+        let _ = config;
+        self.apply_static_config(id);
+        id
+    }
+
+    pub(crate) fn apply_static_config(&mut self, id: InterfaceId) {
+        self.iface_mut(id).apply_static_config();
+        #[cfg(feature = "dns")]
+        self.update_dns_servers();
+        self.state_waker.wake();
+    }
+
+    /// Give the DNS servers of all interfaces to the DNS socket, by increasing interface metric.
+    #[cfg(feature = "dns")]
+    fn update_dns_servers(&mut self) {
+        let mut ids: Vec<InterfaceId, MAX_INTERFACES> = self.interface_ids().collect();
+        ids.sort_unstable_by_key(|id| self.iface(*id).metric);
+
+        let mut dns_servers: Vec<IpAddress, { 6 * MAX_INTERFACES }> = Vec::new();
+        for id in ids {
+            let _iface = self.iface(id);
+            #[cfg(feature = "proto-ipv4")]
+            if let Some(config) = &_iface.static_v4 {
+                for s in &config.dns_servers {
+                    unwrap!(dns_servers.push((*s).into()).ok());
+                }
+            }
+            #[cfg(feature = "proto-ipv6")]
+            if let Some(config) = &_iface.static_v6 {
+                for s in &config.dns_servers {
+                    unwrap!(dns_servers.push((*s).into()).ok());
+                }
+            }
+        }
+
+        if !dns_servers.is_empty() {
+            let count = if dns_servers.len() > crate::DNS_MAX_SERVER_COUNT {
+                warn!("Number of DNS servers exceeds DNS_MAX_SERVER_COUNT, truncating list.");
+                crate::DNS_MAX_SERVER_COUNT
+            } else {
+                dns_servers.len()
+            };
+            let (socket, _) = self.socket_mut::<smoltcp::socket::dns::Socket>(self.dns_socket);
+            socket.update_servers(&dns_servers[..count]);
+        }
+    }
+
+    pub(crate) fn poll<D: Driver>(&mut self, id: InterfaceId, cx: &mut Context<'_>, driver: &mut D) {
+        let (state_changed, configure) = self.iface_mut(id).poll(cx, driver);
+        if state_changed {
+            self.state_waker.wake();
+        }
+        if configure {
+            self.apply_static_config(id);
+        }
+    }
+}
+
+/// Handle to an interface of a network stack, returned by [`Stack::interface()`].
+#[derive(Copy, Clone)]
+pub struct Interface<'d> {
+    stack: Stack<'d>,
+    id: InterfaceId,
+}
+
+impl<'d> Interface<'d> {
+    pub(crate) fn new(stack: Stack<'d>, id: InterfaceId) -> Self {
+        Self { stack, id }
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&Iface) -> R) -> R {
+        self.stack.with(|i| f(i.iface(self.id)))
+    }
+
+    /// Get the identifier of the interface.
+    pub fn id(&self) -> InterfaceId {
+        self.id
+    }
+
+    /// Get the hardware address of the interface.
+    pub fn hardware_address(&self) -> HardwareAddress {
+        self.with(|i| i.hardware_address)
+    }
+
+    /// Get the counters of the interface.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> crate::InterfaceStats {
+        self.with(|i| i.stats.borrow().iface)
+    }
+
+    /// Get the metric of the interface.
+    pub fn metric(&self) -> u32 {
+        self.with(|i| i.metric)
+    }
+
+    /// Set the metric of the interface.
+    ///
+    /// The default gateway and DNS servers of the interface with the lowest metric are preferred.
+    pub fn set_metric(&self, metric: u32) {
+        self.stack.with_mut(|i| {
+            i.iface_mut(self.id).metric = metric;
+            i.apply_static_config(self.id);
+        })
+    }
+
+    /// Check whether the link is up.
+    pub fn is_link_up(&self) -> bool {
+        self.with(|i| i.link_up)
+    }
+
+    /// Check whether the interface has a valid IP configuration.
+    /// This is true if the interface has a static IP configuration or if DHCP has completed
+    pub fn is_config_up(&self) -> bool {
+        self.with(|i| i.is_config_up())
+    }
+
+    /// Wait for the interface to obtain a link signal.
+    pub async fn wait_link_up(&self) {
+        self.stack.wait(|| self.is_link_up()).await
+    }
+
+    /// Wait for the interface to lose link signal.
+    pub async fn wait_link_down(&self) {
+        self.stack.wait(|| !self.is_link_up()).await
+    }
+
+    /// Wait for the interface to obtain a valid IP configuration.
+    pub async fn wait_config_up(&self) {
+        self.stack.wait(|| self.is_config_up()).await
+    }
+
+    /// Wait for the interface to lose a valid IP configuration.
+    pub async fn wait_config_down(&self) {
+        self.stack.wait(|| !self.is_config_up()).await
+    }
+
+    /// Get the current IPv4 configuration.
+    ///
+    /// If using DHCP, this will be None if DHCP hasn't been able to
+    /// acquire an IP address, or Some if it has.
+    #[cfg(feature = "proto-ipv4")]
+    pub fn config_v4(&self) -> Option<StaticConfigV4> {
+        self.with(|i| i.static_v4.clone())
+    }
+
+    /// Get the current IPv6 configuration.
+    #[cfg(feature = "proto-ipv6")]
+    pub fn config_v6(&self) -> Option<StaticConfigV6> {
+        self.with(|i| i.static_v6.clone())
+    }
+
+    /// Set the IPv4 configuration.
+    ///
+    /// Panics if the configuration enables DHCP and the interface has no room for its socket.
+    #[cfg(feature = "proto-ipv4")]
+    pub fn set_config_v4(&self, config: ConfigV4) {
+        self.stack.with_mut(|i| {
+            i.iface_mut(self.id).set_config_v4(config);
+            i.apply_static_config(self.id);
+        })
+    }
+
+    /// Set the IPv6 configuration.
+    #[cfg(feature = "proto-ipv6")]
+    pub fn set_config_v6(&self, config: ConfigV6) {
+        self.stack.with_mut(|i| {
+            i.iface_mut(self.id).set_config_v6(config);
+            i.apply_static_config(self.id);
+        })
+    }
+
+    /// Get the entries of the neighbor table of the interface, up to `N` of them.
+    ///
+    /// Static entries come first, followed by the entries learned from ARP packets. The ARP cache
+    /// of the interface isn't accessible, so the learned entries are tracked separately from the
+    /// ARP packets it receives, following the same rules. They can still differ from the cache in
+    /// corner cases, so use them for diagnostics rather than to predict address resolution.
+    #[cfg(all(feature = "proto-ipv4", feature = "medium-ethernet"))]
+    pub fn neighbors<const N: usize>(&self) -> Vec<Neighbor, N> {
+        let now = Instant::now();
+        self.with(|i| i.neighbors.borrow().entries(now).take(N).collect())
+    }
+
+    /// Get the hardware address an IP address resolved to, if it's in the neighbor table.
+    ///
+    /// Like [`neighbors()`](Self::neighbors), this reflects the ARP packets the interface received,
+    /// not its ARP cache itself.
+    #[cfg(all(feature = "proto-ipv4", feature = "medium-ethernet"))]
+    pub fn neighbor(&self, addr: Ipv4Address) -> Option<EthernetAddress> {
+        self.with(|i| i.neighbors.borrow().lookup(addr))
+    }
+
+    /// Add a static entry to the neighbor table, or replace the static entry of `addr`.
+    ///
+    /// The interface doesn't send ARP requests for static neighbors, and ignores the ARP packets
+    /// claiming their address with another hardware address, reporting them as conflicts. Only
+    /// neighbors in the network of the interface can be reached.
+    #[cfg(all(feature = "proto-ipv4", feature = "medium-ethernet"))]
+    pub fn add_static_neighbor(
+        &self,
+        addr: Ipv4Address,
+        hardware_addr: EthernetAddress,
+    ) -> Result<(), StaticNeighborsFull> {
+        self.stack.with_mut(|i| {
+            let iface = i.iface_mut(self.id);
+            iface.neighbors.get_mut().add_static(addr, hardware_addr)?;
+            iface.waker.wake();
+            Ok(())
+        })
+    }
+
+    /// Remove a static entry from the neighbor table.
+    ///
+    /// The entry remains in the table as a dynamic entry until it expires.
+    #[cfg(all(feature = "proto-ipv4", feature = "medium-ethernet"))]
+    pub fn remove_static_neighbor(&self, addr: Ipv4Address) {
+        self.stack
+            .with_mut(|i| i.iface_mut(self.id).neighbors.get_mut().remove_static(addr))
+    }
+
+    /// Remove the dynamic entries of the neighbor table.
+    #[cfg(all(feature = "proto-ipv4", feature = "medium-ethernet"))]
+    pub fn flush_neighbors(&self) {
+        self.stack.with_mut(|i| {
+            let iface = i.iface_mut(self.id);
+            // smoltcp flushes its neighbor cache when addresses are updated.
+            iface.iface.update_ip_addrs(|_| {});
+            iface.neighbors.get_mut().flush();
+            iface.waker.wake();
+        })
+    }
+
+    /// Get the last neighbor conflict detected by the interface.
+    #[cfg(all(feature = "proto-ipv4", feature = "medium-ethernet"))]
+    pub fn neighbor_conflict(&self) -> Option<NeighborConflict> {
+        self.with(|i| i.neighbors.borrow().last_conflict)
+    }
+
+    /// Wait for the interface to detect a neighbor conflict.
+    ///
+    /// The interface announces its IPv4 address with a gratuitous ARP request when it's
+    /// configured and when the link goes up, so that a host using the same address detects the
+    /// conflict too.
+    #[cfg(all(feature = "proto-ipv4", feature = "medium-ethernet"))]
+    pub async fn wait_neighbor_conflict(&self) -> NeighborConflict {
+        let conflicts = || self.with(|i| i.neighbors.borrow().conflicts);
+        let start = conflicts();
+        self.stack.wait(|| conflicts() != start).await;
+        unwrap!(self.neighbor_conflict())
+    }
+}
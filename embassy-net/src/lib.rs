@@ -12,40 +12,46 @@ compile_error!("You must enable at least one of the following features: proto-ip
 // This mod MUST go first, so that the others see its macros.
 pub(crate) mod fmt;
 
+#[cfg(feature = "dhcpv4-server")]
+pub mod dhcp_server;
 #[cfg(feature = "dns")]
 pub mod dns;
 mod driver_util;
 #[cfg(feature = "icmp")]
 pub mod icmp;
+mod iface;
+#[cfg(all(feature = "proto-ipv4", feature = "medium-ethernet"))]
+mod neighbor;
 #[cfg(feature = "raw")]
 pub mod raw;
+#[cfg(feature = "sntp")]
+pub mod sntp;
+#[cfg(feature = "stats")]
+mod stats;
 #[cfg(feature = "tcp")]
 pub mod tcp;
 mod time;
+#[cfg(feature = "tls")]
+pub mod tls;
 #[cfg(feature = "udp")]
 pub mod udp;
 
 use core::cell::RefCell;
 use core::future::{poll_fn, Future};
 use core::mem::MaybeUninit;
-use core::pin::pin;
-use core::task::{Context, Poll};
+use core::task::Poll;
 
 pub use embassy_net_driver as driver;
-use embassy_net_driver::{Driver, LinkState};
+use embassy_net_driver::Driver;
 use embassy_sync::waitqueue::WakerRegistration;
-use embassy_time::{Instant, Timer};
 use heapless::Vec;
 #[cfg(feature = "dns")]
 pub use smoltcp::config::DNS_MAX_SERVER_COUNT;
 #[cfg(feature = "multicast")]
 pub use smoltcp::iface::MulticastError;
-#[cfg(any(feature = "dns", feature = "dhcpv4"))]
-use smoltcp::iface::SocketHandle;
-use smoltcp::iface::{Interface, SocketSet, SocketStorage};
 use smoltcp::phy::Medium;
 #[cfg(feature = "dhcpv4")]
-use smoltcp::socket::dhcpv4::{self, RetryConfig};
+use smoltcp::socket::dhcpv4::RetryConfig;
 #[cfg(feature = "medium-ethernet")]
 pub use smoltcp::wire::EthernetAddress;
 #[cfg(any(feature = "medium-ethernet", feature = "medium-ieee802154", feature = "medium-ip"))]
@@ -59,9 +65,17 @@ pub use smoltcp::wire::{IpAddress, IpCidr, IpEndpoint};
 pub use smoltcp::wire::{Ipv4Address, Ipv4Cidr};
 #[cfg(feature = "proto-ipv6")]
 pub use smoltcp::wire::{Ipv6Address, Ipv6Cidr};
-
-use crate::driver_util::DriverAdapter;
-use crate::time::{instant_from_smoltcp, instant_to_smoltcp};
+#[cfg(feature = "stats")]
+pub use stats::{InterfaceStats, SocketStats};
+
+use crate::iface::Iface;
+pub use crate::iface::{
+    Interface, InterfaceFull, InterfaceId, InterfaceResources, Route, RouteTableFull, MAX_INTERFACES, MAX_ROUTES,
+};
+#[cfg(all(feature = "proto-ipv4", feature = "medium-ethernet"))]
+pub use crate::neighbor::{
+    Neighbor, NeighborConflict, NeighborConflictKind, StaticNeighborsFull, MAX_STATIC_NEIGHBORS,
+};
 
 const LOCAL_PORT_MIN: u16 = 1025;
 const LOCAL_PORT_MAX: u16 = 65535;
@@ -71,34 +85,27 @@ const MAX_QUERIES: usize = 4;
 const MAX_HOSTNAME_LEN: usize = 32;
 
 /// Memory resources needed for a network stack.
+///
+/// `SOCK` is the number of sockets of the stack, including one socket for DNS if enabled. It's also
+/// the number of sockets of the primary interface, see [`InterfaceResources`]. Creating more
+/// sockets than that panics.
 pub struct StackResources<const SOCK: usize> {
-    sockets: MaybeUninit<[SocketStorage<'static>; SOCK]>,
+    iface: InterfaceResources<SOCK>,
+    sockets: MaybeUninit<[Option<SocketSlot>; SOCK]>,
     inner: MaybeUninit<RefCell<Inner>>,
     #[cfg(feature = "dns")]
     queries: MaybeUninit<[Option<dns::DnsQuery>; MAX_QUERIES]>,
-    #[cfg(feature = "dhcpv4-hostname")]
-    hostname: HostnameResources,
-}
-
-#[cfg(feature = "dhcpv4-hostname")]
-struct HostnameResources {
-    option: MaybeUninit<smoltcp::wire::DhcpOption<'static>>,
-    data: MaybeUninit<[u8; MAX_HOSTNAME_LEN]>,
 }
 
 impl<const SOCK: usize> StackResources<SOCK> {
     /// Create a new set of stack resources.
     pub const fn new() -> Self {
         Self {
+            iface: InterfaceResources::new(),
             sockets: MaybeUninit::uninit(),
             inner: MaybeUninit::uninit(),
             #[cfg(feature = "dns")]
             queries: MaybeUninit::uninit(),
-            #[cfg(feature = "dhcpv4-hostname")]
-            hostname: HostnameResources {
-                option: MaybeUninit::uninit(),
-                data: MaybeUninit::uninit(),
-            },
         }
     }
 }
@@ -248,6 +255,7 @@ pub enum ConfigV6 {
 pub struct Runner<'d, D: Driver> {
     driver: D,
     stack: Stack<'d>,
+    id: InterfaceId,
 }
 
 /// Network stack handle
@@ -260,27 +268,37 @@ pub struct Stack<'d> {
 }
 
 pub(crate) struct Inner {
-    pub(crate) sockets: SocketSet<'static>, // Lifetime type-erased.
-    pub(crate) iface: Interface,
-    /// Waker used for triggering polls.
-    pub(crate) waker: WakerRegistration,
+    /// Interfaces, indexed by `InterfaceId`. Lifetime type-erased.
+    ifaces: Vec<&'static mut Iface, MAX_INTERFACES>,
+    /// Interface and handle of each socket, indexed by `SocketHandle`. Lifetime type-erased.
+    sockets: &'static mut [Option<SocketSlot>],
+    /// Static routes added with `Stack::add_route()`.
+    routes: Vec<Route, MAX_ROUTES>,
     /// Waker used for waiting for link up or config up.
     state_waker: WakerRegistration,
-    hardware_address: HardwareAddress,
+    random_seed: u64,
     next_local_port: u16,
-    link_up: bool,
-    #[cfg(feature = "proto-ipv4")]
-    static_v4: Option<StaticConfigV4>,
-    #[cfg(feature = "proto-ipv6")]
-    static_v6: Option<StaticConfigV6>,
-    #[cfg(feature = "dhcpv4")]
-    dhcp_socket: Option<SocketHandle>,
+    /// Lease slots of the running DHCP server, registered by `DhcpServer::new()`.
+    #[cfg(feature = "dhcpv4-server")]
+    dhcp_server_slots: Option<&'static [core::cell::Cell<Option<dhcp_server::Slot>>]>,
     #[cfg(feature = "dns")]
     dns_socket: SocketHandle,
     #[cfg(feature = "dns")]
     dns_waker: WakerRegistration,
-    #[cfg(feature = "dhcpv4-hostname")]
-    hostname: *mut HostnameResources,
+}
+
+/// Handle of a socket of the stack.
+///
+/// Unlike the handle in the socket set of an interface, it stays the same when the socket moves
+/// to another interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SocketHandle(usize);
+
+/// Location of a socket: its interface, and its handle in the socket set of that interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SocketSlot {
+    iface: InterfaceId,
+    handle: smoltcp::iface::SocketHandle,
 }
 
 fn _assert_covariant<'a, 'b: 'a>(x: Stack<'b>) -> Stack<'a> {
@@ -294,69 +312,50 @@ pub fn new<'d, D: Driver, const SOCK: usize>(
     resources: &'d mut StackResources<SOCK>,
     random_seed: u64,
 ) -> (Stack<'d>, Runner<'d, D>) {
-    let (hardware_address, medium) = to_smoltcp_hardware_address(driver.hardware_address());
-    let mut iface_cfg = smoltcp::iface::Config::new(hardware_address);
-    iface_cfg.random_seed = random_seed;
-
-    let iface = Interface::new(
-        iface_cfg,
-        &mut DriverAdapter {
-            inner: &mut driver,
-            cx: None,
-            medium,
-        },
-        instant_to_smoltcp(Instant::now()),
-    );
+    let iface = Iface::init(&mut driver, &mut resources.iface, random_seed, 0);
+
+    let next_local_port = (random_seed % (LOCAL_PORT_MAX - LOCAL_PORT_MIN) as u64) as u16 + LOCAL_PORT_MIN;
 
     unsafe fn transmute_slice<T>(x: &mut [T]) -> &'static mut [T] {
         core::mem::transmute(x)
     }
 
-    let sockets = resources.sockets.write([SocketStorage::EMPTY; SOCK]);
-    #[allow(unused_mut)]
-    let mut sockets: SocketSet<'static> = SocketSet::new(unsafe { transmute_slice(sockets) });
-
-    let next_local_port = (random_seed % (LOCAL_PORT_MAX - LOCAL_PORT_MIN) as u64) as u16 + LOCAL_PORT_MIN;
+    let sockets = unsafe { transmute_slice(resources.sockets.write([None; SOCK])) };
 
     #[cfg(feature = "dns")]
-    let dns_socket = sockets.add(dns::Socket::new(
-        &[],
-        managed::ManagedSlice::Borrowed(unsafe {
-            transmute_slice(resources.queries.write([const { None }; MAX_QUERIES]))
-        }),
-    ));
+    let dns_socket = {
+        let handle = iface.sockets.add(dns::Socket::new(
+            &[],
+            managed::ManagedSlice::Borrowed(unsafe {
+                transmute_slice(resources.queries.write([const { None }; MAX_QUERIES]))
+            }),
+        ));
+        sockets[0] = Some(SocketSlot {
+            iface: InterfaceId::PRIMARY,
+            handle,
+        });
+        SocketHandle(0)
+    };
 
     let mut inner = Inner {
+        ifaces: Vec::new(),
         sockets,
-        iface,
-        waker: WakerRegistration::new(),
+        routes: Vec::new(),
         state_waker: WakerRegistration::new(),
+        random_seed,
         next_local_port,
-        hardware_address,
-        link_up: false,
-        #[cfg(feature = "proto-ipv4")]
-        static_v4: None,
-        #[cfg(feature = "proto-ipv6")]
-        static_v6: None,
-        #[cfg(feature = "dhcpv4")]
-        dhcp_socket: None,
+        #[cfg(feature = "dhcpv4-server")]
+        dhcp_server_slots: None,
         #[cfg(feature = "dns")]
         dns_socket,
         #[cfg(feature = "dns")]
         dns_waker: WakerRegistration::new(),
-        #[cfg(feature = "dhcpv4-hostname")]
-        hostname: &mut resources.hostname,
     };
-
-    #[cfg(feature = "proto-ipv4")]
-    inner.set_config_v4(config.ipv4);
-    #[cfg(feature = "proto-ipv6")]
-    inner.set_config_v6(config.ipv6);
-    inner.apply_static_config();
+    let id = inner.add_interface(iface, config);
 
     let inner = &*resources.inner.write(RefCell::new(inner));
     let stack = Stack { inner };
-    (stack, Runner { driver, stack })
+    (stack, Runner { driver, stack, id })
 }
 
 fn to_smoltcp_hardware_address(addr: driver::HardwareAddress) -> (HardwareAddress, Medium) {
@@ -388,54 +387,130 @@ impl<'d> Stack<'d> {
         f(&mut self.inner.borrow_mut())
     }
 
-    /// Get the hardware address of the network interface.
+    /// Get a handle to an interface of the stack.
+    ///
+    /// Panics if there's no interface `id`.
+    pub fn interface(&self, id: InterfaceId) -> Interface<'d> {
+        assert!(self.with(|i| id.0 < i.ifaces.len() as u8), "no such interface");
+        Interface::new(*self, id)
+    }
+
+    /// Iterate over the interfaces of the stack.
+    pub fn interfaces(&self) -> impl Iterator<Item = Interface<'d>> + 'd {
+        let stack = *self;
+        let count = self.with(|i| i.ifaces.len() as u8);
+        (0..count).map(move |id| Interface::new(stack, InterfaceId(id)))
+    }
+
+    /// Add an interface to the stack.
+    ///
+    /// Sockets are created on the usable interface with a default gateway and the lowest `metric`,
+    /// and move to the interface their traffic is routed through when they connect or bind to a
+    /// local address, unless bound to an interface. For this, each interface needs room for all the
+    /// sockets using it at the same time, see [`InterfaceResources`].
+    ///
+    /// You must call [`Runner::run()`] on the returned runner in a background task for the
+    /// interface to work.
+    ///
+    /// Panics if the stack already has [`MAX_INTERFACES`] interfaces, or if `config` enables DHCP
+    /// and the interface has no room for its socket.
+    pub fn add_interface<D: Driver, const SOCK: usize>(
+        &self,
+        mut driver: D,
+        config: Config,
+        resources: &'d mut InterfaceResources<SOCK>,
+        metric: u32,
+    ) -> (Interface<'d>, Runner<'d, D>) {
+        let random_seed = self.with(|i| i.random_seed.wrapping_add(i.ifaces.len() as u64));
+        let iface = Iface::init(&mut driver, resources, random_seed, metric);
+        let id = self.with_mut(|i| i.add_interface(iface, config));
+        let stack = *self;
+        (Interface::new(stack, id), Runner { driver, stack, id })
+    }
+
+    /// Add a static route.
+    ///
+    /// The route is also added to the routing table of the interface, which holds its default
+    /// gateways too. Its size is set with the `iface-max-route-count-*` features of smoltcp.
+    ///
+    /// Panics if there's no interface `route.interface`.
+    pub fn add_route(&self, route: Route) -> Result<(), RouteTableFull> {
+        self.with_mut(|i| {
+            assert!(route.interface.0 < i.ifaces.len() as u8, "no such interface");
+            let iface = &mut i.iface_mut(route.interface).iface;
+            let mut res = Ok(());
+            iface.routes_mut().update(|routes| {
+                res = routes
+                    .push(smoltcp::iface::Route {
+                        cidr: route.cidr,
+                        via_router: route.via_router,
+                        preferred_until: None,
+                        expires_at: None,
+                    })
+                    .map_err(|_| RouteTableFull);
+            });
+            res?;
+            if i.routes.push(route).is_err() {
+                i.remove_iface_route(&route);
+                return Err(RouteTableFull);
+            }
+            i.state_waker.wake();
+            Ok(())
+        })
+    }
+
+    /// Remove a static route added with [`Stack::add_route()`].
+    pub fn remove_route(&self, route: Route) {
+        self.with_mut(|i| {
+            if let Some(pos) = i.routes.iter().position(|r| *r == route) {
+                i.routes.remove(pos);
+                i.remove_iface_route(&route);
+            }
+        })
+    }
+
+    /// Get the interface packets to `addr` are currently sent through, if any.
+    ///
+    /// Directly connected networks are preferred, then the route with the longest prefix, default
+    /// gateways being routes with a zero-length prefix. Ties are broken by metric. Only interfaces
+    /// with link and an IP configuration are considered.
+    pub fn route(&self, addr: IpAddress) -> Option<InterfaceId> {
+        self.with(|i| i.route(addr))
+    }
+
+    /// Get the hardware address of the primary interface.
     pub fn hardware_address(&self) -> HardwareAddress {
-        self.with(|i| i.hardware_address)
+        self.primary().hardware_address()
+    }
+
+    /// Get the counters of the primary interface.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> InterfaceStats {
+        self.primary().stats()
     }
 
-    /// Check whether the link is up.
+    /// Check whether the link of the primary interface is up.
     pub fn is_link_up(&self) -> bool {
-        self.with(|i| i.link_up)
+        self.primary().is_link_up()
     }
 
-    /// Check whether the network stack has a valid IP configuration.
+    /// Check whether the primary interface has a valid IP configuration.
     /// This is true if the network stack has a static IP configuration or if DHCP has completed
     pub fn is_config_up(&self) -> bool {
-        let v4_up;
-        let v6_up;
-
-        #[cfg(feature = "proto-ipv4")]
-        {
-            v4_up = self.config_v4().is_some();
-        }
-        #[cfg(not(feature = "proto-ipv4"))]
-        {
-            v4_up = false;
-        }
-
-        #[cfg(feature = "proto-ipv6")]
-        {
-            v6_up = self.config_v6().is_some();
-        }
-        #[cfg(not(feature = "proto-ipv6"))]
-        {
-            v6_up = false;
-        }
-
-        v4_up || v6_up
+        self.primary().is_config_up()
     }
 
-    /// Wait for the network device to obtain a link signal.
+    /// Wait for the network device of the primary interface to obtain a link signal.
     pub async fn wait_link_up(&self) {
         self.wait(|| self.is_link_up()).await
     }
 
-    /// Wait for the network device to lose link signal.
+    /// Wait for the network device of the primary interface to lose link signal.
     pub async fn wait_link_down(&self) {
         self.wait(|| !self.is_link_up()).await
     }
 
-    /// Wait for the network stack to obtain a valid IP configuration.
+    /// Wait for the primary interface to obtain a valid IP configuration.
     ///
     /// ## Notes:
     /// - Ensure [`Runner::run`] has been started before using this function.
@@ -468,12 +543,12 @@ impl<'d> Stack<'d> {
         self.wait(|| self.is_config_up()).await
     }
 
-    /// Wait for the network stack to lose a valid IP configuration.
+    /// Wait for the primary interface to lose a valid IP configuration.
     pub async fn wait_config_down(&self) {
         self.wait(|| !self.is_config_up()).await
     }
 
-    fn wait<'a>(&'a self, mut predicate: impl FnMut() -> bool + 'a) -> impl Future<Output = ()> + 'a {
+    pub(crate) fn wait<'a>(&'a self, mut predicate: impl FnMut() -> bool + 'a) -> impl Future<Output = ()> + 'a {
         poll_fn(move |cx| {
             if predicate() {
                 Poll::Ready(())
@@ -491,36 +566,47 @@ impl<'d> Stack<'d> {
         })
     }
 
-    /// Get the current IPv4 configuration.
+    /// Get the current IPv4 configuration of the primary interface.
     ///
     /// If using DHCP, this will be None if DHCP hasn't been able to
     /// acquire an IP address, or Some if it has.
     #[cfg(feature = "proto-ipv4")]
     pub fn config_v4(&self) -> Option<StaticConfigV4> {
-        self.with(|i| i.static_v4.clone())
+        self.primary().config_v4()
     }
 
-    /// Get the current IPv6 configuration.
+    /// Get the current IPv6 configuration of the primary interface.
     #[cfg(feature = "proto-ipv6")]
     pub fn config_v6(&self) -> Option<StaticConfigV6> {
-        self.with(|i| i.static_v6.clone())
+        self.primary().config_v6()
     }
 
-    /// Set the IPv4 configuration.
+    /// Set the IPv4 configuration of the primary interface.
+    ///
+    /// Panics if the configuration enables DHCP and the interface has no room for its socket.
     #[cfg(feature = "proto-ipv4")]
     pub fn set_config_v4(&self, config: ConfigV4) {
-        self.with_mut(|i| {
-            i.set_config_v4(config);
-            i.apply_static_config();
-        })
+        self.primary().set_config_v4(config)
     }
 
-    /// Set the IPv6 configuration.
+    /// Set the IPv6 configuration of the primary interface.
     #[cfg(feature = "proto-ipv6")]
     pub fn set_config_v6(&self, config: ConfigV6) {
-        self.with_mut(|i| {
-            i.set_config_v6(config);
-            i.apply_static_config();
+        self.primary().set_config_v6(config)
+    }
+
+    fn primary(&self) -> Interface<'d> {
+        Interface::new(*self, InterfaceId::PRIMARY)
+    }
+
+    /// Get the active leases of the [`DhcpServer`](dhcp_server::DhcpServer) running on this stack, up to `N` of them.
+    ///
+    /// Returns no leases if no DHCP server is running.
+    #[cfg(feature = "dhcpv4-server")]
+    pub fn dhcp_server_leases<const N: usize>(&self) -> Vec<dhcp_server::DhcpLease, N> {
+        self.with(|i| match i.dhcp_server_slots {
+            Some(slots) => dhcp_server::active_leases(slots),
+            None => Vec::new(),
         })
     }
 
@@ -550,10 +636,12 @@ impl<'d> Stack<'d> {
 
         let query = poll_fn(|cx| {
             self.with_mut(|i| {
-                let socket = i.sockets.get_mut::<dns::Socket>(i.dns_socket);
-                match socket.start_query(i.iface.context(), name, qtype) {
+                // Send queries through the preferred interface.
+                i.try_move_socket(i.dns_socket, i.preferred_interface());
+                let (socket, iface) = i.socket_mut::<dns::Socket>(i.dns_socket);
+                match socket.start_query(iface.context(), name, qtype) {
                     Ok(handle) => {
-                        i.waker.wake();
+                        i.wake(i.dns_socket);
                         Poll::Ready(Ok(handle))
                     }
                     Err(dns::StartQueryError::NoFreeSlot) => {
@@ -591,16 +679,16 @@ impl<'d> Stack<'d> {
 
         let drop = OnDrop::new(|| {
             self.with_mut(|i| {
-                let socket = i.sockets.get_mut::<dns::Socket>(i.dns_socket);
+                let (socket, _) = i.socket_mut::<dns::Socket>(i.dns_socket);
                 socket.cancel_query(query);
-                i.waker.wake();
+                i.wake(i.dns_socket);
                 i.dns_waker.wake();
             })
         });
 
         let res = poll_fn(|cx| {
             self.with_mut(|i| {
-                let socket = i.sockets.get_mut::<dns::Socket>(i.dns_socket);
+                let (socket, _) = i.socket_mut::<dns::Socket>(i.dns_socket);
                 match socket.get_query_result(query) {
                     Ok(addrs) => {
                         i.dns_waker.wake();
@@ -625,21 +713,64 @@ impl<'d> Stack<'d> {
     }
 }
 
+#[cfg(all(feature = "proto-ipv4", feature = "medium-ethernet"))]
+impl<'d> Stack<'d> {
+    /// Get the entries of the neighbor table of the primary interface, up to `N` of them.
+    ///
+    /// See [`Interface::neighbors()`].
+    pub fn neighbors<const N: usize>(&self) -> Vec<Neighbor, N> {
+        self.primary().neighbors()
+    }
+
+    /// Get the hardware address an IP address resolved to on the primary interface.
+    pub fn neighbor(&self, addr: Ipv4Address) -> Option<EthernetAddress> {
+        self.primary().neighbor(addr)
+    }
+
+    /// Add a static entry to the neighbor table of the primary interface.
+    ///
+    /// See [`Interface::add_static_neighbor()`].
+    pub fn add_static_neighbor(
+        &self,
+        addr: Ipv4Address,
+        hardware_addr: EthernetAddress,
+    ) -> Result<(), StaticNeighborsFull> {
+        self.primary().add_static_neighbor(addr, hardware_addr)
+    }
+
+    /// Remove a static entry from the neighbor table of the primary interface.
+    pub fn remove_static_neighbor(&self, addr: Ipv4Address) {
+        self.primary().remove_static_neighbor(addr)
+    }
+
+    /// Remove the dynamic entries of the neighbor table of the primary interface.
+    pub fn flush_neighbors(&self) {
+        self.primary().flush_neighbors()
+    }
+
+    /// Wait for the primary interface to detect a neighbor conflict.
+    ///
+    /// See [`Interface::wait_neighbor_conflict()`].
+    pub async fn wait_neighbor_conflict(&self) -> NeighborConflict {
+        self.primary().wait_neighbor_conflict().await
+    }
+}
+
 #[cfg(feature = "multicast")]
 impl<'d> Stack<'d> {
-    /// Join a multicast group.
+    /// Join a multicast group on the primary interface.
     pub fn join_multicast_group(&self, addr: impl Into<IpAddress>) -> Result<(), MulticastError> {
-        self.with_mut(|i| i.iface.join_multicast_group(addr))
+        self.with_mut(|i| i.iface_mut(InterfaceId::PRIMARY).iface.join_multicast_group(addr))
     }
 
-    /// Leave a multicast group.
+    /// Leave a multicast group on the primary interface.
     pub fn leave_multicast_group(&self, addr: impl Into<IpAddress>) -> Result<(), MulticastError> {
-        self.with_mut(|i| i.iface.leave_multicast_group(addr))
+        self.with_mut(|i| i.iface_mut(InterfaceId::PRIMARY).iface.leave_multicast_group(addr))
     }
 
-    /// Get whether the network stack has joined the given multicast group.
+    /// Get whether the primary interface has joined the given multicast group.
     pub fn has_multicast_group(&self, addr: impl Into<IpAddress>) -> bool {
-        self.with(|i| i.iface.has_multicast_group(addr))
+        self.with(|i| i.iface(InterfaceId::PRIMARY).iface.has_multicast_group(addr))
     }
 }
 
@@ -651,229 +782,15 @@ impl Inner {
         res
     }
 
-    #[cfg(feature = "proto-ipv4")]
-    pub fn set_config_v4(&mut self, config: ConfigV4) {
-        // Handle static config.
-        self.static_v4 = match config.clone() {
-            ConfigV4::None => None,
-            #[cfg(feature = "dhcpv4")]
-            ConfigV4::Dhcp(_) => None,
-            ConfigV4::Static(c) => Some(c),
-        };
-
-        // Handle DHCP config.
-        #[cfg(feature = "dhcpv4")]
-        match config {
-            ConfigV4::Dhcp(c) => {
-                // Create the socket if it doesn't exist.
-                if self.dhcp_socket.is_none() {
-                    let socket = smoltcp::socket::dhcpv4::Socket::new();
-                    let handle = self.sockets.add(socket);
-                    self.dhcp_socket = Some(handle);
-                }
-
-                // Configure it
-                let socket = self.sockets.get_mut::<dhcpv4::Socket>(unwrap!(self.dhcp_socket));
-                socket.set_ignore_naks(c.ignore_naks);
-                socket.set_max_lease_duration(c.max_lease_duration.map(crate::time::duration_to_smoltcp));
-                socket.set_ports(c.server_port, c.client_port);
-                socket.set_retry_config(c.retry_config);
-
-                socket.set_outgoing_options(&[]);
-                #[cfg(feature = "dhcpv4-hostname")]
-                if let Some(h) = c.hostname {
-                    // safety:
-                    // - we just did set_outgoing_options([]) so we know the socket is no longer holding a reference.
-                    // - we know this pointer lives for as long as the stack exists, because `new()` borrows
-                    //   the resources for `'d`. Therefore it's OK to pass a reference to this to smoltcp.
-                    let hostname = unsafe { &mut *self.hostname };
-
-                    // create data
-                    let data = hostname.data.write([0; MAX_HOSTNAME_LEN]);
-                    data[..h.len()].copy_from_slice(h.as_bytes());
-                    let data: &[u8] = &data[..h.len()];
-
-                    // set the option.
-                    let option = hostname.option.write(smoltcp::wire::DhcpOption { data, kind: 12 });
-                    socket.set_outgoing_options(core::slice::from_ref(option));
-                }
-
-                socket.reset();
+    fn remove_iface_route(&mut self, route: &Route) {
+        self.iface_mut(route.interface).iface.routes_mut().update(|routes| {
+            if let Some(pos) = routes
+                .iter()
+                .position(|r| r.cidr == route.cidr && r.via_router == route.via_router)
+            {
+                routes.remove(pos);
             }
-            _ => {
-                // Remove DHCP socket if any.
-                if let Some(socket) = self.dhcp_socket {
-                    self.sockets.remove(socket);
-                    self.dhcp_socket = None;
-                }
-            }
-        }
-    }
-
-    #[cfg(feature = "proto-ipv6")]
-    pub fn set_config_v6(&mut self, config: ConfigV6) {
-        self.static_v6 = match config {
-            ConfigV6::None => None,
-            ConfigV6::Static(c) => Some(c),
-        };
-    }
-
-    fn apply_static_config(&mut self) {
-        let mut addrs = Vec::new();
-        #[cfg(feature = "dns")]
-        let mut dns_servers: Vec<_, 6> = Vec::new();
-        #[cfg(feature = "proto-ipv4")]
-        let mut gateway_v4 = None;
-        #[cfg(feature = "proto-ipv6")]
-        let mut gateway_v6 = None;
-
-        #[cfg(feature = "proto-ipv4")]
-        if let Some(config) = &self.static_v4 {
-            debug!("IPv4: UP");
-            debug!("   IP address:      {:?}", config.address);
-            debug!("   Default gateway: {:?}", config.gateway);
-
-            unwrap!(addrs.push(IpCidr::Ipv4(config.address)).ok());
-            gateway_v4 = config.gateway;
-            #[cfg(feature = "dns")]
-            for s in &config.dns_servers {
-                debug!("   DNS server:      {:?}", s);
-                unwrap!(dns_servers.push(s.clone().into()).ok());
-            }
-        } else {
-            info!("IPv4: DOWN");
-        }
-
-        #[cfg(feature = "proto-ipv6")]
-        if let Some(config) = &self.static_v6 {
-            debug!("IPv6: UP");
-            debug!("   IP address:      {:?}", config.address);
-            debug!("   Default gateway: {:?}", config.gateway);
-
-            unwrap!(addrs.push(IpCidr::Ipv6(config.address)).ok());
-            gateway_v6 = config.gateway.into();
-            #[cfg(feature = "dns")]
-            for s in &config.dns_servers {
-                debug!("   DNS server:      {:?}", s);
-                unwrap!(dns_servers.push(s.clone().into()).ok());
-            }
-        } else {
-            info!("IPv6: DOWN");
-        }
-
-        // Apply addresses
-        self.iface.update_ip_addrs(|a| *a = addrs);
-
-        // Apply gateways
-        #[cfg(feature = "proto-ipv4")]
-        if let Some(gateway) = gateway_v4 {
-            unwrap!(self.iface.routes_mut().add_default_ipv4_route(gateway));
-        } else {
-            self.iface.routes_mut().remove_default_ipv4_route();
-        }
-        #[cfg(feature = "proto-ipv6")]
-        if let Some(gateway) = gateway_v6 {
-            unwrap!(self.iface.routes_mut().add_default_ipv6_route(gateway));
-        } else {
-            self.iface.routes_mut().remove_default_ipv6_route();
-        }
-
-        // Apply DNS servers
-        #[cfg(feature = "dns")]
-        if !dns_servers.is_empty() {
-            let count = if dns_servers.len() > DNS_MAX_SERVER_COUNT {
-                warn!("Number of DNS servers exceeds DNS_MAX_SERVER_COUNT, truncating list.");
-                DNS_MAX_SERVER_COUNT
-            } else {
-                dns_servers.len()
-            };
-            self.sockets
-                .get_mut::<smoltcp::socket::dns::Socket>(self.dns_socket)
-                .update_servers(&dns_servers[..count]);
-        }
-
-        self.state_waker.wake();
-    }
-
-    fn poll<D: Driver>(&mut self, cx: &mut Context<'_>, driver: &mut D) {
-        self.waker.register(cx.waker());
-
-        let (_hardware_addr, medium) = to_smoltcp_hardware_address(driver.hardware_address());
-
-        #[cfg(any(feature = "medium-ethernet", feature = "medium-ieee802154"))]
-        {
-            let do_set = match medium {
-                #[cfg(feature = "medium-ethernet")]
-                Medium::Ethernet => true,
-                #[cfg(feature = "medium-ieee802154")]
-                Medium::Ieee802154 => true,
-                #[allow(unreachable_patterns)]
-                _ => false,
-            };
-            if do_set {
-                self.iface.set_hardware_addr(_hardware_addr);
-            }
-        }
-
-        let timestamp = instant_to_smoltcp(Instant::now());
-        let mut smoldev = DriverAdapter {
-            cx: Some(cx),
-            inner: driver,
-            medium,
-        };
-        self.iface.poll(timestamp, &mut smoldev, &mut self.sockets);
-
-        // Update link up
-        let old_link_up = self.link_up;
-        self.link_up = driver.link_state(cx) == LinkState::Up;
-
-        // Print when changed
-        if old_link_up != self.link_up {
-            info!("link_up = {:?}", self.link_up);
-            self.state_waker.wake();
-        }
-
-        #[cfg(feature = "dhcpv4")]
-        if let Some(dhcp_handle) = self.dhcp_socket {
-            let socket = self.sockets.get_mut::<dhcpv4::Socket>(dhcp_handle);
-
-            let configure = if self.link_up {
-                if old_link_up != self.link_up {
-                    socket.reset();
-                }
-                match socket.poll() {
-                    None => false,
-                    Some(dhcpv4::Event::Deconfigured) => {
-                        self.static_v4 = None;
-                        true
-                    }
-                    Some(dhcpv4::Event::Configured(config)) => {
-                        self.static_v4 = Some(StaticConfigV4 {
-                            address: config.address,
-                            gateway: config.router,
-                            dns_servers: config.dns_servers,
-                        });
-                        true
-                    }
-                }
-            } else if old_link_up {
-                socket.reset();
-                self.static_v4 = None;
-                true
-            } else {
-                false
-            };
-            if configure {
-                self.apply_static_config()
-            }
-        }
-
-        if let Some(poll_at) = self.iface.poll_at(timestamp, &mut self.sockets) {
-            let t = pin!(Timer::at(instant_from_smoltcp(poll_at)));
-            if t.poll(cx).is_ready() {
-                cx.waker().wake_by_ref();
-            }
-        }
+        });
     }
 }
 
@@ -883,7 +800,7 @@ impl<'d, D: Driver> Runner<'d, D> {
     /// You must call this in a background task, to process network events.
     pub async fn run(&mut self) -> ! {
         poll_fn(|cx| {
-            self.stack.with_mut(|i| i.poll(cx, &mut self.driver));
+            self.stack.with_mut(|i| i.poll(self.id, cx, &mut self.driver));
             Poll::<()>::Pending
         })
         .await;
@@ -0,0 +1,431 @@
+//! ARP neighbor table.
+//!
+//! smoltcp doesn't expose its neighbor cache, so this module tracks the entries it most likely
+//! holds, from the ARP packets the interface receives and with the same rules and timestamps as
+//! smoltcp. This is a best-effort mirror for introspection: smoltcp remains the only authority on
+//! how addresses resolve. Static entries are installed in smoltcp's cache by feeding it ARP
+//! replies before they expire, and ARP packets contradicting them are dropped before smoltcp sees
+//! them.
+
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+use smoltcp::config::IFACE_NEIGHBOR_CACHE_COUNT;
+use smoltcp::iface::{Interface, SocketSet};
+use smoltcp::phy::{self, Device, DeviceCapabilities, Medium};
+use smoltcp::wire::{
+    ArpOperation, ArpPacket, ArpRepr, EthernetAddress, EthernetFrame, EthernetProtocol, EthernetRepr, Ipv4Address,
+    Ipv4Cidr, Ipv4Packet,
+};
+
+use crate::time::instant_to_smoltcp;
+
+/// Maximum number of static neighbors of an interface.
+pub const MAX_STATIC_NEIGHBORS: usize = 4;
+
+/// Lifetime of dynamic entries, as in smoltcp.
+const ENTRY_LIFETIME: Duration = Duration::from_secs(60);
+/// Static entries are installed again when they expire within this time.
+const STATIC_REFRESH: Duration = Duration::from_secs(30);
+
+const ARP_FRAME_LEN: usize = 14 + 28;
+
+/// An entry of the neighbor table of an interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Neighbor {
+    /// IP address of the neighbor.
+    pub address: Ipv4Address,
+    /// Hardware address the IP address resolved to.
+    pub hardware_address: EthernetAddress,
+    /// When the entry expires, or `None` for static entries.
+    pub expires_at: Option<Instant>,
+}
+
+/// A conflict detected from the ARP packets received by an interface.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct NeighborConflict {
+    /// The IP address in conflict.
+    pub address: Ipv4Address,
+    /// Hardware address of the host that claimed the IP address.
+    pub hardware_address: EthernetAddress,
+    /// Kind of conflict.
+    pub kind: NeighborConflictKind,
+}
+
+/// Kind of [`NeighborConflict`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum NeighborConflictKind {
+    /// Another host uses the IP address of the interface.
+    DuplicateAddress,
+    /// A host claimed the IP address of a static neighbor with another hardware address. Its ARP
+    /// packets are ignored.
+    StaticMismatch,
+}
+
+/// Error returned by [`Interface::add_static_neighbor()`](crate::Interface::add_static_neighbor).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StaticNeighborsFull;
+
+pub(crate) struct Neighbors {
+    medium: Medium,
+    hardware_address: EthernetAddress,
+    address: Option<Ipv4Cidr>,
+    statics: Vec<(Ipv4Address, EthernetAddress), MAX_STATIC_NEIGHBORS>,
+    /// Mirror of the neighbor cache of smoltcp, with expiration times.
+    cache: Vec<(Ipv4Address, EthernetAddress, Instant), IFACE_NEIGHBOR_CACHE_COUNT>,
+    /// Timestamp of the current poll of the interface, which smoltcp computes expiration times from.
+    now: Instant,
+    /// Whether the address must be announced with a gratuitous ARP request.
+    announce: bool,
+    pub(crate) conflicts: u32,
+    pub(crate) last_conflict: Option<NeighborConflict>,
+}
+
+impl Neighbors {
+    pub(crate) fn new(medium: Medium) -> Self {
+        Self {
+            medium,
+            hardware_address: EthernetAddress([0; 6]),
+            address: None,
+            statics: Vec::new(),
+            cache: Vec::new(),
+            now: Instant::MIN,
+            announce: false,
+            conflicts: 0,
+            last_conflict: None,
+        }
+    }
+
+    pub(crate) fn set_hardware_address(&mut self, hardware_address: EthernetAddress) {
+        self.hardware_address = hardware_address;
+    }
+
+    /// Update the address of the interface. smoltcp flushes its cache when addresses are set.
+    pub(crate) fn set_address(&mut self, address: Option<Ipv4Cidr>) {
+        self.cache.clear();
+        self.announce = address.is_some() && address != self.address;
+        self.address = address;
+    }
+
+    /// Set the timestamp of the poll of the interface about to start.
+    pub(crate) fn set_now(&mut self, now: Instant) {
+        self.now = now;
+    }
+
+    pub(crate) fn reannounce(&mut self) {
+        self.announce = self.address.is_some();
+    }
+
+    pub(crate) fn flush(&mut self) {
+        self.cache.clear();
+    }
+
+    pub(crate) fn add_static(
+        &mut self,
+        address: Ipv4Address,
+        hardware_address: EthernetAddress,
+    ) -> Result<(), StaticNeighborsFull> {
+        if let Some(entry) = self.statics.iter_mut().find(|(a, _)| *a == address) {
+            entry.1 = hardware_address;
+            return Ok(());
+        }
+        self.statics
+            .push((address, hardware_address))
+            .map_err(|_| StaticNeighborsFull)
+    }
+
+    pub(crate) fn remove_static(&mut self, address: Ipv4Address) {
+        self.statics.retain(|(a, _)| *a != address);
+        // Let the entry expire from smoltcp's cache like a dynamic one.
+    }
+
+    pub(crate) fn lookup(&self, address: Ipv4Address) -> Option<EthernetAddress> {
+        self.entries(Instant::now())
+            .find(|n| n.address == address)
+            .map(|n| n.hardware_address)
+    }
+
+    /// Static entries, followed by the unexpired dynamic entries.
+    pub(crate) fn entries(&self, now: Instant) -> impl Iterator<Item = Neighbor> + '_ {
+        let statics = self.statics.iter().map(|(address, hardware_address)| Neighbor {
+            address: *address,
+            hardware_address: *hardware_address,
+            expires_at: None,
+        });
+        let dynamic = self
+            .cache
+            .iter()
+            .filter(move |(address, _, expires_at)| *expires_at > now && !self.is_static(*address))
+            .map(|(address, hardware_address, expires_at)| Neighbor {
+                address: *address,
+                hardware_address: *hardware_address,
+                expires_at: Some(*expires_at),
+            });
+        statics.chain(dynamic)
+    }
+
+    fn is_static(&self, address: Ipv4Address) -> bool {
+        self.statics.iter().any(|(a, _)| *a == address)
+    }
+
+    /// Fill the cache like smoltcp does, evicting the entry expiring first when full.
+    fn fill(&mut self, address: Ipv4Address, hardware_address: EthernetAddress, expires_at: Instant) {
+        if let Some(entry) = self.cache.iter_mut().find(|(a, _, _)| *a == address) {
+            *entry = (address, hardware_address, expires_at);
+            return;
+        }
+        if self.cache.is_full() {
+            let (oldest, _) = unwrap!(self.cache.iter().enumerate().min_by_key(|(_, (_, _, e))| *e));
+            self.cache.swap_remove(oldest);
+        }
+        unwrap!(self.cache.push((address, hardware_address, expires_at)).ok());
+    }
+
+    fn conflict(&mut self, address: Ipv4Address, hardware_address: EthernetAddress, kind: NeighborConflictKind) {
+        warn!(
+            "neighbor conflict: {:?} claimed by {:?}: {:?}",
+            address, hardware_address, kind
+        );
+        self.conflicts = self.conflicts.wrapping_add(1);
+        self.last_conflict = Some(NeighborConflict {
+            address,
+            hardware_address,
+            kind,
+        });
+    }
+
+    /// Inspect a received frame before smoltcp processes it. Frames that must be ignored are
+    /// mangled so that smoltcp drops them.
+    pub(crate) fn rx_frame(&mut self, frame: &mut [u8]) {
+        if self.medium != Medium::Ethernet {
+            return;
+        }
+        let Ok(mut eth) = EthernetFrame::new_checked(frame) else {
+            return;
+        };
+        match eth.ethertype() {
+            EthernetProtocol::Arp => {}
+            EthernetProtocol::Ipv4 => {
+                // Refresh entries of neighbors sending us packets, if smoltcp accepts them.
+                let Ok(packet) = Ipv4Packet::new_checked(eth.payload_mut()) else {
+                    return;
+                };
+                if !packet.verify_checksum() {
+                    return;
+                }
+                let (src, dst) = (packet.src_addr(), packet.dst_addr());
+                let src_hw = eth.src_addr();
+                if self.address.is_some_and(|a| a.address() == dst) {
+                    if let Some(entry) = self.cache.iter_mut().find(|(a, h, _)| *a == src && *h == src_hw) {
+                        entry.2 = self.now + ENTRY_LIFETIME;
+                    }
+                }
+                return;
+            }
+            _ => return,
+        }
+
+        let Ok(packet) = ArpPacket::new_checked(eth.payload_mut()) else {
+            return;
+        };
+        let Ok(ArpRepr::EthernetIpv4 {
+            operation,
+            source_hardware_addr,
+            source_protocol_addr,
+            target_protocol_addr,
+            ..
+        }) = ArpRepr::parse(&packet)
+        else {
+            return;
+        };
+
+        if source_hardware_addr == self.hardware_address {
+            return;
+        }
+
+        if self.address.is_some_and(|a| a.address() == source_protocol_addr) {
+            self.conflict(
+                source_protocol_addr,
+                source_hardware_addr,
+                NeighborConflictKind::DuplicateAddress,
+            );
+        }
+
+        if let Some((_, hw)) = self.statics.iter().find(|(a, _)| *a == source_protocol_addr) {
+            if *hw != source_hardware_addr {
+                self.conflict(
+                    source_protocol_addr,
+                    source_hardware_addr,
+                    NeighborConflictKind::StaticMismatch,
+                );
+                eth.set_ethertype(EthernetProtocol::Unknown(0));
+                return;
+            }
+        }
+
+        // Conditions under which smoltcp fills its cache.
+        let Some(address) = self.address else {
+            return;
+        };
+        if target_protocol_addr == address.address()
+            && matches!(operation, ArpOperation::Request | ArpOperation::Reply)
+            && !(source_protocol_addr.is_broadcast()
+                || source_protocol_addr.is_multicast()
+                || source_protocol_addr.is_unspecified())
+            && source_hardware_addr.is_unicast()
+            && address.contains_addr(&source_protocol_addr)
+        {
+            self.fill(source_protocol_addr, source_hardware_addr, self.now + ENTRY_LIFETIME);
+        }
+    }
+
+    /// Install the static entries that are missing or about to expire in smoltcp's cache, and
+    /// announce the address of the interface if it changed.
+    pub(crate) fn poll(
+        &mut self,
+        iface: &mut Interface,
+        sockets: &mut SocketSet<'_>,
+        device: &mut impl Device,
+        link_up: bool,
+    ) {
+        if self.medium != Medium::Ethernet {
+            return;
+        }
+        let Some(address) = self.address else {
+            return;
+        };
+        let now = self.now;
+
+        for i in 0..self.statics.len() {
+            let (ip, hw) = self.statics[i];
+            let installed = self
+                .cache
+                .iter()
+                .any(|(a, h, e)| *a == ip && *h == hw && *e > now + STATIC_REFRESH);
+            // smoltcp ignores ARP packets from other networks.
+            if installed || !address.contains_addr(&ip) {
+                continue;
+            }
+
+            let mut frame = [0; ARP_FRAME_LEN];
+            self.arp_frame(
+                &mut frame,
+                ArpOperation::Reply,
+                (hw, ip),
+                self.hardware_address,
+                address.address(),
+            );
+            let mut device = Injector(frame);
+            iface.poll_ingress_single(instant_to_smoltcp(now), &mut device, sockets);
+            self.fill(ip, hw, now + ENTRY_LIFETIME);
+        }
+
+        if self.announce && link_up {
+            if let Some(tx) = device.transmit(instant_to_smoltcp(now)) {
+                self.announce = false;
+                let source = (self.hardware_address, address.address());
+                phy::TxToken::consume(tx, ARP_FRAME_LEN, |buf| {
+                    self.arp_frame(
+                        buf,
+                        ArpOperation::Request,
+                        source,
+                        EthernetAddress::BROADCAST,
+                        address.address(),
+                    )
+                });
+            }
+        }
+    }
+
+    /// When [`poll()`](Self::poll) must be called again to refresh static entries.
+    pub(crate) fn poll_at(&self) -> Option<Instant> {
+        let address = self.address?;
+        self.statics
+            .iter()
+            .filter(|(ip, _)| address.contains_addr(ip))
+            .map(|(ip, hw)| {
+                self.cache
+                    .iter()
+                    .find(|(a, h, _)| a == ip && h == hw)
+                    .map_or(Instant::MIN, |(_, _, e)| *e - STATIC_REFRESH)
+            })
+            .min()
+    }
+
+    fn arp_frame(
+        &self,
+        buf: &mut [u8],
+        operation: ArpOperation,
+        (source_hardware_addr, source_protocol_addr): (EthernetAddress, Ipv4Address),
+        target_hardware_addr: EthernetAddress,
+        target_protocol_addr: Ipv4Address,
+    ) {
+        let mut frame = EthernetFrame::new_unchecked(buf);
+        EthernetRepr {
+            src_addr: source_hardware_addr,
+            dst_addr: target_hardware_addr,
+            ethertype: EthernetProtocol::Arp,
+        }
+        .emit(&mut frame);
+        ArpRepr::EthernetIpv4 {
+            operation,
+            source_hardware_addr,
+            source_protocol_addr,
+            target_hardware_addr: match operation {
+                ArpOperation::Request => EthernetAddress([0; 6]),
+                _ => target_hardware_addr,
+            },
+            target_protocol_addr,
+        }
+        .emit(&mut ArpPacket::new_unchecked(frame.payload_mut()));
+    }
+}
+
+/// Device receiving a single frame, used to feed ARP replies to smoltcp.
+struct Injector([u8; ARP_FRAME_LEN]);
+
+impl Device for Injector {
+    type RxToken<'a> = InjectorRxToken<'a>;
+    type TxToken<'a> = InjectorTxToken;
+
+    fn receive(&mut self, _timestamp: smoltcp::time::Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
+        Some((InjectorRxToken(&self.0), InjectorTxToken))
+    }
+
+    fn transmit(&mut self, _timestamp: smoltcp::time::Instant) -> Option<Self::TxToken<'_>> {
+        None
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut caps = DeviceCapabilities::default();
+        caps.medium = Medium::Ethernet;
+        caps.max_transmission_unit = ARP_FRAME_LEN;
+        caps
+    }
+}
+
+struct InjectorRxToken<'a>(&'a [u8]);
+
+impl phy::RxToken for InjectorRxToken<'_> {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(self.0)
+    }
+}
+
+struct InjectorTxToken;
+
+impl phy::TxToken for InjectorTxToken {
+    fn consume<R, F>(self, _len: usize, _f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        // smoltcp doesn't answer ARP replies.
+        unreachable!()
+    }
+}
@@ -5,12 +5,12 @@ use core::mem;
 use core::task::{Context, Poll};
 
 use embassy_net_driver::Driver;
-use smoltcp::iface::{Interface, SocketHandle};
+use smoltcp::iface::Interface;
 use smoltcp::socket::raw;
 pub use smoltcp::socket::raw::PacketMetadata;
 pub use smoltcp::wire::{IpProtocol, IpVersion};
 
-use crate::Stack;
+use crate::{SocketHandle, Stack};
 
 /// Error returned by [`RawSocket::recv`] and [`RawSocket::send`].
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
@@ -28,6 +28,8 @@ pub struct RawSocket<'a> {
 
 impl<'a> RawSocket<'a> {
     /// Create a new Raw socket using the provided stack and buffers.
+    ///
+    /// Panics if the stack has no room for another socket, see [`StackResources`](crate::StackResources).
     pub fn new<D: Driver>(
         stack: Stack<'a>,
         ip_version: IpVersion,
@@ -42,7 +44,7 @@ impl<'a> RawSocket<'a> {
             let rx_buffer: &'static mut [u8] = unsafe { mem::transmute(rx_buffer) };
             let tx_meta: &'static mut [PacketMetadata] = unsafe { mem::transmute(tx_meta) };
             let tx_buffer: &'static mut [u8] = unsafe { mem::transmute(tx_buffer) };
-            i.sockets.add(raw::Socket::new(
+            i.add_socket(raw::Socket::new(
                 ip_version,
                 ip_protocol,
                 raw::PacketBuffer::new(rx_meta, rx_buffer),
@@ -55,9 +57,9 @@ impl<'a> RawSocket<'a> {
 
     fn with_mut<R>(&self, f: impl FnOnce(&mut raw::Socket, &mut Interface) -> R) -> R {
         self.stack.with_mut(|i| {
-            let socket = i.sockets.get_mut::<raw::Socket>(self.handle);
-            let res = f(socket, &mut i.iface);
-            i.waker.wake();
+            let (socket, iface) = i.socket_mut::<raw::Socket>(self.handle);
+            let res = f(socket, iface);
+            i.wake(self.handle);
             res
         })
     }
@@ -181,7 +183,7 @@ impl<'a> RawSocket<'a> {
 
 impl Drop for RawSocket<'_> {
     fn drop(&mut self) {
-        self.stack.with_mut(|i| i.sockets.remove(self.handle));
+        self.stack.with_mut(|i| i.remove_socket(self.handle));
     }
 }
 
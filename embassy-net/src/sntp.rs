@@ -0,0 +1,518 @@
+//! SNTP client (RFC 4330).
+//!
+//! [`Sntp`] periodically queries a time server and maintains the offset between
+//! [`embassy_time::Instant`] and UTC. The wall-clock time is read with [`Clock::now_utc`].
+
+use core::cell::Cell;
+
+use embassy_time::{with_deadline, Duration, Instant, Timer};
+use smoltcp::wire::IpAddress;
+
+use crate::udp::{PacketMetadata, RecvError, SendError, UdpSocket};
+use crate::Stack;
+
+/// NTP port.
+pub const NTP_PORT: u16 = 123;
+
+const PACKET_LEN: usize = 48;
+/// Maximum length of a response, with a key identifier and an SHA-1 MAC.
+const MAX_PACKET_LEN: usize = PACKET_LEN + 24;
+
+/// Seconds between the NTP epoch (1900) and the Unix epoch (1970).
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// Number of samples kept for filtering.
+const FILTER_LEN: usize = 8;
+
+/// SNTP errors.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// The server name couldn't be resolved.
+    Dns,
+    /// Sending the request failed.
+    Send(SendError),
+    /// No valid response was received in time.
+    Timeout,
+    /// The server is not synchronized, or asked us to stop querying it (kiss-o'-death).
+    Unsynchronized,
+}
+
+/// SNTP client configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct SntpConfig {
+    /// Server host name or IP address.
+    ///
+    /// Host names require the `dns` feature.
+    pub server: &'static str,
+    /// Server port. This is almost always 123.
+    pub port: u16,
+    /// Interval between queries once synchronized.
+    pub poll_interval: Duration,
+    /// How long to wait for a response.
+    pub timeout: Duration,
+    /// Maximum rate at which the clock is slewed, in parts per million.
+    ///
+    /// When set, small corrections are applied gradually, so the wall-clock time never jumps and
+    /// never goes backwards. It must be lower than 1 000 000, as slewing the clock by a second per
+    /// second or more would stop it or make it go backwards. When `None`, every correction is
+    /// applied immediately.
+    pub slew_rate_ppm: Option<u32>,
+    /// Corrections larger than this are applied immediately, even when slewing.
+    pub step_threshold: Duration,
+    /// Maximum age of the samples the offset is chosen from.
+    ///
+    /// The offset is taken from the recent sample with the shortest round-trip delay. The clock
+    /// drifts after a sample is received, so older samples are discarded even if their delay was
+    /// lower.
+    pub max_sample_age: Duration,
+}
+
+impl SntpConfig {
+    /// Create a configuration querying `server` every 15 minutes, stepping the clock.
+    pub const fn new(server: &'static str) -> Self {
+        Self {
+            server,
+            port: NTP_PORT,
+            poll_interval: Duration::from_secs(15 * 60),
+            timeout: Duration::from_secs(5),
+            slew_rate_ppm: None,
+            step_threshold: Duration::from_millis(128),
+            max_sample_age: Duration::from_secs(60 * 60),
+        }
+    }
+}
+
+/// Result of a single query.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Sample {
+    /// Offset of UTC relative to [`Instant`], in microseconds.
+    ///
+    /// UTC time is `Instant::now().as_micros() + offset`.
+    pub offset: i64,
+    /// Round-trip delay to the server, excluding the server's processing time.
+    pub delay: Duration,
+}
+
+/// Clock offset, possibly being slewed towards a target.
+#[derive(Debug, Clone, Copy)]
+struct Offset {
+    /// Offset at `since`.
+    base: i64,
+    since: Instant,
+    target: i64,
+    rate_ppm: u64,
+}
+
+impl Offset {
+    fn at(&self, now: Instant) -> i64 {
+        let elapsed = now.saturating_duration_since(self.since).as_micros();
+        let max = (elapsed.saturating_mul(self.rate_ppm) / 1_000_000).min(i64::MAX as u64) as i64;
+        self.base + (self.target - self.base).clamp(-max, max)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ClockInner {
+    offset: Offset,
+    last_sync: Instant,
+}
+
+/// State for an [`Sntp`] client.
+pub struct SntpState {
+    clock: Cell<Option<ClockInner>>,
+    rx_meta: [PacketMetadata; 2],
+    rx_buffer: [u8; 2 * MAX_PACKET_LEN],
+    tx_meta: [PacketMetadata; 1],
+    tx_buffer: [u8; PACKET_LEN],
+}
+
+impl SntpState {
+    /// Create a new `SntpState`.
+    pub const fn new() -> Self {
+        Self {
+            clock: Cell::new(None),
+            rx_meta: [PacketMetadata::EMPTY; 2],
+            rx_buffer: [0; 2 * MAX_PACKET_LEN],
+            tx_meta: [PacketMetadata::EMPTY; 1],
+            tx_buffer: [0; PACKET_LEN],
+        }
+    }
+}
+
+/// Wall-clock time maintained by an [`Sntp`] client.
+///
+/// Obtained with [`Sntp::clock`]. It's `Copy`, so it can be passed to other tasks by value.
+#[derive(Clone, Copy)]
+pub struct Clock<'d> {
+    state: &'d Cell<Option<ClockInner>>,
+}
+
+impl Clock<'_> {
+    /// Get the current UTC time, as the time elapsed since the Unix epoch.
+    ///
+    /// Returns `None` until the first successful query.
+    pub fn now_utc(&self) -> Option<Duration> {
+        self.to_utc(Instant::now())
+    }
+
+    /// Convert an `Instant` to UTC, as the time elapsed since the Unix epoch.
+    ///
+    /// Returns `None` until the first successful query.
+    pub fn to_utc(&self, instant: Instant) -> Option<Duration> {
+        let inner = self.state.get()?;
+        let micros = instant.as_micros() as i64 + inner.offset.at(instant);
+        Some(Duration::from_micros(micros.max(0) as u64))
+    }
+
+    /// Get the current offset of UTC relative to [`Instant`], in microseconds.
+    pub fn offset(&self) -> Option<i64> {
+        Some(self.state.get()?.offset.at(Instant::now()))
+    }
+
+    /// Get when the clock was last synchronized.
+    pub fn last_sync(&self) -> Option<Instant> {
+        Some(self.state.get()?.last_sync)
+    }
+
+    /// Whether the clock has been synchronized at least once.
+    pub fn is_synchronized(&self) -> bool {
+        self.state.get().is_some()
+    }
+}
+
+/// An SNTP client.
+///
+/// You must call [`Sntp::run()`] in a background task to keep the clock synchronized.
+pub struct Sntp<'d> {
+    stack: Stack<'d>,
+    socket: UdpSocket<'d>,
+    config: SntpConfig,
+    clock: Clock<'d>,
+    /// Recent samples, with the time they were received.
+    samples: [Option<(Sample, Instant)>; FILTER_LEN],
+    next_sample: usize,
+}
+
+impl<'d> Sntp<'d> {
+    /// Create a new SNTP client.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `config.slew_rate_ppm` is 1 000 000 or more.
+    pub fn new(stack: Stack<'d>, state: &'d mut SntpState, config: SntpConfig) -> Self {
+        assert!(
+            config.slew_rate_ppm.is_none_or(|rate| rate < 1_000_000),
+            "slew rate must be lower than 1 000 000 ppm"
+        );
+
+        let mut socket = UdpSocket::new(
+            stack,
+            &mut state.rx_meta,
+            &mut state.rx_buffer,
+            &mut state.tx_meta,
+            &mut state.tx_buffer,
+        );
+        unwrap!(socket.bind(0));
+
+        Self {
+            stack,
+            socket,
+            config,
+            clock: Clock { state: &state.clock },
+            samples: [None; FILTER_LEN],
+            next_sample: 0,
+        }
+    }
+
+    /// Get a handle to the wall-clock time.
+    pub fn clock(&self) -> Clock<'d> {
+        self.clock
+    }
+
+    /// Run the client, querying the server periodically.
+    ///
+    /// Failed queries are retried with an exponential backoff, up to the poll interval.
+    pub async fn run(&mut self) -> ! {
+        let mut retry = Duration::from_secs(2);
+        loop {
+            self.stack.wait_config_up().await;
+            match self.sync().await {
+                Ok(_) => {
+                    retry = Duration::from_secs(2);
+                    Timer::after(self.config.poll_interval).await;
+                }
+                Err(e) => {
+                    warn!("SNTP query failed: {:?}", e);
+                    Timer::after(retry).await;
+                    retry = (retry * 2).min(self.config.poll_interval);
+                }
+            }
+        }
+    }
+
+    /// Query the server once, and update the clock with the result.
+    pub async fn sync(&mut self) -> Result<Sample, Error> {
+        let sample = self.query().await?;
+        debug!("SNTP offset {}us, delay {}us", sample.offset, sample.delay.as_micros());
+
+        let now = Instant::now();
+        let Some(inner) = self.clock.state.get() else {
+            // First synchronization.
+            self.set_offset(now, sample.offset);
+            return Ok(sample);
+        };
+
+        let current = inner.offset.at(now);
+        if (sample.offset - current).unsigned_abs() > self.config.step_threshold.as_micros() {
+            info!("SNTP stepping clock by {}us", sample.offset - current);
+            self.set_offset(now, sample.offset);
+            return Ok(sample);
+        }
+
+        // The offset measured with the shortest round-trip is the most accurate (RFC 5905 section 10),
+        // as long as the clock didn't drift much since.
+        self.samples[self.next_sample] = Some((sample, now));
+        self.next_sample = (self.next_sample + 1) % FILTER_LEN;
+        let (best, _) = unwrap!(self
+            .samples
+            .iter()
+            .flatten()
+            .filter(|(_, received)| now.saturating_duration_since(*received) <= self.config.max_sample_age)
+            .min_by_key(|(s, _)| s.delay));
+
+        let offset = match self.config.slew_rate_ppm {
+            Some(rate_ppm) => Offset {
+                base: current,
+                since: now,
+                target: best.offset,
+                rate_ppm: rate_ppm as u64,
+            },
+            None => step(now, best.offset),
+        };
+        self.clock.state.set(Some(ClockInner { offset, last_sync: now }));
+        Ok(sample)
+    }
+
+    fn set_offset(&mut self, now: Instant, offset: i64) {
+        self.samples = [None; FILTER_LEN];
+        self.clock.state.set(Some(ClockInner {
+            offset: step(now, offset),
+            last_sync: now,
+        }));
+    }
+
+    /// Query the server once, without updating the clock.
+    pub async fn query(&mut self) -> Result<Sample, Error> {
+        let server = self.resolve().await?;
+
+        // Any unique value works as the transmit timestamp, the server echoes it back.
+        let t1 = Instant::now();
+        let nonce = t1.as_ticks();
+        let mut request = [0; PACKET_LEN];
+        // LI = 0, VN = 4, Mode = 3 (client)
+        request[0] = 0x23;
+        request[40..48].copy_from_slice(&nonce.to_be_bytes());
+
+        self.socket
+            .send_to(&request, (server, self.config.port))
+            .await
+            .map_err(Error::Send)?;
+
+        let deadline = t1 + self.config.timeout;
+        loop {
+            let mut buf = [0; MAX_PACKET_LEN];
+            let res = with_deadline(deadline, self.socket.recv_from(&mut buf)).await;
+            let t4 = Instant::now();
+            let (len, meta) = match res {
+                Err(_) => return Err(Error::Timeout),
+                Ok(Ok(r)) => r,
+                Ok(Err(RecvError::Truncated)) => continue,
+            };
+            // Stale responses to previous queries are rejected by `parse_response`.
+            if meta.endpoint.addr != server || len < PACKET_LEN {
+                continue;
+            }
+            // Extension fields and the MAC, if any, are ignored.
+            match parse_response(unwrap!(buf[..PACKET_LEN].try_into()), nonce) {
+                Some(Ok((t2, t3))) => {
+                    let (t1, t4) = (t1.as_micros() as i64, t4.as_micros() as i64);
+                    return Ok(Sample {
+                        offset: ((t2 - t1) + (t3 - t4)) / 2,
+                        delay: Duration::from_micros(((t4 - t1) - (t3 - t2)).max(0) as u64),
+                    });
+                }
+                Some(Err(e)) => return Err(e),
+                None => continue,
+            }
+        }
+    }
+
+    async fn resolve(&self) -> Result<IpAddress, Error> {
+        #[cfg(feature = "dns")]
+        {
+            #[cfg(feature = "proto-ipv4")]
+            let qtype = crate::dns::DnsQueryType::A;
+            #[cfg(not(feature = "proto-ipv4"))]
+            let qtype = crate::dns::DnsQueryType::Aaaa;
+            let addrs = self
+                .stack
+                .dns_query(self.config.server, qtype)
+                .await
+                .map_err(|_| Error::Dns)?;
+            addrs.first().copied().ok_or(Error::Dns)
+        }
+        #[cfg(not(feature = "dns"))]
+        {
+            #[cfg(feature = "proto-ipv4")]
+            if let Ok(ip) = self.config.server.parse() {
+                return Ok(IpAddress::Ipv4(ip));
+            }
+            #[cfg(feature = "proto-ipv6")]
+            if let Ok(ip) = self.config.server.parse() {
+                return Ok(IpAddress::Ipv6(ip));
+            }
+            Err(Error::Dns)
+        }
+    }
+}
+
+fn step(now: Instant, offset: i64) -> Offset {
+    Offset {
+        base: offset,
+        since: now,
+        target: offset,
+        rate_ppm: 0,
+    }
+}
+
+/// Validate a server response to the request with transmit timestamp `nonce`.
+///
+/// Returns the server receive and transmit timestamps, in microseconds since the Unix epoch.
+/// Returns `None` for packets that aren't a response to our request, which are ignored.
+fn parse_response(buf: &[u8; PACKET_LEN], nonce: u64) -> Option<Result<(i64, i64), Error>> {
+    let li = buf[0] >> 6;
+    let version = (buf[0] >> 3) & 0x7;
+    let mode = buf[0] & 0x7;
+    let stratum = buf[1];
+    let originate = u64::from_be_bytes(unwrap!(buf[24..32].try_into()));
+
+    if mode != 4 || !(3..=4).contains(&version) || originate != nonce {
+        return None;
+    }
+    if li == 3 || stratum == 0 || stratum > 15 {
+        return Some(Err(Error::Unsynchronized));
+    }
+
+    let receive = u64::from_be_bytes(unwrap!(buf[32..40].try_into()));
+    let transmit = u64::from_be_bytes(unwrap!(buf[40..48].try_into()));
+    if transmit == 0 {
+        return None;
+    }
+    // Timestamps before the Unix epoch are bogus, and can't be converted.
+    let (Some(receive), Some(transmit)) = (ntp_to_unix_micros(receive), ntp_to_unix_micros(transmit)) else {
+        return None;
+    };
+    Some(Ok((receive, transmit)))
+}
+
+/// Convert an NTP timestamp to microseconds since the Unix epoch.
+///
+/// Returns `None` for timestamps before the Unix epoch.
+fn ntp_to_unix_micros(timestamp: u64) -> Option<i64> {
+    let mut secs = timestamp >> 32;
+    // Timestamps with the top bit clear are in era 1, which starts in 2036 (RFC 4330 section 3).
+    if secs & 0x8000_0000 == 0 {
+        secs += 1 << 32;
+    }
+    let frac = ((timestamp & 0xffff_ffff) * 1_000_000) >> 32;
+    Some((secs.checked_sub(NTP_UNIX_OFFSET)? * 1_000_000 + frac) as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2025-01-01T00:00:00Z
+    const UNIX_SECS: u64 = 1_735_689_600;
+
+    #[test]
+    fn ntp_to_unix() {
+        let ntp_secs = UNIX_SECS + NTP_UNIX_OFFSET;
+        assert_eq!(ntp_to_unix_micros(ntp_secs << 32), Some(UNIX_SECS as i64 * 1_000_000));
+        assert_eq!(
+            ntp_to_unix_micros((ntp_secs << 32) | 0x8000_0000),
+            Some(UNIX_SECS as i64 * 1_000_000 + 500_000)
+        );
+        assert_eq!(ntp_to_unix_micros(NTP_UNIX_OFFSET << 32), Some(0));
+
+        // Era 1 starts at 2036-02-07T06:28:16Z.
+        assert_eq!(ntp_to_unix_micros(0), Some(2_085_978_496 * 1_000_000));
+        assert_eq!(ntp_to_unix_micros(1 << 32), Some(2_085_978_497 * 1_000_000));
+
+        // Before the Unix epoch.
+        assert_eq!(ntp_to_unix_micros((NTP_UNIX_OFFSET - 1) << 32), None);
+        assert_eq!(ntp_to_unix_micros(0x8000_0000 << 32), None);
+    }
+
+    #[test]
+    fn response_before_unix_epoch() {
+        let nonce = 0x1234;
+        let mut response = [0; PACKET_LEN];
+        // LI = 0, VN = 4, Mode = 4 (server)
+        response[0] = 0x24;
+        response[1] = 1;
+        response[24..32].copy_from_slice(&u64::to_be_bytes(nonce));
+        let timestamp = (UNIX_SECS + NTP_UNIX_OFFSET) << 32;
+        response[32..40].copy_from_slice(&timestamp.to_be_bytes());
+        response[40..48].copy_from_slice(&timestamp.to_be_bytes());
+        let micros = UNIX_SECS as i64 * 1_000_000;
+        assert_eq!(parse_response(&response, nonce), Some(Ok((micros, micros))));
+
+        response[40..48].copy_from_slice(&u64::to_be_bytes(0x8000_0000 << 32));
+        assert_eq!(parse_response(&response, nonce), None);
+    }
+
+    #[test]
+    fn slew() {
+        let since = Instant::from_secs(100);
+        let offset = Offset {
+            base: 0,
+            since,
+            target: 1000,
+            rate_ppm: 500,
+        };
+        assert_eq!(offset.at(since - Duration::from_secs(1)), 0);
+        assert_eq!(offset.at(since), 0);
+        assert_eq!(offset.at(since + Duration::from_secs(1)), 500);
+        assert_eq!(offset.at(since + Duration::from_secs(2)), 1000);
+        assert_eq!(offset.at(since + Duration::from_secs(10)), 1000);
+        assert_eq!(offset.at(Instant::MAX), 1000);
+
+        // Slowing the clock down never makes it go backwards.
+        let offset = Offset {
+            base: 0,
+            since,
+            target: -1_000_000,
+            rate_ppm: 999_999,
+        };
+        let mut last = 0;
+        for ms in 0..1100 {
+            let now = since + Duration::from_millis(ms);
+            let utc = now.as_micros() as i64 + offset.at(now);
+            assert!(utc >= last);
+            last = utc;
+        }
+        assert_eq!(offset.at(since + Duration::from_secs(2)), -1_000_000);
+    }
+
+    #[test]
+    fn step_offset() {
+        let now = Instant::from_secs(100);
+        let offset = step(now, -42);
+        assert_eq!(offset.at(now), -42);
+        assert_eq!(offset.at(now + Duration::from_secs(100)), -42);
+    }
+}
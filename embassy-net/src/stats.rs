@@ -0,0 +1,447 @@
+//! Interface and socket statistics.
+
+use core::mem::MaybeUninit;
+
+use heapless::Vec;
+use smoltcp::config::IFACE_MAX_ADDR_COUNT;
+use smoltcp::iface::{Interface, SocketHandle, SocketSet};
+use smoltcp::phy::{ChecksumCapabilities, Medium};
+#[cfg(feature = "proto-ipv4")]
+use smoltcp::wire::Ipv4Packet;
+#[cfg(feature = "proto-ipv6")]
+use smoltcp::wire::Ipv6Packet;
+#[cfg(feature = "medium-ethernet")]
+use smoltcp::wire::{EthernetFrame, EthernetProtocol};
+use smoltcp::wire::{IpAddress, IpCidr, IpEndpoint, IpListenEndpoint, IpProtocol, IpVersion, TcpPacket, UdpPacket};
+
+/// Counters of a network interface, returned by [`Stack::stats()`](crate::Stack::stats).
+///
+/// Counters start at zero when the stack is created, and are updated by [`Runner::run()`](crate::Runner::run).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct InterfaceStats {
+    /// Packets received from the driver.
+    pub rx_packets: u64,
+    /// Bytes received from the driver, including link-layer headers.
+    pub rx_bytes: u64,
+    /// Packets transmitted to the driver.
+    pub tx_packets: u64,
+    /// Bytes transmitted to the driver, including link-layer headers.
+    pub tx_bytes: u64,
+    /// Received packets dropped because there was no room for them: UDP datagrams for a socket
+    /// whose receive buffer was full, and TCP segments carrying data for a connection whose
+    /// receive window was closed.
+    pub rx_no_buffer: u64,
+    /// Transmissions delayed because the driver had no transmit buffer available.
+    pub tx_no_buffer: u64,
+    /// Received packets dropped because of an invalid IPv4 header, TCP or UDP checksum.
+    ///
+    /// Only checksums that are verified in software, according to the driver's
+    /// [`Capabilities`](embassy_net_driver::Capabilities), are counted.
+    pub rx_checksum_errors: u64,
+    /// TCP segments sent again, carrying data or SYN/FIN flags that were already sent.
+    pub tcp_retransmits: u64,
+    /// TCP resets received.
+    pub tcp_rx_resets: u64,
+    /// TCP resets sent.
+    pub tcp_tx_resets: u64,
+}
+
+/// Counters of a socket, returned by [`TcpSocket::stats()`](crate::tcp::TcpSocket::stats) and
+/// [`UdpSocket::stats()`](crate::udp::UdpSocket::stats).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[non_exhaustive]
+pub struct SocketStats {
+    /// Payload bytes read from the socket.
+    pub rx_bytes: u64,
+    /// Payload bytes written to the socket.
+    pub tx_bytes: u64,
+}
+
+/// Outgoing TCP flow, tracked to detect retransmissions.
+#[derive(Clone, Copy)]
+pub(crate) struct Flow {
+    local: IpEndpoint,
+    remote: IpEndpoint,
+    /// Sequence number following the highest sequence number sent.
+    snd_max: u32,
+}
+
+/// Room in the receive buffer of a socket when a poll starts, to count the packets it drops.
+#[derive(Clone, Copy)]
+pub(crate) enum RxRoom {
+    /// A UDP socket, with the datagrams received for it during the poll.
+    #[cfg_attr(not(feature = "udp"), allow(dead_code))]
+    Udp {
+        handle: SocketHandle,
+        endpoint: IpListenEndpoint,
+        queued: usize,
+        datagrams: usize,
+        bytes: usize,
+    },
+    /// A TCP connection whose receive window is closed.
+    #[cfg_attr(not(feature = "tcp"), allow(dead_code))]
+    TcpFull { local: IpEndpoint, remote: IpEndpoint },
+}
+
+pub(crate) struct StatsResources<const SOCK: usize> {
+    sockets: MaybeUninit<[Option<(SocketHandle, SocketStats)>; SOCK]>,
+    flows: MaybeUninit<[Option<Flow>; SOCK]>,
+    rx_room: MaybeUninit<[Option<RxRoom>; SOCK]>,
+}
+
+impl<const SOCK: usize> StatsResources<SOCK> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            sockets: MaybeUninit::uninit(),
+            flows: MaybeUninit::uninit(),
+            rx_room: MaybeUninit::uninit(),
+        }
+    }
+}
+
+pub(crate) struct Stats {
+    pub(crate) iface: InterfaceStats,
+    medium: Medium,
+    checksum: ChecksumCapabilities,
+    sockets: &'static mut [Option<(SocketHandle, SocketStats)>], // Lifetime type-erased.
+    flows: &'static mut [Option<Flow>],                          // Lifetime type-erased.
+    next_flow: usize,
+    rx_room: &'static mut [Option<RxRoom>], // Lifetime type-erased.
+    /// Addresses of the interface when the poll started.
+    addrs: Vec<IpCidr, IFACE_MAX_ADDR_COUNT>,
+}
+
+impl Stats {
+    pub(crate) fn new<const SOCK: usize>(resources: &mut StatsResources<SOCK>, medium: Medium) -> Self {
+        unsafe fn transmute_slice<T>(x: &mut [T]) -> &'static mut [T] {
+            core::mem::transmute(x)
+        }
+
+        Self {
+            iface: InterfaceStats::default(),
+            medium,
+            checksum: ChecksumCapabilities::default(),
+            sockets: unsafe { transmute_slice(resources.sockets.write([None; SOCK])) },
+            flows: unsafe { transmute_slice(resources.flows.write([None; SOCK])) },
+            next_flow: 0,
+            rx_room: unsafe { transmute_slice(resources.rx_room.write([None; SOCK])) },
+            addrs: Vec::new(),
+        }
+    }
+
+    /// Record the room left in the receive buffer of the sockets, before `iface` is polled.
+    #[cfg_attr(not(feature = "udp"), allow(unused_variables))]
+    pub(crate) fn start_poll(&mut self, iface: &Interface, sockets: &SocketSet<'_>, checksum: ChecksumCapabilities) {
+        self.checksum = checksum;
+        self.addrs = iface.ip_addrs().iter().copied().collect();
+
+        self.rx_room.fill(None);
+        let mut slots = self.rx_room.iter_mut();
+        for (handle, socket) in sockets.iter() {
+            let room = match socket {
+                #[cfg(feature = "udp")]
+                smoltcp::socket::Socket::Udp(s) if s.is_open() => Some(RxRoom::Udp {
+                    handle,
+                    endpoint: s.endpoint(),
+                    queued: s.recv_queue(),
+                    datagrams: 0,
+                    bytes: 0,
+                }),
+                #[cfg(feature = "tcp")]
+                smoltcp::socket::Socket::Tcp(s) if s.recv_queue() == s.recv_capacity() => {
+                    match (s.local_endpoint(), s.remote_endpoint()) {
+                        (Some(local), Some(remote)) => Some(RxRoom::TcpFull { local, remote }),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            };
+            // There are as many slots as sockets in the socket set.
+            if let (Some(room), Some(slot)) = (room, slots.next()) {
+                *slot = Some(room);
+            }
+        }
+    }
+
+    /// Count the UDP datagrams that the sockets had no room for, once `iface` was polled.
+    #[cfg_attr(not(feature = "udp"), allow(unused_variables))]
+    pub(crate) fn end_poll(&mut self, sockets: &SocketSet<'_>) {
+        for room in self.rx_room.iter_mut() {
+            #[cfg(feature = "udp")]
+            if let Some(RxRoom::Udp {
+                handle,
+                queued,
+                datagrams,
+                bytes,
+                ..
+            }) = *room
+            {
+                let accepted = sockets
+                    .get::<smoltcp::socket::udp::Socket>(handle)
+                    .recv_queue()
+                    .saturating_sub(queued)
+                    .min(bytes);
+                if accepted < bytes {
+                    // The socket isn't read during the poll, so it mostly accepts the first
+                    // datagrams: assume they're all of the same size.
+                    self.iface.rx_no_buffer += (datagrams - datagrams * accepted / bytes) as u64;
+                }
+            }
+            *room = None;
+        }
+    }
+
+    pub(crate) fn add_socket(&mut self, handle: SocketHandle, stats: SocketStats) {
+        // There are as many slots as sockets in the socket set.
+        if let Some(slot) = self.sockets.iter_mut().find(|s| s.is_none()) {
+            *slot = Some((handle, stats));
+        }
+    }
+
+    pub(crate) fn remove_socket(&mut self, handle: SocketHandle) {
+        for slot in self.sockets.iter_mut() {
+            if matches!(slot, Some((h, _)) if *h == handle) {
+                *slot = None;
+            }
+        }
+    }
+
+    pub(crate) fn socket(&self, handle: SocketHandle) -> SocketStats {
+        self.sockets
+            .iter()
+            .flatten()
+            .find(|(h, _)| *h == handle)
+            .map(|(_, s)| *s)
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn count_socket(&mut self, handle: SocketHandle, rx_bytes: usize, tx_bytes: usize) {
+        if let Some((_, stats)) = self.sockets.iter_mut().flatten().find(|(h, _)| *h == handle) {
+            stats.rx_bytes += rx_bytes as u64;
+            stats.tx_bytes += tx_bytes as u64;
+        }
+    }
+
+    pub(crate) fn rx_packet(&mut self, frame: &[u8]) {
+        self.iface.rx_packets += 1;
+        self.iface.rx_bytes += frame.len() as u64;
+        self.inspect(frame, true);
+    }
+
+    pub(crate) fn tx_packet(&mut self, frame: &[u8]) {
+        self.iface.tx_packets += 1;
+        self.iface.tx_bytes += frame.len() as u64;
+        self.inspect(frame, false);
+    }
+
+    pub(crate) fn tx_no_buffer(&mut self) {
+        self.iface.tx_no_buffer += 1;
+    }
+
+    #[cfg_attr(
+        not(any(feature = "medium-ethernet", feature = "medium-ip")),
+        allow(unused_variables)
+    )]
+    fn inspect(&mut self, frame: &[u8], rx: bool) {
+        let packet = match self.medium {
+            #[cfg(feature = "medium-ethernet")]
+            Medium::Ethernet => match EthernetFrame::new_checked(frame) {
+                Ok(frame) if matches!(frame.ethertype(), EthernetProtocol::Ipv4 | EthernetProtocol::Ipv6) => {
+                    Some(frame.payload())
+                }
+                _ => None,
+            },
+            #[cfg(feature = "medium-ip")]
+            Medium::Ip => Some(frame),
+            // 6LoWPAN compressed headers are not inspected.
+            #[allow(unreachable_patterns)]
+            _ => None,
+        };
+        let Some(packet) = packet.filter(|p: &&[u8]| !p.is_empty()) else {
+            return;
+        };
+
+        let (src, dst, protocol, payload): (IpAddress, IpAddress, _, _) = match IpVersion::of_packet(packet) {
+            #[cfg(feature = "proto-ipv4")]
+            Ok(IpVersion::Ipv4) => {
+                let Ok(packet) = Ipv4Packet::new_checked(packet) else {
+                    return;
+                };
+                if rx && self.checksum.ipv4.rx() && !packet.verify_checksum() {
+                    self.iface.rx_checksum_errors += 1;
+                    return;
+                }
+                // Fragments are only reassembled by smoltcp, and are not inspected.
+                if packet.more_frags() || packet.frag_offset() != 0 {
+                    return;
+                }
+                (
+                    packet.src_addr().into(),
+                    packet.dst_addr().into(),
+                    packet.next_header(),
+                    packet.payload(),
+                )
+            }
+            #[cfg(feature = "proto-ipv6")]
+            Ok(IpVersion::Ipv6) => {
+                let Ok(packet) = Ipv6Packet::new_checked(packet) else {
+                    return;
+                };
+                (
+                    packet.src_addr().into(),
+                    packet.dst_addr().into(),
+                    packet.next_header(),
+                    packet.payload(),
+                )
+            }
+            #[allow(unreachable_patterns)]
+            _ => return,
+        };
+
+        match protocol {
+            IpProtocol::Tcp => {
+                let Ok(segment) = TcpPacket::new_checked(payload) else {
+                    return;
+                };
+                if rx && self.checksum.tcp.rx() && !segment.verify_checksum(&src, &dst) {
+                    self.iface.rx_checksum_errors += 1;
+                    return;
+                }
+                let (src, dst) = (
+                    IpEndpoint::new(src, segment.src_port()),
+                    IpEndpoint::new(dst, segment.dst_port()),
+                );
+                if !rx {
+                    self.tcp_tx(src, dst, &segment);
+                } else if segment.rst() {
+                    self.iface.tcp_rx_resets += 1;
+                    self.remove_flow(dst, src);
+                } else if !segment.payload().is_empty() && self.tcp_full(dst, src) {
+                    self.iface.rx_no_buffer += 1;
+                }
+            }
+            IpProtocol::Udp if rx => {
+                let Ok(datagram) = UdpPacket::new_checked(payload) else {
+                    return;
+                };
+                if self.checksum.udp.rx() && !datagram.verify_checksum(&src, &dst) {
+                    self.iface.rx_checksum_errors += 1;
+                    return;
+                }
+                // Empty datagrams take no room in the buffer, so their drops can't be counted.
+                if !datagram.payload().is_empty() && self.is_local(dst) {
+                    self.udp_rx(dst, datagram.dst_port(), datagram.payload().len());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Returns whether smoltcp delivers packets sent to `addr` to the sockets.
+    fn is_local(&self, addr: IpAddress) -> bool {
+        self.is_shared(addr) || self.addrs.iter().any(|cidr| cidr.address() == addr)
+    }
+
+    /// Returns whether `addr` is a broadcast or multicast address, which all sockets accept.
+    fn is_shared(&self, addr: IpAddress) -> bool {
+        addr.is_broadcast()
+            || addr.is_multicast()
+            || self.addrs.iter().any(|cidr| match cidr {
+                #[cfg(feature = "proto-ipv4")]
+                IpCidr::Ipv4(cidr) => cidr.broadcast().map(IpAddress::Ipv4) == Some(addr),
+                #[allow(unreachable_patterns)]
+                _ => false,
+            })
+    }
+
+    /// Returns whether the receive window of the connection was closed when the poll started.
+    fn tcp_full(&self, local: IpEndpoint, remote: IpEndpoint) -> bool {
+        self.rx_room
+            .iter()
+            .flatten()
+            .any(|room| matches!(room, RxRoom::TcpFull { local: l, remote: r } if *l == local && *r == remote))
+    }
+
+    /// Add a datagram to the first UDP socket accepting it, like smoltcp does.
+    fn udp_rx(&mut self, dst: IpAddress, port: u16, len: usize) {
+        let shared = self.is_shared(dst);
+        for room in self.rx_room.iter_mut().flatten() {
+            if let RxRoom::Udp {
+                endpoint,
+                datagrams,
+                bytes,
+                ..
+            } = room
+            {
+                if endpoint.port == port && (endpoint.addr.is_none() || endpoint.addr == Some(dst) || shared) {
+                    *datagrams += 1;
+                    *bytes += len;
+                    return;
+                }
+            }
+        }
+    }
+
+    fn tcp_tx(&mut self, local: IpEndpoint, remote: IpEndpoint, segment: &TcpPacket<&[u8]>) {
+        if segment.rst() {
+            self.iface.tcp_tx_resets += 1;
+            self.remove_flow(local, remote);
+            return;
+        }
+
+        let len = segment.payload().len() + segment.syn() as usize + segment.fin() as usize;
+        if len == 0 {
+            return;
+        }
+        let seq = segment.seq_number().0 as u32;
+        let end = seq.wrapping_add(len as u32);
+
+        let flow = self
+            .flows
+            .iter_mut()
+            .flatten()
+            .find(|f| f.local == local && f.remote == remote);
+        match flow {
+            // A SYN either starts a new connection, or is retransmitted with the same sequence number.
+            Some(flow) if segment.syn() => {
+                if flow.snd_max == end {
+                    self.iface.tcp_retransmits += 1;
+                }
+                flow.snd_max = end;
+            }
+            Some(flow) => {
+                if (seq.wrapping_sub(flow.snd_max) as i32) < 0 {
+                    self.iface.tcp_retransmits += 1;
+                }
+                if (end.wrapping_sub(flow.snd_max) as i32) > 0 {
+                    flow.snd_max = end;
+                }
+            }
+            None => {
+                let flow = Some(Flow {
+                    local,
+                    remote,
+                    snd_max: end,
+                });
+                // Use a free slot, or evict flows in a round-robin fashion.
+                if let Some(slot) = self.flows.iter_mut().find(|f| f.is_none()) {
+                    *slot = flow;
+                } else if !self.flows.is_empty() {
+                    self.flows[self.next_flow] = flow;
+                    self.next_flow = (self.next_flow + 1) % self.flows.len();
+                }
+            }
+        }
+    }
+
+    fn remove_flow(&mut self, local: IpEndpoint, remote: IpEndpoint) {
+        for slot in self.flows.iter_mut() {
+            if matches!(slot, Some(f) if f.local == local && f.remote == remote) {
+                *slot = None;
+            }
+        }
+    }
+}
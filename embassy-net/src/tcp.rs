@@ -2,24 +2,24 @@
 //!
 //! # Listening
 //!
-//! `embassy-net` does not have a `TcpListener`. Instead, individual `TcpSocket`s can be put into
-//! listening mode by calling [`TcpSocket::accept`].
+//! Individual `TcpSocket`s can be put into listening mode by calling [`TcpSocket::accept`].
 //!
 //! Incoming connections when no socket is listening are rejected. To accept many incoming
-//! connections, create many sockets and put them all into listening mode.
+//! connections, create many sockets and put them all into listening mode, or use a
+//! [`TcpListener`](listener::TcpListener), which does this for you.
 
 use core::future::{poll_fn, Future};
 use core::mem;
 use core::task::{Context, Poll};
 
 use embassy_time::Duration;
-use smoltcp::iface::{Interface, SocketHandle};
+use smoltcp::iface::Interface;
 use smoltcp::socket::tcp;
 pub use smoltcp::socket::tcp::State;
 use smoltcp::wire::{IpEndpoint, IpListenEndpoint};
 
 use crate::time::duration_to_smoltcp;
-use crate::Stack;
+use crate::{InterfaceFull, InterfaceId, SocketHandle, Stack};
 
 /// Error returned by TcpSocket read/write functions.
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
@@ -60,6 +60,7 @@ pub enum AcceptError {
 /// A TCP socket.
 pub struct TcpSocket<'a> {
     io: TcpIo<'a>,
+    bound: bool,
 }
 
 /// The reader half of a TCP socket.
@@ -72,6 +73,16 @@ pub struct TcpWriter<'a> {
     io: TcpIo<'a>,
 }
 
+/// Copyable handle to a TCP socket, for both reading and writing.
+///
+/// Used by TLS connections, whose reader and writer halves each need a handle to the socket. The
+/// handle borrows the [`TcpSocket`], so that the socket outlives it.
+#[cfg(feature = "tls")]
+#[derive(Copy, Clone)]
+pub(crate) struct TcpHandle<'a> {
+    io: TcpIo<'a>,
+}
+
 impl<'a> TcpReader<'a> {
     /// Wait until the socket becomes readable.
     ///
@@ -175,11 +186,13 @@ impl<'a> TcpWriter<'a> {
 
 impl<'a> TcpSocket<'a> {
     /// Create a new TCP socket on the given stack, with the given buffers.
+    ///
+    /// Panics if the stack has no room for another socket, see [`StackResources`](crate::StackResources).
     pub fn new(stack: Stack<'a>, rx_buffer: &'a mut [u8], tx_buffer: &'a mut [u8]) -> Self {
         let handle = stack.with_mut(|i| {
             let rx_buffer: &'static mut [u8] = unsafe { mem::transmute(rx_buffer) };
             let tx_buffer: &'static mut [u8] = unsafe { mem::transmute(tx_buffer) };
-            i.sockets.add(tcp::Socket::new(
+            i.add_socket(tcp::Socket::new(
                 tcp::SocketBuffer::new(rx_buffer),
                 tcp::SocketBuffer::new(tx_buffer),
             ))
@@ -187,6 +200,7 @@ impl<'a> TcpSocket<'a> {
 
         Self {
             io: TcpIo { stack, handle },
+            bound: false,
         }
     }
 
@@ -234,17 +248,73 @@ impl<'a> TcpSocket<'a> {
         self.io.read_with(f).await
     }
 
+    /// Get a copyable handle to the socket.
+    #[cfg(feature = "tls")]
+    pub(crate) fn handle(&self) -> TcpHandle<'_> {
+        TcpHandle { io: self.io }
+    }
+
     /// Split the socket into reader and a writer halves.
     pub fn split(&mut self) -> (TcpReader<'_>, TcpWriter<'_>) {
         (TcpReader { io: self.io }, TcpWriter { io: self.io })
     }
 
+    /// Bind the socket to an interface of the stack.
+    ///
+    /// The socket then connects and accepts connections through this interface only, instead of
+    /// the one chosen by the routing table of the stack. This must be done before connecting.
+    ///
+    /// Fails if the interface has no room for another socket, in which case the socket stays
+    /// where it is, unbound.
+    pub fn bind_interface(&mut self, id: InterfaceId) -> Result<(), InterfaceFull> {
+        self.io.stack.with_mut(|i| i.move_socket(self.io.handle, id))?;
+        self.bound = true;
+        Ok(())
+    }
+
+    /// Get the interface the socket is on.
+    pub fn interface(&self) -> InterfaceId {
+        self.io.stack.with(|i| i.socket_interface(self.io.handle))
+    }
+
+    /// Move a closed socket which isn't bound to an interface to the interface to listen on
+    /// `local_endpoint` from.
+    fn move_to_listen(&mut self, local_endpoint: IpListenEndpoint) {
+        if self.bound {
+            return;
+        }
+        self.io.stack.with_mut(|i| {
+            let iface = match local_endpoint.addr {
+                Some(addr) => i.interface_with_addr(addr),
+                None => Some(i.preferred_interface()),
+            };
+            if let Some(iface) = iface {
+                if i.socket::<tcp::Socket>(self.io.handle).0.state() == tcp::State::Closed {
+                    i.try_move_socket(self.io.handle, iface);
+                }
+            }
+        });
+    }
+
     /// Connect to a remote host.
+    ///
+    /// Unless the socket is bound to an interface with [`bind_interface()`](Self::bind_interface),
+    /// it connects through the interface the stack currently routes `remote_endpoint` through, if
+    /// that interface has room for it.
     pub async fn connect<T>(&mut self, remote_endpoint: T) -> Result<(), ConnectError>
     where
         T: Into<IpEndpoint>,
     {
-        let local_port = self.io.stack.with_mut(|i| i.get_local_port());
+        let remote_endpoint = remote_endpoint.into();
+        let local_port = self.io.stack.with_mut(|i| {
+            if !self.bound && i.socket::<tcp::Socket>(self.io.handle).0.state() == tcp::State::Closed {
+                let iface = i
+                    .route(remote_endpoint.addr)
+                    .unwrap_or(i.socket_interface(self.io.handle));
+                i.try_move_socket(self.io.handle, iface);
+            }
+            i.get_local_port()
+        });
 
         match {
             self.io
@@ -272,10 +342,20 @@ impl<'a> TcpSocket<'a> {
     /// Accept a connection from a remote host.
     ///
     /// This function puts the socket in listening mode, and waits until a connection is received.
+    ///
+    /// The socket only accepts connections arriving on the interface it's on. Unless it's bound
+    /// to an interface with [`bind_interface()`](Self::bind_interface), it first moves to the
+    /// interface owning the address of `local_endpoint` if it's specified, or else to the interface
+    /// the stack currently prefers, if that interface has room for it. While listening, the socket
+    /// stays on its interface: to accept connections on several interfaces, use a socket bound to
+    /// each of them.
     pub async fn accept<T>(&mut self, local_endpoint: T) -> Result<(), AcceptError>
     where
         T: Into<IpListenEndpoint>,
     {
+        let local_endpoint = local_endpoint.into();
+        self.move_to_listen(local_endpoint);
+
         match self.io.with_mut(|s, _| s.listen(local_endpoint)) {
             Ok(()) => {}
             Err(tcp::ListenError::InvalidState) => return Err(AcceptError::InvalidState),
@@ -380,6 +460,12 @@ impl<'a> TcpSocket<'a> {
         self.io.with(|s, _| s.local_endpoint())
     }
 
+    /// Get the byte counters of the socket.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> crate::SocketStats {
+        self.io.stack.with(|i| i.socket_stats(self.io.handle))
+    }
+
     /// Get the remote endpoint of the socket.
     ///
     /// Returns `None` if the socket is not connected.
@@ -451,7 +537,7 @@ impl<'a> TcpSocket<'a> {
 
 impl<'a> Drop for TcpSocket<'a> {
     fn drop(&mut self) {
-        self.io.stack.with_mut(|i| i.sockets.remove(self.io.handle));
+        self.io.stack.with_mut(|i| i.remove_socket(self.io.handle));
     }
 }
 
@@ -476,16 +562,26 @@ struct TcpIo<'a> {
 impl<'d> TcpIo<'d> {
     fn with<R>(&self, f: impl FnOnce(&tcp::Socket, &Interface) -> R) -> R {
         self.stack.with(|i| {
-            let socket = i.sockets.get::<tcp::Socket>(self.handle);
-            f(socket, &i.iface)
+            let (socket, iface) = i.socket::<tcp::Socket>(self.handle);
+            f(socket, iface)
         })
     }
 
     fn with_mut<R>(&self, f: impl FnOnce(&mut tcp::Socket, &mut Interface) -> R) -> R {
         self.stack.with_mut(|i| {
-            let socket = i.sockets.get_mut::<tcp::Socket>(self.handle);
-            let res = f(socket, &mut i.iface);
-            i.waker.wake();
+            let (socket, iface) = i.socket_mut::<tcp::Socket>(self.handle);
+            #[cfg(feature = "stats")]
+            let (recv_queue, send_queue) = (socket.recv_queue(), socket.send_queue());
+            let res = f(socket, iface);
+            // Data only leaves the receive buffer and enters the transmit buffer through the
+            // socket API, unless the socket was aborted.
+            #[cfg(feature = "stats")]
+            if socket.state() != tcp::State::Closed {
+                let rx = recv_queue.saturating_sub(socket.recv_queue());
+                let tx = socket.send_queue().saturating_sub(send_queue);
+                i.count_socket(self.handle, rx, tx);
+            }
+            i.wake(self.handle);
             res
         })
     }
@@ -742,14 +838,40 @@ mod embedded_io_impls {
             Ok(self.io.with(|s, _| s.can_send()))
         }
     }
+
+    #[cfg(feature = "tls")]
+    impl<'d> embedded_io_async::ErrorType for TcpHandle<'d> {
+        type Error = Error;
+    }
+
+    #[cfg(feature = "tls")]
+    impl<'d> embedded_io_async::Read for TcpHandle<'d> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            self.io.read(buf).await
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    impl<'d> embedded_io_async::Write for TcpHandle<'d> {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            self.io.write(buf).await
+        }
+
+        async fn flush(&mut self) -> Result<(), Self::Error> {
+            self.io.flush().await
+        }
+    }
 }
 
 /// TCP client compatible with `embedded-nal-async` traits.
 pub mod client {
-    use core::cell::{Cell, UnsafeCell};
+    use core::cell::{Cell, RefCell, UnsafeCell};
     use core::mem::MaybeUninit;
     use core::net::IpAddr;
     use core::ptr::NonNull;
+    use core::task::Waker;
+
+    use embassy_sync::waitqueue::WakerRegistration;
 
     use super::*;
 
@@ -813,29 +935,52 @@ pub mod client {
         }
     }
 
-    /// Opened TCP connection in a [`TcpClient`].
+    /// Opened TCP connection in a [`TcpClient`] or [`TcpListener`](super::listener::TcpListener).
     pub struct TcpConnection<'d, const N: usize, const TX_SZ: usize, const RX_SZ: usize> {
-        socket: TcpSocket<'d>,
-        state: &'d TcpClientState<N, TX_SZ, RX_SZ>,
+        pub(super) socket: TcpSocket<'d>,
+        pool: &'d Pool<([u8; TX_SZ], [u8; RX_SZ]), N>,
         bufs: NonNull<([u8; TX_SZ], [u8; RX_SZ])>,
     }
 
     impl<'d, const N: usize, const TX_SZ: usize, const RX_SZ: usize> TcpConnection<'d, N, TX_SZ, RX_SZ> {
         fn new(stack: Stack<'d>, state: &'d TcpClientState<N, TX_SZ, RX_SZ>) -> Result<Self, Error> {
-            let mut bufs = state.pool.alloc().ok_or(Error::ConnectionReset)?;
+            Self::from_pool(stack, &state.pool)
+        }
+
+        pub(super) fn from_pool(
+            stack: Stack<'d>,
+            pool: &'d Pool<([u8; TX_SZ], [u8; RX_SZ]), N>,
+        ) -> Result<Self, Error> {
+            let mut bufs = pool.alloc().ok_or(Error::ConnectionReset)?;
             Ok(Self {
                 socket: unsafe { TcpSocket::new(stack, &mut bufs.as_mut().1, &mut bufs.as_mut().0) },
-                state,
+                pool,
                 bufs,
             })
         }
+
+        /// Get the local endpoint of the connection.
+        pub fn local_endpoint(&self) -> Option<IpEndpoint> {
+            self.socket.local_endpoint()
+        }
+
+        /// Get the byte counters of the connection.
+        #[cfg(feature = "stats")]
+        pub fn stats(&self) -> crate::SocketStats {
+            self.socket.stats()
+        }
+
+        /// Get the remote endpoint of the connection.
+        pub fn remote_endpoint(&self) -> Option<IpEndpoint> {
+            self.socket.remote_endpoint()
+        }
     }
 
     impl<'d, const N: usize, const TX_SZ: usize, const RX_SZ: usize> Drop for TcpConnection<'d, N, TX_SZ, RX_SZ> {
         fn drop(&mut self) {
             unsafe {
                 self.socket.close();
-                self.state.pool.free(self.bufs);
+                self.pool.free(self.bufs);
             }
         }
     }
@@ -878,25 +1023,33 @@ pub mod client {
         }
     }
 
-    struct Pool<T, const N: usize> {
+    pub(crate) struct Pool<T, const N: usize> {
         used: [Cell<bool>; N],
         data: [UnsafeCell<MaybeUninit<T>>; N],
+        /// Waker used for waiting for a free slot.
+        waker: RefCell<WakerRegistration>,
     }
 
     impl<T, const N: usize> Pool<T, N> {
         const VALUE: Cell<bool> = Cell::new(false);
         const UNINIT: UnsafeCell<MaybeUninit<T>> = UnsafeCell::new(MaybeUninit::uninit());
 
-        const fn new() -> Self {
+        pub(crate) const fn new() -> Self {
             Self {
                 used: [Self::VALUE; N],
                 data: [Self::UNINIT; N],
+                waker: RefCell::new(WakerRegistration::new()),
             }
         }
+
+        /// Register a waker to be woken when a slot is freed.
+        pub(super) fn register_waker(&self, waker: &Waker) {
+            self.waker.borrow_mut().register(waker);
+        }
     }
 
     impl<T, const N: usize> Pool<T, N> {
-        fn alloc(&self) -> Option<NonNull<T>> {
+        pub(crate) fn alloc(&self) -> Option<NonNull<T>> {
             for n in 0..N {
                 // this can't race because Pool is not Sync.
                 if !self.used[n].get() {
@@ -909,12 +1062,158 @@ pub mod client {
         }
 
         /// safety: p must be a pointer obtained from self.alloc that hasn't been freed yet.
-        unsafe fn free(&self, p: NonNull<T>) {
+        pub(crate) unsafe fn free(&self, p: NonNull<T>) {
             let origin = self.data.as_ptr() as *mut T;
             let n = p.as_ptr().offset_from(origin);
             assert!(n >= 0);
             assert!((n as usize) < N);
             self.used[n as usize].set(false);
+            self.waker.borrow_mut().wake();
+        }
+    }
+}
+
+/// TCP listener accepting connections into a fixed pool of sockets.
+pub mod listener {
+    use super::client::{Pool, TcpConnection};
+    use super::*;
+
+    /// TCP listener that keeps a pool of sockets listening on a port.
+    ///
+    /// The listener is capable of accepting up to N concurrent connections with tx and rx buffers
+    /// according to TX_SZ and RX_SZ. All sockets not handed out as a [`TcpConnection`] are kept in
+    /// listening mode, so up to N connections can be in their handshake at the same time.
+    ///
+    /// When a [`TcpConnection`] returned by [`accept()`](Self::accept) is dropped, its socket is
+    /// put back into listening mode by `accept()`: right away if a task is waiting in it, or else on
+    /// the next call. Until then, fewer sockets are listening, and connection attempts beyond the
+    /// number of listening sockets are refused with a RST. To keep all free sockets listening,
+    /// call `accept()` again as soon as it returns, as in an accept loop spawning a task per
+    /// connection.
+    pub struct TcpListener<'d, const N: usize, const TX_SZ: usize = 1024, const RX_SZ: usize = 1024> {
+        stack: Stack<'d>,
+        state: &'d TcpListenerState<N, TX_SZ, RX_SZ>,
+        local_endpoint: IpListenEndpoint,
+        interface: Option<InterfaceId>,
+        socket_timeout: Option<Duration>,
+        slots: [Option<TcpConnection<'d, N, TX_SZ, RX_SZ>>; N],
+    }
+
+    impl<'d, const N: usize, const TX_SZ: usize, const RX_SZ: usize> TcpListener<'d, N, TX_SZ, RX_SZ> {
+        /// Create a new `TcpListener` listening on `local_endpoint`.
+        ///
+        /// Sockets are put into listening mode lazily, on the first call to [`accept()`](Self::accept).
+        pub fn new<T>(stack: Stack<'d>, state: &'d TcpListenerState<N, TX_SZ, RX_SZ>, local_endpoint: T) -> Self
+        where
+            T: Into<IpListenEndpoint>,
+        {
+            Self {
+                stack,
+                state,
+                local_endpoint: local_endpoint.into(),
+                interface: None,
+                socket_timeout: None,
+                slots: [const { None }; N],
+            }
+        }
+
+        /// Accept connections through an interface of the stack only.
+        ///
+        /// Otherwise, each socket listens on the interface owning the address of the local
+        /// endpoint if it's specified, or else on the interface the stack prefers when the socket
+        /// is put into listening mode. Sockets don't move while listening, so to accept
+        /// connections on several interfaces, use a listener bound to each of them.
+        ///
+        /// This only applies to sockets put into listening mode after this call.
+        pub fn bind_interface(&mut self, id: InterfaceId) {
+            self.interface = Some(id);
+        }
+
+        /// Set the timeout for each socket accepted by this `TcpListener`.
+        ///
+        /// If the timeout is set, the socket will be closed if no data is received for the
+        /// specified duration.
+        ///
+        /// This only applies to sockets put into listening mode after this call.
+        pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+            self.socket_timeout = timeout;
+        }
+
+        /// Get the local endpoint the listener is bound to.
+        pub fn local_endpoint(&self) -> IpListenEndpoint {
+            self.local_endpoint
+        }
+
+        /// Wait for an incoming connection and return it.
+        ///
+        /// Free slots are put back into listening mode before waiting, and while waiting as soon as
+        /// a connection is dropped. If all N connections are handed out, this waits until one of
+        /// them is dropped.
+        pub async fn accept(&mut self) -> Result<TcpConnection<'d, N, TX_SZ, RX_SZ>, AcceptError> {
+            poll_fn(|cx| {
+                // Re-arm free slots. This must happen before checking for established connections,
+                // so that a slot freed since the last poll starts listening as soon as possible.
+                for slot in self.slots.iter_mut() {
+                    if slot.is_some() {
+                        continue;
+                    }
+                    let Ok(mut conn) = TcpConnection::from_pool(self.stack, &self.state.pool) else {
+                        break;
+                    };
+                    conn.socket.set_timeout(self.socket_timeout);
+                    match self.interface {
+                        Some(id) => {
+                            // Wait for a socket to be freed on the interface.
+                            if conn.socket.bind_interface(id).is_err() {
+                                break;
+                            }
+                        }
+                        None => conn.socket.move_to_listen(self.local_endpoint),
+                    }
+                    match conn.socket.io.with_mut(|s, _| s.listen(self.local_endpoint)) {
+                        Ok(()) => {}
+                        Err(tcp::ListenError::InvalidState) => return Poll::Ready(Err(AcceptError::InvalidState)),
+                        Err(tcp::ListenError::Unaddressable) => return Poll::Ready(Err(AcceptError::InvalidPort)),
+                    }
+                    *slot = Some(conn);
+                }
+
+                for slot in self.slots.iter_mut() {
+                    let Some(conn) = slot else { continue };
+                    match conn.socket.io.with_mut(|s, _| {
+                        let state = s.state();
+                        if matches!(state, tcp::State::Listen | tcp::State::SynReceived) {
+                            s.register_send_waker(cx.waker());
+                        }
+                        state
+                    }) {
+                        tcp::State::Listen | tcp::State::SynReceived => {}
+                        // The socket was closed before we got to it, e.g. by a timeout or a RST
+                        // right after the handshake. Re-arm it on the next poll.
+                        tcp::State::Closed | tcp::State::TimeWait => {
+                            *slot = None;
+                            cx.waker().wake_by_ref();
+                        }
+                        _ => return Poll::Ready(Ok(unwrap!(slot.take()))),
+                    }
+                }
+
+                self.state.pool.register_waker(cx.waker());
+                Poll::Pending
+            })
+            .await
+        }
+    }
+
+    /// State for TcpListener
+    pub struct TcpListenerState<const N: usize, const TX_SZ: usize, const RX_SZ: usize> {
+        pool: Pool<([u8; TX_SZ], [u8; RX_SZ]), N>,
+    }
+
+    impl<const N: usize, const TX_SZ: usize, const RX_SZ: usize> TcpListenerState<N, TX_SZ, RX_SZ> {
+        /// Create a new `TcpListenerState`.
+        pub const fn new() -> Self {
+            Self { pool: Pool::new() }
         }
     }
 }
@@ -0,0 +1,374 @@
+//! TLS 1.3 client sessions over TCP, using [`embedded-tls`](https://crates.io/crates/embedded-tls).
+//!
+//! [`TlsSocket`] borrows a connected [`TcpSocket`], and [`TlsClient`] wraps any `embedded-nal-async`
+//! [`TcpConnect`] implementation such as [`TcpClient`](crate::tcp::client::TcpClient), so users
+//! of that trait get TLS connections transparently.
+//!
+//! # Authentication
+//!
+//! The server certificate is checked against the CA in the [`TlsConfig`] by the verifier type
+//! parameter `V` of [`TlsSocket::open`] and [`TlsClient`], which is called back during the
+//! handshake (see [`TlsVerifier`]). [`NoVerify`] skips the check, and must only be used when the
+//! server is authenticated by other means, such as a pre-shared key set with
+//! [`TlsConfig::with_psk`].
+//!
+//! # Buffers
+//!
+//! The record read buffer must fit a whole encrypted TLS record, which is up to 16640 bytes,
+//! unless the server supports a smaller maximum fragment length. The write buffer must be at
+//! least [`TLS_RECORD_OVERHEAD`] bytes larger than the largest write.
+
+use core::marker::PhantomData;
+use core::mem::ManuallyDrop;
+use core::net::SocketAddr;
+use core::ptr::NonNull;
+
+use embassy_sync::blocking_mutex::raw::NoopRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_time::{with_timeout, Duration};
+use embedded_io_async::{Error as _, ErrorKind};
+use embedded_nal_async::TcpConnect;
+pub use embedded_tls::{
+    Aes128GcmSha256, Aes256GcmSha384, Certificate, MaxFragmentLength, NoVerify, SplitConnectionState, TlsCipherSuite,
+    TlsConfig, TlsError, TlsVerifier, TLS_RECORD_OVERHEAD,
+};
+use embedded_tls::{TlsConnection, TlsContext};
+use rand_core::{CryptoRng, RngCore};
+
+use crate::tcp::client::Pool;
+use crate::tcp::{TcpHandle, TcpSocket};
+
+/// Perform the handshake, with an optional timeout.
+async fn handshake<'v, S, CS, V, RNG>(
+    conn: &mut TlsConnection<'_, S, CS>,
+    config: &'v TlsConfig<'v, CS>,
+    rng: &'v mut RNG,
+    timeout: Option<Duration>,
+) -> Result<(), TlsError>
+where
+    S: embedded_io_async::Read + embedded_io_async::Write,
+    CS: TlsCipherSuite + 'static,
+    V: TlsVerifier<'v, CS>,
+    RNG: CryptoRng + RngCore,
+{
+    let open = conn.open::<_, V>(TlsContext::new(config, rng));
+    match timeout {
+        Some(timeout) => with_timeout(timeout, open)
+            .await
+            .unwrap_or(Err(TlsError::Io(ErrorKind::TimedOut))),
+        None => open.await,
+    }
+}
+
+/// A TLS session over a [`TcpSocket`].
+///
+/// The session borrows the socket, which can be used again once the session is dropped.
+pub struct TlsSocket<'a, CS: TlsCipherSuite + 'static = Aes128GcmSha256> {
+    conn: TlsConnection<'a, TcpHandle<'a>, CS>,
+    socket: &'a TcpSocket<'a>,
+    handshake_timeout: Option<Duration>,
+}
+
+impl<'a, CS: TlsCipherSuite + 'static> TlsSocket<'a, CS> {
+    /// Create a new TLS session over `socket`, which must be connected before calling
+    /// [`open()`](Self::open).
+    pub fn new(socket: &'a mut TcpSocket<'_>, record_read_buf: &'a mut [u8], record_write_buf: &'a mut [u8]) -> Self {
+        let socket: &'a TcpSocket<'a> = socket;
+        Self {
+            conn: TlsConnection::new(socket.handle(), record_read_buf, record_write_buf),
+            socket,
+            handshake_timeout: None,
+        }
+    }
+
+    /// Set the timeout for the handshake performed by [`open()`](Self::open).
+    ///
+    /// Timeouts while reading and writing are set on the socket, with [`TcpSocket::set_timeout`].
+    pub fn set_handshake_timeout(&mut self, timeout: Option<Duration>) {
+        self.handshake_timeout = timeout;
+    }
+
+    /// Get the underlying socket.
+    pub fn socket(&self) -> &TcpSocket<'a> {
+        self.socket
+    }
+
+    /// Perform the TLS handshake, verifying the server with `V`.
+    ///
+    /// If this fails, the session can't be used anymore.
+    pub async fn open<'v, V, RNG>(&mut self, config: &'v TlsConfig<'v, CS>, rng: &'v mut RNG) -> Result<(), TlsError>
+    where
+        V: TlsVerifier<'v, CS>,
+        RNG: CryptoRng + RngCore,
+    {
+        handshake::<_, _, V, _>(&mut self.conn, config, rng, self.handshake_timeout).await
+    }
+
+    /// Read decrypted data.
+    pub async fn read(&mut self, buf: &mut [u8]) -> Result<usize, TlsError> {
+        self.conn.read(buf).await
+    }
+
+    /// Write data to be encrypted.
+    ///
+    /// The data may be buffered until [`flush()`](Self::flush) is called.
+    pub async fn write(&mut self, buf: &[u8]) -> Result<usize, TlsError> {
+        self.conn.write(buf).await
+    }
+
+    /// Send all buffered data.
+    pub async fn flush(&mut self) -> Result<(), TlsError> {
+        self.conn.flush().await
+    }
+
+    /// Close the TLS session.
+    ///
+    /// The TCP connection isn't closed, call [`TcpSocket::close`] on the socket to do so.
+    pub async fn close(self) -> Result<(), TlsError> {
+        match self.conn.close().await {
+            Ok(_) => Ok(()),
+            Err((_, e)) => Err(e),
+        }
+    }
+
+    /// Split the session into reader and writer halves, which can be used concurrently.
+    ///
+    /// Both halves borrow the socket.
+    pub fn split(self, state: &'a mut SplitConnectionState) -> (TlsReader<'a, CS>, TlsWriter<'a, CS>) {
+        let (reader, writer) = self.conn.split_with(state);
+        (
+            TlsReader { inner: reader },
+            TlsWriter {
+                inner: writer,
+                socket: self.socket,
+                handshake_timeout: self.handshake_timeout,
+            },
+        )
+    }
+
+    /// Join reader and writer halves obtained from [`split()`](Self::split).
+    pub fn unsplit(reader: TlsReader<'a, CS>, writer: TlsWriter<'a, CS>) -> Self {
+        Self {
+            conn: TlsConnection::unsplit(reader.inner, writer.inner),
+            socket: writer.socket,
+            handshake_timeout: writer.handshake_timeout,
+        }
+    }
+}
+
+/// The reader half of a [`TlsSocket`].
+pub struct TlsReader<'a, CS: TlsCipherSuite + 'static = Aes128GcmSha256> {
+    inner: embedded_tls::TlsReader<'a, TcpHandle<'a>, CS, &'a SplitConnectionState>,
+}
+
+/// The writer half of a [`TlsSocket`].
+pub struct TlsWriter<'a, CS: TlsCipherSuite + 'static = Aes128GcmSha256> {
+    inner: embedded_tls::TlsWriter<'a, TcpHandle<'a>, CS, &'a SplitConnectionState>,
+    socket: &'a TcpSocket<'a>,
+    handshake_timeout: Option<Duration>,
+}
+
+/// TLS client compatible with `embedded-nal-async` traits.
+///
+/// Connections are made with the inner [`TcpConnect`] implementation, and use record buffers from
+/// a pool of `N`, sized according to `RX_SZ` and `TX_SZ`. Handshakes are performed one at a time,
+/// since they share the random number generator.
+pub struct TlsClient<
+    'd,
+    T: TcpConnect,
+    V,
+    RNG,
+    const N: usize,
+    const RX_SZ: usize = 16640,
+    const TX_SZ: usize = 4096,
+    CS: TlsCipherSuite + 'static = Aes128GcmSha256,
+> {
+    tcp: &'d T,
+    state: &'d TlsClientState<N, RX_SZ, TX_SZ>,
+    config: TlsConfig<'d, CS>,
+    rng: Mutex<NoopRawMutex, RNG>,
+    handshake_timeout: Option<Duration>,
+    _verifier: PhantomData<fn() -> V>,
+}
+
+impl<'d, T, V, RNG, const N: usize, const RX_SZ: usize, const TX_SZ: usize, CS>
+    TlsClient<'d, T, V, RNG, N, RX_SZ, TX_SZ, CS>
+where
+    T: TcpConnect,
+    CS: TlsCipherSuite + 'static,
+{
+    /// Create a new `TlsClient`, connecting with `tcp`.
+    ///
+    /// The server name for SNI and certificate verification is set in `config`, with
+    /// [`TlsConfig::with_server_name`].
+    pub fn new(tcp: &'d T, state: &'d TlsClientState<N, RX_SZ, TX_SZ>, config: TlsConfig<'d, CS>, rng: RNG) -> Self {
+        Self {
+            tcp,
+            state,
+            config,
+            rng: Mutex::new(rng),
+            handshake_timeout: None,
+            _verifier: PhantomData,
+        }
+    }
+
+    /// Set the timeout for the handshake of each connection.
+    pub fn set_handshake_timeout(&mut self, timeout: Option<Duration>) {
+        self.handshake_timeout = timeout;
+    }
+}
+
+impl<T, V, RNG, const N: usize, const RX_SZ: usize, const TX_SZ: usize, CS> TcpConnect
+    for TlsClient<'_, T, V, RNG, N, RX_SZ, TX_SZ, CS>
+where
+    T: TcpConnect,
+    V: for<'v> TlsVerifier<'v, CS>,
+    RNG: CryptoRng + RngCore,
+    CS: TlsCipherSuite + 'static,
+{
+    type Error = TlsError;
+    type Connection<'m>
+        = TlsConnectionHandle<'m, T::Connection<'m>, N, RX_SZ, TX_SZ, CS>
+    where
+        Self: 'm;
+
+    async fn connect(&self, remote: SocketAddr) -> Result<Self::Connection<'_>, Self::Error> {
+        let tcp = self.tcp.connect(remote).await.map_err(|e| TlsError::Io(e.kind()))?;
+        let mut bufs = self.state.pool.alloc().ok_or(TlsError::Io(ErrorKind::OutOfMemory))?;
+        // safety: the buffers are only used by this connection, and freed when it's dropped.
+        let (rx, tx) = unsafe { bufs.as_mut() };
+        let mut conn = TlsConnectionHandle {
+            conn: ManuallyDrop::new(TlsConnection::new(tcp, rx, tx)),
+            pool: &self.state.pool,
+            bufs,
+        };
+
+        let mut rng = self.rng.lock().await;
+        handshake::<_, _, V, _>(&mut conn.conn, &self.config, &mut *rng, self.handshake_timeout).await?;
+        Ok(conn)
+    }
+}
+
+/// Opened TLS connection in a [`TlsClient`].
+pub struct TlsConnectionHandle<'d, S, const N: usize, const RX_SZ: usize, const TX_SZ: usize, CS>
+where
+    S: embedded_io_async::Read + embedded_io_async::Write,
+    CS: TlsCipherSuite + 'static,
+{
+    conn: ManuallyDrop<TlsConnection<'d, S, CS>>,
+    pool: &'d Pool<([u8; RX_SZ], [u8; TX_SZ]), N>,
+    bufs: NonNull<([u8; RX_SZ], [u8; TX_SZ])>,
+}
+
+impl<S, const N: usize, const RX_SZ: usize, const TX_SZ: usize, CS> Drop
+    for TlsConnectionHandle<'_, S, N, RX_SZ, TX_SZ, CS>
+where
+    S: embedded_io_async::Read + embedded_io_async::Write,
+    CS: TlsCipherSuite + 'static,
+{
+    fn drop(&mut self) {
+        // safety: the connection borrows the buffers, so it's dropped before they're freed, and
+        // not used afterwards.
+        unsafe {
+            ManuallyDrop::drop(&mut self.conn);
+            self.pool.free(self.bufs);
+        }
+    }
+}
+
+/// State for [`TlsClient`].
+pub struct TlsClientState<const N: usize, const RX_SZ: usize, const TX_SZ: usize> {
+    pool: Pool<([u8; RX_SZ], [u8; TX_SZ]), N>,
+}
+
+impl<const N: usize, const RX_SZ: usize, const TX_SZ: usize> TlsClientState<N, RX_SZ, TX_SZ> {
+    /// Create a new `TlsClientState`.
+    pub const fn new() -> Self {
+        Self { pool: Pool::new() }
+    }
+}
+
+mod embedded_io_impls {
+    use embedded_io_async::{ErrorType, Read, Write};
+
+    use super::*;
+
+    impl<CS: TlsCipherSuite + 'static> ErrorType for TlsSocket<'_, CS> {
+        type Error = TlsError;
+    }
+
+    impl<CS: TlsCipherSuite + 'static> Read for TlsSocket<'_, CS> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            self.conn.read(buf).await
+        }
+    }
+
+    impl<CS: TlsCipherSuite + 'static> Write for TlsSocket<'_, CS> {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            self.conn.write(buf).await
+        }
+
+        async fn flush(&mut self) -> Result<(), Self::Error> {
+            self.conn.flush().await
+        }
+    }
+
+    impl<CS: TlsCipherSuite + 'static> ErrorType for TlsReader<'_, CS> {
+        type Error = TlsError;
+    }
+
+    impl<CS: TlsCipherSuite + 'static> Read for TlsReader<'_, CS> {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            self.inner.read(buf).await
+        }
+    }
+
+    impl<CS: TlsCipherSuite + 'static> ErrorType for TlsWriter<'_, CS> {
+        type Error = TlsError;
+    }
+
+    impl<CS: TlsCipherSuite + 'static> Write for TlsWriter<'_, CS> {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            self.inner.write(buf).await
+        }
+
+        async fn flush(&mut self) -> Result<(), Self::Error> {
+            self.inner.flush().await
+        }
+    }
+
+    impl<S, const N: usize, const RX_SZ: usize, const TX_SZ: usize, CS> ErrorType
+        for TlsConnectionHandle<'_, S, N, RX_SZ, TX_SZ, CS>
+    where
+        S: Read + Write,
+        CS: TlsCipherSuite + 'static,
+    {
+        type Error = TlsError;
+    }
+
+    impl<S, const N: usize, const RX_SZ: usize, const TX_SZ: usize, CS> Read
+        for TlsConnectionHandle<'_, S, N, RX_SZ, TX_SZ, CS>
+    where
+        S: Read + Write,
+        CS: TlsCipherSuite + 'static,
+    {
+        async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+            self.conn.read(buf).await
+        }
+    }
+
+    impl<S, const N: usize, const RX_SZ: usize, const TX_SZ: usize, CS> Write
+        for TlsConnectionHandle<'_, S, N, RX_SZ, TX_SZ, CS>
+    where
+        S: Read + Write,
+        CS: TlsCipherSuite + 'static,
+    {
+        async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+            self.conn.write(buf).await
+        }
+
+        async fn flush(&mut self) -> Result<(), Self::Error> {
+            self.conn.flush().await
+        }
+    }
+}
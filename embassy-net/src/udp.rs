@@ -4,12 +4,12 @@ use core::future::{poll_fn, Future};
 use core::mem;
 use core::task::{Context, Poll};
 
-use smoltcp::iface::{Interface, SocketHandle};
+use smoltcp::iface::Interface;
 use smoltcp::socket::udp;
 pub use smoltcp::socket::udp::{PacketMetadata, UdpMetadata};
 use smoltcp::wire::IpListenEndpoint;
 
-use crate::Stack;
+use crate::{InterfaceFull, InterfaceId, SocketHandle, Stack};
 
 /// Error returned by [`UdpSocket::bind`].
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
@@ -42,13 +42,23 @@ pub enum RecvError {
 }
 
 /// An UDP socket.
+///
+/// The socket sends and receives datagrams through a single interface of the stack at a time: the
+/// one owning the address it's bound to, or the one set with
+/// [`bind_interface()`](Self::bind_interface). Otherwise, it starts on the interface preferred by
+/// the stack when it's created, and moves to the interface the stack routes the destination of a
+/// datagram through when sending it, so that it follows failovers. Datagrams arriving on the
+/// interface it left are then no longer received.
 pub struct UdpSocket<'a> {
     stack: Stack<'a>,
     handle: SocketHandle,
+    bound: bool,
 }
 
 impl<'a> UdpSocket<'a> {
     /// Create a new UDP socket using the provided stack and buffers.
+    ///
+    /// Panics if the stack has no room for another socket, see [`StackResources`](crate::StackResources).
     pub fn new(
         stack: Stack<'a>,
         rx_meta: &'a mut [PacketMetadata],
@@ -61,13 +71,49 @@ impl<'a> UdpSocket<'a> {
             let rx_buffer: &'static mut [u8] = unsafe { mem::transmute(rx_buffer) };
             let tx_meta: &'static mut [PacketMetadata] = unsafe { mem::transmute(tx_meta) };
             let tx_buffer: &'static mut [u8] = unsafe { mem::transmute(tx_buffer) };
-            i.sockets.add(udp::Socket::new(
+            i.add_socket(udp::Socket::new(
                 udp::PacketBuffer::new(rx_meta, rx_buffer),
                 udp::PacketBuffer::new(tx_meta, tx_buffer),
             ))
         });
 
-        Self { stack, handle }
+        Self {
+            stack,
+            handle,
+            bound: false,
+        }
+    }
+
+    /// Bind the socket to an interface of the stack.
+    ///
+    /// This must be done before binding the socket to a local endpoint. Fails if the interface has
+    /// no room for another socket, in which case the socket stays where it is, unbound.
+    pub fn bind_interface(&mut self, id: InterfaceId) -> Result<(), InterfaceFull> {
+        self.stack.with_mut(|i| i.move_socket(self.handle, id))?;
+        self.bound = true;
+        Ok(())
+    }
+
+    /// Get the interface the socket is on.
+    pub fn interface(&self) -> InterfaceId {
+        self.stack.with(|i| i.socket_interface(self.handle))
+    }
+
+    /// Move a socket bound to neither an interface nor an address to the interface the stack
+    /// routes `remote_endpoint` through, unless datagrams are waiting to be sent.
+    fn follow_route(&self, remote_endpoint: &UdpMetadata) {
+        if self.bound || remote_endpoint.local_address.is_some() {
+            return;
+        }
+        self.stack.with_mut(|i| {
+            let socket = i.socket::<udp::Socket>(self.handle).0;
+            if socket.endpoint().addr.is_some() || socket.send_queue() > 0 {
+                return;
+            }
+            if let Some(iface) = i.route(remote_endpoint.endpoint.addr) {
+                i.try_move_socket(self.handle, iface);
+            }
+        });
     }
 
     /// Bind the socket to a local endpoint.
@@ -82,6 +128,16 @@ impl<'a> UdpSocket<'a> {
             endpoint.port = self.stack.with_mut(|i| i.get_local_port());
         }
 
+        if let (false, Some(addr)) = (self.bound, endpoint.addr) {
+            self.stack.with_mut(|i| {
+                if let Some(iface) = i.interface_with_addr(addr) {
+                    if !i.socket::<udp::Socket>(self.handle).0.is_open() {
+                        i.try_move_socket(self.handle, iface);
+                    }
+                }
+            });
+        }
+
         match self.with_mut(|s, _| s.bind(endpoint)) {
             Ok(()) => Ok(()),
             Err(udp::BindError::InvalidState) => Err(BindError::InvalidState),
@@ -91,20 +147,29 @@ impl<'a> UdpSocket<'a> {
 
     fn with<R>(&self, f: impl FnOnce(&udp::Socket, &Interface) -> R) -> R {
         self.stack.with(|i| {
-            let socket = i.sockets.get::<udp::Socket>(self.handle);
-            f(socket, &i.iface)
+            let (socket, iface) = i.socket::<udp::Socket>(self.handle);
+            f(socket, iface)
         })
     }
 
     fn with_mut<R>(&self, f: impl FnOnce(&mut udp::Socket, &mut Interface) -> R) -> R {
         self.stack.with_mut(|i| {
-            let socket = i.sockets.get_mut::<udp::Socket>(self.handle);
-            let res = f(socket, &mut i.iface);
-            i.waker.wake();
+            let (socket, iface) = i.socket_mut::<udp::Socket>(self.handle);
+            let res = f(socket, iface);
+            i.wake(self.handle);
             res
         })
     }
 
+    #[cfg(feature = "stats")]
+    fn count(&self, rx_bytes: usize, tx_bytes: usize) {
+        self.stack
+            .with_mut(|i| i.count_socket(self.handle, rx_bytes, tx_bytes));
+    }
+
+    #[cfg(not(feature = "stats"))]
+    fn count(&self, _rx_bytes: usize, _tx_bytes: usize) {}
+
     /// Wait until the socket becomes readable.
     ///
     /// A socket is readable when a packet has been received, or when there are queued packets in
@@ -155,7 +220,7 @@ impl<'a> UdpSocket<'a> {
         buf: &mut [u8],
         cx: &mut Context<'_>,
     ) -> Poll<Result<(usize, UdpMetadata), RecvError>> {
-        self.with_mut(|s, _| match s.recv_slice(buf) {
+        let res = self.with_mut(|s, _| match s.recv_slice(buf) {
             Ok((n, meta)) => Poll::Ready(Ok((n, meta))),
             // No data ready
             Err(udp::RecvError::Truncated) => Poll::Ready(Err(RecvError::Truncated)),
@@ -163,7 +228,11 @@ impl<'a> UdpSocket<'a> {
                 s.register_recv_waker(cx.waker());
                 Poll::Pending
             }
-        })
+        });
+        if let Poll::Ready(Ok((n, _))) = res {
+            self.count(n, 0);
+        }
+        res
     }
 
     /// Receive a datagram with a zero-copy function.
@@ -179,10 +248,10 @@ impl<'a> UdpSocket<'a> {
         F: FnOnce(&[u8], UdpMetadata) -> R,
     {
         let mut f = Some(f);
-        poll_fn(move |cx| {
+        let (len, res) = poll_fn(|cx| {
             self.with_mut(|s, _| {
                 match s.recv() {
-                    Ok((buffer, endpoint)) => Poll::Ready(unwrap!(f.take())(buffer, endpoint)),
+                    Ok((buffer, endpoint)) => Poll::Ready((buffer.len(), unwrap!(f.take())(buffer, endpoint))),
                     Err(udp::RecvError::Truncated) => unreachable!(),
                     Err(udp::RecvError::Exhausted) => {
                         // socket buffer is empty wait until at least one byte has arrived
@@ -192,7 +261,9 @@ impl<'a> UdpSocket<'a> {
                 }
             })
         })
-        .await
+        .await;
+        self.count(len, 0);
+        res
     }
 
     /// Wait until the socket becomes writable.
@@ -257,7 +328,10 @@ impl<'a> UdpSocket<'a> {
             return Poll::Ready(Err(SendError::PacketTooLarge));
         }
 
-        self.with_mut(|s, _| match s.send_slice(buf, remote_endpoint) {
+        let remote_endpoint: UdpMetadata = remote_endpoint.into();
+        self.follow_route(&remote_endpoint);
+
+        let res = self.with_mut(|s, _| match s.send_slice(buf, remote_endpoint) {
             // Entire datagram has been sent
             Ok(()) => Poll::Ready(Ok(())),
             Err(udp::SendError::BufferFull) => {
@@ -272,7 +346,11 @@ impl<'a> UdpSocket<'a> {
                     Poll::Ready(Err(SendError::NoRoute))
                 }
             }
-        })
+        });
+        if let Poll::Ready(Ok(())) = res {
+            self.count(0, buf.len());
+        }
+        res
     }
 
     /// Send a datagram to the specified remote endpoint with a zero-copy function.
@@ -294,8 +372,11 @@ impl<'a> UdpSocket<'a> {
             return Err(SendError::PacketTooLarge);
         }
 
+        let remote_endpoint: UdpMetadata = remote_endpoint.into();
+        self.follow_route(&remote_endpoint);
+
         let mut f = Some(f);
-        poll_fn(move |cx| {
+        let res = poll_fn(|cx| {
             self.with_mut(|s, _| {
                 match s.send(size, remote_endpoint) {
                     Ok(buffer) => Poll::Ready(Ok(unwrap!(f.take())(buffer))),
@@ -314,7 +395,11 @@ impl<'a> UdpSocket<'a> {
                 }
             })
         })
-        .await
+        .await;
+        if res.is_ok() {
+            self.count(0, size);
+        }
+        res
     }
 
     /// Flush the socket.
@@ -338,6 +423,12 @@ impl<'a> UdpSocket<'a> {
         self.with(|s, _| s.endpoint())
     }
 
+    /// Get the byte counters of the socket.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> crate::SocketStats {
+        self.stack.with(|i| i.socket_stats(self.handle))
+    }
+
     /// Returns whether the socket is open.
 
     pub fn is_open(&self) -> bool {
@@ -387,7 +478,7 @@ impl<'a> UdpSocket<'a> {
 
 impl Drop for UdpSocket<'_> {
     fn drop(&mut self) {
-        self.stack.with_mut(|i| i.sockets.remove(self.handle));
+        self.stack.with_mut(|i| i.remove_socket(self.handle));
     }
 }
 
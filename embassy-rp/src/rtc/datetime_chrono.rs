@@ -60,3 +60,28 @@ pub(super) fn datetime_from_registers(rtc_0: Rtc0, rtc_1: Rtc1) -> Result<DateTi
     let time = chrono::NaiveTime::from_hms_opt(hour, minute, second).ok_or(Error::InvalidTime)?;
     Ok(DateTime::new(date, time))
 }
+
+pub(super) fn to_rtc_datetime(dt: &DateTime) -> Result<embassy_time::rtc::DateTime, Error> {
+    use embassy_time::rtc::DateTimeError;
+
+    let year = u16::try_from(dt.year()).map_err(|_| Error::InvalidYear)?;
+    embassy_time::rtc::DateTime::new(
+        year,
+        dt.month() as u8,
+        dt.day() as u8,
+        dt.hour() as u8,
+        dt.minute() as u8,
+        dt.second() as u8,
+    )
+    .map_err(|e| match e {
+        DateTimeError::InvalidYear => Error::InvalidYear,
+        DateTimeError::InvalidMonth | DateTimeError::InvalidDay => Error::InvalidDate,
+        _ => Error::InvalidTime,
+    })
+}
+
+pub(super) fn from_rtc_datetime(dt: &embassy_time::rtc::DateTime) -> DateTime {
+    let date = chrono::NaiveDate::from_ymd_opt(dt.year() as i32, dt.month() as u32, dt.day() as u32).unwrap();
+    let time = chrono::NaiveTime::from_hms_opt(dt.hour() as u32, dt.minute() as u32, dt.second() as u32).unwrap();
+    DateTime::new(date, time)
+}
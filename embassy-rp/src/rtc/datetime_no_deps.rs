@@ -126,3 +126,36 @@ pub(super) fn datetime_from_registers(rtc_0: Rtc0, rtc_1: Rtc1) -> Result<DateTi
         second,
     })
 }
+
+pub(super) fn to_rtc_datetime(dt: &DateTime) -> Result<embassy_time::rtc::DateTime, Error> {
+    embassy_time::rtc::DateTime::new(dt.year, dt.month, dt.day, dt.hour, dt.minute, dt.second).map_err(map_error)
+}
+
+pub(super) fn from_rtc_datetime(dt: &embassy_time::rtc::DateTime) -> DateTime {
+    let day_of_week = match dt.day_of_week() {
+        embassy_time::rtc::DayOfWeek::Sunday => DayOfWeek::Sunday,
+        dotw => day_of_week_from_u8(dotw.number_from_monday()).unwrap(),
+    };
+    DateTime {
+        year: dt.year(),
+        month: dt.month(),
+        day: dt.day(),
+        day_of_week,
+        hour: dt.hour(),
+        minute: dt.minute(),
+        second: dt.second(),
+    }
+}
+
+fn map_error(error: embassy_time::rtc::DateTimeError) -> Error {
+    use embassy_time::rtc::DateTimeError;
+
+    match error {
+        DateTimeError::InvalidYear => Error::InvalidYear,
+        DateTimeError::InvalidMonth => Error::InvalidMonth,
+        DateTimeError::InvalidDay => Error::InvalidDay,
+        DateTimeError::InvalidHour => Error::InvalidHour,
+        DateTimeError::InvalidMinute => Error::InvalidMinute,
+        DateTimeError::InvalidSecond | DateTimeError::InvalidMicrosecond => Error::InvalidSecond,
+    }
+}
@@ -176,6 +176,19 @@ impl<'d, T: Instance> Rtc<'d, T> {
     }
 }
 
+impl<T: Instance> embassy_time::rtc::Rtc for Rtc<'_, T> {
+    type Error = RtcError;
+
+    fn now(&mut self) -> Result<embassy_time::rtc::DateTime, Self::Error> {
+        let now = Rtc::now(self)?;
+        self::datetime::to_rtc_datetime(&now).map_err(RtcError::InvalidDateTime)
+    }
+
+    fn set_datetime(&mut self, datetime: embassy_time::rtc::DateTime) -> Result<(), Self::Error> {
+        Rtc::set_datetime(self, self::datetime::from_rtc_datetime(&datetime))
+    }
+}
+
 /// Errors that can occur on methods on [Rtc]
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum RtcError {
@@ -159,6 +159,58 @@ impl From<DateTime> for chrono::NaiveDateTime {
     }
 }
 
+#[cfg(feature = "time")]
+impl TryFrom<DateTime> for embassy_time::rtc::DateTime {
+    type Error = embassy_time::rtc::DateTimeError;
+
+    fn try_from(date_time: DateTime) -> Result<Self, Self::Error> {
+        embassy_time::rtc::DateTime::new(
+            date_time.year,
+            date_time.month,
+            date_time.day,
+            date_time.hour,
+            date_time.minute,
+            date_time.second,
+        )?
+        .with_microsecond(date_time.usecond)
+    }
+}
+
+#[cfg(feature = "time")]
+impl TryFrom<embassy_time::rtc::DateTime> for DateTime {
+    type Error = Error;
+
+    fn try_from(date_time: embassy_time::rtc::DateTime) -> Result<Self, Self::Error> {
+        DateTime::from(
+            date_time.year(),
+            date_time.month(),
+            date_time.day(),
+            day_of_week_from_u8(date_time.day_of_week().number_from_monday())?,
+            date_time.hour(),
+            date_time.minute(),
+            date_time.second(),
+            date_time.microsecond(),
+        )
+    }
+}
+
+#[cfg(feature = "time")]
+impl From<embassy_time::rtc::DateTimeError> for Error {
+    fn from(error: embassy_time::rtc::DateTimeError) -> Self {
+        use embassy_time::rtc::DateTimeError;
+
+        match error {
+            DateTimeError::InvalidYear => Error::InvalidYear,
+            DateTimeError::InvalidMonth => Error::InvalidMonth,
+            DateTimeError::InvalidDay => Error::InvalidDay,
+            DateTimeError::InvalidHour => Error::InvalidHour,
+            DateTimeError::InvalidMinute => Error::InvalidMinute,
+            DateTimeError::InvalidSecond => Error::InvalidSecond,
+            DateTimeError::InvalidMicrosecond => Error::InvalidMicrosecond,
+        }
+    }
+}
+
 /// A day of the week
 #[repr(u8)]
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Ord, PartialOrd, Hash)]
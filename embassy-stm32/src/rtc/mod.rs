@@ -292,6 +292,25 @@ pub(crate) fn bcd2_to_byte(bcd: (u8, u8)) -> u8 {
     tmp + (value & 0x0F)
 }
 
+#[cfg(feature = "time")]
+impl embassy_time::rtc::Rtc for Rtc {
+    type Error = RtcError;
+
+    fn now(&mut self) -> Result<embassy_time::rtc::DateTime, Self::Error> {
+        let now = self.time_provider().now()?;
+        now.try_into()
+            .map_err(|e: embassy_time::rtc::DateTimeError| RtcError::InvalidDateTime(e.into()))
+    }
+
+    fn set_datetime(&mut self, datetime: embassy_time::rtc::DateTime) -> Result<(), Self::Error> {
+        // The calendar only stores the two last digits of the year, from 2000.
+        if !(2000..=2099).contains(&datetime.year()) {
+            return Err(RtcError::InvalidDateTime(DateTimeError::InvalidYear));
+        }
+        Rtc::set_datetime(self, datetime.try_into().map_err(RtcError::InvalidDateTime)?)
+    }
+}
+
 trait SealedInstance {
     const BACKUP_REGISTER_COUNT: usize;
 
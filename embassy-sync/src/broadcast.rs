@@ -0,0 +1,500 @@
+//! A broadcast channel with a bounded queue per subscriber, disconnecting slow subscribers.
+//!
+//! Each message is received by all subscribers, which each have their own queue of `CAP`
+//! messages. Unlike a [`PubSubChannel`](crate::pubsub::PubSubChannel), a subscriber never
+//! misses a message: when its queue is full, the publisher waits for it, and after a timeout
+//! disconnects it, so that a slow subscriber can't hold back the others forever.
+//!
+//! This is useful to fan out events to optional consumers, such as a logger or a BLE notifier,
+//! which may stall without affecting the main consumers.
+
+use core::cell::RefCell;
+use core::future::{pending, poll_fn, Future};
+use core::pin::pin;
+use core::task::{Context, Poll};
+
+use heapless::Deque;
+
+use crate::blocking_mutex::raw::RawMutex;
+use crate::blocking_mutex::Mutex;
+use crate::waitqueue::{MultiWakerRegistration, WakerRegistration};
+
+/// A broadcast channel with a queue of `CAP` messages for each of up to `SUBS` subscribers.
+///
+/// `PUBS` is the number of publishers that can wait for room at the same time. More publishers
+/// can wait, but then all waiting publishers are woken whenever another one registers.
+///
+/// Publishing waits until every connected subscriber has room in its queue.
+/// [`publish_timeout`](Self::publish_timeout) bounds this wait with a timeout future, such as an
+/// `embassy_time::Timer`: when it completes, the subscribers whose queue is still full are
+/// disconnected, and the message is delivered to the others.
+///
+/// A disconnected subscriber still receives the messages that are in its queue, and then
+/// [`Disconnected`]. It keeps its slot until it's dropped.
+///
+/// ```
+/// use futures_executor::block_on;
+/// use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+/// use embassy_sync::broadcast::{BroadcastChannel, Disconnected};
+///
+/// let f = async {
+///
+/// static EVENTS: BroadcastChannel<CriticalSectionRawMutex, u8, 1, 2, 1> = BroadcastChannel::new();
+///
+/// let mut logger = EVENTS.subscriber().unwrap();
+/// let mut notifier = EVENTS.subscriber().unwrap();
+///
+/// EVENTS.publish(1).await;
+/// assert_eq!(notifier.receive().await, Ok(1));
+///
+/// // The logger hasn't read its message: it's disconnected, and the notifier gets the new one.
+/// assert_eq!(EVENTS.publish_immediate(2), 1);
+/// assert_eq!(EVENTS.subscriber_count(), 1);
+/// assert_eq!(notifier.receive().await, Ok(2));
+/// assert_eq!(logger.receive().await, Ok(1));
+/// assert_eq!(logger.receive().await, Err(Disconnected));
+///
+/// };
+/// block_on(f);
+/// ```
+pub struct BroadcastChannel<M: RawMutex, T: Clone, const CAP: usize, const SUBS: usize, const PUBS: usize> {
+    inner: Mutex<M, RefCell<BroadcastState<T, CAP, SUBS, PUBS>>>,
+}
+
+impl<M: RawMutex, T: Clone, const CAP: usize, const SUBS: usize, const PUBS: usize>
+    BroadcastChannel<M, T, CAP, SUBS, PUBS>
+{
+    /// Create a new channel.
+    pub const fn new() -> Self {
+        Self {
+            inner: Mutex::new(RefCell::new(BroadcastState::new())),
+        }
+    }
+
+    fn lock<R>(&self, f: impl FnOnce(&mut BroadcastState<T, CAP, SUBS, PUBS>) -> R) -> R {
+        self.inner.lock(|rc| f(&mut *unwrap!(rc.try_borrow_mut())))
+    }
+
+    /// Create a new subscriber. It will only receive messages that are published after its creation.
+    ///
+    /// If there are no subscriber slots left, an error will be returned.
+    pub fn subscriber(&self) -> Result<Subscriber<'_, M, T, CAP, SUBS, PUBS>, Error> {
+        self.lock(|s| {
+            let index = s
+                .subscribers
+                .iter()
+                .position(|sub| sub.status == Status::Free)
+                .ok_or(Error::MaximumSubscribersReached)?;
+            s.subscribers[index].status = Status::Connected;
+            Ok(Subscriber { channel: self, index })
+        })
+    }
+
+    /// Publish a message to all connected subscribers, waiting until they all have room for it.
+    pub async fn publish(&self, message: T) {
+        self.publish_timeout(message, pending::<()>()).await;
+    }
+
+    /// Publish a message to all connected subscribers, waiting until they all have room for it,
+    /// or until `timeout` completes.
+    ///
+    /// On timeout, the subscribers whose queue is still full are disconnected, and the message
+    /// is delivered to the others.
+    ///
+    /// Returns the number of subscribers that were disconnected.
+    pub async fn publish_timeout(&self, message: T, timeout: impl Future) -> usize {
+        let mut timeout = pin!(timeout);
+        let mut message = Some(message);
+        poll_fn(|cx| {
+            let ready = self.lock(|s| {
+                if s.poll_ready(Some(cx)) {
+                    s.deliver(unwrap!(message.take()));
+                    true
+                } else {
+                    false
+                }
+            });
+            if ready {
+                return Poll::Ready(0);
+            }
+            match timeout.as_mut().poll(cx) {
+                Poll::Ready(_) => Poll::Ready(self.publish_immediate(unwrap!(message.take()))),
+                Poll::Pending => Poll::Pending,
+            }
+        })
+        .await
+    }
+
+    /// Publish a message to all connected subscribers if they all have room for it.
+    pub fn try_publish(&self, message: T) -> Result<(), T> {
+        self.lock(|s| {
+            if s.poll_ready(None) {
+                s.deliver(message);
+                Ok(())
+            } else {
+                Err(message)
+            }
+        })
+    }
+
+    /// Publish a message right now, disconnecting the subscribers whose queue is full.
+    ///
+    /// Returns the number of subscribers that were disconnected.
+    pub fn publish_immediate(&self, message: T) -> usize {
+        self.lock(|s| {
+            let disconnected = s.disconnect_where(|sub| sub.queue.is_full());
+            s.deliver(message);
+            disconnected
+        })
+    }
+
+    /// Returns the number of connected subscribers.
+    pub fn subscriber_count(&self) -> usize {
+        self.lock(|s| {
+            s.subscribers
+                .iter()
+                .filter(|sub| sub.status == Status::Connected)
+                .count()
+        })
+    }
+
+    /// Disconnect all subscribers.
+    ///
+    /// They still receive the messages in their queue, and then [`Disconnected`].
+    /// New subscribers can be created in the free slots.
+    pub fn disconnect_all(&self) {
+        self.lock(|s| {
+            s.disconnect_where(|_| true);
+        })
+    }
+
+    /// Returns the maximum number of messages in the queue of a subscriber.
+    pub const fn capacity(&self) -> usize {
+        CAP
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Free,
+    Connected,
+    Disconnected,
+}
+
+struct SubscriberState<T, const CAP: usize> {
+    status: Status,
+    queue: Deque<T, CAP>,
+    waker: WakerRegistration,
+}
+
+impl<T, const CAP: usize> SubscriberState<T, CAP> {
+    const fn new() -> Self {
+        Self {
+            status: Status::Free,
+            queue: Deque::new(),
+            waker: WakerRegistration::new(),
+        }
+    }
+}
+
+struct BroadcastState<T, const CAP: usize, const SUBS: usize, const PUBS: usize> {
+    subscribers: [SubscriberState<T, CAP>; SUBS],
+    publisher_wakers: MultiWakerRegistration<PUBS>,
+}
+
+impl<T: Clone, const CAP: usize, const SUBS: usize, const PUBS: usize> BroadcastState<T, CAP, SUBS, PUBS> {
+    const fn new() -> Self {
+        Self {
+            subscribers: [const { SubscriberState::new() }; SUBS],
+            publisher_wakers: MultiWakerRegistration::new(),
+        }
+    }
+
+    /// Returns whether all connected subscribers have room for a message.
+    ///
+    /// If not and a context is given, then its waker is registered.
+    fn poll_ready(&mut self, cx: Option<&mut Context<'_>>) -> bool {
+        let ready = self
+            .subscribers
+            .iter()
+            .all(|sub| sub.status != Status::Connected || !sub.queue.is_full());
+        if !ready {
+            if let Some(cx) = cx {
+                self.publisher_wakers.register(cx.waker());
+            }
+        }
+        ready
+    }
+
+    /// Push the message in the queue of the connected subscribers which have room for it.
+    fn deliver(&mut self, message: T) {
+        for sub in self.subscribers.iter_mut() {
+            if sub.status == Status::Connected && sub.queue.push_back(message.clone()).is_ok() {
+                sub.waker.wake();
+            }
+        }
+    }
+
+    /// Disconnect the connected subscribers for which `f` returns true, and return their number.
+    fn disconnect_where(&mut self, f: impl Fn(&SubscriberState<T, CAP>) -> bool) -> usize {
+        let mut count = 0;
+        for sub in self.subscribers.iter_mut() {
+            if sub.status == Status::Connected && f(sub) {
+                sub.status = Status::Disconnected;
+                sub.waker.wake();
+                count += 1;
+            }
+        }
+        if count > 0 {
+            self.publisher_wakers.wake();
+        }
+        count
+    }
+
+    fn poll_receive(&mut self, index: usize, cx: Option<&mut Context<'_>>) -> Poll<Result<T, Disconnected>> {
+        let sub = &mut self.subscribers[index];
+        if let Some(message) = sub.queue.pop_front() {
+            self.publisher_wakers.wake();
+            return Poll::Ready(Ok(message));
+        }
+        if sub.status == Status::Disconnected {
+            return Poll::Ready(Err(Disconnected));
+        }
+        if let Some(cx) = cx {
+            sub.waker.register(cx.waker());
+        }
+        Poll::Pending
+    }
+}
+
+/// A subscriber to a [`BroadcastChannel`], with its own queue of messages.
+///
+/// Dropping the subscriber frees its slot, and its queue.
+pub struct Subscriber<'a, M: RawMutex, T: Clone, const CAP: usize, const SUBS: usize, const PUBS: usize> {
+    channel: &'a BroadcastChannel<M, T, CAP, SUBS, PUBS>,
+    index: usize,
+}
+
+impl<M: RawMutex, T: Clone, const CAP: usize, const SUBS: usize, const PUBS: usize>
+    Subscriber<'_, M, T, CAP, SUBS, PUBS>
+{
+    /// Receive the next message.
+    ///
+    /// Once the subscriber is disconnected and its queue is empty, this returns [`Disconnected`].
+    pub async fn receive(&mut self) -> Result<T, Disconnected> {
+        poll_fn(|cx| self.poll_receive(cx)).await
+    }
+
+    /// Attempt to immediately receive the next message.
+    pub fn try_receive(&mut self) -> Result<T, TryReceiveError> {
+        match self.channel.lock(|s| s.poll_receive(self.index, None)) {
+            Poll::Ready(Ok(message)) => Ok(message),
+            Poll::Ready(Err(Disconnected)) => Err(TryReceiveError::Disconnected),
+            Poll::Pending => Err(TryReceiveError::Empty),
+        }
+    }
+
+    /// Poll for the next message.
+    pub fn poll_receive(&mut self, cx: &mut Context<'_>) -> Poll<Result<T, Disconnected>> {
+        self.channel.lock(|s| s.poll_receive(self.index, Some(cx)))
+    }
+
+    /// Returns whether the subscriber still receives new messages.
+    pub fn is_connected(&self) -> bool {
+        self.channel
+            .lock(|s| s.subscribers[self.index].status == Status::Connected)
+    }
+
+    /// Returns the number of messages in the queue of this subscriber.
+    pub fn len(&self) -> usize {
+        self.channel.lock(|s| s.subscribers[self.index].queue.len())
+    }
+
+    /// Returns whether the queue of this subscriber is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<M: RawMutex, T: Clone, const CAP: usize, const SUBS: usize, const PUBS: usize> Drop
+    for Subscriber<'_, M, T, CAP, SUBS, PUBS>
+{
+    fn drop(&mut self) {
+        self.channel.lock(|s| {
+            let sub = &mut s.subscribers[self.index];
+            sub.status = Status::Free;
+            sub.queue.clear();
+            // A publisher may be waiting for room in our queue.
+            s.publisher_wakers.wake();
+        })
+    }
+}
+
+/// Error type for the [`BroadcastChannel`].
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Error {
+    /// All subscriber slots are used. To add another subscriber, first another subscriber must be dropped or
+    /// the number of subscribers of the channel must be increased.
+    MaximumSubscribersReached,
+}
+
+/// Error returned by [`Subscriber::receive`] when the subscriber was disconnected.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Disconnected;
+
+/// Error returned by [`Subscriber::try_receive`].
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum TryReceiveError {
+    /// The queue of the subscriber is empty.
+    Empty,
+    /// The subscriber was disconnected, and its queue is empty.
+    Disconnected,
+}
+
+#[cfg(test)]
+mod tests {
+    use core::time::Duration;
+
+    use futures_executor::block_on;
+    use futures_test::task::new_count_waker;
+    use futures_timer::Delay;
+    use futures_util::future::join;
+
+    use super::*;
+    use crate::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
+
+    #[test]
+    fn lossless_delivery() {
+        let channel = BroadcastChannel::<NoopRawMutex, u32, 2, 2, 1>::new();
+        let mut sub0 = channel.subscriber().unwrap();
+        let mut sub1 = channel.subscriber().unwrap();
+        assert_eq!(channel.subscriber().err(), Some(Error::MaximumSubscribersReached));
+
+        assert_eq!(channel.try_publish(1), Ok(()));
+        assert_eq!(channel.try_publish(2), Ok(()));
+        // sub1 has room, but sub0 doesn't.
+        assert_eq!(sub1.try_receive(), Ok(1));
+        assert_eq!(channel.try_publish(3), Err(3));
+
+        assert_eq!(sub0.try_receive(), Ok(1));
+        assert_eq!(channel.try_publish(3), Ok(()));
+        assert_eq!(sub0.len(), 2);
+        assert_eq!(sub1.len(), 2);
+
+        for expected in [2, 3] {
+            assert_eq!(sub0.try_receive(), Ok(expected));
+            assert_eq!(sub1.try_receive(), Ok(expected));
+        }
+        assert_eq!(sub0.try_receive(), Err(TryReceiveError::Empty));
+    }
+
+    #[test]
+    fn slow_subscriber_disconnected_on_timeout() {
+        static CHANNEL: BroadcastChannel<CriticalSectionRawMutex, u32, 1, 2, 1> = BroadcastChannel::new();
+
+        let mut fast = CHANNEL.subscriber().unwrap();
+        let mut slow = CHANNEL.subscriber().unwrap();
+
+        let publish = async {
+            let mut disconnected = 0;
+            for i in 0..4 {
+                disconnected += CHANNEL.publish_timeout(i, Delay::new(Duration::from_millis(50))).await;
+            }
+            disconnected
+        };
+        let receive = async {
+            let mut received = [0; 4];
+            for r in received.iter_mut() {
+                *r = fast.receive().await.unwrap();
+            }
+            received
+        };
+
+        let (disconnected, received) = block_on(join(publish, receive));
+        assert_eq!(disconnected, 1);
+        assert_eq!(received, [0, 1, 2, 3]);
+        assert_eq!(CHANNEL.subscriber_count(), 1);
+
+        // The slow subscriber got the messages up to its disconnection.
+        assert!(!slow.is_connected());
+        assert_eq!(slow.try_receive(), Ok(0));
+        assert_eq!(slow.try_receive(), Err(TryReceiveError::Disconnected));
+        assert_eq!(block_on(slow.receive()), Err(Disconnected));
+
+        // Dropping the slow subscriber frees its slot.
+        drop(slow);
+        let sub = CHANNEL.subscriber().unwrap();
+        assert!(sub.is_connected());
+        assert_eq!(CHANNEL.subscriber_count(), 2);
+    }
+
+    #[test]
+    fn publish_waits_for_subscribers() {
+        let channel = BroadcastChannel::<CriticalSectionRawMutex, u32, 1, 1, 1>::new();
+        let mut sub = channel.subscriber().unwrap();
+
+        let publish = async {
+            channel.publish(1).await;
+            channel.publish(2).await;
+        };
+        let receive = async {
+            Delay::new(Duration::from_millis(10)).await;
+            (sub.receive().await, sub.receive().await)
+        };
+
+        let (_, received) = block_on(join(publish, receive));
+        assert_eq!(received, (Ok(1), Ok(2)));
+        assert!(sub.is_connected());
+    }
+
+    #[test]
+    fn waiting_publishers_are_all_woken() {
+        let channel = BroadcastChannel::<NoopRawMutex, u32, 1, 1, 2>::new();
+        let mut sub = channel.subscriber().unwrap();
+        assert_eq!(channel.try_publish(0), Ok(()));
+
+        let (waker0, count0) = new_count_waker();
+        let (waker1, count1) = new_count_waker();
+        let mut publish0 = pin!(channel.publish(1));
+        let mut publish1 = pin!(channel.publish(2));
+        assert!(publish0.as_mut().poll(&mut Context::from_waker(&waker0)).is_pending());
+        assert!(publish1.as_mut().poll(&mut Context::from_waker(&waker1)).is_pending());
+
+        // The second publisher doesn't take the place of the first one.
+        assert_eq!(count0.get(), 0);
+
+        assert_eq!(sub.try_receive(), Ok(0));
+        assert_eq!((count0.get(), count1.get()), (1, 1));
+        assert!(publish0.as_mut().poll(&mut Context::from_waker(&waker0)).is_ready());
+        assert!(publish1.as_mut().poll(&mut Context::from_waker(&waker1)).is_pending());
+        assert_eq!(sub.try_receive(), Ok(1));
+        assert!(publish1.as_mut().poll(&mut Context::from_waker(&waker1)).is_ready());
+        assert_eq!(sub.try_receive(), Ok(2));
+    }
+
+    #[test]
+    fn disconnect_all() {
+        let channel = BroadcastChannel::<NoopRawMutex, u32, 2, 3, 1>::new();
+        let mut sub0 = channel.subscriber().unwrap();
+        let sub1 = channel.subscriber().unwrap();
+        assert_eq!(channel.subscriber_count(), 2);
+
+        assert_eq!(channel.try_publish(1), Ok(()));
+        channel.disconnect_all();
+        assert_eq!(channel.subscriber_count(), 0);
+
+        // Publishing without subscribers succeeds.
+        assert_eq!(channel.try_publish(2), Ok(()));
+        assert_eq!(sub0.try_receive(), Ok(1));
+        assert_eq!(sub0.try_receive(), Err(TryReceiveError::Disconnected));
+
+        // The disconnected subscribers keep their slots until dropped.
+        let _sub2 = channel.subscriber().unwrap();
+        assert_eq!(channel.subscriber().err(), Some(Error::MaximumSubscribersReached));
+        drop(sub1);
+        assert!(channel.subscriber().is_ok());
+    }
+}
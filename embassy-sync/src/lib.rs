@@ -11,6 +11,7 @@ pub(crate) mod fmt;
 mod ring_buffer;
 
 pub mod blocking_mutex;
+pub mod broadcast;
 pub mod channel;
 pub mod lazy_lock;
 pub mod mutex;
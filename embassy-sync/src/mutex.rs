@@ -4,12 +4,13 @@
 use core::cell::{RefCell, UnsafeCell};
 use core::future::{poll_fn, Future};
 use core::ops::{Deref, DerefMut};
-use core::task::Poll;
+use core::pin::Pin;
+use core::task::{Context, Poll};
 use core::{fmt, mem};
 
 use crate::blocking_mutex::raw::RawMutex;
 use crate::blocking_mutex::Mutex as BlockingMutex;
-use crate::waitqueue::WakerRegistration;
+use crate::waitqueue::{WaitList, WaitNode, WakerRegistration};
 
 /// Error returned by [`Mutex::try_lock`]
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
@@ -19,6 +20,22 @@ pub struct TryLockError;
 struct State {
     locked: bool,
     waker: WakerRegistration,
+    /// Tasks waiting in [`Mutex::lock_fair()`]. A node is removed when the mutex is handed over to it.
+    waiters: WaitList<()>,
+}
+
+impl State {
+    fn unlock(&mut self) {
+        // Hand the mutex over to the first fair waiter, if any, so that it stays locked.
+        let handed_over = self.waiters.pop_front_if(|node| {
+            node.wake();
+            true
+        });
+        if !handed_over {
+            self.locked = false;
+            self.waker.wake();
+        }
+    }
 }
 
 /// Async mutex.
@@ -36,6 +53,10 @@ struct State {
 ///
 /// Use [`ThreadModeRawMutex`](crate::blocking_mutex::raw::ThreadModeRawMutex) when data is shared between tasks running on the same executor but you want a singleton.
 ///
+/// [`Mutex::lock()`] gives no ordering guarantee to the waiting tasks: under contention, a task
+/// may starve. [`Mutex::lock_fair()`] serves them in FIFO order instead, which bounds the time a
+/// task waits for the mutex.
+///
 pub struct Mutex<M, T>
 where
     M: RawMutex,
@@ -60,6 +81,7 @@ where
             state: BlockingMutex::new(RefCell::new(State {
                 locked: false,
                 waker: WakerRegistration::new(),
+                waiters: WaitList::new(),
             })),
         }
     }
@@ -94,6 +116,21 @@ where
         })
     }
 
+    /// Lock the mutex, in FIFO order.
+    ///
+    /// This will wait for the mutex to be unlocked if it's already locked. Tasks waiting in this
+    /// method are served in the order they started waiting, before the tasks waiting in
+    /// [`lock()`](Self::lock): when the mutex is unlocked, it's handed over to the task that has
+    /// been waiting the longest. Any number of tasks can wait, the wait list is stored in the
+    /// returned futures.
+    pub fn lock_fair(&self) -> impl Future<Output = MutexGuard<'_, M, T>> {
+        LockFair {
+            mutex: self,
+            node: WaitNode::new(()),
+            queued: false,
+        }
+    }
+
     /// Attempt to immediately lock the mutex.
     ///
     /// If the mutex is already locked, this will return an error instead of waiting.
@@ -101,6 +138,7 @@ where
         self.state.lock(|s| {
             let mut s = s.borrow_mut();
             if s.locked {
+                // The mutex is handed over to fair waiters while locked, so they can't be overtaken.
                 Err(TryLockError)
             } else {
                 s.locked = true;
@@ -164,6 +202,83 @@ where
     }
 }
 
+/// Future returned by [`Mutex::lock_fair()`].
+struct LockFair<'a, M, T>
+where
+    M: RawMutex,
+    T: ?Sized,
+{
+    mutex: &'a Mutex<M, T>,
+    /// Node in the wait list of the mutex.
+    node: WaitNode<()>,
+    /// Whether `node` was added to the wait list.
+    queued: bool,
+}
+
+impl<'a, M, T> Future for LockFair<'a, M, T>
+where
+    M: RawMutex,
+    T: ?Sized,
+{
+    type Output = MutexGuard<'a, M, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: `node` is never moved, and `queued` is not structurally pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        let node = unsafe { Pin::new_unchecked(&this.node) };
+
+        let ready = this.mutex.state.lock(|s| {
+            let mut s = s.borrow_mut();
+            if this.queued {
+                if node.is_linked() {
+                    node.register(cx.waker());
+                    return false;
+                }
+                // The mutex was handed over to us.
+                this.queued = false;
+                return true;
+            }
+
+            if !s.locked {
+                s.locked = true;
+                true
+            } else {
+                node.register(cx.waker());
+                // Safety: the node is pinned, and removed from the list in `drop`.
+                unsafe { s.waiters.push_back(node) };
+                this.queued = true;
+                false
+            }
+        });
+
+        if ready {
+            Poll::Ready(MutexGuard { mutex: this.mutex })
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<'a, M, T> Drop for LockFair<'a, M, T>
+where
+    M: RawMutex,
+    T: ?Sized,
+{
+    fn drop(&mut self) {
+        if self.queued {
+            self.mutex.state.lock(|s| {
+                let mut s = unwrap!(s.try_borrow_mut());
+                if self.node.is_linked() {
+                    s.waiters.remove(&self.node);
+                } else {
+                    // The mutex was handed over to us, but we don't want it anymore.
+                    s.unlock();
+                }
+            })
+        }
+    }
+}
+
 /// Async mutex guard.
 ///
 /// Owning an instance of this type indicates having
@@ -205,11 +320,7 @@ where
     T: ?Sized,
 {
     fn drop(&mut self) {
-        self.mutex.state.lock(|s| {
-            let mut s = unwrap!(s.try_borrow_mut());
-            s.locked = false;
-            s.waker.wake();
-        })
+        self.mutex.state.lock(|s| unwrap!(s.try_borrow_mut()).unlock())
     }
 }
 
@@ -319,11 +430,7 @@ where
     T: ?Sized,
 {
     fn drop(&mut self) {
-        self.state.lock(|s| {
-            let mut s = unwrap!(s.try_borrow_mut());
-            s.locked = false;
-            s.waker.wake();
-        })
+        self.state.lock(|s| unwrap!(s.try_borrow_mut()).unlock())
     }
 }
 
@@ -363,6 +470,11 @@ where
 
 #[cfg(test)]
 mod tests {
+    use core::pin::pin;
+    use core::task::Poll;
+
+    use futures_util::poll;
+
     use crate::blocking_mutex::raw::NoopRawMutex;
     use crate::mutex::{Mutex, MutexGuard};
 
@@ -388,4 +500,76 @@ mod tests {
 
         assert_eq!(*mutex.lock().await, [0, 3]);
     }
+
+    #[futures_test::test]
+    async fn fair_mutex_serves_waiters_in_order() {
+        let mutex: Mutex<NoopRawMutex, u32> = Mutex::new(0);
+        let guard = mutex.lock().await;
+
+        let mut a = pin!(mutex.lock_fair());
+        let mut b = pin!(mutex.lock_fair());
+        assert!(poll!(a.as_mut()).is_pending());
+        assert!(poll!(b.as_mut()).is_pending());
+
+        core::mem::drop(guard);
+
+        // The mutex is handed over to `a`, even if `b` is polled first.
+        assert!(poll!(b.as_mut()).is_pending());
+        assert!(mutex.try_lock().is_err());
+        let Poll::Ready(mut guard) = poll!(a.as_mut()) else {
+            panic!("`a` should hold the mutex");
+        };
+        *guard = 1;
+        core::mem::drop(guard);
+
+        let Poll::Ready(guard) = poll!(b.as_mut()) else {
+            panic!("`b` should hold the mutex");
+        };
+        assert_eq!(*guard, 1);
+    }
+
+    #[futures_test::test]
+    async fn fair_mutex_cancelled_waiter() {
+        let mutex: Mutex<NoopRawMutex, u32> = Mutex::new(0);
+        let guard = mutex.lock().await;
+
+        {
+            let mut a = pin!(mutex.lock_fair());
+            let mut b = pin!(mutex.lock_fair());
+            assert!(poll!(a.as_mut()).is_pending());
+            assert!(poll!(b.as_mut()).is_pending());
+            let mut c = pin!(mutex.lock_fair());
+            assert!(poll!(c.as_mut()).is_pending());
+
+            // `b` gives up while waiting, then `a` after the mutex was handed over to it.
+            b.set(mutex.lock_fair());
+            core::mem::drop(guard);
+            a.set(mutex.lock_fair());
+
+            assert!(poll!(c.as_mut()).is_ready());
+        }
+
+        assert!(mutex.try_lock().is_ok());
+    }
+
+    #[futures_test::test]
+    async fn fair_waiters_go_first() {
+        let mutex: Mutex<NoopRawMutex, u32> = Mutex::new(0);
+        let guard = mutex.lock().await;
+
+        // `lock()` futures are `Unpin`, they don't need to be pinned.
+        let mut unfair = mutex.lock();
+        let mut fair = pin!(mutex.lock_fair());
+        assert!(poll!(&mut unfair).is_pending());
+        assert!(poll!(fair.as_mut()).is_pending());
+
+        core::mem::drop(guard);
+
+        assert!(poll!(&mut unfair).is_pending());
+        let Poll::Ready(guard) = poll!(fair.as_mut()) else {
+            panic!("the fair waiter should hold the mutex");
+        };
+        core::mem::drop(guard);
+        assert!(poll!(&mut unfair).is_ready());
+    }
 }
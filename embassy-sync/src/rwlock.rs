@@ -5,11 +5,12 @@ use core::cell::{RefCell, UnsafeCell};
 use core::fmt;
 use core::future::{poll_fn, Future};
 use core::ops::{Deref, DerefMut};
-use core::task::Poll;
+use core::pin::Pin;
+use core::task::{Context, Poll};
 
 use crate::blocking_mutex::raw::RawMutex;
 use crate::blocking_mutex::Mutex as BlockingMutex;
-use crate::waitqueue::WakerRegistration;
+use crate::waitqueue::{WaitList, WaitNode, WakerRegistration};
 
 /// Error returned by [`RwLock::try_read`] and [`RwLock::try_write`] when the lock is already held.
 #[derive(PartialEq, Eq, Clone, Copy, Debug)]
@@ -20,6 +21,61 @@ struct State {
     readers: usize,
     writer: bool,
     waker: WakerRegistration,
+    /// Tasks waiting in [`RwLock::read_fair()`] and [`RwLock::write_fair()`]. The value of a node
+    /// is true for writers. A node is removed when the lock is granted to it.
+    waiters: WaitList<bool>,
+}
+
+impl State {
+    /// Whether the lock can be taken now for reading or writing, without overtaking fair waiters.
+    fn available(&self, write: bool) -> bool {
+        !self.writer && (!write || self.readers == 0) && self.waiters.is_empty()
+    }
+
+    /// Take the lock for reading or writing.
+    fn take(&mut self, write: bool) {
+        if write {
+            self.writer = true;
+        } else {
+            self.readers += 1;
+        }
+    }
+
+    /// Release the lock for reading or writing.
+    fn release(&mut self, write: bool) {
+        if write {
+            self.writer = false;
+        } else {
+            self.readers -= 1;
+        }
+        self.wake();
+    }
+
+    /// Wake the waiters. Grant the lock to the fair waiters at the front of the list: a writer, or
+    /// all the readers up to the next writer. Once there are none, wake the other waiters when the
+    /// lock is free.
+    fn wake(&mut self) {
+        loop {
+            let (writer, readers) = (self.writer, self.readers);
+            let mut granted = None;
+            self.waiters.pop_front_if(|node| {
+                let write = node.value();
+                if writer || (write && readers > 0) {
+                    return false;
+                }
+                node.wake();
+                granted = Some(write);
+                true
+            });
+            match granted {
+                Some(write) => self.take(write),
+                None => break,
+            }
+        }
+        if !self.writer && self.readers == 0 && self.waiters.is_empty() {
+            self.waker.wake();
+        }
+    }
 }
 
 /// Async read-write lock.
@@ -37,7 +93,10 @@ struct State {
 ///
 /// Use [`ThreadModeRawMutex`](crate::blocking_mutex::raw::ThreadModeRawMutex) when data is shared between tasks running on the same executor but you want a singleton.
 ///
-
+/// [`RwLock::read()`] and [`RwLock::write()`] give no ordering guarantee to the waiting tasks:
+/// under contention, a task may starve. [`RwLock::read_fair()`] and [`RwLock::write_fair()`] serve
+/// them in FIFO order instead: a reader arriving after a waiting writer waits for the writer to be
+/// done.
 pub struct RwLock<M, T>
 where
     M: RawMutex,
@@ -63,6 +122,7 @@ where
                 readers: 0,
                 writer: false,
                 waker: WakerRegistration::new(),
+                waiters: WaitList::new(),
             })),
         }
     }
@@ -80,12 +140,12 @@ where
         poll_fn(|cx| {
             let ready = self.state.lock(|s| {
                 let mut s = s.borrow_mut();
-                if s.writer {
+                if s.available(false) {
+                    s.take(false);
+                    true
+                } else {
                     s.waker.register(cx.waker());
                     false
-                } else {
-                    s.readers += 1;
-                    true
                 }
             });
 
@@ -104,12 +164,12 @@ where
         poll_fn(|cx| {
             let ready = self.state.lock(|s| {
                 let mut s = s.borrow_mut();
-                if s.writer || s.readers > 0 {
+                if s.available(true) {
+                    s.take(true);
+                    true
+                } else {
                     s.waker.register(cx.waker());
                     false
-                } else {
-                    s.writer = true;
-                    true
                 }
             });
 
@@ -121,6 +181,33 @@ where
         })
     }
 
+    /// Lock the read-write lock for reading, in FIFO order.
+    ///
+    /// This will wait for the lock to be available if it's already locked for writing, or if a
+    /// writer is waiting in [`write_fair()`](Self::write_fair). Tasks waiting in this method or in
+    /// `write_fair()` are served in the order they started waiting, before the tasks waiting in
+    /// [`read()`](Self::read) or [`write()`](Self::write). Any number of tasks can wait, the wait
+    /// list is stored in the returned futures.
+    pub fn read_fair(&self) -> impl Future<Output = RwLockReadGuard<'_, M, T>> {
+        let acquire = Acquire::new(self, false);
+        async move {
+            acquire.await;
+            RwLockReadGuard { rwlock: self }
+        }
+    }
+
+    /// Lock the read-write lock for writing, in FIFO order.
+    ///
+    /// This will wait for the lock to be available if it's already locked for reading or writing.
+    /// Waiting tasks are served in order, like in [`read_fair()`](Self::read_fair).
+    pub fn write_fair(&self) -> impl Future<Output = RwLockWriteGuard<'_, M, T>> {
+        let acquire = Acquire::new(self, true);
+        async move {
+            acquire.await;
+            RwLockWriteGuard { rwlock: self }
+        }
+    }
+
     /// Attempt to immediately lock the rwlock.
     ///
     /// If the rwlock is already locked, this will return an error instead of waiting.
@@ -128,10 +215,10 @@ where
         self.state
             .lock(|s| {
                 let mut s = s.borrow_mut();
-                if s.writer {
+                if !s.available(false) {
                     return Err(());
                 }
-                s.readers += 1;
+                s.take(false);
                 Ok(())
             })
             .map_err(|_| TryLockError)?;
@@ -146,10 +233,10 @@ where
         self.state
             .lock(|s| {
                 let mut s = s.borrow_mut();
-                if s.writer || s.readers > 0 {
+                if !s.available(true) {
                     return Err(());
                 }
-                s.writer = true;
+                s.take(true);
                 Ok(())
             })
             .map_err(|_| TryLockError)?;
@@ -205,6 +292,94 @@ where
     }
 }
 
+/// Future taking the lock for reading or writing, in FIFO order.
+struct Acquire<'a, M, T>
+where
+    M: RawMutex,
+    T: ?Sized,
+{
+    rwlock: &'a RwLock<M, T>,
+    /// Node in the wait list of the lock. Its value is true for writing.
+    node: WaitNode<bool>,
+    /// Whether `node` was added to the wait list.
+    queued: bool,
+}
+
+impl<'a, M, T> Acquire<'a, M, T>
+where
+    M: RawMutex,
+    T: ?Sized,
+{
+    fn new(rwlock: &'a RwLock<M, T>, write: bool) -> Self {
+        Self {
+            rwlock,
+            node: WaitNode::new(write),
+            queued: false,
+        }
+    }
+}
+
+impl<'a, M, T> Future for Acquire<'a, M, T>
+where
+    M: RawMutex,
+    T: ?Sized,
+{
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        // Safety: `node` is never moved, and `queued` is not structurally pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        let node = unsafe { Pin::new_unchecked(&this.node) };
+        let write = node.value();
+
+        this.rwlock.state.lock(|s| {
+            let mut s = s.borrow_mut();
+            if this.queued {
+                if node.is_linked() {
+                    node.register(cx.waker());
+                    return Poll::Pending;
+                }
+                // The lock was granted to us.
+                this.queued = false;
+                return Poll::Ready(());
+            }
+
+            if s.available(write) {
+                s.take(write);
+                Poll::Ready(())
+            } else {
+                node.register(cx.waker());
+                // Safety: the node is pinned, and removed from the list in `drop`.
+                unsafe { s.waiters.push_back(node) };
+                this.queued = true;
+                Poll::Pending
+            }
+        })
+    }
+}
+
+impl<'a, M, T> Drop for Acquire<'a, M, T>
+where
+    M: RawMutex,
+    T: ?Sized,
+{
+    fn drop(&mut self) {
+        if self.queued {
+            self.rwlock.state.lock(|s| {
+                let mut s = unwrap!(s.try_borrow_mut());
+                if self.node.is_linked() {
+                    // The waiters behind us may be able to take the lock now.
+                    s.waiters.remove(&self.node);
+                    s.wake();
+                } else {
+                    // The lock was granted to us, but we don't want it anymore.
+                    s.release(self.node.value());
+                }
+            })
+        }
+    }
+}
+
 /// Async read lock guard.
 ///
 /// Owning an instance of this type indicates having
@@ -227,13 +402,7 @@ where
     T: ?Sized,
 {
     fn drop(&mut self) {
-        self.rwlock.state.lock(|s| {
-            let mut s = unwrap!(s.try_borrow_mut());
-            s.readers -= 1;
-            if s.readers == 0 {
-                s.waker.wake();
-            }
-        })
+        self.rwlock.state.lock(|s| unwrap!(s.try_borrow_mut()).release(false))
     }
 }
 
@@ -292,11 +461,7 @@ where
     T: ?Sized,
 {
     fn drop(&mut self) {
-        self.rwlock.state.lock(|s| {
-            let mut s = unwrap!(s.try_borrow_mut());
-            s.writer = false;
-            s.waker.wake();
-        })
+        self.rwlock.state.lock(|s| unwrap!(s.try_borrow_mut()).release(true))
     }
 }
 
@@ -347,6 +512,11 @@ where
 
 #[cfg(test)]
 mod tests {
+    use core::pin::pin;
+    use core::task::Poll;
+
+    use futures_util::poll;
+
     use crate::blocking_mutex::raw::NoopRawMutex;
     use crate::rwlock::RwLock;
 
@@ -384,4 +554,71 @@ mod tests {
 
         assert_eq!(*rwlock.read().await, [0, 2]);
     }
+
+    #[futures_test::test]
+    async fn fair_rwlock_readers_wait_for_queued_writer() {
+        let rwlock: RwLock<NoopRawMutex, u32> = RwLock::new(0);
+        let r1 = rwlock.read().await;
+
+        let mut w = pin!(rwlock.write_fair());
+        assert!(poll!(w.as_mut()).is_pending());
+
+        // A new reader doesn't overtake the waiting writer.
+        assert!(rwlock.try_read().is_err());
+        let mut r2 = pin!(rwlock.read_fair());
+        let mut r3 = pin!(rwlock.read_fair());
+        assert!(poll!(r2.as_mut()).is_pending());
+        assert!(poll!(r3.as_mut()).is_pending());
+
+        core::mem::drop(r1);
+        assert!(poll!(r2.as_mut()).is_pending());
+        let w = poll!(w.as_mut());
+        assert!(w.is_ready());
+
+        // All the readers queued behind the writer get the lock together.
+        core::mem::drop(w);
+        assert!(poll!(r3.as_mut()).is_ready());
+        assert!(poll!(r2.as_mut()).is_ready());
+    }
+
+    #[futures_test::test]
+    async fn fair_rwlock_cancelled_writer() {
+        let rwlock: RwLock<NoopRawMutex, u32> = RwLock::new(0);
+        let r1 = rwlock.read().await;
+
+        {
+            let mut w = pin!(rwlock.write_fair());
+            assert!(poll!(w.as_mut()).is_pending());
+            let mut r2 = pin!(rwlock.read_fair());
+            assert!(poll!(r2.as_mut()).is_pending());
+
+            // The reader behind the writer gets the lock when the writer gives up.
+            w.set(rwlock.write_fair());
+            assert!(poll!(r2.as_mut()).is_ready());
+        }
+
+        core::mem::drop(r1);
+        assert!(rwlock.try_write().is_ok());
+    }
+
+    #[futures_test::test]
+    async fn fair_waiters_go_first() {
+        let rwlock: RwLock<NoopRawMutex, u32> = RwLock::new(0);
+        let r1 = rwlock.read().await;
+
+        let mut w = pin!(rwlock.write_fair());
+        assert!(poll!(w.as_mut()).is_pending());
+
+        // `read()` futures are `Unpin`, and don't overtake the fair waiters either.
+        let mut r2 = rwlock.read();
+        assert!(poll!(&mut r2).is_pending());
+
+        core::mem::drop(r1);
+        assert!(poll!(&mut r2).is_pending());
+        let Poll::Ready(w) = poll!(w.as_mut()) else {
+            panic!("the fair writer should hold the lock");
+        };
+        core::mem::drop(w);
+        assert!(poll!(&mut r2).is_ready());
+    }
 }
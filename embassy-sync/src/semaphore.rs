@@ -2,13 +2,14 @@
 use core::cell::{Cell, RefCell};
 use core::convert::Infallible;
 use core::future::{poll_fn, Future};
-use core::task::{Poll, Waker};
+use core::pin::Pin;
+use core::task::{Context, Poll, Waker};
 
 use heapless::Deque;
 
 use crate::blocking_mutex::raw::RawMutex;
 use crate::blocking_mutex::Mutex;
-use crate::waitqueue::WakerRegistration;
+use crate::waitqueue::{WaitList, WaitNode, WakerRegistration};
 
 /// An asynchronous semaphore.
 ///
@@ -484,6 +485,238 @@ impl<const N: usize> FairSemaphoreState<N> {
     }
 }
 
+/// A fair [`Semaphore`] implementation, with no limit on the number of waiting tasks.
+///
+/// Like [`FairSemaphore`], tasks acquire permits in FIFO order: a task waiting to acquire
+/// a large number of permits will prevent other tasks from acquiring any permits until its
+/// request is satisfied. The wait list is stored in the futures of the waiting tasks instead
+/// of a fixed-size queue, so any number of tasks can wait.
+pub struct FifoSemaphore<M>
+where
+    M: RawMutex,
+{
+    state: Mutex<M, RefCell<FifoSemaphoreState>>,
+}
+
+impl<M> Default for FifoSemaphore<M>
+where
+    M: RawMutex,
+{
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl<M> FifoSemaphore<M>
+where
+    M: RawMutex,
+{
+    /// Create a new `FifoSemaphore`.
+    pub const fn new(permits: usize) -> Self {
+        Self {
+            state: Mutex::new(RefCell::new(FifoSemaphoreState {
+                permits,
+                waiters: WaitList::new(),
+            })),
+        }
+    }
+
+    #[cfg(test)]
+    fn permits(&self) -> usize {
+        self.state.lock(|cell| cell.borrow().permits)
+    }
+
+    fn try_acquire_impl(&self, permits: usize, acquire_all: bool) -> Option<SemaphoreReleaser<'_, Self>> {
+        self.state.lock(|cell| {
+            let mut state = cell.borrow_mut();
+            if !state.waiters.is_empty() {
+                return None;
+            }
+            let permits = state.take(permits, acquire_all)?;
+            Some(SemaphoreReleaser {
+                semaphore: self,
+                permits,
+            })
+        })
+    }
+}
+
+impl<M: RawMutex> Semaphore for FifoSemaphore<M> {
+    type Error = Infallible;
+
+    fn acquire(&self, permits: usize) -> impl Future<Output = Result<SemaphoreReleaser<'_, Self>, Self::Error>> {
+        FifoAcquire::new(self, permits, false)
+    }
+
+    fn try_acquire(&self, permits: usize) -> Option<SemaphoreReleaser<'_, Self>> {
+        self.try_acquire_impl(permits, false)
+    }
+
+    fn acquire_all(&self, min: usize) -> impl Future<Output = Result<SemaphoreReleaser<'_, Self>, Self::Error>> {
+        FifoAcquire::new(self, min, true)
+    }
+
+    fn try_acquire_all(&self, min: usize) -> Option<SemaphoreReleaser<'_, Self>> {
+        self.try_acquire_impl(min, true)
+    }
+
+    fn release(&self, permits: usize) {
+        if permits > 0 {
+            self.state.lock(|cell| {
+                let mut state = cell.borrow_mut();
+                state.permits += permits;
+                state.wake();
+            });
+        }
+    }
+
+    fn set(&self, permits: usize) {
+        self.state.lock(|cell| {
+            let mut state = cell.borrow_mut();
+            state.permits = permits;
+            state.wake();
+        });
+    }
+}
+
+/// Request of a task waiting on a [`FifoSemaphore`].
+#[derive(Clone, Copy)]
+struct FifoRequest {
+    permits: usize,
+    acquire_all: bool,
+    /// The number of permits acquired for the task, once its request is satisfied.
+    granted: usize,
+}
+
+struct FifoAcquire<'a, M: RawMutex> {
+    sema: &'a FifoSemaphore<M>,
+    /// Node in the wait list of the semaphore.
+    node: WaitNode<FifoRequest>,
+    /// Whether `node` was added to the wait list.
+    queued: bool,
+}
+
+impl<'a, M: RawMutex> FifoAcquire<'a, M> {
+    fn new(sema: &'a FifoSemaphore<M>, permits: usize, acquire_all: bool) -> Self {
+        Self {
+            sema,
+            node: WaitNode::new(FifoRequest {
+                permits,
+                acquire_all,
+                granted: 0,
+            }),
+            queued: false,
+        }
+    }
+}
+
+impl<'a, M: RawMutex> Drop for FifoAcquire<'a, M> {
+    fn drop(&mut self) {
+        if self.queued {
+            self.sema.state.lock(|cell| {
+                let mut state = cell.borrow_mut();
+                if self.node.is_linked() {
+                    // The waiters behind us may be satisfied now.
+                    state.waiters.remove(&self.node);
+                } else {
+                    // The permits were acquired for us, but we don't want them anymore.
+                    state.permits += self.node.value().granted;
+                }
+                state.wake();
+            })
+        }
+    }
+}
+
+impl<'a, M: RawMutex> Future for FifoAcquire<'a, M> {
+    type Output = Result<SemaphoreReleaser<'a, FifoSemaphore<M>>, Infallible>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // Safety: `node` is never moved, and `queued` is not structurally pinned.
+        let this = unsafe { self.get_unchecked_mut() };
+        let node = unsafe { Pin::new_unchecked(&this.node) };
+        let sema = this.sema;
+        let request = node.value();
+
+        let permits = sema.state.lock(|cell| {
+            let mut state = cell.borrow_mut();
+            if this.queued {
+                if node.is_linked() {
+                    node.register(cx.waker());
+                    return None;
+                }
+                this.queued = false;
+                return Some(request.granted);
+            }
+
+            if state.waiters.is_empty() {
+                if let Some(permits) = state.take(request.permits, request.acquire_all) {
+                    return Some(permits);
+                }
+            }
+            node.register(cx.waker());
+            // Safety: the node is pinned, and removed from the list in `drop`.
+            unsafe { state.waiters.push_back(node) };
+            this.queued = true;
+            None
+        });
+
+        match permits {
+            Some(permits) => Poll::Ready(Ok(SemaphoreReleaser {
+                semaphore: sema,
+                permits,
+            })),
+            None => Poll::Pending,
+        }
+    }
+}
+
+struct FifoSemaphoreState {
+    permits: usize,
+    waiters: WaitList<FifoRequest>,
+}
+
+impl FifoSemaphoreState {
+    fn take(&mut self, mut permits: usize, acquire_all: bool) -> Option<usize> {
+        if self.permits < permits {
+            None
+        } else {
+            if acquire_all {
+                permits = self.permits;
+            }
+            self.permits -= permits;
+            Some(permits)
+        }
+    }
+
+    /// Satisfy the requests at the front of the wait list, in order.
+    fn wake(&mut self) {
+        loop {
+            let available = self.permits;
+            let mut granted = None;
+            self.waiters.pop_front_if(|node| {
+                let mut request = node.value();
+                if request.permits > available {
+                    return false;
+                }
+                request.granted = if request.acquire_all {
+                    available
+                } else {
+                    request.permits
+                };
+                node.set_value(request);
+                node.wake();
+                granted = Some(request.granted);
+                true
+            });
+            match granted {
+                Some(permits) => self.permits -= permits,
+                None => break,
+            }
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     mod greedy {
@@ -769,4 +1002,100 @@ mod tests {
             assert_eq!(c.permits(), 1);
         }
     }
+
+    mod fifo {
+        use core::pin::pin;
+
+        use futures_util::poll;
+
+        use super::super::*;
+        use crate::blocking_mutex::raw::NoopRawMutex;
+
+        #[test]
+        fn try_acquire() {
+            let semaphore = FifoSemaphore::<NoopRawMutex>::new(3);
+
+            let a = semaphore.try_acquire(1).unwrap();
+            assert_eq!(a.permits(), 1);
+            assert_eq!(semaphore.permits(), 2);
+
+            core::mem::drop(a);
+            assert_eq!(semaphore.permits(), 3);
+        }
+
+        #[futures_test::test]
+        async fn acquire_all() {
+            let semaphore = FifoSemaphore::<NoopRawMutex>::new(3);
+
+            let a = semaphore.acquire_all(1).await.unwrap();
+            assert_eq!(a.permits(), 3);
+            assert_eq!(semaphore.permits(), 0);
+        }
+
+        #[test]
+        fn set() {
+            let semaphore = FifoSemaphore::<NoopRawMutex>::new(3);
+            semaphore.set(2);
+            assert_eq!(semaphore.permits(), 2);
+        }
+
+        #[futures_test::test]
+        async fn fairness() {
+            let semaphore = FifoSemaphore::<NoopRawMutex>::new(3);
+
+            let a = semaphore.try_acquire(1);
+            assert!(a.is_some());
+
+            let mut b_fut = pin!(semaphore.acquire(3));
+            assert!(poll!(b_fut.as_mut()).is_pending());
+
+            // Any number of tasks can wait, in order.
+            let mut c_fut = pin!(semaphore.acquire(1));
+            let mut d_fut = pin!(semaphore.acquire(1));
+            let mut e_fut = pin!(semaphore.acquire_all(1));
+            assert!(poll!(c_fut.as_mut()).is_pending());
+            assert!(poll!(d_fut.as_mut()).is_pending());
+            assert!(poll!(e_fut.as_mut()).is_pending());
+            assert!(semaphore.try_acquire(1).is_none());
+
+            core::mem::drop(a);
+            assert!(poll!(c_fut.as_mut()).is_pending()); // `c` is still blocked behind `b`
+            let Poll::Ready(Ok(b)) = poll!(b_fut.as_mut()) else {
+                panic!("`b` should have acquired its permits");
+            };
+
+            core::mem::drop(b);
+            let Poll::Ready(Ok(e)) = poll!(e_fut.as_mut()) else {
+                panic!("`e` should have acquired its permits");
+            };
+            assert_eq!(e.permits(), 1);
+            assert!(poll!(c_fut.as_mut()).is_ready());
+            assert!(poll!(d_fut.as_mut()).is_ready());
+        }
+
+        #[futures_test::test]
+        async fn cancelled_waiter() {
+            let semaphore = FifoSemaphore::<NoopRawMutex>::new(1);
+
+            let mut a_fut = pin!(semaphore.acquire(2));
+            let mut b_fut = pin!(semaphore.acquire(1));
+            assert!(poll!(a_fut.as_mut()).is_pending());
+            assert!(poll!(b_fut.as_mut()).is_pending());
+
+            // `b` gets its permit when `a` gives up.
+            a_fut.set(semaphore.acquire(2));
+            let Poll::Ready(Ok(b)) = poll!(b_fut.as_mut()) else {
+                panic!("`b` should have acquired its permit");
+            };
+            assert_eq!(semaphore.permits(), 0);
+
+            // Permits acquired for a cancelled waiter are released.
+            assert!(poll!(a_fut.as_mut()).is_pending());
+            semaphore.release(1);
+            core::mem::drop(b);
+            assert_eq!(semaphore.permits(), 0);
+            a_fut.set(semaphore.acquire(2));
+            assert_eq!(semaphore.permits(), 2);
+        }
+    }
 }
@@ -0,0 +1,148 @@
+use core::cell::Cell;
+use core::marker::PhantomPinned;
+use core::pin::Pin;
+use core::ptr::NonNull;
+use core::task::Waker;
+
+type Link<T> = Option<NonNull<WaitNode<T>>>;
+
+/// Intrusive FIFO list of waiting tasks.
+///
+/// The nodes are stored in the futures of the waiting tasks, so the list can hold any number
+/// of waiters without a fixed-size buffer. Each node carries a value of type `T`, typically
+/// the request of the waiter and whether it was granted.
+///
+/// The list and its nodes must only be accessed while holding the lock of the primitive that
+/// owns the list. A node must be removed from the list before it's dropped.
+pub(crate) struct WaitList<T> {
+    head: Link<T>,
+    tail: Link<T>,
+}
+
+// Safety: the nodes are only accessed while holding the lock of the primitive owning the list.
+unsafe impl<T: Send> Send for WaitList<T> {}
+
+impl<T: Copy> WaitList<T> {
+    /// Create a new empty list.
+    pub const fn new() -> Self {
+        Self { head: None, tail: None }
+    }
+
+    /// Return whether no task is waiting.
+    pub fn is_empty(&self) -> bool {
+        self.head.is_none()
+    }
+
+    /// Append `node` at the end of the list.
+    ///
+    /// # Safety
+    ///
+    /// `node` must not be in a list already, and must be removed from this list before it's dropped.
+    pub unsafe fn push_back(&mut self, node: Pin<&WaitNode<T>>) {
+        let node = node.get_ref();
+        let ptr = NonNull::from(node);
+        node.prev.set(self.tail);
+        node.next.set(None);
+        node.linked.set(true);
+        match self.tail {
+            Some(tail) => tail.as_ref().next.set(Some(ptr)),
+            None => self.head = Some(ptr),
+        }
+        self.tail = Some(ptr);
+    }
+
+    /// Remove `node` from the list, if it's in it.
+    ///
+    /// `node` must not be in another list.
+    pub fn remove(&mut self, node: &WaitNode<T>) {
+        if !node.linked.get() {
+            return;
+        }
+        // Safety: linked nodes are alive, as they remove themselves before being dropped.
+        unsafe {
+            match node.prev.get() {
+                Some(prev) => prev.as_ref().next.set(node.next.get()),
+                None => self.head = node.next.get(),
+            }
+            match node.next.get() {
+                Some(next) => next.as_ref().prev.set(node.prev.get()),
+                None => self.tail = node.prev.get(),
+            }
+        }
+        node.prev.set(None);
+        node.next.set(None);
+        node.linked.set(false);
+    }
+
+    /// Call `f` with the first node of the list, and remove the node if `f` returns true.
+    ///
+    /// Returns false if the list is empty, or `f` returned false.
+    pub fn pop_front_if(&mut self, f: impl FnOnce(&WaitNode<T>) -> bool) -> bool {
+        let Some(head) = self.head else {
+            return false;
+        };
+        // Safety: linked nodes are alive, as they remove themselves before being dropped.
+        let head = unsafe { head.as_ref() };
+        if !f(head) {
+            return false;
+        }
+        self.remove(head);
+        true
+    }
+}
+
+/// Node of a [`WaitList`], stored in the future of a waiting task.
+pub(crate) struct WaitNode<T> {
+    waker: Cell<Option<Waker>>,
+    value: Cell<T>,
+    linked: Cell<bool>,
+    prev: Cell<Link<T>>,
+    next: Cell<Link<T>>,
+    _pin: PhantomPinned,
+}
+
+// Safety: the shared fields are only accessed while holding the lock of the primitive owning the list.
+unsafe impl<T: Send> Send for WaitNode<T> {}
+unsafe impl<T: Send> Sync for WaitNode<T> {}
+
+impl<T: Copy> WaitNode<T> {
+    /// Create a new node, not in any list.
+    pub const fn new(value: T) -> Self {
+        Self {
+            waker: Cell::new(None),
+            value: Cell::new(value),
+            linked: Cell::new(false),
+            prev: Cell::new(None),
+            next: Cell::new(None),
+            _pin: PhantomPinned,
+        }
+    }
+
+    /// Return whether the node is in a list.
+    pub fn is_linked(&self) -> bool {
+        self.linked.get()
+    }
+
+    pub fn value(&self) -> T {
+        self.value.get()
+    }
+
+    pub fn set_value(&self, value: T) {
+        self.value.set(value)
+    }
+
+    /// Register the waker of the waiting task.
+    pub fn register(&self, w: &Waker) {
+        match self.waker.take() {
+            Some(old) if old.will_wake(w) => self.waker.set(Some(old)),
+            _ => self.waker.set(Some(w.clone())),
+        }
+    }
+
+    /// Wake the waiting task.
+    pub fn wake(&self) {
+        if let Some(w) = self.waker.take() {
+            w.wake()
+        }
+    }
+}
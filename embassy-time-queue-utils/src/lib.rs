@@ -2,12 +2,19 @@
 #![doc = include_str!("../README.md")]
 #![warn(missing_docs)]
 
+#[cfg(all(feature = "_generic-queue", feature = "timer-wheel"))]
+compile_error!("The `timer-wheel` feature can't be enabled with a `generic-queue-*` feature.");
+
 #[cfg(feature = "_generic-queue")]
 pub mod queue_generic;
-#[cfg(not(feature = "_generic-queue"))]
+#[cfg(not(any(feature = "_generic-queue", feature = "timer-wheel")))]
 pub mod queue_integrated;
+#[cfg(all(feature = "timer-wheel", not(feature = "_generic-queue")))]
+pub mod queue_wheel;
 
 #[cfg(feature = "_generic-queue")]
 pub use queue_generic::Queue;
-#[cfg(not(feature = "_generic-queue"))]
+#[cfg(not(any(feature = "_generic-queue", feature = "timer-wheel")))]
 pub use queue_integrated::Queue;
+#[cfg(all(feature = "timer-wheel", not(feature = "_generic-queue")))]
+pub use queue_wheel::Queue;
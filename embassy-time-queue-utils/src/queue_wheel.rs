@@ -0,0 +1,482 @@
+//! Timer wheel queue, with items integrated into tasks.
+//!
+//! The timers are sorted in buckets by expiration time, in a hierarchy of [`LEVELS`] wheels of
+//! [`SLOTS`] buckets each. Level 0 has a bucket per unit of time, level 1 a bucket per
+//! [`SLOTS`] units, and so on. Inserting or removing a timer is O(1), regardless of the number of
+//! timers. When time reaches a bucket of an upper level, its timers are moved down to the lower
+//! levels, until they expire.
+//!
+//! The unit of time is a power of two number of ticks, of about a millisecond at most, depending
+//! on the tick rate of `embassy-time-driver`. Timers are still woken at their exact tick.
+//!
+//! The timers are linked in both directions, using the payload of the [`TimerQueueItem`] to
+//! store the previous timer.
+//!
+//! [`TimerQueueItem`]: embassy_executor::raw::timer_queue::TimerQueueItem
+
+use core::cell::Cell;
+use core::mem;
+use core::task::Waker;
+
+use embassy_executor::raw::TaskRef;
+use embassy_time_driver::TICK_HZ;
+
+/// Number of levels of the wheel.
+///
+/// Timers further in the future than the levels cover are kept in an overflow list.
+pub const LEVELS: usize = 4;
+/// Number of buckets in each level.
+pub const SLOTS: usize = 64;
+
+const SLOT_BITS: u32 = SLOTS.trailing_zeros();
+/// Units covered by the levels of the wheel.
+const HORIZON: u64 = 1 << (SLOT_BITS * LEVELS as u32);
+/// Index of the overflow list, after the buckets.
+const OVERFLOW: usize = LEVELS * SLOTS;
+
+/// Log2 of the number of ticks in a unit of time: a unit lasts at most a millisecond.
+const UNIT_SHIFT: u32 = if TICK_HZ >= 2000 { (TICK_HZ / 1000).ilog2() } else { 0 };
+
+const _: () = {
+    // The previous timer is stored in the payload of the timer queue item.
+    assert!(mem::size_of::<Cell<Option<TaskRef>>>() <= 8);
+    assert!(mem::align_of::<Cell<Option<TaskRef>>>() <= 8);
+};
+
+/// A hierarchical timer wheel, with items integrated into tasks.
+pub struct Queue {
+    buckets: [[Option<TaskRef>; SLOTS]; LEVELS],
+    /// Bitmap of the non-empty buckets of each level.
+    occupied: [u64; LEVELS],
+    overflow: Option<TaskRef>,
+    /// Start of the epoch of the earliest timer of the overflow list, in units.
+    ///
+    /// Removing that timer leaves it too early, which only causes a spurious alarm.
+    overflow_start: u64,
+    /// Time up to which the wheel has been processed, in units.
+    elapsed: u64,
+}
+
+impl Queue {
+    /// Creates a new timer queue.
+    pub const fn new() -> Self {
+        Self {
+            buckets: [[None; SLOTS]; LEVELS],
+            occupied: [0; LEVELS],
+            overflow: None,
+            overflow_start: u64::MAX,
+            elapsed: 0,
+        }
+    }
+
+    /// Schedules a task to run at a specific time.
+    ///
+    /// If this function returns `true`, the called should find the next expiration time and set
+    /// a new alarm for that time.
+    pub fn schedule_wake(&mut self, at: u64, waker: &Waker) -> bool {
+        let task = embassy_executor::raw::task_from_waker(waker);
+        let item = task.timer_queue_item();
+        // Timers in the past expire in the current bucket.
+        let at = at.max(self.elapsed << UNIT_SHIFT);
+        if item.next.get().is_none() {
+            if at == u64::MAX {
+                // The timer never expires.
+                return false;
+            }
+            item.expires_at.set(at);
+            self.insert(task);
+            true
+        } else if at <= item.expires_at.get() {
+            // If expiration is sooner than previously set, move it to its new bucket.
+            self.remove(task);
+            item.expires_at.set(at);
+            self.insert(task);
+            true
+        } else {
+            // Task does not need to be updated.
+            false
+        }
+    }
+
+    /// Dequeues expired timers and returns the next alarm time.
+    pub fn next_expiration(&mut self, now: u64) -> u64 {
+        let now_unit = now >> UNIT_SHIFT;
+
+        while let Some((bucket, start)) = self.next_bucket() {
+            if start > now_unit {
+                break;
+            }
+            self.elapsed = start;
+
+            // Wake the expired timers of the bucket, and move the others down the wheel.
+            let mut next = self.take(bucket);
+            while let Some(task) = next {
+                let item = task.timer_queue_item();
+                next = Self::next_of(task);
+                item.next.set(None);
+                prev_of(task).set(None);
+                if item.expires_at.get() <= now {
+                    embassy_executor::raw::wake_task(task);
+                } else {
+                    self.insert(task);
+                }
+            }
+
+            if bucket < SLOTS && start == now_unit {
+                // The timers left in the current bucket expire later in this unit.
+                break;
+            }
+        }
+
+        match self.next_bucket() {
+            Some((bucket, _)) if bucket < SLOTS => {
+                let mut next_expiration = u64::MAX;
+                let mut next = self.buckets[0][bucket];
+                while let Some(task) = next {
+                    next_expiration = next_expiration.min(task.timer_queue_item().expires_at.get());
+                    next = Self::next_of(task);
+                }
+                next_expiration
+            }
+            // Wake up at the start of the bucket, to move its timers down the wheel.
+            Some((_, start)) => start << UNIT_SHIFT,
+            None => {
+                self.elapsed = self.elapsed.max(now_unit);
+                u64::MAX
+            }
+        }
+    }
+
+    /// Returns the earliest non-empty bucket, with the time it starts at, in units.
+    fn next_bucket(&self) -> Option<(usize, u64)> {
+        for (level, &occupied) in self.occupied.iter().enumerate() {
+            if occupied == 0 {
+                continue;
+            }
+            let shift = level as u32 * SLOT_BITS;
+            let pos = ((self.elapsed >> shift) as usize) % SLOTS;
+            let slot = (pos + occupied.rotate_right(pos as u32).trailing_zeros() as usize) % SLOTS;
+
+            let level_range = 1u64 << (shift + SLOT_BITS);
+            let mut start = (self.elapsed & !(level_range - 1)) + ((slot as u64) << shift);
+            if slot < pos {
+                start += level_range;
+            }
+            return Some((level * SLOTS + slot, start));
+        }
+
+        self.overflow.map(|_| (OVERFLOW, self.overflow_start))
+    }
+
+    /// Returns the bucket of a timer expiring at `when`, in units.
+    ///
+    /// This doesn't change until the wheel reaches the bucket, as `elapsed` only moves to the
+    /// start of the earliest bucket.
+    fn bucket_for(&self, when: u64) -> usize {
+        let masked = (self.elapsed ^ when) | (SLOTS as u64 - 1);
+        if masked >= HORIZON {
+            return OVERFLOW;
+        }
+        let level = ((63 - masked.leading_zeros()) / SLOT_BITS) as usize;
+        let slot = ((when >> (level as u32 * SLOT_BITS)) as usize) % SLOTS;
+        level * SLOTS + slot
+    }
+
+    fn head(&mut self, bucket: usize) -> &mut Option<TaskRef> {
+        if bucket == OVERFLOW {
+            &mut self.overflow
+        } else {
+            &mut self.buckets[bucket / SLOTS][bucket % SLOTS]
+        }
+    }
+
+    fn set_head(&mut self, bucket: usize, head: Option<TaskRef>) {
+        *self.head(bucket) = head;
+        if bucket == OVERFLOW {
+            if head.is_none() {
+                self.overflow_start = u64::MAX;
+            }
+        } else {
+            let bit = 1 << (bucket % SLOTS);
+            match head {
+                Some(_) => self.occupied[bucket / SLOTS] |= bit,
+                None => self.occupied[bucket / SLOTS] &= !bit,
+            }
+        }
+    }
+
+    /// Empties a bucket, returning its first timer.
+    fn take(&mut self, bucket: usize) -> Option<TaskRef> {
+        let head = *self.head(bucket);
+        self.set_head(bucket, None);
+        head
+    }
+
+    /// Adds a timer at the front of its bucket.
+    fn insert(&mut self, task: TaskRef) {
+        let when = task.timer_queue_item().expires_at.get() >> UNIT_SHIFT;
+        let bucket = self.bucket_for(when);
+        if bucket == OVERFLOW {
+            self.overflow_start = self.overflow_start.min(when & !(HORIZON - 1));
+        }
+        let head = *self.head(bucket);
+
+        task.timer_queue_item()
+            .next
+            .set(Some(head.unwrap_or(unsafe { TaskRef::dangling() })));
+        prev_of(task).set(None);
+        if let Some(head) = head {
+            prev_of(head).set(Some(task));
+        }
+        self.set_head(bucket, Some(task));
+    }
+
+    /// Removes a timer from its bucket.
+    fn remove(&mut self, task: TaskRef) {
+        let item = task.timer_queue_item();
+        let next = Self::next_of(task);
+        let prev = prev_of(task).get();
+
+        match prev {
+            Some(prev) => prev.timer_queue_item().next.set(item.next.get()),
+            None => {
+                let bucket = self.bucket_for(item.expires_at.get() >> UNIT_SHIFT);
+                self.set_head(bucket, next);
+            }
+        }
+        if let Some(next) = next {
+            prev_of(next).set(prev);
+        }
+
+        item.next.set(None);
+        prev_of(task).set(None);
+    }
+
+    /// Returns the timer after `task` in its bucket.
+    fn next_of(task: TaskRef) -> Option<TaskRef> {
+        // The last timer of a bucket has a dangling `next`.
+        task.timer_queue_item()
+            .next
+            .get()
+            .filter(|next| unsafe { *next != TaskRef::dangling() })
+    }
+}
+
+/// Returns the previous timer of `task` in its bucket, or `None` if it's the first one.
+fn prev_of(task: TaskRef) -> &'static Cell<Option<TaskRef>> {
+    // safety: the payload is large and aligned enough, as checked above, and a zero-initialized
+    // `Option<TaskRef>` is `None`.
+    unsafe { task.timer_queue_item().payload.as_ref::<Cell<Option<TaskRef>>>() }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use core::future::poll_fn;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use core::task::Poll;
+    use std::boxed::Box;
+    use std::sync::Mutex;
+    use std::vec::Vec;
+
+    use embassy_executor::raw::{Executor, TaskStorage};
+
+    use super::*;
+
+    const UNIT: u64 = 1 << UNIT_SHIFT;
+    // A unit lasts at most a millisecond.
+    const _: () = assert!(UNIT * 1000 <= TICK_HZ || UNIT_SHIFT == 0);
+
+    #[export_name = "__pender"]
+    fn __pender(_context: *mut ()) {}
+
+    /// Tasks waiting for timers, and the time the queue has been run up to.
+    struct Timers {
+        executor: &'static Executor,
+        wakers: Vec<Waker>,
+        polls: Vec<&'static AtomicUsize>,
+        seen: Vec<usize>,
+        now: u64,
+    }
+
+    impl Timers {
+        fn new(count: usize) -> Self {
+            let executor = &*Box::leak(Box::new(Executor::new(core::ptr::null_mut())));
+            let mut polls = Vec::new();
+            let mut slots = Vec::new();
+            for _ in 0..count {
+                let count = &*Box::leak(Box::new(AtomicUsize::new(0)));
+                let slot = &*Box::leak(Box::new(Mutex::new(None)));
+                let storage = Box::leak(Box::new(TaskStorage::new()));
+                let token = storage.spawn(move || {
+                    poll_fn(move |cx| {
+                        count.fetch_add(1, Ordering::Relaxed);
+                        *slot.lock().unwrap() = Some(cx.waker().clone());
+                        Poll::<()>::Pending
+                    })
+                });
+                executor.spawner().spawn(token).unwrap();
+                polls.push(count);
+                slots.push(slot);
+            }
+            unsafe { executor.poll() };
+
+            Self {
+                executor,
+                wakers: slots.iter().map(|slot| slot.lock().unwrap().take().unwrap()).collect(),
+                seen: polls.iter().map(|polls| polls.load(Ordering::Relaxed)).collect(),
+                polls,
+                now: 0,
+            }
+        }
+
+        fn schedule(&self, queue: &mut Queue, timer: usize, at: u64) -> bool {
+            queue.schedule_wake(at, &self.wakers[timer])
+        }
+
+        /// Returns the timers woken since the last call.
+        fn woken(&mut self) -> Vec<usize> {
+            unsafe { self.executor.poll() };
+            let mut woken = Vec::new();
+            for (timer, polls) in self.polls.iter().enumerate() {
+                let polls = polls.load(Ordering::Relaxed);
+                if polls != self.seen[timer] {
+                    self.seen[timer] = polls;
+                    woken.push(timer);
+                }
+            }
+            woken
+        }
+
+        /// Follows the alarms of the queue up to `until`, returning the timers woken with the
+        /// time they were woken at, and the number of alarms.
+        fn run(&mut self, queue: &mut Queue, until: u64) -> (Vec<(usize, u64)>, usize) {
+            let mut woken = Vec::new();
+            let mut alarms = 0;
+            loop {
+                let alarm = queue.next_expiration(self.now);
+                woken.extend(self.woken().into_iter().map(|timer| (timer, self.now)));
+                if alarm > until {
+                    break;
+                }
+                assert!(alarm > self.now, "alarm at {} is not after {}", alarm, self.now);
+                self.now = alarm;
+                alarms += 1;
+            }
+            self.now = self.now.max(until);
+            (woken, alarms)
+        }
+    }
+
+    #[test]
+    fn exact_tick_within_unit() {
+        let mut queue = Queue::new();
+        let mut timers = Timers::new(3);
+        assert!(timers.schedule(&mut queue, 0, 3 * UNIT + UNIT - 1));
+        assert!(timers.schedule(&mut queue, 1, 3 * UNIT + UNIT / 2));
+        assert!(timers.schedule(&mut queue, 2, 5 * UNIT));
+
+        assert_eq!(queue.next_expiration(0), 3 * UNIT + UNIT / 2);
+        assert_eq!(
+            timers.run(&mut queue, u64::MAX - 1).0,
+            [(1, 3 * UNIT + UNIT / 2), (0, 3 * UNIT + UNIT - 1), (2, 5 * UNIT)]
+        );
+        assert_eq!(queue.next_expiration(timers.now), u64::MAX);
+    }
+
+    #[test]
+    fn cascade_across_levels() {
+        let mut queue = Queue::new();
+        let mut timers = Timers::new(LEVELS);
+        let mut deadlines = Vec::new();
+        for level in 0..LEVELS as u32 {
+            let at = ((5u64 << (level * SLOT_BITS)) + 3) * UNIT + 1;
+            assert_eq!(queue.bucket_for(at >> UNIT_SHIFT) / SLOTS, level as usize);
+            assert!(timers.schedule(&mut queue, level as usize, at));
+            deadlines.push((level as usize, at));
+        }
+
+        let (woken, alarms) = timers.run(&mut queue, u64::MAX - 1);
+        assert_eq!(woken, deadlines);
+        // Each timer is moved down a level at most once per level.
+        assert!(alarms <= LEVELS * (LEVELS + 1));
+        assert_eq!(queue.next_expiration(timers.now), u64::MAX);
+    }
+
+    #[test]
+    fn reschedule_earlier() {
+        let mut queue = Queue::new();
+        let mut timers = Timers::new(3);
+        for timer in 0..3 {
+            assert!(timers.schedule(&mut queue, timer, 1000 * UNIT + timer as u64));
+        }
+
+        // A later deadline is ignored.
+        assert!(!timers.schedule(&mut queue, 1, 2000 * UNIT));
+        // Moves the timer out of the middle of its bucket.
+        assert!(timers.schedule(&mut queue, 1, 10 * UNIT));
+        assert_eq!(queue.next_expiration(0), 10 * UNIT);
+
+        let (woken, _) = timers.run(&mut queue, u64::MAX - 1);
+        assert_eq!(woken, [(1, 10 * UNIT), (0, 1000 * UNIT), (2, 1000 * UNIT + 2)]);
+    }
+
+    #[test]
+    fn remove_last_timer_of_bucket() {
+        let mut queue = Queue::new();
+        let mut timers = Timers::new(2);
+        assert!(timers.schedule(&mut queue, 0, 100 * UNIT));
+        assert!(timers.schedule(&mut queue, 0, 50 * UNIT));
+        assert!(timers.schedule(&mut queue, 1, 70 * UNIT));
+
+        let (woken, _) = timers.run(&mut queue, u64::MAX - 1);
+        assert_eq!(woken, [(0, 50 * UNIT), (1, 70 * UNIT)]);
+        assert_eq!(queue.next_expiration(timers.now), u64::MAX);
+    }
+
+    #[test]
+    fn overflow_and_epoch_rollover() {
+        let mut queue = Queue::new();
+        let mut timers = Timers::new(3);
+        let first = (2 * HORIZON + 7) * UNIT;
+        let second = (3 * HORIZON + HORIZON / 2) * UNIT + 1;
+        let removed = (HORIZON + 1) * UNIT;
+        assert!(timers.schedule(&mut queue, 0, second));
+        assert!(timers.schedule(&mut queue, 1, first));
+        assert_eq!(queue.bucket_for(first >> UNIT_SHIFT), OVERFLOW);
+        assert_eq!(queue.next_expiration(0), 2 * HORIZON * UNIT);
+
+        // Removing the earliest timer of the overflow list only leaves a spurious alarm.
+        assert!(timers.schedule(&mut queue, 2, removed));
+        assert_eq!(queue.next_expiration(0), HORIZON * UNIT);
+        assert!(timers.schedule(&mut queue, 2, 10 * UNIT));
+
+        let (woken, _) = timers.run(&mut queue, first - 1);
+        assert_eq!(woken, [(2, 10 * UNIT)]);
+        let (woken, _) = timers.run(&mut queue, u64::MAX - 1);
+        assert_eq!(woken, [(1, first), (0, second)]);
+        assert_eq!(queue.next_expiration(timers.now), u64::MAX);
+    }
+
+    #[test]
+    fn deadline_in_the_past() {
+        let mut queue = Queue::new();
+        let mut timers = Timers::new(2);
+        timers.run(&mut queue, 1000 * UNIT + 1);
+        assert!(timers.schedule(&mut queue, 0, 5));
+        assert!(timers.schedule(&mut queue, 1, 0));
+
+        let mut woken = timers.run(&mut queue, timers.now).0;
+        woken.sort();
+        assert_eq!(woken, [(0, 1000 * UNIT + 1), (1, 1000 * UNIT + 1)]);
+    }
+
+    #[test]
+    fn never_expires() {
+        let mut queue = Queue::new();
+        let timers = Timers::new(1);
+        assert!(!timers.schedule(&mut queue, 0, u64::MAX));
+        assert_eq!(queue.next_expiration(0), u64::MAX);
+    }
+}
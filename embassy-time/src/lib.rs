@@ -13,6 +13,7 @@ pub(crate) mod fmt;
 mod delay;
 mod duration;
 mod instant;
+pub mod rtc;
 mod timer;
 
 #[cfg(feature = "mock-driver")]
@@ -0,0 +1,418 @@
+//! Calendar time, and a common interface to real time clocks.
+//!
+//! [`DateTime`] is a calendar date and time in UTC, which converts to and from Unix time. The
+//! [`Rtc`] trait is implemented by the real time clocks of the HALs, to read and set the
+//! calendar time, and to wait until a given date and time.
+
+use core::fmt;
+
+use crate::{Duration, Instant, Timer};
+
+/// Errors regarding the [`DateTime`] struct.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum DateTimeError {
+    /// The year is invalid. Must be between `1970..=9999`.
+    InvalidYear,
+    /// The month is invalid. Must be between `1..=12`.
+    InvalidMonth,
+    /// The day is invalid. Must be between `1` and the number of days of the month.
+    InvalidDay,
+    /// The hour is invalid. Must be between `0..=23`.
+    InvalidHour,
+    /// The minute is invalid. Must be between `0..=59`.
+    InvalidMinute,
+    /// The second is invalid. Must be between `0..=59`.
+    InvalidSecond,
+    /// The microsecond is invalid. Must be between `0..=999_999`.
+    InvalidMicrosecond,
+}
+
+/// A day of the week.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Ord, PartialOrd, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[allow(missing_docs)]
+pub enum DayOfWeek {
+    Monday = 1,
+    Tuesday = 2,
+    Wednesday = 3,
+    Thursday = 4,
+    Friday = 5,
+    Saturday = 6,
+    Sunday = 7,
+}
+
+impl DayOfWeek {
+    /// Get the day of week from its number, from 1 for Monday to 7 for Sunday.
+    pub const fn from_number_from_monday(n: u8) -> Option<Self> {
+        Some(match n {
+            1 => Self::Monday,
+            2 => Self::Tuesday,
+            3 => Self::Wednesday,
+            4 => Self::Thursday,
+            5 => Self::Friday,
+            6 => Self::Saturday,
+            7 => Self::Sunday,
+            _ => return None,
+        })
+    }
+
+    /// Get the number of the day of week, from 1 for Monday to 7 for Sunday.
+    pub const fn number_from_monday(self) -> u8 {
+        self as u8
+    }
+}
+
+const MIN_YEAR: u16 = 1970;
+const MAX_YEAR: u16 = 9999;
+const SECS_PER_DAY: u64 = 86_400;
+
+/// A calendar date and time, in UTC, with a microsecond resolution.
+///
+/// Dates from 1970 to 9999 are supported, so that they can be converted to Unix time.
+/// `DateTime`s are ordered chronologically.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DateTime {
+    year: u16,
+    month: u8,
+    day: u8,
+    hour: u8,
+    minute: u8,
+    second: u8,
+    microsecond: u32,
+}
+
+impl DateTime {
+    /// Unix epoch, `1970-01-01 00:00:00`.
+    pub const UNIX_EPOCH: DateTime = DateTime {
+        year: MIN_YEAR,
+        month: 1,
+        day: 1,
+        hour: 0,
+        minute: 0,
+        second: 0,
+        microsecond: 0,
+    };
+
+    /// Create a new DateTime, at the start of the given second.
+    pub const fn new(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> Result<Self, DateTimeError> {
+        if year < MIN_YEAR || year > MAX_YEAR {
+            Err(DateTimeError::InvalidYear)
+        } else if month < 1 || month > 12 {
+            Err(DateTimeError::InvalidMonth)
+        } else if day < 1 || day > days_in_month(year, month) {
+            Err(DateTimeError::InvalidDay)
+        } else if hour > 23 {
+            Err(DateTimeError::InvalidHour)
+        } else if minute > 59 {
+            Err(DateTimeError::InvalidMinute)
+        } else if second > 59 {
+            Err(DateTimeError::InvalidSecond)
+        } else {
+            Ok(Self {
+                year,
+                month,
+                day,
+                hour,
+                minute,
+                second,
+                microsecond: 0,
+            })
+        }
+    }
+
+    /// Set the microsecond within the second.
+    pub const fn with_microsecond(mut self, microsecond: u32) -> Result<Self, DateTimeError> {
+        if microsecond > 999_999 {
+            return Err(DateTimeError::InvalidMicrosecond);
+        }
+        self.microsecond = microsecond;
+        Ok(self)
+    }
+
+    /// Get the year (1970..=9999)
+    pub const fn year(&self) -> u16 {
+        self.year
+    }
+
+    /// Get the month (1..=12, 1 is January)
+    pub const fn month(&self) -> u8 {
+        self.month
+    }
+
+    /// Get the day (1..=31)
+    pub const fn day(&self) -> u8 {
+        self.day
+    }
+
+    /// Get the hour (0..=23)
+    pub const fn hour(&self) -> u8 {
+        self.hour
+    }
+
+    /// Get the minute (0..=59)
+    pub const fn minute(&self) -> u8 {
+        self.minute
+    }
+
+    /// Get the second (0..=59)
+    pub const fn second(&self) -> u8 {
+        self.second
+    }
+
+    /// Get the microsecond (0..=999_999)
+    pub const fn microsecond(&self) -> u32 {
+        self.microsecond
+    }
+
+    /// Get the day of week.
+    pub const fn day_of_week(&self) -> DayOfWeek {
+        // 1970-01-01 was a Thursday.
+        let n = (days_from_civil(self.year, self.month, self.day) + 3) % 7;
+        match n {
+            0 => DayOfWeek::Monday,
+            1 => DayOfWeek::Tuesday,
+            2 => DayOfWeek::Wednesday,
+            3 => DayOfWeek::Thursday,
+            4 => DayOfWeek::Friday,
+            5 => DayOfWeek::Saturday,
+            _ => DayOfWeek::Sunday,
+        }
+    }
+
+    /// Create a DateTime from a Unix timestamp, in seconds since `1970-01-01 00:00:00`.
+    pub const fn from_unix_timestamp(secs: u64) -> Result<Self, DateTimeError> {
+        let (year, month, day) = civil_from_days(secs / SECS_PER_DAY);
+        if year > MAX_YEAR as u64 {
+            return Err(DateTimeError::InvalidYear);
+        }
+        let secs = secs % SECS_PER_DAY;
+        Ok(Self {
+            year: year as u16,
+            month,
+            day,
+            hour: (secs / 3600) as u8,
+            minute: (secs / 60 % 60) as u8,
+            second: (secs % 60) as u8,
+            microsecond: 0,
+        })
+    }
+
+    /// Get the Unix timestamp, in seconds since `1970-01-01 00:00:00`.
+    pub const fn unix_timestamp(&self) -> u64 {
+        days_from_civil(self.year, self.month, self.day) * SECS_PER_DAY
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+
+    const fn unix_timestamp_micros(&self) -> u64 {
+        self.unix_timestamp() * 1_000_000 + self.microsecond as u64
+    }
+
+    const fn from_unix_timestamp_micros(micros: u64) -> Result<Self, DateTimeError> {
+        match Self::from_unix_timestamp(micros / 1_000_000) {
+            Ok(dt) => dt.with_microsecond((micros % 1_000_000) as u32),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Adds a Duration to self, returning `None` if the result is out of the supported range.
+    ///
+    /// The duration is rounded down to the microsecond.
+    pub fn checked_add(&self, duration: Duration) -> Option<Self> {
+        let micros = self.unix_timestamp_micros().checked_add(duration.as_micros())?;
+        Self::from_unix_timestamp_micros(micros).ok()
+    }
+
+    /// Subtracts a Duration from self, returning `None` if the result is out of the supported range.
+    ///
+    /// The duration is rounded down to the microsecond.
+    pub fn checked_sub(&self, duration: Duration) -> Option<Self> {
+        let micros = self.unix_timestamp_micros().checked_sub(duration.as_micros())?;
+        Self::from_unix_timestamp_micros(micros).ok()
+    }
+
+    /// Duration between this DateTime and an earlier one, or `None` if `earlier` is later than self.
+    pub fn checked_duration_since(&self, earlier: &DateTime) -> Option<Duration> {
+        let micros = self
+            .unix_timestamp_micros()
+            .checked_sub(earlier.unix_timestamp_micros())?;
+        Some(Duration::from_micros(micros))
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:06}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second, self.microsecond
+        )
+    }
+}
+
+const fn is_leap_year(year: u16) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || year % 400 == 0
+}
+
+const fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 of a date, which must be valid and not before 1970.
+///
+/// See <http://howardhinnant.github.io/date_algorithms.html#days_from_civil>.
+const fn days_from_civil(year: u16, month: u8, day: u8) -> u64 {
+    let y = year as u64 - if month <= 2 { 1 } else { 0 };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let mp = (month as u64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as u64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Date of a number of days since 1970-01-01, as `(year, month, day)`.
+///
+/// See <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>.
+const fn civil_from_days(days: u64) -> (u64, u8, u8) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u8;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u8;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// A real time clock, keeping the calendar time.
+///
+/// The provided methods relate the calendar time to the [`Instant`]s of `embassy-time`, by
+/// reading both clocks. [`wait_until`](Self::wait_until) relies on a [`Timer`], so it works with
+/// any real time clock, but implementations may use a hardware alarm instead.
+pub trait Rtc {
+    /// Error type of the real time clock.
+    type Error: fmt::Debug;
+
+    /// Return the current calendar time.
+    fn now(&mut self) -> Result<DateTime, Self::Error>;
+
+    /// Set the calendar time.
+    fn set_datetime(&mut self, datetime: DateTime) -> Result<(), Self::Error>;
+
+    /// Return the [`Instant`] at which the real time clock will reach `datetime`.
+    ///
+    /// The result is only as accurate as the real time clock and the time driver agree: they
+    /// usually run from different oscillators, so it should be recomputed for distant dates.
+    /// Dates before boot give [`Instant::MIN`].
+    fn instant_at(&mut self, datetime: &DateTime) -> Result<Instant, Self::Error> {
+        let now = self.now()?;
+        let instant = Instant::now();
+        Ok(match datetime.checked_duration_since(&now) {
+            Some(until) => instant.saturating_add(until),
+            None => instant.saturating_sub(unwrap!(now.checked_duration_since(datetime))),
+        })
+    }
+
+    /// Wait until the real time clock reaches `datetime`.
+    ///
+    /// Returns immediately if `datetime` is in the past.
+    async fn wait_until(&mut self, datetime: DateTime) -> Result<(), Self::Error> {
+        loop {
+            let now = self.now()?;
+            let Some(until) = datetime.checked_duration_since(&now) else {
+                return Ok(());
+            };
+            if until == Duration::from_ticks(0) {
+                return Ok(());
+            }
+            // The clocks may drift apart, so check the real time clock again after waiting.
+            Timer::after(until).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unix_timestamp() {
+        assert_eq!(DateTime::UNIX_EPOCH.unix_timestamp(), 0);
+        assert_eq!(DateTime::from_unix_timestamp(0), Ok(DateTime::UNIX_EPOCH));
+
+        let dt = DateTime::new(2024, 2, 29, 13, 37, 42).unwrap();
+        assert_eq!(dt.unix_timestamp(), 1_709_213_862);
+        assert_eq!(DateTime::from_unix_timestamp(1_709_213_862), Ok(dt));
+
+        let dt = DateTime::new(9999, 12, 31, 23, 59, 59).unwrap();
+        assert_eq!(DateTime::from_unix_timestamp(dt.unix_timestamp()), Ok(dt));
+        assert_eq!(
+            DateTime::from_unix_timestamp(dt.unix_timestamp() + 1),
+            Err(DateTimeError::InvalidYear)
+        );
+
+        // Every day of a few centuries round-trips.
+        let mut prev = DateTime::UNIX_EPOCH;
+        for days in 1..(200 * 366) {
+            let dt = DateTime::from_unix_timestamp(days * SECS_PER_DAY).unwrap();
+            assert!(dt > prev);
+            assert_eq!(dt.unix_timestamp(), days * SECS_PER_DAY);
+            prev = dt;
+        }
+    }
+
+    #[test]
+    fn validation() {
+        assert_eq!(DateTime::new(1969, 12, 31, 0, 0, 0), Err(DateTimeError::InvalidYear));
+        assert_eq!(DateTime::new(2023, 2, 29, 0, 0, 0), Err(DateTimeError::InvalidDay));
+        assert_eq!(DateTime::new(2100, 2, 29, 0, 0, 0), Err(DateTimeError::InvalidDay));
+        assert!(DateTime::new(2000, 2, 29, 0, 0, 0).is_ok());
+        assert_eq!(DateTime::new(2024, 4, 31, 0, 0, 0), Err(DateTimeError::InvalidDay));
+        assert_eq!(DateTime::new(2024, 13, 1, 0, 0, 0), Err(DateTimeError::InvalidMonth));
+        assert_eq!(DateTime::new(2024, 1, 1, 24, 0, 0), Err(DateTimeError::InvalidHour));
+        assert_eq!(
+            DateTime::UNIX_EPOCH.with_microsecond(1_000_000),
+            Err(DateTimeError::InvalidMicrosecond)
+        );
+    }
+
+    #[test]
+    fn day_of_week() {
+        assert_eq!(DateTime::UNIX_EPOCH.day_of_week(), DayOfWeek::Thursday);
+        assert_eq!(
+            DateTime::new(2024, 2, 29, 0, 0, 0).unwrap().day_of_week(),
+            DayOfWeek::Thursday
+        );
+        assert_eq!(
+            DateTime::new(2025, 6, 1, 0, 0, 0).unwrap().day_of_week(),
+            DayOfWeek::Sunday
+        );
+    }
+
+    #[test]
+    fn arithmetic() {
+        let dt = DateTime::new(2024, 12, 31, 23, 59, 59)
+            .unwrap()
+            .with_microsecond(500_000)
+            .unwrap();
+        let next = dt.checked_add(Duration::from_millis(500)).unwrap();
+        assert_eq!(next, DateTime::new(2025, 1, 1, 0, 0, 0).unwrap());
+        assert_eq!(next.checked_sub(Duration::from_millis(500)), Some(dt));
+        assert_eq!(next.checked_duration_since(&dt), Some(Duration::from_millis(500)));
+        assert_eq!(dt.checked_duration_since(&next), None);
+        assert_eq!(DateTime::UNIX_EPOCH.checked_sub(Duration::from_micros(1)), None);
+        assert_eq!(next.to_string(), "2025-01-01 00:00:00.000000");
+    }
+}
@@ -54,6 +54,7 @@ impl From<FirmwareUpdaterError> for Status {
             },
             FirmwareUpdaterError::Signature(_) => Status::ErrVerify,
             FirmwareUpdaterError::BadState => Status::ErrUnknown,
+            FirmwareUpdaterError::BadPatch => Status::ErrFile,
         }
     }
 }
@@ -0,0 +1,46 @@
+//! Encode a firmware update as a patch for `FirmwareUpdater::patch_writer` of embassy-boot.
+//!
+//! Run with `--active` set to the firmware running on the devices to update for a delta update,
+//! or without it for an update which is only compressed.
+
+use std::fs;
+
+use clap::Parser;
+use embassy_boot::PatchBuilder;
+use log::*;
+
+#[derive(Parser)]
+#[clap(version = "1.0")]
+struct Opts {
+    /// Firmware running on the devices to update
+    #[clap(long)]
+    active: Option<String>,
+    /// Firmware to update to
+    #[clap(long)]
+    firmware: String,
+    /// Output file for the patch
+    #[clap(long)]
+    output: String,
+}
+
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Info)
+        .format_timestamp_nanos()
+        .init();
+
+    let opts: Opts = Opts::parse();
+    let active = match &opts.active {
+        Some(path) => fs::read(path).unwrap(),
+        None => Vec::new(),
+    };
+    let firmware = fs::read(&opts.firmware).unwrap();
+
+    let patch = PatchBuilder::diff(&active, &firmware);
+    fs::write(&opts.output, patch.as_bytes()).unwrap();
+    info!(
+        "encoded {} bytes of firmware in a {} bytes patch",
+        firmware.len(),
+        patch.as_bytes().len()
+    );
+}
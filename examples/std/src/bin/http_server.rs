@@ -0,0 +1,110 @@
+use clap::Parser;
+use embassy_executor::{Executor, Spawner};
+use embassy_http_server::{Completed, Config as ServerConfig, Error, Handler, Method, Request, Router, Server, StatusCode};
+use embassy_net::{Config, Ipv4Address, Ipv4Cidr, StackResources};
+use embassy_net_tuntap::TunTapDevice;
+use embedded_io_async::{Read, Write};
+use heapless::Vec;
+use log::*;
+use rand_core::{OsRng, RngCore};
+use static_cell::StaticCell;
+
+#[derive(Parser)]
+#[clap(version = "1.0")]
+struct Opts {
+    /// TAP device name
+    #[clap(long, default_value = "tap0")]
+    tap: String,
+    /// use a static IP instead of DHCP
+    #[clap(long)]
+    static_ip: bool,
+}
+
+#[embassy_executor::task]
+async fn net_task(mut runner: embassy_net::Runner<'static, TunTapDevice>) -> ! {
+    runner.run().await
+}
+
+struct Index;
+
+impl Handler for Index {
+    async fn handle<C: Read + Write>(&self, request: &mut Request<'_, C>) -> Result<Completed, Error> {
+        request
+            .response()
+            .send(
+                StatusCode::OK,
+                &[("Content-Type", "text/html")],
+                b"<html><body><h1>Hello from embassy!</h1></body></html>",
+            )
+            .await
+    }
+}
+
+/// Echo the request body back to the client.
+struct Echo;
+
+impl Handler for Echo {
+    async fn handle<C: Read + Write>(&self, request: &mut Request<'_, C>) -> Result<Completed, Error> {
+        let mut buf = [0; 1024];
+        let body = request.body().read_to_end(&mut buf).await?;
+        request.response().send(StatusCode::OK, &[], body).await
+    }
+}
+
+#[embassy_executor::task]
+async fn main_task(spawner: Spawner) {
+    let opts: Opts = Opts::parse();
+
+    // Init network device
+    let device = TunTapDevice::new(&opts.tap).unwrap();
+
+    // Choose between dhcp or static ip
+    let config = if opts.static_ip {
+        Config::ipv4_static(embassy_net::StaticConfigV4 {
+            address: Ipv4Cidr::new(Ipv4Address::new(192, 168, 69, 2), 24),
+            dns_servers: Vec::new(),
+            gateway: Some(Ipv4Address::new(192, 168, 69, 1)),
+        })
+    } else {
+        Config::dhcpv4(Default::default())
+    };
+
+    // Generate random seed
+    let mut seed = [0; 8];
+    OsRng.fill_bytes(&mut seed);
+    let seed = u64::from_le_bytes(seed);
+
+    // Init network stack
+    static RESOURCES: StaticCell<StackResources<3>> = StaticCell::new();
+    let (stack, runner) = embassy_net::new(device, config, RESOURCES.init(StackResources::new()), seed);
+
+    // Launch network task
+    spawner.spawn(net_task(runner)).unwrap();
+
+    let router = Router::new()
+        .route(Method::Get, "/", Index)
+        .route(Method::Post, "/echo", Echo);
+    let server = Server::new(router, ServerConfig::default());
+
+    let mut rx_buffer = [0; 4096];
+    let mut tx_buffer = [0; 4096];
+    let mut buf = [0; 1024];
+
+    info!("Listening on TCP:8080...");
+    server.run(stack, 8080, &mut rx_buffer, &mut tx_buffer, &mut buf).await;
+}
+
+static EXECUTOR: StaticCell<Executor> = StaticCell::new();
+
+fn main() {
+    env_logger::builder()
+        .filter_level(log::LevelFilter::Debug)
+        .filter_module("async_io", log::LevelFilter::Info)
+        .format_timestamp_nanos()
+        .init();
+
+    let executor = EXECUTOR.init(Executor::new());
+    executor.run(|spawner| {
+        spawner.spawn(main_task(spawner)).unwrap();
+    });
+}